@@ -34,10 +34,42 @@ fn run_bench(c: &mut Criterion, device: &Device) {
     group.finish();
 }
 
+// The attention products of a 7b model, 32 heads of size 128: the scores of a 512 tokens
+// prompt, then the scores of a single token over a 2048 positions context.
+fn run_attention_bench(c: &mut Criterion, device: &Device) {
+    let mut dtypes = vec![DType::F32, DType::F16];
+    if !device.is_cpu() || cfg!(any(feature = "mkl", feature = "accelerate")) {
+        dtypes.push(DType::BF16)
+    }
+    for (name, m, n) in [("prefill", 512, 512), ("decode", 1, 2048)] {
+        for &dtype in dtypes.iter() {
+            let q = Tensor::zeros((32, m, 128), dtype, device).unwrap();
+            let k = Tensor::zeros((32, n, 128), dtype, device).unwrap();
+            let flops = 32 * m * n * 128;
+
+            let bench_name = device.bench_name(format!("matmul_attn_{name}_{dtype:?}"));
+            let mut group = c.benchmark_group(bench_name);
+            group.throughput(Throughput::Bytes(flops as u64));
+            group.bench_function("iter", move |b| {
+                b.iter_custom(|iters| {
+                    let start = Instant::now();
+                    for _i in 0..iters {
+                        run(black_box(&q), black_box(&k));
+                    }
+                    device.sync().unwrap();
+                    start.elapsed()
+                })
+            });
+            group.finish();
+        }
+    }
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     let handler = BenchDeviceHandler::new().unwrap();
     for device in handler.devices {
         run_bench(c, &device);
+        run_attention_bench(c, &device);
     }
 }
 
@@ -2,6 +2,9 @@
 
 pub mod erf;
 pub mod kernels;
+pub mod numa;
+
+pub use numa::{set_numa_policy, NumaPolicy};
 
 #[allow(unused)]
 trait Cpu<const ARR: usize> {
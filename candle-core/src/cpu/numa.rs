@@ -0,0 +1,416 @@
+//! NUMA placement of the large cpu storages and pinning of the worker threads.
+//!
+//! On multi-socket machines the weights should live on the nodes of the threads that read them.
+//! [`set_numa_policy`] selects how the storages of the quantized tensors loaded from ggml/gguf
+//! files are placed, and [`pin_worker_threads`] pins the threads of the rayon pool used by the
+//! cpu ops to the matching cores. Both are no-ops on machines with a single NUMA node. Placing
+//! pages and pinning threads requires the `numa` feature on linux, otherwise the placement is
+//! only reported to the allocation observer, see [`set_allocation_observer`].
+use crate::{Error, Result};
+use std::sync::{Arc, Mutex};
+
+/// How the large cpu storages are spread over the NUMA nodes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumaPolicy {
+    /// Leave the placement to the operating system.
+    #[default]
+    Disabled,
+    /// Spread the pages over all the nodes, the worker threads are assigned to the nodes in
+    /// turn. This is `--numa distribute` in llama.cpp.
+    Interleave,
+    /// Allocate on a single node and only run the worker threads on its cpus.
+    BindNode(usize),
+    /// Follow the cpus the process is allowed to run on, e.g. as set with `numactl`: bind to
+    /// their node when they are all on the same one, interleave over their nodes otherwise.
+    Auto,
+}
+
+impl std::str::FromStr for NumaPolicy {
+    type Err = Error;
+
+    /// Parses the llama.cpp names, `distribute`, `isolate` which binds to the node the process
+    /// currently runs on, and `numactl`, as well as `disabled`, `interleave`, `auto`, and
+    /// `node=<n>`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disabled" => Ok(Self::Disabled),
+            "distribute" | "interleave" => Ok(Self::Interleave),
+            "numactl" | "auto" => Ok(Self::Auto),
+            "isolate" => Ok(Self::BindNode(current_node(&Topology::detect()))),
+            _ => match s.strip_prefix("node=").map(|n| n.parse::<usize>()) {
+                Some(Ok(node)) => Ok(Self::BindNode(node)),
+                _ => crate::bail!(
+                    "unknown numa policy {s:?}, expected distribute, isolate, numactl, disabled, or node=<n>"
+                ),
+            },
+        }
+    }
+}
+
+/// The NUMA nodes of the machine and their cpus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    nodes: Vec<(usize, Vec<usize>)>,
+}
+
+impl Topology {
+    /// Reads the nodes from `/sys/devices/system/node`, a machine without this information is
+    /// reported as a single node with all the cpus.
+    pub fn detect() -> Self {
+        let mut nodes = vec![];
+        if let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") {
+            for entry in entries.flatten() {
+                let name = entry.file_name();
+                let Some(id) = name.to_str().and_then(|n| n.strip_prefix("node")) else {
+                    continue;
+                };
+                let Ok(id) = id.parse::<usize>() else {
+                    continue;
+                };
+                let cpus = std::fs::read_to_string(entry.path().join("cpulist"))
+                    .ok()
+                    .and_then(|cpus| parse_cpulist(&cpus).ok());
+                match cpus {
+                    Some(cpus) if !cpus.is_empty() => nodes.push((id, cpus)),
+                    _ => {}
+                }
+            }
+        }
+        if nodes.is_empty() {
+            nodes.push((0, (0..crate::utils::get_num_threads()).collect()))
+        }
+        Self::new(nodes)
+    }
+
+    /// A topology from `(node id, cpus)` pairs.
+    pub fn new(mut nodes: Vec<(usize, Vec<usize>)>) -> Self {
+        nodes.sort();
+        Self { nodes }
+    }
+
+    pub fn nodes(&self) -> &[(usize, Vec<usize>)] {
+        &self.nodes
+    }
+
+    pub fn is_numa(&self) -> bool {
+        self.nodes.len() > 1
+    }
+
+    pub fn cpus(&self, node: usize) -> Option<&[usize]> {
+        self.nodes
+            .iter()
+            .find(|(id, _)| *id == node)
+            .map(|(_, cpus)| cpus.as_slice())
+    }
+
+    pub fn node_of_cpu(&self, cpu: usize) -> Option<usize> {
+        self.nodes
+            .iter()
+            .find(|(_, cpus)| cpus.contains(&cpu))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Parses a kernel cpu list such as `0-3,8-11`.
+pub fn parse_cpulist(s: &str) -> Result<Vec<usize>> {
+    let mut cpus = vec![];
+    for range in s.trim().split(',').filter(|r| !r.is_empty()) {
+        let parse = |v: &str| {
+            v.parse::<usize>()
+                .map_err(|_| Error::Msg(format!("invalid cpu list {s:?}")))
+        };
+        match range.split_once('-') {
+            None => cpus.push(parse(range)?),
+            Some((start, end)) => cpus.extend(parse(start)?..=parse(end)?),
+        }
+    }
+    Ok(cpus)
+}
+
+/// Where the pages of an allocation go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placement {
+    Interleave(Vec<usize>),
+    Bind(usize),
+}
+
+impl NumaPolicy {
+    /// The placement of the large storages, `None` when the policy is disabled or the machine
+    /// has a single node. `allowed_cpus` are the cpus the process may run on, only used by
+    /// [`Self::Auto`].
+    pub fn placement(&self, topology: &Topology, allowed_cpus: &[usize]) -> Option<Placement> {
+        if !topology.is_numa() {
+            return None;
+        }
+        match self {
+            Self::Disabled => None,
+            Self::Interleave => Some(Placement::Interleave(
+                topology.nodes.iter().map(|(id, _)| *id).collect(),
+            )),
+            Self::BindNode(node) => Some(Placement::Bind(*node)),
+            Self::Auto => {
+                let mut nodes = allowed_cpus
+                    .iter()
+                    .filter_map(|&cpu| topology.node_of_cpu(cpu))
+                    .collect::<Vec<_>>();
+                nodes.sort();
+                nodes.dedup();
+                match nodes.as_slice() {
+                    [] => None,
+                    [node] => Some(Placement::Bind(*node)),
+                    _ => Some(Placement::Interleave(nodes)),
+                }
+            }
+        }
+    }
+}
+
+impl Placement {
+    /// The cpus the worker thread `index` gets pinned to: all the cpus of the node when binding,
+    /// the cpus of the nodes in turn when interleaving.
+    pub fn worker_cpus(&self, topology: &Topology, index: usize) -> Vec<usize> {
+        let node = match self {
+            Self::Bind(node) => *node,
+            Self::Interleave(nodes) if nodes.is_empty() => return vec![],
+            Self::Interleave(nodes) => nodes[index % nodes.len()],
+        };
+        topology.cpus(node).map(|c| c.to_vec()).unwrap_or_default()
+    }
+
+    /// The number of cpus covered by the placement.
+    pub fn n_cpus(&self, topology: &Topology) -> usize {
+        let count = |node: &usize| topology.cpus(*node).map_or(0, |c| c.len());
+        match self {
+            Self::Bind(node) => count(node),
+            Self::Interleave(nodes) => nodes.iter().map(count).sum(),
+        }
+    }
+}
+
+/// An allocation that the current policy placed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationHint {
+    pub bytes: usize,
+    pub placement: Placement,
+    /// Whether the pages were actually placed, this requires the `numa` feature on linux.
+    pub applied: bool,
+}
+
+/// A callback for the allocations placed by the policy.
+pub type AllocationObserver = Box<dyn Fn(&AllocationHint) + Send + Sync>;
+
+type Observer = Arc<dyn Fn(&AllocationHint) + Send + Sync>;
+
+struct State {
+    policy: NumaPolicy,
+    topology: Option<Topology>,
+    placement: Option<Placement>,
+    observer: Option<Observer>,
+}
+
+static STATE: Mutex<State> = Mutex::new(State {
+    policy: NumaPolicy::Disabled,
+    topology: None,
+    placement: None,
+    observer: None,
+});
+
+fn state() -> std::sync::MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The allocations smaller than this are left to the allocator, they do not span enough pages
+/// to matter.
+pub const MIN_PLACED_BYTES: usize = 1 << 20;
+
+/// Whether pages can be placed and threads pinned in this build.
+pub fn is_supported() -> bool {
+    cfg!(all(feature = "numa", target_os = "linux"))
+}
+
+/// Sets the policy for the following allocations, this fails if the policy binds to a node that
+/// does not exist.
+pub fn set_numa_policy(policy: NumaPolicy) -> Result<()> {
+    set_numa_policy_with_topology(policy, Topology::detect())
+}
+
+/// Like [`set_numa_policy`] with a given topology rather than the one of the machine.
+#[doc(hidden)]
+pub fn set_numa_policy_with_topology(policy: NumaPolicy, topology: Topology) -> Result<()> {
+    if let NumaPolicy::BindNode(node) = policy {
+        if topology.is_numa() && topology.cpus(node).is_none() {
+            crate::bail!("no numa node {node}, the nodes are {:?}", topology.nodes)
+        }
+    }
+    let placement = policy.placement(&topology, &allowed_cpus(&topology));
+    let mut state = state();
+    state.policy = policy;
+    state.topology = Some(topology);
+    state.placement = placement;
+    Ok(())
+}
+
+pub fn numa_policy() -> NumaPolicy {
+    state().policy
+}
+
+/// The placement of the large allocations under the current policy.
+pub fn placement() -> Option<Placement> {
+    state().placement.clone()
+}
+
+/// Calls `observer` for each allocation placed by the policy, e.g. for logging.
+pub fn set_allocation_observer(observer: Option<AllocationObserver>) {
+    state().observer = observer.map(Arc::from)
+}
+
+/// Copies `data` to a new vector whose pages follow the current policy.
+pub(crate) fn to_placed_vec<T: Clone>(data: &[T]) -> Vec<T> {
+    let bytes = std::mem::size_of_val(data);
+    let (placement, observer) = {
+        let state = state();
+        match &state.placement {
+            Some(placement) if bytes >= MIN_PLACED_BYTES => {
+                (placement.clone(), state.observer.clone())
+            }
+            _ => return data.to_vec(),
+        }
+    };
+    let mut vec = Vec::with_capacity(data.len());
+    // The pages are placed before being touched by the copy.
+    let applied = sys::mbind(vec.as_ptr() as *const u8, bytes, &placement);
+    vec.extend_from_slice(data);
+    if let Some(observer) = observer {
+        observer(&AllocationHint {
+            bytes,
+            placement,
+            applied,
+        })
+    }
+    vec
+}
+
+/// Builds the global rayon pool with its threads pinned according to the current policy, this
+/// must run before any cpu op uses the pool. The pool gets one thread per cpu of the placement
+/// unless `RAYON_NUM_THREADS` is set. This does nothing when the policy does not place the
+/// allocations.
+pub fn pin_worker_threads() -> Result<()> {
+    let (placement, topology) = {
+        let state = state();
+        match (&state.placement, &state.topology) {
+            (Some(placement), Some(topology)) => (placement.clone(), topology.clone()),
+            _ => return Ok(()),
+        }
+    };
+    let n_threads = match std::env::var("RAYON_NUM_THREADS") {
+        Ok(_) => crate::utils::get_num_threads(),
+        Err(_) => placement.n_cpus(&topology).max(1),
+    };
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(n_threads)
+        .start_handler(move |index| {
+            sys::pin_current_thread(&placement.worker_cpus(&topology, index));
+        })
+        .build_global()
+        .map_err(|e| Error::Msg(format!("cannot pin the worker threads: {e}")))
+}
+
+fn allowed_cpus(topology: &Topology) -> Vec<usize> {
+    sys::allowed_cpus()
+        .unwrap_or_else(|| topology.nodes.iter().flat_map(|(_, c)| c.clone()).collect())
+}
+
+fn current_node(topology: &Topology) -> usize {
+    sys::current_cpu()
+        .and_then(|cpu| topology.node_of_cpu(cpu))
+        .or_else(|| topology.nodes.first().map(|(id, _)| *id))
+        .unwrap_or(0)
+}
+
+#[cfg(all(feature = "numa", target_os = "linux"))]
+mod sys {
+    use super::Placement;
+
+    const MPOL_BIND: libc::c_long = 2;
+    const MPOL_INTERLEAVE: libc::c_long = 3;
+
+    // Applies the placement to the whole pages within `len` bytes from `ptr`.
+    pub(super) fn mbind(ptr: *const u8, len: usize, placement: &Placement) -> bool {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let start = (ptr as usize).next_multiple_of(page_size);
+        let end = (ptr as usize + len) / page_size * page_size;
+        if end <= start {
+            return false;
+        }
+        let (mode, nodes) = match placement {
+            Placement::Bind(node) => (MPOL_BIND, std::slice::from_ref(node)),
+            Placement::Interleave(nodes) => (MPOL_INTERLEAVE, nodes.as_slice()),
+        };
+        let bits = libc::c_ulong::BITS as usize;
+        let max_node = nodes.iter().max().copied().unwrap_or(0) + 1;
+        let mut mask = vec![0 as libc::c_ulong; max_node.div_ceil(bits)];
+        for &node in nodes {
+            mask[node / bits] |= 1 << (node % bits);
+        }
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                start,
+                end - start,
+                mode,
+                mask.as_ptr(),
+                mask.len() * bits + 1,
+                0,
+            )
+        };
+        res == 0
+    }
+
+    pub(super) fn pin_current_thread(cpus: &[usize]) {
+        if cpus.is_empty() {
+            return;
+        }
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            for &cpu in cpus {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set);
+        }
+    }
+
+    pub(super) fn allowed_cpus() -> Option<Vec<usize>> {
+        unsafe {
+            let mut set: libc::cpu_set_t = std::mem::zeroed();
+            if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+                return None;
+            }
+            let n = libc::CPU_SETSIZE as usize;
+            Some((0..n).filter(|&cpu| libc::CPU_ISSET(cpu, &set)).collect())
+        }
+    }
+
+    pub(super) fn current_cpu() -> Option<usize> {
+        let cpu = unsafe { libc::sched_getcpu() };
+        (cpu >= 0).then_some(cpu as usize)
+    }
+}
+
+#[cfg(not(all(feature = "numa", target_os = "linux")))]
+mod sys {
+    use super::Placement;
+
+    pub(super) fn mbind(_ptr: *const u8, _len: usize, _placement: &Placement) -> bool {
+        false
+    }
+
+    pub(super) fn pin_current_thread(_cpus: &[usize]) {}
+
+    pub(super) fn allowed_cpus() -> Option<Vec<usize>> {
+        None
+    }
+
+    pub(super) fn current_cpu() -> Option<usize> {
+        None
+    }
+}
@@ -0,0 +1,268 @@
+//! f16 and bf16 matmuls computed with a f32 blas gemm.
+//!
+//! The blas libraries do not all provide half precision gemms, `upcast_gemm` converts the
+//! operands of each product of a batch to f32, runs the f32 gemm so that the accumulation is
+//! done in f32, and rounds the result once when converting it back.
+#![cfg_attr(not(any(feature = "mkl", feature = "accelerate")), allow(dead_code))]
+use crate::{Result, WithDType};
+use half::slice::HalfFloatSliceExt;
+
+/// The signature of the f32 blas gemms, e.g. [`crate::mkl::sgemm`].
+pub(super) type Sgemm =
+    unsafe fn(u8, u8, i32, i32, i32, f32, &[f32], i32, &[f32], i32, f32, &mut [f32], i32);
+
+/// A column major gemm operand, `rows x cols` once `trans` is applied, the operands of two
+/// consecutive products of a batch are `skip` elements apart.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Operand {
+    pub trans: u8,
+    pub ld: usize,
+    pub rows: usize,
+    pub cols: usize,
+    pub skip: usize,
+}
+
+impl Operand {
+    // The number of elements from the first element of the operand to its last one.
+    fn len(&self) -> usize {
+        let (rows, cols) = match self.trans {
+            b'N' => (self.rows, self.cols),
+            _ => (self.cols, self.rows),
+        };
+        if rows == 0 || cols == 0 {
+            0
+        } else {
+            self.ld * (cols - 1) + rows
+        }
+    }
+
+    // Converts the operand of product `step` to f32, the conversion is skipped when all the
+    // products share the same operand.
+    fn convert<H>(&self, data: &[H], step: usize, dst: &mut [f32]) -> Result<()>
+    where
+        [H]: HalfFloatSliceExt,
+    {
+        if step > 0 && self.skip == 0 {
+            return Ok(());
+        }
+        let start = step * self.skip;
+        match data.get(start..start + dst.len()) {
+            Some(src) => src.convert_to_f32_slice(dst),
+            None => crate::bail!(
+                "matmul operand of {} elements at {start} is out of a buffer of {}",
+                dst.len(),
+                data.len()
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Computes the `batch` products `c = op(a) op(b)` where `op(a)` is `m x k` and `op(b)` is
+/// `k x n`, `c` holds the `m x n` column major results one after the other.
+#[allow(clippy::too_many_arguments)]
+pub(super) fn upcast_gemm<H>(
+    sgemm: Sgemm,
+    batch: usize,
+    (m, n, k): (usize, usize, usize),
+    a: &[H],
+    a_op: Operand,
+    b: &[H],
+    b_op: Operand,
+    c: &mut [H],
+) -> Result<()>
+where
+    H: WithDType,
+    [H]: HalfFloatSliceExt,
+{
+    let c_skip = m * n;
+    if c.len() < batch * c_skip {
+        crate::bail!("matmul output of {} elements for {batch} products", c.len())
+    }
+    let mut a32 = vec![0f32; a_op.len()];
+    let mut b32 = vec![0f32; b_op.len()];
+    let mut c32 = vec![0f32; c_skip];
+    for step in 0..batch {
+        a_op.convert(a, step, &mut a32)?;
+        b_op.convert(b, step, &mut b32)?;
+        unsafe {
+            sgemm(
+                a_op.trans,
+                b_op.trans,
+                /* m= */ m as i32,
+                /* n= */ n as i32,
+                /* k= */ k as i32,
+                /* alpha= */ 1.,
+                /* a= */ &a32,
+                /* lda= */ a_op.ld.max(1) as i32,
+                /* b= */ &b32,
+                /* ldb= */ b_op.ld.max(1) as i32,
+                /* beta= */ 0.,
+                /* c= */ &mut c32,
+                /* ldc= */ m.max(1) as i32,
+            )
+        }
+        c[step * c_skip..(step + 1) * c_skip].convert_from_f32_slice(&c32);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DType, Device, Tensor};
+    use half::{bf16, f16};
+
+    // A plain column major gemm with the blas calling convention.
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn reference_sgemm(
+        transa: u8,
+        transb: u8,
+        m: i32,
+        n: i32,
+        k: i32,
+        alpha: f32,
+        a: &[f32],
+        lda: i32,
+        b: &[f32],
+        ldb: i32,
+        beta: f32,
+        c: &mut [f32],
+        ldc: i32,
+    ) {
+        let (m, n, k, lda, ldb, ldc) = (
+            m as usize,
+            n as usize,
+            k as usize,
+            lda as usize,
+            ldb as usize,
+            ldc as usize,
+        );
+        let a_at = |i: usize, l: usize| match transa {
+            b'N' => a[i + l * lda],
+            _ => a[l + i * lda],
+        };
+        let b_at = |l: usize, j: usize| match transb {
+            b'N' => b[l + j * ldb],
+            _ => b[j + l * ldb],
+        };
+        for i in 0..m {
+            for j in 0..n {
+                let sum = (0..k).map(|l| a_at(i, l) * b_at(l, j)).sum::<f32>();
+                c[i + j * ldc] = alpha * sum + beta * c[i + j * ldc];
+            }
+        }
+    }
+
+    // `lhs @ rhs` for row major `(batch, m, k)` and `(batch, k, n)` tensors, with the same
+    // operand mapping as the blas matmul: the row major result is the column major `n x m`
+    // product of the rhs and the lhs. `rhs_t` passes the rhs as the transpose of a contiguous
+    // `(batch, n, k)` tensor.
+    fn matmul<H>(lhs: &Tensor, rhs: &Tensor, rhs_t: bool) -> Result<Vec<H>>
+    where
+        H: WithDType,
+        [H]: HalfFloatSliceExt,
+    {
+        let (batch, m, k) = lhs.dims3()?;
+        let n = rhs.dim(2)?;
+        let lhs_data = lhs.flatten_all()?.to_vec1::<H>()?;
+        let rhs_data = match rhs_t {
+            true => rhs.transpose(1, 2)?.contiguous()?,
+            false => rhs.contiguous()?,
+        };
+        let rhs_data = rhs_data.flatten_all()?.to_vec1::<H>()?;
+        let (trans, ld) = if rhs_t { (b'T', k) } else { (b'N', n) };
+        let a_op = Operand {
+            trans,
+            ld,
+            rows: n,
+            cols: k,
+            skip: n * k,
+        };
+        let b_op = Operand {
+            trans: b'N',
+            ld: k,
+            rows: k,
+            cols: m,
+            skip: m * k,
+        };
+        let mut dst = vec![H::zero(); batch * m * n];
+        upcast_gemm(
+            reference_sgemm,
+            batch,
+            (n, m, k),
+            &rhs_data,
+            a_op,
+            &lhs_data,
+            b_op,
+            &mut dst,
+        )?;
+        Ok(dst)
+    }
+
+    fn max_diff(a: &[f32], b: &[f32]) -> f32 {
+        a.iter()
+            .zip(b.iter())
+            .map(|(a, b)| (a - b).abs())
+            .fold(0., f32::max)
+    }
+
+    #[test]
+    fn f16_parity_with_gemm() -> Result<()> {
+        let dev = &Device::Cpu;
+        // Attention shaped products: scores then values, for a few heads.
+        for (batch, m, k, n) in [(4, 7, 32, 9), (2, 1, 64, 33), (3, 16, 16, 1)] {
+            let lhs = Tensor::randn(0f32, 1., (batch, m, k), dev)?.to_dtype(DType::F16)?;
+            let rhs = Tensor::randn(0f32, 1., (batch, k, n), dev)?.to_dtype(DType::F16)?;
+            // The gemm crate result, used when no blas feature is enabled.
+            let expected = lhs.matmul(&rhs)?.to_dtype(DType::F32)?;
+            let expected = expected.flatten_all()?.to_vec1::<f32>()?;
+            for rhs_t in [false, true] {
+                let dst = matmul::<f16>(&lhs, &rhs, rhs_t)?;
+                let dst = dst.iter().map(|v| v.to_f32()).collect::<Vec<_>>();
+                let diff = max_diff(&dst, &expected);
+                assert!(diff < 0.05, "{batch} {m} {k} {n} {rhs_t}: {diff}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn bf16_accumulates_in_f32() -> Result<()> {
+        let dev = &Device::Cpu;
+        let lhs = Tensor::randn(0f32, 1., (2, 5, 256), dev)?.to_dtype(DType::BF16)?;
+        let rhs = Tensor::randn(0f32, 1., (2, 256, 3), dev)?.to_dtype(DType::BF16)?;
+        // The same inputs in f32, the only difference is the final rounding to bf16.
+        let lhs32 = lhs.to_dtype(DType::F32)?;
+        let rhs32 = rhs.to_dtype(DType::F32)?;
+        let expected = lhs32.matmul(&rhs32)?.flatten_all()?.to_vec1::<f32>()?;
+        for rhs_t in [false, true] {
+            let dst = matmul::<bf16>(&lhs, &rhs, rhs_t)?;
+            for (v, e) in dst.iter().zip(expected.iter()) {
+                assert!((v.to_f32() - e).abs() <= e.abs() / 128. + 1e-3, "{v} {e}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shared_operand() -> Result<()> {
+        // A lhs broadcast over the batch is converted once.
+        let a = [1f32, 2., 3., 4.].map(f16::from_f32);
+        let b = [1f32, 0., 0., 1., 2., 0., 0., 2.].map(f16::from_f32);
+        let op = |skip| Operand {
+            trans: b'N',
+            ld: 2,
+            rows: 2,
+            cols: 2,
+            skip,
+        };
+        let mut c = [f16::ZERO; 8];
+        upcast_gemm(reference_sgemm, 2, (2, 2, 2), &a, op(0), &b, op(4), &mut c)?;
+        let c = c.map(|v| v.to_f32());
+        assert_eq!(c, [1., 2., 3., 4., 2., 4., 6., 8.]);
+        let mut c = [f16::ZERO; 8];
+        assert!(upcast_gemm(reference_sgemm, 2, (2, 2, 2), &a, op(4), &b, op(4), &mut c).is_err());
+        Ok(())
+    }
+}
@@ -5,6 +5,7 @@ use crate::{DType, Error, IntDType, Layout, Result, Shape, WithDType};
 use half::{bf16, f16};
 use rayon::prelude::*;
 
+mod half_gemm;
 mod utils;
 pub use utils::{
     binary_map, binary_map_vec, unary_map, unary_map_vec, Map1, Map1Any, Map2, Map2InPlace, Map2U8,
@@ -1302,6 +1303,54 @@ impl MatMul {
     }
 }
 
+impl MatMul {
+    // Runs a f16 or bf16 matmul with a f32 blas gemm, `H` has to be the dtype `T`. The
+    // operands use the same blas conventions as the f32 matmul: the rhs is the blas `a`.
+    #[cfg(any(feature = "mkl", feature = "accelerate"))]
+    #[allow(clippy::too_many_arguments)]
+    fn upcast_gemm<T: WithDType, H: WithDType>(
+        &self,
+        sgemm: half_gemm::Sgemm,
+        lhs: &[T],
+        rhs: &[T],
+        (a_skip, b_skip): (usize, usize),
+        (transa, lda): (u8, i32),
+        (transb, ldb): (u8, i32),
+        dst: &mut [T],
+    ) -> Result<()>
+    where
+        [H]: half::slice::HalfFloatSliceExt,
+    {
+        if T::DTYPE != H::DTYPE {
+            crate::bail!("unexpected dtype {:?} for {:?}", T::DTYPE, H::DTYPE)
+        }
+        let (b, m, n, k) = self.0;
+        // Safety: T and H are the same type as checked above.
+        let (lhs, rhs, dst) = unsafe {
+            (
+                std::slice::from_raw_parts(lhs.as_ptr() as *const H, lhs.len()),
+                std::slice::from_raw_parts(rhs.as_ptr() as *const H, rhs.len()),
+                std::slice::from_raw_parts_mut(dst.as_mut_ptr() as *mut H, dst.len()),
+            )
+        };
+        let a_op = half_gemm::Operand {
+            trans: transa,
+            ld: lda as usize,
+            rows: n,
+            cols: k,
+            skip: b_skip,
+        };
+        let b_op = half_gemm::Operand {
+            trans: transb,
+            ld: ldb as usize,
+            rows: k,
+            cols: m,
+            skip: a_skip,
+        };
+        half_gemm::upcast_gemm(sgemm, b, (n, m, k), rhs, a_op, lhs, b_op, dst)
+    }
+}
+
 impl Map2 for MatMul {
     const OP: &'static str = "mat_mul";
 
@@ -1429,9 +1478,24 @@ impl Map2 for MatMul {
 
         let mut dst = vec![T::zero(); b * m * n];
         match T::DTYPE {
-            DType::F16 => {
-                crate::bail!("the accelerate backend does not support f16 matmul")
-            }
+            DType::F16 => self.upcast_gemm::<T, f16>(
+                crate::accelerate::sgemm,
+                lhs,
+                rhs,
+                (a_skip, b_skip),
+                (transa, lda),
+                (transb, ldb),
+                &mut dst,
+            )?,
+            DType::BF16 => self.upcast_gemm::<T, bf16>(
+                crate::accelerate::sgemm,
+                lhs,
+                rhs,
+                (a_skip, b_skip),
+                (transa, lda),
+                (transb, ldb),
+                &mut dst,
+            )?,
             DType::F32 => {
                 for step in 0..b {
                     let lhs_p = &lhs[step * a_skip..];
@@ -1550,6 +1614,15 @@ impl Map2 for MatMul {
                     }
                 }
             }
+            DType::BF16 => self.upcast_gemm::<T, bf16>(
+                crate::mkl::sgemm,
+                lhs,
+                rhs,
+                (a_skip, b_skip),
+                (transa, lda),
+                (transb, ldb),
+                &mut dst,
+            )?,
             DType::F32 => {
                 for step in 0..b {
                     let lhs_p = &lhs[step * a_skip..];
@@ -8,6 +8,12 @@ pub trait CustomOp1 {
     // Box<dyn> does not support const yet, so use a function to get the name.
     fn name(&self) -> &'static str;
 
+    /// The parameters of this instance of the op, e.g. `eps=1e-5`, recorded in the `params`
+    /// field of its tracing span, see [`crate::op_registry`].
+    fn params(&self) -> String {
+        String::new()
+    }
+
     /// The forward pass, as run on a cpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cpu_fwd(&self, storage: &CpuStorage, layout: &Layout) -> Result<(CpuStorage, Shape)>;
@@ -43,6 +49,12 @@ pub trait CustomOp1 {
 pub trait CustomOp2 {
     fn name(&self) -> &'static str;
 
+    /// The parameters of this instance of the op, e.g. `eps=1e-5`, recorded in the `params`
+    /// field of its tracing span, see [`crate::op_registry`].
+    fn params(&self) -> String {
+        String::new()
+    }
+
     /// The forward pass, as run on a cpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cpu_fwd(
@@ -95,6 +107,12 @@ pub trait CustomOp2 {
 pub trait CustomOp3 {
     fn name(&self) -> &'static str;
 
+    /// The parameters of this instance of the op, e.g. `eps=1e-5`, recorded in the `params`
+    /// field of its tracing span, see [`crate::op_registry`].
+    fn params(&self) -> String {
+        String::new()
+    }
+
     /// The forward pass, as run on a cpu device. Note that the storage can use arbitrary strides,
     /// offsets etc so the associated layout should be used to access it.
     fn cpu_fwd(
@@ -0,0 +1,146 @@
+//! Running ops on the cpu when the device backend has no kernel for them.
+//!
+//! This is disabled by default. Once enabled with [`set_fallback_to_cpu`], an op that fails on
+//! a cuda or metal storage because the backend lacks a kernel, e.g. `where_cond` for some dtype
+//! combination on metal, is run again on cpu copies of its operands and the result is moved
+//! back to the device. A `tracing` warning naming the op and the dtypes is emitted the first
+//! time each of these falls back. Copying the operands back and forth is slow, the warning is
+//! meant to point at the kernels that are worth adding.
+//!
+//! The fallback covers the ops that produce a new storage. Custom ops and the in-place ops
+//! such as `scatter_set` are not covered.
+use crate::backend::BackendDevice;
+use crate::{DType, Device, Error, Result, Storage};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+static FALLBACK_TO_CPU: AtomicBool = AtomicBool::new(false);
+static FALLBACK_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// The ops and dtypes for which a warning has already been printed.
+static WARNED: Mutex<Vec<(&'static str, Vec<DType>)>> = Mutex::new(Vec::new());
+
+#[cfg(feature = "simulate-missing-kernel")]
+thread_local! {
+    static SIMULATED_MISSING_KERNEL: std::cell::Cell<Option<&'static str>> = const { std::cell::Cell::new(None) };
+}
+
+/// Enables or disables the cpu fallback for ops that have no kernel on the device, this is
+/// process wide.
+pub fn set_fallback_to_cpu(enabled: bool) {
+    FALLBACK_TO_CPU.store(enabled, Ordering::Relaxed)
+}
+
+pub fn fallback_to_cpu() -> bool {
+    FALLBACK_TO_CPU.load(Ordering::Relaxed)
+}
+
+/// The number of ops that have been run on the cpu by the fallback since the process started.
+pub fn fallback_count() -> usize {
+    FALLBACK_COUNT.load(Ordering::Relaxed)
+}
+
+/// Test hook, the op with this name fails on the current thread as if the backend had no
+/// kernel for it, on all devices including the cpu. The fallback then runs it on a copy of the
+/// operands. Use `None` to go back to normal. Only available with the `simulate-missing-kernel`
+/// feature.
+#[cfg(feature = "simulate-missing-kernel")]
+#[doc(hidden)]
+pub fn simulate_missing_kernel(op: Option<&'static str>) {
+    SIMULATED_MISSING_KERNEL.with(|m| m.set(op))
+}
+
+fn is_missing_kernel(err: &Error) -> bool {
+    match err {
+        Error::UnsupportedDTypeForOp(_, _) => true,
+        #[cfg(feature = "metal")]
+        Error::Metal(crate::MetalError::NotImplemented(_)) => true,
+        #[cfg(feature = "cuda")]
+        Error::Cuda(err) => matches!(
+            err.downcast_ref::<crate::cuda_backend::CudaError>(),
+            Some(crate::cuda_backend::CudaError::UnsupportedDtype { .. })
+        ),
+        Error::WithBacktrace { inner, .. }
+        | Error::Context { inner, .. }
+        | Error::WithPath { inner, .. } => is_missing_kernel(inner),
+        _ => false,
+    }
+}
+
+fn to_cpu(storage: &Storage) -> Result<Storage> {
+    use crate::backend::BackendStorage;
+
+    let storage = match storage {
+        Storage::Cpu(storage) => storage.clone(),
+        Storage::Cuda(storage) => storage.to_cpu_storage()?,
+        Storage::Metal(storage) => storage.to_cpu_storage()?,
+    };
+    Ok(Storage::Cpu(storage))
+}
+
+fn to_device(storage: Storage, device: &Device) -> Result<Storage> {
+    let storage = match storage {
+        Storage::Cpu(storage) => storage,
+        storage => crate::bail!("expected a cpu storage, got {:?}", storage.device()),
+    };
+    match device {
+        Device::Cpu => Ok(Storage::Cpu(storage)),
+        Device::Cuda(device) => Ok(Storage::Cuda(
+            device.storage_from_cpu_storage_owned(storage)?,
+        )),
+        Device::Metal(device) => Ok(Storage::Metal(
+            device.storage_from_cpu_storage_owned(storage)?,
+        )),
+    }
+}
+
+fn warn_once(op: &'static str, device: &Device, dtypes: Vec<DType>) {
+    let mut warned = match WARNED.lock() {
+        Ok(warned) => warned,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if !warned.iter().any(|(o, d)| *o == op && *d == dtypes) {
+        tracing::warn!(
+            "no {op} kernel for {dtypes:?} on {:?}, running it on the cpu",
+            device.location()
+        );
+        warned.push((op, dtypes));
+    }
+}
+
+/// Runs `f` on the operands, if this fails because of a missing kernel and the fallback is
+/// enabled, runs `f` again on cpu copies of the operands and moves the result to the device.
+pub(crate) fn run<F>(op: &'static str, operands: &[&Storage], f: F) -> Result<Storage>
+where
+    F: Fn(&[&Storage]) -> Result<Storage>,
+{
+    if !fallback_to_cpu() {
+        return f(operands);
+    }
+    #[cfg(feature = "simulate-missing-kernel")]
+    let simulated = SIMULATED_MISSING_KERNEL.with(|m| m.get() == Some(op));
+    #[cfg(not(feature = "simulate-missing-kernel"))]
+    let simulated = false;
+    let err = if simulated {
+        Error::UnsupportedDTypeForOp(operands[0].dtype(), op).bt()
+    } else {
+        match f(operands) {
+            Ok(storage) => return Ok(storage),
+            Err(err) => err,
+        }
+    };
+    let device = operands[0].device();
+    if !is_missing_kernel(&err) || (device.is_cpu() && !simulated) {
+        return Err(err);
+    }
+    let dtypes = operands.iter().map(|s| s.dtype()).collect();
+    warn_once(op, &device, dtypes);
+    FALLBACK_COUNT.fetch_add(1, Ordering::Relaxed);
+    let cpu_operands = operands
+        .iter()
+        .map(|s| to_cpu(s))
+        .collect::<Result<Vec<_>>>()?;
+    let cpu_operands = cpu_operands.iter().collect::<Vec<_>>();
+    let storage = f(&cpu_operands)?;
+    to_device(storage, &device)
+}
@@ -64,6 +64,7 @@ mod dtype;
 pub mod dummy_cuda_backend;
 mod dummy_metal_backend;
 pub mod error;
+mod fallback;
 mod indexer;
 pub mod layout;
 #[cfg(feature = "metal")]
@@ -72,6 +73,7 @@ pub mod metal_backend;
 mod mkl;
 pub mod npy;
 pub mod op;
+pub mod op_registry;
 pub mod pickle;
 pub mod quantized;
 pub mod safetensors;
@@ -95,6 +97,10 @@ pub use custom_op::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, Inp
 pub use device::{Device, DeviceLocation, NdArray};
 pub use dtype::{DType, DTypeParseError, FloatDType, IntDType, WithDType};
 pub use error::{Context, Error, Result};
+#[cfg(feature = "simulate-missing-kernel")]
+#[doc(hidden)]
+pub use fallback::simulate_missing_kernel;
+pub use fallback::{fallback_count, fallback_to_cpu, set_fallback_to_cpu};
 pub use indexer::{IndexOp, TensorIndexer};
 pub use layout::Layout;
 pub use shape::{Shape, D};
@@ -59,6 +59,15 @@ pub enum MetalError {
         expected: DType,
         got: DType,
     },
+    /// The backend has no kernel for this op and dtype combination.
+    #[error("{0} not implemented")]
+    NotImplemented(String),
+}
+
+macro_rules! not_implemented {
+    ($($arg:tt)*) => {
+        return Err(crate::Error::Metal(MetalError::NotImplemented(format!($($arg)*))).bt())
+    };
 }
 
 impl From<String> for MetalError {
@@ -123,7 +132,7 @@ impl BackendStorage for MetalStorage {
                 DType::BF16 => "affine_bf16",
                 DType::U8 => "affine_u8",
                 DType::U32 => "affine_u32",
-                dtype => crate::bail!("Metal contiguous affine {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal contiguous affine {dtype:?}"),
             };
             candle_metal_kernels::call_affine(
                 &device.device,
@@ -142,7 +151,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "affine_f32_strided",
                 DType::F16 => "affine_f16_strided",
                 DType::BF16 => "affine_bf16_strided",
-                dtype => crate::bail!("Metal strided affine {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal strided affine {dtype:?}"),
             };
             candle_metal_kernels::call_affine_strided(
                 &device.device,
@@ -176,7 +185,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "powf_f32",
                 DType::F16 => "powf_f16",
                 DType::BF16 => "powf_bf16",
-                dtype => crate::bail!("Metal contiguous powf {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal contiguous powf {dtype:?}"),
             };
             candle_metal_kernels::call_powf(
                 &device.device,
@@ -194,7 +203,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "powf_f32_strided",
                 DType::F16 => "powf_f16_strided",
                 DType::BF16 => "powf_bf16_strided",
-                dtype => crate::bail!("Metal strided powf {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal strided powf {dtype:?}"),
             };
             candle_metal_kernels::call_powf_strided(
                 &device.device,
@@ -227,7 +236,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "elu_f32",
                 DType::F16 => "elu_f16",
                 DType::BF16 => "elu_bf16",
-                dtype => crate::bail!("Metal contiguous elu {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal contiguous elu {dtype:?}"),
             };
             candle_metal_kernels::call_elu(
                 &device.device,
@@ -245,7 +254,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "elu_f32_strided",
                 DType::F16 => "elu_f16_strided",
                 DType::BF16 => "elu_bf16_strided",
-                dtype => crate::bail!("Metal strided elu {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal strided elu {dtype:?}"),
             };
             candle_metal_kernels::call_elu_strided(
                 &device.device,
@@ -320,7 +329,7 @@ impl BackendStorage for MetalStorage {
                 (ReduceOp::ArgMin, DType::U8) => ("fast_argmin_u8", true, true),
                 (ReduceOp::ArgMax, DType::U8) => ("fast_argmax_u8", true, true),
                 (k, dtype) => {
-                    crate::bail!("Metal contiguous reduce op {k:?} {dtype:?} not implemented")
+                    not_implemented!("Metal contiguous reduce op {k:?} {dtype:?}")
                 }
             };
             if check_empty && layout.shape().elem_count() == 0 {
@@ -376,7 +385,7 @@ impl BackendStorage for MetalStorage {
             (ReduceOp::Max, DType::U8) => ("fast_max_u8_strided", true, false),
             (ReduceOp::ArgMin, DType::U8) => ("fast_argmin_u8_strided", true, true),
             (ReduceOp::ArgMax, DType::U8) => ("fast_argmax_u8_strided", true, true),
-            (k, dtype) => crate::bail!("Metal strided reduce op {k:?} {dtype:?} not implemented"),
+            (k, dtype) => not_implemented!("Metal strided reduce op {k:?} {dtype:?}"),
         };
         if check_empty && layout.shape().elem_count() == 0 {
             Err(crate::Error::EmptyTensor { op: "reduce" }.bt())?
@@ -553,7 +562,7 @@ impl BackendStorage for MetalStorage {
                 (DType::BF16, DType::U8) => "cast_bf16_u8",
 
                 (left, right) => {
-                    crate::bail!("Metal contiguous to_dtype {left:?} {right:?} not implemented")
+                    not_implemented!("Metal contiguous to_dtype {left:?} {right:?}")
                 }
             };
             candle_metal_kernels::call_cast_contiguous(
@@ -605,7 +614,7 @@ impl BackendStorage for MetalStorage {
                 (DType::U8, DType::U32) => "cast_u8_u32_strided",
 
                 (left, right) => {
-                    crate::bail!("Metal strided to_dtype {left:?} {right:?} not implemented")
+                    not_implemented!("Metal strided to_dtype {left:?} {right:?}")
                 }
             };
             candle_metal_kernels::call_cast_strided(
@@ -697,9 +706,7 @@ impl BackendStorage for MetalStorage {
                     ("usign", DType::BF16) => contiguous_tiled::sign::BFLOAT,
                     ("usign", DType::I64) => contiguous_tiled::sign::I64,
                     (name, dtype) => {
-                        crate::bail!(
-                            "Metal contiguous_tiled unary {name} {dtype:?} not implemented"
-                        )
+                        not_implemented!("Metal contiguous_tiled unary {name} {dtype:?}")
                     }
                 };
                 candle_metal_kernels::call_unary_contiguous_tiled(
@@ -775,7 +782,7 @@ impl BackendStorage for MetalStorage {
                     ("usign", DType::BF16) => contiguous::sign::BFLOAT,
                     ("usign", DType::I64) => contiguous::sign::I64,
                     (name, dtype) => {
-                        crate::bail!("Metal contiguous unary {name} {dtype:?} not implemented")
+                        not_implemented!("Metal contiguous unary {name} {dtype:?}")
                     }
                 };
                 candle_metal_kernels::call_unary_contiguous(
@@ -847,7 +854,7 @@ impl BackendStorage for MetalStorage {
                     ("utanh", DType::BF16) => strided::tanh::BFLOAT,
 
                     (name, dtype) => {
-                        crate::bail!("Metal strided unary {name} {dtype:?} not implemented")
+                        not_implemented!("Metal strided unary {name} {dtype:?}")
                     }
                 };
                 let dst = BufferOffset::zero_offset(&buffer);
@@ -907,7 +914,7 @@ impl BackendStorage for MetalStorage {
             (DType::U8, DType::I64) => "where_u8_i64",
             (DType::U8, DType::U32) => "where_u8_u32",
             (DType::U8, DType::U8) => "where_u8_u8",
-            (left, right) => crate::bail!("Metal where_cond {left:?} {right:?} not implemented"),
+            (left, right) => not_implemented!("Metal where_cond {left:?} {right:?}"),
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         let t = buffer_o(&t.buffer, t_l, t.dtype);
@@ -954,7 +961,7 @@ impl BackendStorage for MetalStorage {
         let command_buffer = self.device.command_buffer()?;
         let name = match self.dtype {
             DType::F32 => "im2col1d_f32",
-            dtype => crate::bail!("Metal conv1d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal conv1d {dtype:?}"),
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         candle_metal_kernels::call_im2col1d_strided(
@@ -1035,7 +1042,7 @@ impl BackendStorage for MetalStorage {
                 DType::F32 => "col2im1d_f32",
                 DType::U32 => "col2im1d_u32",
                 DType::U8 => "col2im1d_u8",
-                dtype => crate::bail!("metal col2im1d {dtype:?} not implemented"),
+                dtype => not_implemented!("metal col2im1d {dtype:?}"),
             };
             let col = {
                 // This merges the last two dimensions of the kernel together.
@@ -1082,7 +1089,7 @@ impl BackendStorage for MetalStorage {
                 DType::BF16 => "conv_transpose1d_bf16",
                 DType::U32 => "conv_transpose1d_u32",
                 DType::U8 => "conv_transpose1d_u8",
-                dtype => crate::bail!("Metal conv_transpose1d {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal conv_transpose1d {dtype:?}"),
             };
             candle_metal_kernels::call_conv_transpose1d(
                 &self.device.device,
@@ -1144,7 +1151,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "im2col_bf16",
             DType::U8 => "im2col_u8",
             DType::U32 => "im2col_u32",
-            dtype => crate::bail!("Metal conv2d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal conv2d {dtype:?}"),
         };
         let src = buffer_o(&self.buffer, layout, self.dtype);
         candle_metal_kernels::call_im2col_strided(
@@ -1226,7 +1233,7 @@ impl BackendStorage for MetalStorage {
             DType::F32 => "conv_transpose2d_f32",
             DType::F16 => "conv_transpose2d_f16",
             DType::BF16 => "conv_transpose2d_bf16",
-            dtype => crate::bail!("Metal conv_transpose2d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal conv_transpose2d {dtype:?}"),
         };
 
         candle_metal_kernels::call_conv_transpose2d(
@@ -1273,7 +1280,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "avg_pool2d_bf16",
             DType::U8 => "avg_pool2d_u8",
             DType::U32 => "avg_pool2d_u32",
-            dtype => crate::bail!("Metal avg_pool2d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal avg_pool2d {dtype:?}"),
         };
         let out_w = (width - w_k) / w_stride + 1;
         let out_h = (height - h_k) / h_stride + 1;
@@ -1315,7 +1322,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "max_pool2d_bf16",
             DType::U8 => "max_pool2d_u8",
             DType::U32 => "max_pool2d_u32",
-            dtype => crate::bail!("Metal max_pool2d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal max_pool2d {dtype:?}"),
         };
         let out_w = (width - w_k) / w_stride + 1;
         let out_h = (height - h_k) / h_stride + 1;
@@ -1343,7 +1350,7 @@ impl BackendStorage for MetalStorage {
     }
 
     fn upsample_nearest1d(&self, _: &Layout, _: usize) -> Result<Self> {
-        crate::bail!("Metal upsample_nearest1d not implemented")
+        not_implemented!("Metal upsample_nearest1d")
     }
 
     fn upsample_nearest2d(&self, inp_l: &Layout, out_w: usize, out_h: usize) -> Result<Self> {
@@ -1360,7 +1367,7 @@ impl BackendStorage for MetalStorage {
             DType::BF16 => "upsample_nearest2d_bf16",
             DType::U8 => "upsample_nearest2d_u8",
             DType::U32 => "upsample_nearest2d_u32",
-            dtype => crate::bail!("Metal upsample_nearest2d {dtype:?} not implemented"),
+            dtype => not_implemented!("Metal upsample_nearest2d {dtype:?}"),
         };
 
         let dst_el = out_w * out_h * dims[0] * dims[1];
@@ -1405,7 +1412,7 @@ impl BackendStorage for MetalStorage {
             (DType::I64, DType::BF16) => "gather_i64_bf16",
             (DType::I64, DType::U32) => "gather_i64_u32",
             (DType::I64, DType::I64) => "gather_i64_i64",
-            (left, right) => crate::bail!("Metal gather {left:?} {right:?} not implemented"),
+            (left, right) => not_implemented!("Metal gather {left:?} {right:?}"),
         };
         let command_buffer = self.device.command_buffer()?;
         let src = buffer_o(&self.buffer, src_l, dtype);
@@ -1558,7 +1565,7 @@ impl BackendStorage for MetalStorage {
             (DType::I64, DType::BF16) => "is_i64_bf16",
 
             (left, right) => {
-                crate::bail!("Metal contiguous index_select {left:?} {right:?} not implemented")
+                not_implemented!("Metal contiguous index_select {left:?} {right:?}")
             }
         };
         let command_buffer = self.device.command_buffer()?;
@@ -1728,7 +1735,7 @@ impl BackendStorage for MetalStorage {
                 DType::I64 => candle_metal_kernels::copy2d::I64,
                 DType::U32 => candle_metal_kernels::copy2d::U32,
                 DType::U8 => candle_metal_kernels::copy2d::U8,
-                dtype => crate::bail!("Metal copy2d {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal copy2d {dtype:?}"),
             };
             candle_metal_kernels::call_copy2d(
                 &self.device.device,
@@ -1774,7 +1781,7 @@ impl BackendStorage for MetalStorage {
                 DType::I64 => candle_metal_kernels::unary::strided::copy::I64,
                 DType::U32 => candle_metal_kernels::unary::strided::copy::U32,
                 DType::U8 => candle_metal_kernels::unary::strided::copy::U8,
-                dtype => crate::bail!("Metal copy_strided {dtype:?} not implemented"),
+                dtype => not_implemented!("Metal copy_strided {dtype:?}"),
             };
             let src = buffer_o(&self.buffer, src_l, self.dtype);
             let dst = BufferOffset {
@@ -1896,7 +1903,7 @@ impl MetalStorage {
                 ("gt", DType::U8) => (contiguous::gt::U8, DType::U8),
 
                 (name, dtype) => {
-                    crate::bail!("Metal contiguous binary {name} {dtype:?} not implemented")
+                    not_implemented!("Metal contiguous binary {name} {dtype:?}")
                 }
             };
             let buffer = device.new_buffer(el_count, dtype, op)?;
@@ -1995,7 +2002,7 @@ impl MetalStorage {
                 ("gt", DType::U8) => (strided::gt::U8, DType::U8),
 
                 (name, dtype) => {
-                    crate::bail!("Metal strided binary {name} {dtype:?} not implemented")
+                    not_implemented!("Metal strided binary {name} {dtype:?}")
                 }
             };
             let buffer = device.new_buffer(el_count, dtype, op)?;
@@ -0,0 +1,96 @@
+//! Descriptors of the custom ops, to tell them apart in traces.
+//!
+//! Each call to a [`CustomOp1`](crate::CustomOp1), [`CustomOp2`](crate::CustomOp2), or
+//! [`CustomOp3`](crate::CustomOp3) runs in a `custom-op` tracing span at the trace level, with
+//! the name of the op in its `op` field, the parameters of the instance in `params`, and the
+//! shapes of the arguments in `shapes`. The crates that define custom ops register a
+//! [`CustomOpDescriptor`] for each of them so that the names found in a trace can be matched
+//! with [`list`] or [`get`]. The ops of this crate are always registered.
+use crate::{Layout, Result};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+/// The description of a custom op.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CustomOpDescriptor {
+    /// The name returned by the `name` method of the op, it has to be unique.
+    pub name: &'static str,
+    /// A summary of the parameters of the op, e.g. `eps: f32`, empty when it has none.
+    pub params: &'static str,
+    /// The module that defines the op.
+    pub source: &'static str,
+}
+
+const CORE_OPS: [CustomOpDescriptor; 2] = [
+    CustomOpDescriptor {
+        name: "argsort",
+        params: "asc: bool",
+        source: "candle_core::sort",
+    },
+    CustomOpDescriptor {
+        name: "qmatmul",
+        params: "dtype: GgmlDType, the dtype of the quantized weight",
+        source: "candle_core::quantized",
+    },
+];
+
+static REGISTRY: Mutex<BTreeMap<&'static str, CustomOpDescriptor>> = Mutex::new(BTreeMap::new());
+
+/// Registers a custom op. Registering the same descriptor twice is a no-op, registering another
+/// op with the same name is an error.
+pub fn register(descriptor: CustomOpDescriptor) -> Result<()> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let known = registry.get(descriptor.name).copied();
+    match known.or_else(|| CORE_OPS.into_iter().find(|d| d.name == descriptor.name)) {
+        Some(known) if known != descriptor => crate::bail!(
+            "custom op {} from {} is already registered by {}",
+            descriptor.name,
+            descriptor.source,
+            known.source
+        ),
+        Some(_) => {}
+        None => {
+            registry.insert(descriptor.name, descriptor);
+        }
+    }
+    Ok(())
+}
+
+/// Registers each of `descriptors`, see [`register`].
+pub fn register_all(descriptors: &[CustomOpDescriptor]) -> Result<()> {
+    for descriptor in descriptors.iter() {
+        register(*descriptor)?
+    }
+    Ok(())
+}
+
+/// The registered custom ops, sorted by name.
+pub fn list() -> Vec<CustomOpDescriptor> {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let mut descriptors = registry.values().copied().collect::<Vec<_>>();
+    descriptors.extend(CORE_OPS);
+    descriptors.sort_by_key(|d| d.name);
+    descriptors
+}
+
+/// The descriptor registered under `name`.
+pub fn get(name: &str) -> Option<CustomOpDescriptor> {
+    if let Some(descriptor) = CORE_OPS.into_iter().find(|d| d.name == name) {
+        return Some(descriptor);
+    }
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry.get(name).copied()
+}
+
+// Enters the span of a custom op, the field values are only evaluated when the span is enabled.
+pub(crate) fn enter_span(
+    name: &'static str,
+    params: impl FnOnce() -> String,
+    layouts: &[&Layout],
+) -> tracing::span::EnteredSpan {
+    let shapes = || {
+        let shapes = layouts.iter().map(|l| format!("{:?}", l.dims()));
+        shapes.collect::<Vec<_>>().join(" ")
+    };
+    tracing::trace_span!("custom-op", op = name, params = params(), shapes = shapes()).entered()
+}
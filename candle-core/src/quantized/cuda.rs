@@ -1,4 +1,4 @@
-use super::{GgmlDType, QStorage};
+use super::{GgmlDType, QMatMulKernel, QStorage};
 use crate::quantized::k_quants::GgmlType;
 use crate::{backend::BackendDevice, cuda_backend::WrapErr};
 use crate::{builder_arg as barg, CudaDevice, CudaStorage, Result};
@@ -431,6 +431,7 @@ impl QCudaStorage {
         match self.dtype {
             GgmlDType::F32 => deq::<f32>(&buffer, block_len, &mut out)?,
             GgmlDType::F16 => deq::<half::f16>(&buffer, block_len, &mut out)?,
+            GgmlDType::BF16 => deq::<half::bf16>(&buffer, block_len, &mut out)?,
             GgmlDType::Q4_0 => deq::<crate::quantized::BlockQ4_0>(&buffer, block_len, &mut out)?,
             GgmlDType::Q4_1 => deq::<crate::quantized::BlockQ4_1>(&buffer, block_len, &mut out)?,
             GgmlDType::Q5_0 => deq::<crate::quantized::BlockQ5_0>(&buffer, block_len, &mut out)?,
@@ -453,6 +454,41 @@ impl QCudaStorage {
         dequantize_f16(&self.data, self.dtype, elem_count, self.device())
     }
 
+    pub fn gather_rows(&self, rows: &[usize], row_bytes: usize) -> Result<Self> {
+        let len = rows.len() * row_bytes;
+        let padded_len =
+            len + MATRIX_ROW_PADDING * self.dtype.type_size() / self.dtype.block_size();
+        let mut inner = unsafe { self.device.alloc::<u8>(padded_len)? };
+        for (dst, &row) in rows.iter().enumerate() {
+            let src = self
+                .data
+                .inner
+                .slice(row * row_bytes..(row + 1) * row_bytes);
+            let mut dst = inner.slice_mut(dst * row_bytes..(dst + 1) * row_bytes);
+            self.device.memcpy_dtod(&src, &mut dst)?
+        }
+        Ok(QCudaStorage {
+            data: PaddedCudaSlice { inner, len },
+            device: self.device.clone(),
+            dtype: self.dtype,
+        })
+    }
+
+    pub fn to_bf16(&self, elem_count: usize) -> Result<CudaStorage> {
+        if self.dtype != GgmlDType::BF16 {
+            crate::bail!("to_bf16 requires bf16 storage, got {:?}", self.dtype)
+        }
+        let buffer = self
+            .device
+            .memcpy_dtov(&self.data.inner.slice(..elem_count * 2))?;
+        let data = buffer
+            .chunks_exact(2)
+            .map(|b| half::bf16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        self.device
+            .storage_from_cpu_storage_owned(crate::CpuStorage::BF16(data))
+    }
+
     pub fn quantize(&mut self, src: &CudaStorage) -> Result<()> {
         // Run the quantization on cpu.
         let src = match &src.slice {
@@ -486,20 +522,28 @@ impl QCudaStorage {
         storage: &CudaStorage,
         layout: &crate::Layout,
     ) -> Result<(CudaStorage, crate::Shape)> {
-        let max_bm = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
-            1
-        } else {
-            8
-        };
+        match self.kernel(layout) {
+            QMatMulKernel::CudaDmmv | QMatMulKernel::CudaMmvq => {
+                self.dequantize_matmul_vec(self_shape, storage, layout)
+            }
+            _ => self.dequantize_matmul(self_shape, storage, layout),
+        }
+    }
+
+    /// The kernel that [`Self::fwd`] uses for an input with this layout.
+    pub fn kernel(&self, layout: &crate::Layout) -> QMatMulKernel {
+        let force_dmmv = FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed);
+        let max_bm = if force_dmmv { 1 } else { 8 };
         let use_vec_kernel = match layout.shape().dims() {
             [b, m, _k] => b * m <= max_bm,
             [b, _k] => *b <= max_bm,
             _ => false,
         };
-        if use_vec_kernel {
-            self.dequantize_matmul_vec(self_shape, storage, layout)
-        } else {
-            self.dequantize_matmul(self_shape, storage, layout)
+        match (use_vec_kernel, force_dmmv) {
+            (true, true) => QMatMulKernel::CudaDmmv,
+            (true, false) => QMatMulKernel::CudaMmvq,
+            (false, true) => QMatMulKernel::CudaDequantize,
+            (false, false) => QMatMulKernel::CudaMmq,
         }
     }
 }
@@ -28,6 +28,14 @@ impl QCudaStorage {
         Err(Error::NotCompiledWithCudaSupport)
     }
 
+    pub fn gather_rows(&self, _rows: &[usize], _row_bytes: usize) -> Result<Self> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
+    pub fn to_bf16(&self, _elem_count: usize) -> Result<CudaStorage> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
     pub fn quantize(&mut self, _src: &CudaStorage) -> Result<()> {
         Err(Error::NotCompiledWithCudaSupport)
     }
@@ -44,6 +52,10 @@ impl QCudaStorage {
     ) -> Result<(CudaStorage, crate::Shape)> {
         Err(Error::NotCompiledWithCudaSupport)
     }
+
+    pub fn kernel(&self, _layout: &crate::Layout) -> super::QMatMulKernel {
+        super::QMatMulKernel::CudaMmq
+    }
 }
 
 pub fn load_quantized<T: super::GgmlType + Send + Sync + 'static>(
@@ -103,11 +103,18 @@ pub struct Vocab {
 }
 
 impl Vocab {
-    fn read<R: std::io::Read>(reader: &mut R, n_vocab: usize) -> Result<Self> {
+    fn read<R: std::io::Read>(reader: &mut R, n_vocab: usize, file_len: u64) -> Result<Self> {
         // https://github.com/ggerganov/llama.cpp/blob/468ea24fb4633a0d681f7ac84089566c1c6190cb/llama.cpp#L556
-        let mut token_score_pairs = Vec::with_capacity(n_vocab);
+        // Each token uses at least 8 bytes, its length and its score.
+        if n_vocab as u64 > file_len / 8 {
+            crate::bail!("a vocabulary of {n_vocab} tokens does not fit in the file")
+        }
+        let mut token_score_pairs = Vec::with_capacity(n_vocab.min(4096));
         for _index in 0..n_vocab {
             let len = reader.read_u32::<LittleEndian>()? as usize;
+            if len as u64 > file_len {
+                crate::bail!("a token of {len} bytes does not fit in the file")
+            }
             let mut word = vec![0u8; len];
             reader.read_exact(&mut word)?;
             let score = reader.read_f32::<LittleEndian>()?;
@@ -117,6 +124,14 @@ impl Vocab {
     }
 }
 
+/// The number of elements of a tensor with these dimensions, this fails on overflows.
+pub(crate) fn elem_count(dims: &[usize]) -> Result<usize> {
+    match dims.iter().try_fold(1usize, |acc, &d| acc.checked_mul(d)) {
+        Some(elem_count) => Ok(elem_count),
+        None => crate::bail!("the number of elements of a tensor of shape {dims:?} overflows"),
+    }
+}
+
 fn from_raw_data<T: super::GgmlType + Send + Sync + 'static>(
     raw_data: &[u8],
     size_in_bytes: usize,
@@ -125,9 +140,26 @@ fn from_raw_data<T: super::GgmlType + Send + Sync + 'static>(
 ) -> Result<super::QTensor> {
     let raw_data_ptr = raw_data.as_ptr();
     let n_blocks = size_in_bytes / std::mem::size_of::<T>();
-    let data = unsafe { std::slice::from_raw_parts(raw_data_ptr as *const T, n_blocks) };
+    // The blocks can only be borrowed from the raw data when it is aligned for T, this is not
+    // always the case, e.g. for empty buffers, so the data is copied otherwise.
+    let aligned;
+    let data = if raw_data_ptr.align_offset(std::mem::align_of::<T>()) == 0 {
+        unsafe { std::slice::from_raw_parts(raw_data_ptr as *const T, n_blocks) }
+    } else {
+        let mut blocks = Vec::<T>::with_capacity(n_blocks);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                raw_data_ptr,
+                blocks.as_mut_ptr() as *mut u8,
+                n_blocks * std::mem::size_of::<T>(),
+            );
+            blocks.set_len(n_blocks);
+        }
+        aligned = blocks;
+        aligned.as_slice()
+    };
     let data: QStorage = match device {
-        Device::Cpu => QStorage::Cpu(Box::new(data.to_vec())),
+        Device::Cpu => QStorage::Cpu(Box::new(crate::cpu::numa::to_placed_vec(data))),
         Device::Metal(metal) => super::metal::load_quantized(metal, data)?,
         Device::Cuda(cuda) => super::cuda::load_quantized(cuda, data)?,
     };
@@ -141,7 +173,7 @@ pub fn qtensor_from_ggml(
     dims: Vec<usize>,
     device: &Device,
 ) -> Result<super::QTensor> {
-    let tensor_elems = dims.iter().product::<usize>();
+    let tensor_elems = elem_count(&dims)?;
     let block_size = ggml_dtype.block_size();
     if tensor_elems % block_size != 0 {
         crate::bail!(
@@ -149,10 +181,17 @@ pub fn qtensor_from_ggml(
         )
     }
     let size_in_bytes = tensor_elems / block_size * ggml_dtype.type_size();
+    if raw_data.len() < size_in_bytes {
+        crate::bail!(
+            "got {} bytes of data for a {ggml_dtype:?} tensor of shape {dims:?}, expected {size_in_bytes}",
+            raw_data.len()
+        )
+    }
 
     match ggml_dtype {
         GgmlDType::F32 => from_raw_data::<f32>(raw_data, size_in_bytes, dims, device),
         GgmlDType::F16 => from_raw_data::<half::f16>(raw_data, size_in_bytes, dims, device),
+        GgmlDType::BF16 => from_raw_data::<half::bf16>(raw_data, size_in_bytes, dims, device),
         GgmlDType::Q4_0 => {
             from_raw_data::<k_quants::BlockQ4_0>(raw_data, size_in_bytes, dims, device)
         }
@@ -190,12 +229,17 @@ pub fn qtensor_from_ggml(
 fn read_one_tensor<R: std::io::Seek + std::io::Read>(
     reader: &mut R,
     magic: VersionedMagic,
+    file_len: u64,
     device: &Device,
 ) -> Result<(String, super::QTensor)> {
     let n_dims = reader.read_u32::<LittleEndian>()?;
     let name_len = reader.read_u32::<LittleEndian>()?;
     let ggml_dtype = reader.read_u32::<LittleEndian>()?;
     let ggml_dtype = GgmlDType::from_u32(ggml_dtype)?;
+    let remaining = file_len.saturating_sub(reader.stream_position()?);
+    if (n_dims as u64) * 4 + name_len as u64 > remaining {
+        crate::bail!("the header of a tensor with {n_dims} dimensions and a {name_len} bytes name does not fit in the file")
+    }
     let mut dims = vec![0u32; n_dims as usize];
     reader.read_u32_into::<LittleEndian>(&mut dims)?;
     // The dimensions are stored in reverse order, see for example:
@@ -210,8 +254,16 @@ fn read_one_tensor<R: std::io::Seek + std::io::Read>(
         reader.seek(std::io::SeekFrom::Current(((32 - pos % 32) % 32) as i64))?;
     }
     let dims = dims.iter().map(|&u| u as usize).collect::<Vec<_>>();
-    let tensor_elems = dims.iter().product::<usize>();
-    let size_in_bytes = tensor_elems * ggml_dtype.type_size() / ggml_dtype.block_size();
+    let tensor_elems =
+        elem_count(&dims).map_err(|e| e.context(format!("invalid shape for {name}")))?;
+    let size_in_bytes = tensor_elems
+        .checked_mul(ggml_dtype.type_size())
+        .map(|size| size / ggml_dtype.block_size());
+    let remaining = file_len.saturating_sub(reader.stream_position()?);
+    let size_in_bytes = match size_in_bytes {
+        Some(size) if size as u64 <= remaining => size,
+        _ => crate::bail!("the data of tensor {name} does not fit in the file"),
+    };
     // TODO: Mmap version to avoid copying the data around?
     let mut raw_data = vec![0u8; size_in_bytes];
     reader.read_exact(&mut raw_data)?;
@@ -239,11 +291,11 @@ impl Content {
         reader.seek(std::io::SeekFrom::Start(0))?;
         let magic = VersionedMagic::read(reader)?;
         let hparams = HParams::read(reader)?;
-        let vocab = Vocab::read(reader, hparams.n_vocab as usize)?;
+        let vocab = Vocab::read(reader, hparams.n_vocab as usize, last_position)?;
         let mut tensors = HashMap::new();
 
-        while reader.stream_position()? != last_position {
-            let (name, tensor) = read_one_tensor(reader, magic, device)?;
+        while reader.stream_position()? < last_position {
+            let (name, tensor) = read_one_tensor(reader, magic, last_position, device)?;
             tensors.insert(name, tensor);
         }
         let device = device.clone();
@@ -5,6 +5,7 @@ use super::{GgmlDType, QTensor};
 use crate::{Context, Device, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::collections::HashMap;
+use std::io::Read;
 
 pub const DEFAULT_ALIGNMENT: u64 = 32;
 
@@ -60,14 +61,17 @@ impl TensorInfo {
         tensor_data_offset: u64,
         device: &Device,
     ) -> Result<QTensor> {
-        let tensor_elems = self.shape.elem_count();
-        let block_size = self.ggml_dtype.block_size();
-        if tensor_elems % block_size != 0 {
-            crate::bail!(
-            "the number of elements {tensor_elems} is not divisible by the block size {block_size}"
-        )
+        let size_in_bytes = self.size_in_bytes()?;
+        // Check the tensor against the file length before allocating its buffer.
+        let file_len = reader.seek(std::io::SeekFrom::End(0))?;
+        let start = tensor_data_offset.checked_add(self.offset);
+        match start.and_then(|start| start.checked_add(size_in_bytes as u64)) {
+            Some(end) if end <= file_len => {}
+            _ => crate::bail!(
+                "the tensor data at offset {} with {size_in_bytes} bytes is out of the file of {file_len} bytes",
+                self.offset
+            ),
         }
-        let size_in_bytes = tensor_elems / block_size * self.ggml_dtype.type_size();
         let mut raw_data = vec![0u8; size_in_bytes];
         reader.seek(std::io::SeekFrom::Start(tensor_data_offset + self.offset))?;
         reader.read_exact(&mut raw_data)?;
@@ -78,6 +82,17 @@ impl TensorInfo {
             device,
         )
     }
+
+    fn size_in_bytes(&self) -> Result<usize> {
+        let tensor_elems = super::ggml_file::elem_count(self.shape.dims())?;
+        let block_size = self.ggml_dtype.block_size();
+        if tensor_elems % block_size != 0 {
+            crate::bail!(
+            "the number of elements {tensor_elems} is not divisible by the block size {block_size}"
+        )
+        }
+        Ok(tensor_elems / block_size * self.ggml_dtype.type_size())
+    }
 }
 
 #[derive(Debug)]
@@ -88,13 +103,51 @@ pub struct Content {
     pub tensor_data_offset: u64,
 }
 
-fn read_string<R: std::io::Read>(reader: &mut R, magic: &VersionedMagic) -> Result<String> {
-    let len = match magic {
-        VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-        VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-            reader.read_u64::<LittleEndian>()? as usize
+// The maximum nesting of metadata arrays.
+const MAX_ARRAY_DEPTH: usize = 16;
+
+// Reads the header of a gguf file and keeps track of the number of bytes left in the file, the
+// lengths read from the file are checked against this before allocating anything.
+struct HeaderReader<'a, R> {
+    inner: &'a mut R,
+    remaining: u64,
+}
+
+impl<R: std::io::Read> std::io::Read for HeaderReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.remaining = self.remaining.saturating_sub(n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: std::io::Read> HeaderReader<'_, R> {
+    fn read_len(&mut self, magic: &VersionedMagic) -> Result<u64> {
+        let len = match magic {
+            VersionedMagic::GgufV1 => self.read_u32::<LittleEndian>()? as u64,
+            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => self.read_u64::<LittleEndian>()?,
+        };
+        Ok(len)
+    }
+
+    // Checks that `len` elements each using at least `elem_size` bytes fit in the rest of the file.
+    fn check_len(&self, len: u64, elem_size: u64, what: &str) -> Result<usize> {
+        match len.checked_mul(elem_size) {
+            Some(size) if size <= self.remaining => Ok(len as usize),
+            _ => crate::bail!(
+                "{what} of length {len} does not fit in the {} bytes left in the file",
+                self.remaining
+            ),
         }
-    };
+    }
+}
+
+fn read_string<R: std::io::Read>(
+    reader: &mut HeaderReader<R>,
+    magic: &VersionedMagic,
+) -> Result<String> {
+    let len = reader.read_len(magic)?;
+    let len = reader.check_len(len, 1, "string")?;
     let mut v = vec![0u8; len];
     reader.read_exact(&mut v)?;
     // GGUF strings are supposed to be non-null terminated but in practice this happens.
@@ -272,9 +325,10 @@ impl Value {
     }
 
     fn read<R: std::io::Read>(
-        reader: &mut R,
+        reader: &mut HeaderReader<R>,
         value_type: ValueType,
         magic: &VersionedMagic,
+        depth: usize,
     ) -> Result<Self> {
         let v = match value_type {
             ValueType::U8 => Self::U8(reader.read_u8()?),
@@ -296,15 +350,16 @@ impl Value {
             ValueType::Array => {
                 let value_type = reader.read_u32::<LittleEndian>()?;
                 let value_type = ValueType::from_u32(value_type)?;
-                let len = match magic {
-                    VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-                    VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                        reader.read_u64::<LittleEndian>()? as usize
-                    }
-                };
-                let mut vs = Vec::with_capacity(len);
+                if value_type == ValueType::Array && depth >= MAX_ARRAY_DEPTH {
+                    crate::bail!("metadata arrays are nested more than {MAX_ARRAY_DEPTH} times")
+                }
+                let len = reader.read_len(magic)?;
+                let len = reader.check_len(len, value_type.min_size(magic), "array")?;
+                // The values take more memory than their encoding so the capacity is not
+                // trusted, the vector grows as the values get read.
+                let mut vs = Vec::with_capacity(len.min(4096));
                 for _ in 0..len {
-                    vs.push(Value::read(reader, value_type, magic)?)
+                    vs.push(Value::read(reader, value_type, magic, depth + 1)?)
                 }
                 Self::Array(vs)
             }
@@ -389,31 +444,48 @@ impl ValueType {
             Self::F64 => 12,
         }
     }
+
+    // The minimum number of bytes used to encode a value of this type.
+    fn min_size(self, magic: &VersionedMagic) -> u64 {
+        let len_size = match magic {
+            VersionedMagic::GgufV1 => 4,
+            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => 8,
+        };
+        match self {
+            Self::U8 | Self::I8 | Self::Bool => 1,
+            Self::U16 | Self::I16 => 2,
+            Self::U32 | Self::I32 | Self::F32 => 4,
+            Self::U64 | Self::I64 | Self::F64 => 8,
+            Self::String => len_size,
+            Self::Array => 4 + len_size,
+        }
+    }
 }
 
 impl Content {
+    /// Reads the metadata and the tensor infos, the tensor data is only read by
+    /// [`Content::tensor`]. The lengths found in the file are checked against the file size
+    /// before allocating anything, so a malformed file results in an error.
     pub fn read<R: std::io::Seek + std::io::Read>(reader: &mut R) -> Result<Self> {
+        let start = reader.stream_position()?;
+        let file_len = reader.seek(std::io::SeekFrom::End(0))?;
+        reader.seek(std::io::SeekFrom::Start(start))?;
+        let mut header = HeaderReader {
+            inner: reader,
+            remaining: file_len.saturating_sub(start),
+        };
+        let reader = &mut header;
         let magic = VersionedMagic::read(reader)?;
 
-        let tensor_count = match magic {
-            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                reader.read_u64::<LittleEndian>()? as usize
-            }
-        };
-        let metadata_kv_count = match magic {
-            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                reader.read_u64::<LittleEndian>()? as usize
-            }
-        };
+        let tensor_count = reader.read_len(&magic)?;
+        let metadata_kv_count = reader.read_len(&magic)?;
 
         let mut metadata = HashMap::new();
         for _idx in 0..metadata_kv_count {
             let key = read_string(reader, &magic)?;
             let value_type = reader.read_u32::<LittleEndian>()?;
             let value_type = ValueType::from_u32(value_type)?;
-            let value = Value::read(reader, value_type, &magic)?;
+            let value = Value::read(reader, value_type, &magic, 0)?;
             metadata.insert(key, value);
         }
         let mut tensor_infos = HashMap::new();
@@ -423,18 +495,22 @@ impl Content {
 
             let mut dimensions: Vec<usize> = match magic {
                 VersionedMagic::GgufV1 => {
-                    let mut dimensions = vec![0; n_dimensions as usize];
+                    let n_dimensions = reader.check_len(n_dimensions as u64, 4, "shape")?;
+                    let mut dimensions = vec![0; n_dimensions];
                     reader.read_u32_into::<LittleEndian>(&mut dimensions)?;
                     dimensions.into_iter().map(|c| c as usize).collect()
                 }
                 VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                    let mut dimensions = vec![0; n_dimensions as usize];
+                    let n_dimensions = reader.check_len(n_dimensions as u64, 8, "shape")?;
+                    let mut dimensions = vec![0; n_dimensions];
                     reader.read_u64_into::<LittleEndian>(&mut dimensions)?;
                     dimensions.into_iter().map(|c| c as usize).collect()
                 }
             };
 
             dimensions.reverse();
+            super::ggml_file::elem_count(&dimensions)
+                .map_err(|e| e.context(format!("invalid shape for {tensor_name}")))?;
             let ggml_dtype = reader.read_u32::<LittleEndian>()?;
             let ggml_dtype = GgmlDType::from_u32(ggml_dtype)?;
             let offset = reader.read_u64::<LittleEndian>()?;
@@ -447,16 +523,8 @@ impl Content {
                 },
             );
         }
-        let position = reader.stream_position()?;
-        let alignment = match metadata.get("general.alignment") {
-            Some(Value::U8(v)) => *v as u64,
-            Some(Value::U16(v)) => *v as u64,
-            Some(Value::U32(v)) => *v as u64,
-            Some(Value::I8(v)) if *v >= 0 => *v as u64,
-            Some(Value::I16(v)) if *v >= 0 => *v as u64,
-            Some(Value::I32(v)) if *v >= 0 => *v as u64,
-            _ => DEFAULT_ALIGNMENT,
-        };
+        let position = header.inner.stream_position()?;
+        let alignment = alignment(&metadata)?;
         let tensor_data_offset = position.div_ceil(alignment) * alignment;
         Ok(Self {
             magic,
@@ -480,6 +548,22 @@ impl Content {
     }
 }
 
+fn alignment(metadata: &HashMap<String, Value>) -> Result<u64> {
+    let alignment = match metadata.get("general.alignment") {
+        Some(Value::U8(v)) => *v as u64,
+        Some(Value::U16(v)) => *v as u64,
+        Some(Value::U32(v)) => *v as u64,
+        Some(Value::I8(v)) if *v >= 0 => *v as u64,
+        Some(Value::I16(v)) if *v >= 0 => *v as u64,
+        Some(Value::I32(v)) if *v >= 0 => *v as u64,
+        _ => DEFAULT_ALIGNMENT,
+    };
+    if alignment == 0 {
+        crate::bail!("invalid general.alignment 0")
+    }
+    Ok(alignment)
+}
+
 fn write_string<W: std::io::Write>(w: &mut W, str: &str) -> Result<()> {
     let bytes = str.as_bytes();
     w.write_u64::<LittleEndian>(bytes.len() as u64)?;
@@ -487,6 +571,23 @@ fn write_string<W: std::io::Write>(w: &mut W, str: &str) -> Result<()> {
     Ok(())
 }
 
+fn write_tensor_info<W: std::io::Write>(
+    w: &mut W,
+    name: &str,
+    dims: &[usize],
+    dtype: GgmlDType,
+    offset: u64,
+) -> Result<()> {
+    write_string(w, name)?;
+    w.write_u32::<LittleEndian>(dims.len() as u32)?;
+    for &dim in dims.iter().rev() {
+        w.write_u64::<LittleEndian>(dim as u64)?;
+    }
+    w.write_u32::<LittleEndian>(dtype.to_u32())?;
+    w.write_u64::<LittleEndian>(offset)?;
+    Ok(())
+}
+
 pub fn write<W: std::io::Seek + std::io::Write>(
     w: &mut W,
     metadata: &[(&str, &Value)],
@@ -504,14 +605,13 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     let mut offset = 0usize;
     let mut offsets = Vec::with_capacity(tensors.len());
     for (name, tensor) in tensors.iter() {
-        write_string(w, name)?;
-        let dims = tensor.shape().dims();
-        w.write_u32::<LittleEndian>(dims.len() as u32)?;
-        for &dim in dims.iter().rev() {
-            w.write_u64::<LittleEndian>(dim as u64)?;
-        }
-        w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
-        w.write_u64::<LittleEndian>(offset as u64)?;
+        write_tensor_info(
+            w,
+            name,
+            tensor.shape().dims(),
+            tensor.dtype(),
+            offset as u64,
+        )?;
         offsets.push(offset);
         let size_in_bytes = tensor.storage_size_in_bytes();
         let padding = 31 - (31 + size_in_bytes) % 32;
@@ -536,3 +636,115 @@ pub fn write<W: std::io::Seek + std::io::Write>(
     }
     Ok(())
 }
+
+/// A change applied by [`edit`].
+#[derive(Debug, Clone)]
+pub enum MetadataEdit {
+    /// Adds the metadata key or replaces its value.
+    Set(String, Value),
+    /// Removes the metadata key, it is an error if the key does not exist.
+    Delete(String),
+    /// Renames a tensor, it is an error if the old name does not exist or the new one does.
+    RenameTensor(String, String),
+}
+
+/// Applies `edits` in order to the gguf file `path_in` and writes the result to `path_out`,
+/// which can be the same path as the result goes through a temporary file next to it.
+///
+/// The tensor data is copied byte for byte in the order of the original file, with a fixed size
+/// buffer, so editing a large model takes about the time of copying it. The metadata keys are
+/// written in sorted order.
+pub fn edit<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+    path_in: P,
+    path_out: Q,
+    edits: Vec<MetadataEdit>,
+) -> Result<()> {
+    use std::io::{Seek, Write};
+
+    let (path_in, path_out) = (path_in.as_ref(), path_out.as_ref());
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path_in)?);
+    let mut content = Content::read(&mut reader).map_err(|e| e.with_path(path_in))?;
+    for edit in edits {
+        match edit {
+            MetadataEdit::Set(key, value) => {
+                content.metadata.insert(key, value);
+            }
+            MetadataEdit::Delete(key) => {
+                if content.metadata.remove(&key).is_none() {
+                    crate::bail!("cannot delete {key}, there is no such metadata key")
+                }
+            }
+            MetadataEdit::RenameTensor(old, new) => {
+                if content.tensor_infos.contains_key(&new) {
+                    crate::bail!("cannot rename {old} to {new}, there is already a tensor {new}")
+                }
+                match content.tensor_infos.remove(&old) {
+                    None => crate::bail!("cannot rename {old}, there is no such tensor"),
+                    Some(info) => content.tensor_infos.insert(new, info),
+                };
+            }
+        }
+    }
+    let alignment = alignment(&content.metadata)?;
+    let mut metadata = content.metadata.iter().collect::<Vec<_>>();
+    metadata.sort_by_key(|(k, _)| *k);
+    let mut tensors = content
+        .tensor_infos
+        .iter()
+        .map(|(name, info)| Ok((name, info, info.size_in_bytes()?)))
+        .collect::<Result<Vec<_>>>()?;
+    tensors.sort_by_key(|(_, info, _)| info.offset);
+
+    let file_name = path_out.file_name().map(|f| f.to_string_lossy());
+    let tmp_path = path_out.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.as_deref().unwrap_or("gguf"),
+        std::process::id()
+    ));
+    let mut write = || -> Result<()> {
+        let mut w = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        let version = match content.magic {
+            VersionedMagic::GgufV3 => 3,
+            VersionedMagic::GgufV1 | VersionedMagic::GgufV2 => 2,
+        };
+        w.write_u32::<LittleEndian>(0x46554747)?;
+        w.write_u32::<LittleEndian>(version)?;
+        w.write_u64::<LittleEndian>(tensors.len() as u64)?;
+        w.write_u64::<LittleEndian>(metadata.len() as u64)?;
+        for (name, value) in metadata.iter() {
+            write_string(&mut w, name)?;
+            w.write_u32::<LittleEndian>(value.value_type().to_u32())?;
+            value.write(&mut w)?;
+        }
+        let mut offset = 0u64;
+        for (name, info, size_in_bytes) in tensors.iter() {
+            write_tensor_info(&mut w, name, info.shape.dims(), info.ggml_dtype, offset)?;
+            offset = (offset + *size_in_bytes as u64).div_ceil(alignment) * alignment;
+        }
+        let mut pos = w.stream_position()?;
+        let padding = pos.div_ceil(alignment) * alignment - pos;
+        w.write_all(&vec![0u8; padding as usize])?;
+        for (name, info, size_in_bytes) in tensors.iter() {
+            let size_in_bytes = *size_in_bytes as u64;
+            reader.seek(std::io::SeekFrom::Start(
+                content.tensor_data_offset + info.offset,
+            ))?;
+            let copied = std::io::copy(&mut (&mut reader).take(size_in_bytes), &mut w)?;
+            if copied != size_in_bytes {
+                crate::bail!("unexpected end of file in the data of {name}")
+            }
+            pos = w.stream_position()?;
+            let padding = pos.div_ceil(alignment) * alignment - pos;
+            w.write_all(&vec![0u8; padding as usize])?;
+        }
+        w.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    };
+    match write().and_then(|()| Ok(std::fs::rename(&tmp_path, path_out)?)) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            Err(err.with_path(path_out))
+        }
+    }
+}
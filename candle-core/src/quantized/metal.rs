@@ -1,6 +1,6 @@
 use super::{GgmlDType, QStorage};
 use crate::backend::BackendStorage;
-use crate::{DType, MetalDevice, MetalStorage, Result, Shape};
+use crate::{DType, Error, MetalDevice, MetalStorage, Result, Shape};
 use metal::Buffer;
 use std::sync::Arc;
 
@@ -55,6 +55,10 @@ impl QMetalStorage {
                 let vec: Vec<half::f16> = read_to_vec(&buffer, block_len);
                 half::f16::to_float(&vec, &mut out)?;
             }
+            GgmlDType::BF16 => {
+                let vec: Vec<half::bf16> = read_to_vec(&buffer, block_len);
+                half::bf16::to_float(&vec, &mut out)?;
+            }
             GgmlDType::Q4_0 => {
                 let vec: Vec<crate::quantized::BlockQ4_0> = read_to_vec(&buffer, block_len);
                 crate::quantized::BlockQ4_0::to_float(&vec, &mut out)?;
@@ -114,6 +118,25 @@ impl QMetalStorage {
         ))
     }
 
+    pub fn to_bf16(&self, elem_count: usize) -> Result<MetalStorage> {
+        if self.dtype != GgmlDType::BF16 {
+            crate::bail!("to_bf16 requires bf16 storage, got {:?}", self.dtype)
+        }
+        let buffer = self.device.new_buffer(elem_count, DType::BF16, "to_bf16")?;
+        let command_buffer = self.device.command_buffer()?;
+        command_buffer.set_label("to_bf16");
+        let blit = command_buffer.new_blit_command_encoder();
+        blit.set_label("blit_to_bf16");
+        blit.copy_from_buffer(&self.buffer, 0, &buffer, 0, (elem_count * 2) as u64);
+        blit.end_encoding();
+        Ok(MetalStorage::new(
+            buffer,
+            self.device.clone(),
+            elem_count,
+            DType::BF16,
+        ))
+    }
+
     pub fn quantize(&mut self, src: &MetalStorage) -> Result<()> {
         // Quantization only happens on CPU for now.
         let src = src.to_cpu::<f32>()?;
@@ -147,6 +170,7 @@ impl QMetalStorage {
             crate::bail!("input tensor has only one dimension {layout:?}")
         }
         let (n, k) = self_shape.dims2()?;
+        let dtype: candle_metal_kernels::GgmlDType = self.dtype.try_into()?;
         let mut dst_shape = src_shape.dims().to_vec();
 
         // We always use a single batch dimension and stack all the tensors in the batch on the
@@ -173,7 +197,7 @@ impl QMetalStorage {
                 device.device(),
                 &command_buffer,
                 device.kernels(),
-                self.dtype.into(),
+                dtype,
                 (1, 1, n, k),
                 storage.buffer(),
                 (layout.start_offset() + batch_id * k) * storage.dtype().size_in_bytes(),
@@ -208,9 +232,11 @@ fn read_to_vec<T: Clone>(buffer: &Buffer, n: usize) -> Vec<T> {
     slice.to_vec()
 }
 
-impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
-    fn from(value: GgmlDType) -> Self {
-        match value {
+impl TryFrom<GgmlDType> for candle_metal_kernels::GgmlDType {
+    type Error = Error;
+
+    fn try_from(value: GgmlDType) -> Result<Self> {
+        let dtype = match value {
             GgmlDType::Q4_0 => candle_metal_kernels::GgmlDType::Q4_0,
             GgmlDType::Q4_1 => candle_metal_kernels::GgmlDType::Q4_1,
             GgmlDType::Q5_0 => candle_metal_kernels::GgmlDType::Q5_0,
@@ -225,6 +251,12 @@ impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
             GgmlDType::Q8K => candle_metal_kernels::GgmlDType::Q8K,
             GgmlDType::F16 => candle_metal_kernels::GgmlDType::F16,
             GgmlDType::F32 => candle_metal_kernels::GgmlDType::F32,
-        }
+            // There is no quantized kernel for bf16, `QMatMul` uses these weights as a bf16 tensor
+            // in a regular matmul.
+            GgmlDType::BF16 => {
+                return Err(Error::UnsupportedDTypeForOp(DType::BF16, "qmatmul").bt())
+            }
+        };
+        Ok(dtype)
     }
 }
@@ -28,13 +28,14 @@ pub mod neon;
 #[cfg(target_feature = "simd128")]
 pub mod simd128;
 pub mod utils;
-use half::f16;
+use half::{bf16, f16};
 
 pub use k_quants::GgmlType;
 
 pub struct QTensor {
     storage: QStorage,
     shape: Shape,
+    kernels: KernelLog,
 }
 
 impl Device {
@@ -115,6 +116,39 @@ impl QStorage {
         }
     }
 
+    // A storage with the given rows of `row_bytes` bytes each, in this order.
+    fn gather_rows(&self, rows: &[usize], row_bytes: usize) -> Result<QStorage> {
+        match self {
+            QStorage::Cpu(storage) => {
+                let data = self.data()?;
+                let mut bytes = Vec::with_capacity(rows.len() * row_bytes);
+                for &row in rows.iter() {
+                    bytes.extend_from_slice(&data[row * row_bytes..(row + 1) * row_bytes])
+                }
+                let dtype = storage.dtype();
+                let elem_count = bytes.len() / dtype.type_size() * dtype.block_size();
+                let qtensor =
+                    ggml_file::qtensor_from_ggml(dtype, &bytes, vec![elem_count], &Device::Cpu)?;
+                Ok(qtensor.storage)
+            }
+            QStorage::Metal(_) => crate::bail!("row lookups are not supported on metal"),
+            QStorage::Cuda(storage) => Ok(QStorage::Cuda(storage.gather_rows(rows, row_bytes)?)),
+        }
+    }
+
+    // Only valid for bf16 storage, the values are copied as is without going through f32.
+    fn to_bf16(&self, elem_count: usize) -> Result<Storage> {
+        match self {
+            QStorage::Cpu(storage) => {
+                let data = storage.as_ptr() as *const bf16;
+                let data = unsafe { std::slice::from_raw_parts(data, elem_count) };
+                Ok(Storage::Cpu(CpuStorage::BF16(data.to_vec())))
+            }
+            QStorage::Metal(storage) => Ok(Storage::Metal(storage.to_bf16(elem_count)?)),
+            QStorage::Cuda(storage) => Ok(Storage::Cuda(storage.to_bf16(elem_count)?)),
+        }
+    }
+
     fn data(&self) -> Result<Cow<[u8]>> {
         match self {
             QStorage::Cpu(storage) => {
@@ -134,6 +168,7 @@ impl QStorage {
 pub enum GgmlDType {
     F32,
     F16,
+    BF16,
     Q4_0,
     Q4_1,
     Q5_0,
@@ -165,6 +200,7 @@ impl GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            30 => Self::BF16,
             _ => crate::bail!("unknown dtype for tensor {u}"),
         };
         Ok(dtype)
@@ -186,6 +222,7 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
+            Self::BF16 => 30,
         }
     }
 
@@ -194,6 +231,7 @@ impl GgmlDType {
         match self {
             Self::F32 => Box::new(vec![f32::zeros(); elem_count]),
             Self::F16 => Box::new(vec![f16::zeros(); elem_count]),
+            Self::BF16 => Box::new(vec![bf16::zeros(); elem_count]),
             Self::Q4_0 => Box::new(vec![BlockQ4_0::zeros(); elem_count / BlockQ4_0::BLCK_SIZE]),
             Self::Q4_1 => Box::new(vec![BlockQ4_1::zeros(); elem_count / BlockQ4_1::BLCK_SIZE]),
             Self::Q5_0 => Box::new(vec![BlockQ5_0::zeros(); elem_count / BlockQ5_0::BLCK_SIZE]),
@@ -213,7 +251,7 @@ impl GgmlDType {
         use k_quants::*;
         match self {
             Self::F32 => 4,
-            Self::F16 => 2,
+            Self::F16 | Self::BF16 => 2,
             Self::Q4_0 => std::mem::size_of::<BlockQ4_0>(),
             Self::Q4_1 => std::mem::size_of::<BlockQ4_1>(),
             Self::Q5_0 => std::mem::size_of::<BlockQ5_0>(),
@@ -234,7 +272,7 @@ impl GgmlDType {
     pub fn block_size(&self) -> usize {
         match self {
             Self::F32 => 1,
-            Self::F16 => 1,
+            Self::F16 | Self::BF16 => 1,
             Self::Q4_0 => k_quants::QK4_0,
             Self::Q4_1 => k_quants::QK4_1,
             Self::Q5_0 => k_quants::QK5_0,
@@ -319,7 +357,11 @@ impl QTensor {
     pub fn new<S: Into<Shape>>(storage: QStorage, shape: S) -> Result<Self> {
         let shape = shape.into();
         check_shape(&shape, storage.block_size())?;
-        Ok(Self { storage, shape })
+        Ok(Self {
+            storage,
+            shape,
+            kernels: KernelLog::default(),
+        })
     }
 
     pub fn quantize(src: &Tensor, dtype: GgmlDType) -> Result<Self> {
@@ -339,6 +381,7 @@ impl QTensor {
         Ok(Self {
             storage,
             shape: shape.clone(),
+            kernels: KernelLog::default(),
         })
     }
 
@@ -381,6 +424,55 @@ impl QTensor {
         }
     }
 
+    /// Returns a bf16 tensor on the same device for bf16 quantized tensors, the data is copied
+    /// without going through f32.
+    pub fn to_bf16_tensor(&self) -> Result<Tensor> {
+        if self.dtype() != GgmlDType::BF16 {
+            crate::bail!(
+                "to_bf16_tensor requires a bf16 tensor, got {:?}",
+                self.dtype()
+            )
+        }
+        let storage = self.storage.to_bf16(self.shape.elem_count())?;
+        let none = crate::op::BackpropOp::none();
+        Ok(crate::tensor::from_storage(
+            storage,
+            self.shape.clone(),
+            none,
+            false,
+        ))
+    }
+
+    /// Looks up the rows of a `(rows, cols)` tensor such as a token embedding table. Only the
+    /// selected rows are dequantized, the result has the shape of `ids` with an extra `cols`
+    /// dimension, the requested dtype, and is on the device of this tensor.
+    pub fn embedding(&self, ids: &Tensor, dtype: crate::DType) -> Result<Tensor> {
+        let (n_rows, n_cols) = self.shape.dims2()?;
+        let mut dims = ids.dims().to_vec();
+        dims.push(n_cols);
+        let ids = ids
+            .flatten_all()?
+            .to_dtype(crate::DType::U32)?
+            .to_vec1::<u32>()?;
+        if let Some(id) = ids.iter().find(|&&id| id as usize >= n_rows) {
+            crate::bail!("index {id} out of range for a table of {n_rows} rows")
+        }
+        if ids.is_empty() {
+            return Tensor::zeros(dims, dtype, &self.device());
+        }
+        let rows = ids.iter().map(|&id| id as usize).collect::<Vec<_>>();
+        let row_bytes = n_cols / self.dtype().block_size() * self.dtype().type_size();
+        let storage = self.storage.gather_rows(&rows, row_bytes)?;
+        let elem_count = rows.len() * n_cols;
+        // Same kernels as `dequantize` and `dequantize_f16` so that the rows match them exactly.
+        let storage = match (&storage, dtype) {
+            (QStorage::Cuda(s), crate::DType::F16) => Storage::Cuda(s.dequantize_f16(elem_count)?),
+            _ => storage.dequantize(elem_count)?,
+        };
+        let none = crate::op::BackpropOp::none();
+        crate::tensor::from_storage(storage, dims, none, false).to_dtype(dtype)
+    }
+
     pub fn storage_size_in_bytes(&self) -> usize {
         self.storage.size_in_bytes()
     }
@@ -388,6 +480,100 @@ impl QTensor {
     pub fn data(&self) -> Result<Cow<'_, [u8]>> {
         self.storage.data()
     }
+
+    /// The kernels used by the matmuls with this tensor so far, see [`QMatMulKernel`].
+    pub fn kernels(&self) -> KernelSelection {
+        self.kernels.selection()
+    }
+
+    /// The kernel used by the last matmul with this tensor, `None` before the first one.
+    pub fn last_kernel(&self) -> Option<QMatMulKernel> {
+        self.kernels.last()
+    }
+}
+
+/// The kernel that computed a quantized matmul.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum QMatMulKernel {
+    /// The cpu vec-dot kernels, working on the quantized blocks.
+    CpuVecDot = 1,
+    /// The cuda matrix-vector kernel that dequantizes the weights (dmmv), used with
+    /// `set_force_dmmv`.
+    CudaDmmv,
+    /// The cuda matrix-vector kernel on the q8_1 quantized input (mmvq).
+    CudaMmvq,
+    /// The cuda matrix-matrix kernel on the q8_1 quantized input (mmq).
+    CudaMmq,
+    /// The weights are dequantized on each call and multiplied with a f32 cuda matmul, used
+    /// for the batched shapes with `set_force_dmmv`.
+    CudaDequantize,
+    /// The metal matrix-vector kernel, run once per row of the input.
+    MetalMatVec,
+    /// The weights have been dequantized when building the [`QMatMul`] and a regular matmul is
+    /// used, e.g. for f32/f16 weights or with `CANDLE_DEQUANTIZE_ALL`.
+    Dequantized,
+}
+
+impl QMatMulKernel {
+    const ALL: [Self; 7] = [
+        Self::CpuVecDot,
+        Self::CudaDmmv,
+        Self::CudaMmvq,
+        Self::CudaMmq,
+        Self::CudaDequantize,
+        Self::MetalMatVec,
+        Self::Dequantized,
+    ];
+
+    fn from_u8(v: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| *k as u8 == v)
+    }
+}
+
+/// The kernels used for the matrix-vector products, where the input has a single row, and for
+/// the batched products.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct KernelSelection {
+    pub matvec: Option<QMatMulKernel>,
+    pub batched: Option<QMatMulKernel>,
+}
+
+// The kernels recorded by the forward passes, 0 stands for no kernel.
+#[derive(Default)]
+struct KernelLog {
+    matvec: std::sync::atomic::AtomicU8,
+    batched: std::sync::atomic::AtomicU8,
+    last: std::sync::atomic::AtomicU8,
+}
+
+impl KernelLog {
+    fn record(&self, kernel: QMatMulKernel, layout: &crate::Layout) {
+        use std::sync::atomic::Ordering::Relaxed;
+        let dims = layout.dims();
+        let rows = dims[..dims.len().saturating_sub(1)]
+            .iter()
+            .product::<usize>();
+        let slot = if rows <= 1 {
+            &self.matvec
+        } else {
+            &self.batched
+        };
+        slot.store(kernel as u8, Relaxed);
+        self.last.store(kernel as u8, Relaxed);
+    }
+
+    fn selection(&self) -> KernelSelection {
+        use std::sync::atomic::Ordering::Relaxed;
+        KernelSelection {
+            matvec: QMatMulKernel::from_u8(self.matvec.load(Relaxed)),
+            batched: QMatMulKernel::from_u8(self.batched.load(Relaxed)),
+        }
+    }
+
+    fn last(&self) -> Option<QMatMulKernel> {
+        QMatMulKernel::from_u8(self.last.load(std::sync::atomic::Ordering::Relaxed))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -423,6 +609,10 @@ impl QMatMul {
     pub fn from_arc(qtensor: std::sync::Arc<QTensor>) -> Result<Self> {
         let dequantize = match qtensor.dtype() {
             GgmlDType::F32 | GgmlDType::F16 => true,
+            // bf16 weights are never dequantized. There is no bf16 gemm on cpu so the vec-dot
+            // kernel runs on the bf16 values there, other devices use a regular bf16 matmul.
+            GgmlDType::BF16 if qtensor.device().is_cpu() => return Ok(Self::QTensor(qtensor)),
+            GgmlDType::BF16 => return Ok(Self::Tensor(qtensor.to_bf16_tensor()?)),
             _ => DEQUANTIZE_ALL.with(|b| *b),
         };
         let t = if dequantize {
@@ -441,6 +631,27 @@ impl QMatMul {
         Self::from_arc(std::sync::Arc::new(qtensor))
     }
 
+    /// The kernel used by the last forward pass, `None` before the first one. The dequantized
+    /// variants always use [`QMatMulKernel::Dequantized`].
+    pub fn last_kernel(&self) -> Option<QMatMulKernel> {
+        match self {
+            Self::QTensor(t) => t.last_kernel(),
+            Self::Tensor(_) | Self::TensorF16(_) => Some(QMatMulKernel::Dequantized),
+        }
+    }
+
+    /// The kernels used by the forward passes so far, for the single row inputs and for the
+    /// batched ones.
+    pub fn kernels(&self) -> KernelSelection {
+        match self {
+            Self::QTensor(t) => t.kernels(),
+            Self::Tensor(_) | Self::TensorF16(_) => KernelSelection {
+                matvec: Some(QMatMulKernel::Dequantized),
+                batched: Some(QMatMulKernel::Dequantized),
+            },
+        }
+    }
+
     pub fn dequantize_f16(&self) -> Result<Tensor> {
         match self {
             Self::QTensor(t) => t.dequantize_f16(&t.device()),
@@ -466,6 +677,10 @@ impl crate::CustomOp1 for QTensor {
         "qmatmul"
     }
 
+    fn params(&self) -> String {
+        format!("dtype={:?}", self.dtype())
+    }
+
     fn cpu_fwd(
         &self,
         storage: &crate::CpuStorage,
@@ -496,6 +711,7 @@ impl crate::CustomOp1 for QTensor {
         let slice = &slice[layout.start_offset()..layout.start_offset() + src_shape.elem_count()];
         let mut dst_storage = vec![0f32; dst_shape.elem_count()];
         self_storage.matmul_t((dst_shape.elem_count() / n, k, n), slice, &mut dst_storage)?;
+        self.kernels.record(QMatMulKernel::CpuVecDot, layout);
         Ok((crate::CpuStorage::F32(dst_storage), dst_shape))
     }
 
@@ -508,7 +724,9 @@ impl crate::CustomOp1 for QTensor {
             QStorage::Metal(metal) => metal,
             _ => unreachable!("Cannot call metal matmul on non metal QTensor"),
         };
-        self_storage.fwd(&self.shape, storage, layout)
+        let out = self_storage.fwd(&self.shape, storage, layout)?;
+        self.kernels.record(QMatMulKernel::MetalMatVec, layout);
+        Ok(out)
     }
 
     fn cuda_fwd(
@@ -520,13 +738,69 @@ impl crate::CustomOp1 for QTensor {
             QStorage::Cuda(cuda) => cuda,
             _ => unreachable!("Cannot call cuda matmul on non cuda QTensor"),
         };
-        self_storage.fwd(&self.shape, storage, layout)
+        let kernel = self_storage.kernel(layout);
+        let out = self_storage.fwd(&self.shape, storage, layout)?;
+        self.kernels.record(kernel, layout);
+        Ok(out)
+    }
+
+    // Only the gradient with respect to the input is computed, the quantized weight is treated
+    // as a constant. It gets dequantized when running the backward pass so that nothing besides
+    // the input is saved during the forward pass.
+    fn bwd(&self, arg: &Tensor, _res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        let (n, _k) = self.shape.dims2()?;
+        let w = self.dequantize(arg.device())?;
+        let grad_arg = grad_res.reshape(((), n))?.matmul(&w)?;
+        Ok(Some(grad_arg.reshape(arg.shape())?))
+    }
+}
+
+/// The op used when a gradient has to flow through a quantized matmul, this shares the weight
+/// with the [`QMatMul`] rather than copying it.
+struct QMatMulOp(std::sync::Arc<QTensor>);
+
+impl crate::CustomOp1 for QMatMulOp {
+    fn name(&self) -> &'static str {
+        "qmatmul"
+    }
+
+    fn params(&self) -> String {
+        format!("dtype={:?}", self.0.dtype())
+    }
+
+    fn cpu_fwd(
+        &self,
+        storage: &crate::CpuStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CpuStorage, Shape)> {
+        self.0.cpu_fwd(storage, layout)
+    }
+
+    fn metal_fwd(
+        &self,
+        storage: &crate::MetalStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::MetalStorage, Shape)> {
+        self.0.metal_fwd(storage, layout)
+    }
+
+    fn cuda_fwd(
+        &self,
+        storage: &crate::CudaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::CudaStorage, Shape)> {
+        self.0.cuda_fwd(storage, layout)
+    }
+
+    fn bwd(&self, arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
+        self.0.bwd(arg, res, grad_res)
     }
 }
 
 impl crate::Module for QMatMul {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
+            Self::QTensor(t) if xs.track_op() => xs.apply_op1(QMatMulOp(t.clone())),
             Self::QTensor(t) => xs.apply_op1_no_bwd(t.as_ref()),
             Self::Tensor(w) => {
                 let w = match *xs.dims() {
@@ -534,7 +808,12 @@ impl crate::Module for QMatMul {
                     [bsize, _, _] => w.broadcast_left(bsize)?.t()?,
                     _ => w.t()?,
                 };
-                xs.matmul(&w)
+                if w.dtype() == xs.dtype() {
+                    xs.matmul(&w)
+                } else {
+                    let in_dtype = xs.dtype();
+                    xs.to_dtype(w.dtype())?.matmul(&w)?.to_dtype(in_dtype)
+                }
             }
             Self::TensorF16(w) => {
                 let in_dtype = xs.dtype();
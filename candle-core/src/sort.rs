@@ -106,6 +106,10 @@ impl crate::CustomOp1 for ArgSort {
         "argsort"
     }
 
+    fn params(&self) -> String {
+        format!("asc={}", self.asc)
+    }
+
     fn cpu_fwd(
         &self,
         storage: &crate::CpuStorage,
@@ -1,4 +1,5 @@
 use crate::backend::BackendStorage;
+use crate::fallback;
 use crate::op::{self, CmpOp, ReduceOp};
 use crate::scalar::Scalar;
 use crate::{CpuStorage, CudaStorage, DType, Device, Error, Layout, MetalStorage, Result, Shape};
@@ -83,6 +84,12 @@ impl Storage {
     }
 
     pub(crate) fn affine(&self, layout: &Layout, mul: f64, add: f64) -> Result<Self> {
+        fallback::run("affine", &[self], |s| {
+            s[0].affine_no_fallback(layout, mul, add)
+        })
+    }
+
+    fn affine_no_fallback(&self, layout: &Layout, mul: f64, add: f64) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.affine(layout, mul, add)?;
@@ -100,6 +107,10 @@ impl Storage {
     }
 
     pub(crate) fn powf(&self, layout: &Layout, alpha: f64) -> Result<Self> {
+        fallback::run("powf", &[self], |s| s[0].powf_no_fallback(layout, alpha))
+    }
+
+    fn powf_no_fallback(&self, layout: &Layout, alpha: f64) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.powf(layout, alpha)?;
@@ -117,6 +128,10 @@ impl Storage {
     }
 
     pub(crate) fn elu(&self, layout: &Layout, alpha: f64) -> Result<Self> {
+        fallback::run("elu", &[self], |s| s[0].elu_no_fallback(layout, alpha))
+    }
+
+    fn elu_no_fallback(&self, layout: &Layout, alpha: f64) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.elu(layout, alpha)?;
@@ -139,6 +154,18 @@ impl Storage {
         rhs: &Self,
         lhs_layout: &Layout,
         rhs_layout: &Layout,
+    ) -> Result<Self> {
+        fallback::run("cmp", &[self, rhs], |s| {
+            s[0].cmp_no_fallback(op, s[1], lhs_layout, rhs_layout)
+        })
+    }
+
+    fn cmp_no_fallback(
+        &self,
+        op: CmpOp,
+        rhs: &Self,
+        lhs_layout: &Layout,
+        rhs_layout: &Layout,
     ) -> Result<Self> {
         self.same_device(rhs, "cmp")?;
         self.same_dtype(rhs, "cmp")?;
@@ -169,6 +196,12 @@ impl Storage {
     }
 
     pub(crate) fn reduce_op(&self, op: ReduceOp, layout: &Layout, s: &[usize]) -> Result<Self> {
+        fallback::run(op.name(), &[self], |st| {
+            st[0].reduce_op_no_fallback(op, layout, s)
+        })
+    }
+
+    fn reduce_op_no_fallback(&self, op: ReduceOp, layout: &Layout, s: &[usize]) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.reduce_op(op, layout, s)?;
@@ -186,6 +219,12 @@ impl Storage {
     }
 
     pub(crate) fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
+        fallback::run("to-dtype", &[self], |s| {
+            s[0].to_dtype_no_fallback(layout, dtype)
+        })
+    }
+
+    fn to_dtype_no_fallback(&self, layout: &Layout, dtype: DType) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.to_dtype(layout, dtype)?;
@@ -203,6 +242,7 @@ impl Storage {
     }
 
     pub(crate) fn apply_op1(&self, l: &Layout, c: &dyn CustomOp1) -> Result<(Self, Shape)> {
+        let _span = crate::op_registry::enter_span(c.name(), || c.params(), &[l]);
         match self {
             Self::Cpu(storage) => {
                 let (storage, shape) = c.cpu_fwd(storage, l)?;
@@ -227,6 +267,7 @@ impl Storage {
         c: &dyn CustomOp2,
     ) -> Result<(Self, Shape)> {
         self.same_device(t2, c.name())?;
+        let _span = crate::op_registry::enter_span(c.name(), || c.params(), &[l1, l2]);
         match (self, t2) {
             (Self::Cpu(s1), Self::Cpu(s2)) => {
                 let (s, shape) = c.cpu_fwd(s1, l1, s2, l2)?;
@@ -255,6 +296,7 @@ impl Storage {
     ) -> Result<(Self, Shape)> {
         self.same_device(t2, c.name())?;
         self.same_device(t3, c.name())?;
+        let _span = crate::op_registry::enter_span(c.name(), || c.params(), &[l1, l2, l3]);
         match (self, t2, t3) {
             (Self::Cpu(s1), Self::Cpu(s2), Self::Cpu(s3)) => {
                 let (s, shape) = c.cpu_fwd(s1, l1, s2, l2, s3, l3)?;
@@ -318,6 +360,12 @@ impl Storage {
     }
 
     pub(crate) fn unary_impl<B: op::UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
+        fallback::run(B::NAME, &[self], |s| {
+            s[0].unary_impl_no_fallback::<B>(layout)
+        })
+    }
+
+    fn unary_impl_no_fallback<B: op::UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.unary_impl::<B>(layout)?;
@@ -339,6 +387,17 @@ impl Storage {
         rhs: &Self,
         lhs_layout: &Layout,
         rhs_layout: &Layout,
+    ) -> Result<Self> {
+        fallback::run(B::NAME, &[self, rhs], |s| {
+            s[0].binary_impl_no_fallback::<B>(s[1], lhs_layout, rhs_layout)
+        })
+    }
+
+    fn binary_impl_no_fallback<B: op::BinaryOpT>(
+        &self,
+        rhs: &Self,
+        lhs_layout: &Layout,
+        rhs_layout: &Layout,
     ) -> Result<Self> {
         self.same_device(rhs, B::NAME)?;
         self.same_dtype(rhs, B::NAME)?;
@@ -374,6 +433,18 @@ impl Storage {
         kernel: &Self,
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv1D,
+    ) -> Result<Self> {
+        fallback::run("conv1d", &[self, kernel], |s| {
+            s[0].conv1d_no_fallback(l, s[1], kernel_l, params)
+        })
+    }
+
+    fn conv1d_no_fallback(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv1D,
     ) -> Result<Self> {
         self.same_device(kernel, "conv1d")?;
         self.same_dtype(kernel, "conv1d")?;
@@ -405,6 +476,18 @@ impl Storage {
         kernel: &Self,
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose1D,
+    ) -> Result<Self> {
+        fallback::run("conv-transpose1d", &[self, kernel], |s| {
+            s[0].conv_transpose1d_no_fallback(l, s[1], kernel_l, params)
+        })
+    }
+
+    fn conv_transpose1d_no_fallback(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose1D,
     ) -> Result<Self> {
         self.same_device(kernel, "conv-transpose1d")?;
         self.same_dtype(kernel, "conv-transpose1d")?;
@@ -436,6 +519,18 @@ impl Storage {
         kernel: &Self,
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv2D,
+    ) -> Result<Self> {
+        fallback::run("conv2d", &[self, kernel], |s| {
+            s[0].conv2d_no_fallback(l, s[1], kernel_l, params)
+        })
+    }
+
+    fn conv2d_no_fallback(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConv2D,
     ) -> Result<Self> {
         self.same_device(kernel, "conv2d")?;
         self.same_dtype(kernel, "conv2d")?;
@@ -467,6 +562,18 @@ impl Storage {
         kernel: &Self,
         kernel_l: &Layout,
         params: &crate::conv::ParamsConvTranspose2D,
+    ) -> Result<Self> {
+        fallback::run("conv-transpose2d", &[self, kernel], |s| {
+            s[0].conv_transpose2d_no_fallback(l, s[1], kernel_l, params)
+        })
+    }
+
+    fn conv_transpose2d_no_fallback(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &crate::conv::ParamsConvTranspose2D,
     ) -> Result<Self> {
         self.same_device(kernel, "conv_transpose2d")?;
         self.same_dtype(kernel, "conv_transpose2d")?;
@@ -497,6 +604,17 @@ impl Storage {
         layout: &Layout,
         kernel_size: (usize, usize),
         stride: (usize, usize),
+    ) -> Result<Self> {
+        fallback::run("avg-pool2d", &[self], |s| {
+            s[0].avg_pool2d_no_fallback(layout, kernel_size, stride)
+        })
+    }
+
+    fn avg_pool2d_no_fallback(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
     ) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
@@ -519,6 +637,17 @@ impl Storage {
         layout: &Layout,
         kernel_size: (usize, usize),
         stride: (usize, usize),
+    ) -> Result<Self> {
+        fallback::run("max-pool2d", &[self], |s| {
+            s[0].max_pool2d_no_fallback(layout, kernel_size, stride)
+        })
+    }
+
+    fn max_pool2d_no_fallback(
+        &self,
+        layout: &Layout,
+        kernel_size: (usize, usize),
+        stride: (usize, usize),
     ) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
@@ -537,6 +666,12 @@ impl Storage {
     }
 
     pub(crate) fn upsample_nearest1d(&self, layout: &Layout, sz: usize) -> Result<Self> {
+        fallback::run("upsample-nearest1d", &[self], |s| {
+            s[0].upsample_nearest1d_no_fallback(layout, sz)
+        })
+    }
+
+    fn upsample_nearest1d_no_fallback(&self, layout: &Layout, sz: usize) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest1d(layout, sz)?;
@@ -554,6 +689,12 @@ impl Storage {
     }
 
     pub(crate) fn upsample_nearest2d(&self, layout: &Layout, h: usize, w: usize) -> Result<Self> {
+        fallback::run("upsample-nearest2d", &[self], |s| {
+            s[0].upsample_nearest2d_no_fallback(layout, h, w)
+        })
+    }
+
+    fn upsample_nearest2d_no_fallback(&self, layout: &Layout, h: usize, w: usize) -> Result<Self> {
         match self {
             Storage::Cpu(storage) => {
                 let storage = storage.upsample_nearest2d(layout, h, w)?;
@@ -577,6 +718,19 @@ impl Storage {
         layout_t: &Layout,
         f: &Self,
         layout_f: &Layout,
+    ) -> Result<Self> {
+        fallback::run("where-cond", &[self, t, f], |s| {
+            s[0].where_cond_no_fallback(layout, s[1], layout_t, s[2], layout_f)
+        })
+    }
+
+    fn where_cond_no_fallback(
+        &self,
+        layout: &Layout,
+        t: &Self,
+        layout_t: &Layout,
+        f: &Self,
+        layout_f: &Layout,
     ) -> Result<Self> {
         self.same_device(t, "where")?;
         self.same_device(f, "where")?;
@@ -609,6 +763,18 @@ impl Storage {
         indexes: &Self,
         indexes_l: &Layout,
         d: usize,
+    ) -> Result<Self> {
+        fallback::run("gather", &[self, indexes], |s| {
+            s[0].gather_no_fallback(l, s[1], indexes_l, d)
+        })
+    }
+
+    fn gather_no_fallback(
+        &self,
+        l: &Layout,
+        indexes: &Self,
+        indexes_l: &Layout,
+        d: usize,
     ) -> Result<Self> {
         self.same_device(indexes, "index-add")?;
         match (self, indexes) {
@@ -688,6 +854,20 @@ impl Storage {
         source: &Self,
         source_l: &Layout,
         d: usize,
+    ) -> Result<Self> {
+        fallback::run("index-add", &[self, indexes, source], |s| {
+            s[0].index_add_no_fallback(l, s[1], indexes_l, s[2], source_l, d)
+        })
+    }
+
+    fn index_add_no_fallback(
+        &self,
+        l: &Layout,
+        indexes: &Self,
+        indexes_l: &Layout,
+        source: &Self,
+        source_l: &Layout,
+        d: usize,
     ) -> Result<Self> {
         self.same_device(indexes, "index-add")?;
         self.same_device(source, "index-add")?;
@@ -714,6 +894,18 @@ impl Storage {
         lhs_l: &Layout,
         rhs_l: &Layout,
         d: usize,
+    ) -> Result<Self> {
+        fallback::run("index-select", &[self, rhs], |s| {
+            s[0].index_select_no_fallback(s[1], lhs_l, rhs_l, d)
+        })
+    }
+
+    fn index_select_no_fallback(
+        &self,
+        rhs: &Self,
+        lhs_l: &Layout,
+        rhs_l: &Layout,
+        d: usize,
     ) -> Result<Self> {
         self.same_device(rhs, "index-select")?;
         match (self, rhs) {
@@ -744,6 +936,18 @@ impl Storage {
         bmnk: (usize, usize, usize, usize),
         lhs_layout: &Layout,
         rhs_layout: &Layout,
+    ) -> Result<Self> {
+        fallback::run("matmul", &[self, rhs], |s| {
+            s[0].matmul_no_fallback(s[1], bmnk, lhs_layout, rhs_layout)
+        })
+    }
+
+    fn matmul_no_fallback(
+        &self,
+        rhs: &Self,
+        bmnk: (usize, usize, usize, usize),
+        lhs_layout: &Layout,
+        rhs_layout: &Layout,
     ) -> Result<Self> {
         self.same_device(rhs, "matmul")?;
         self.same_dtype(rhs, "matmul")?;
@@ -0,0 +1,61 @@
+use candle::{test_device, DType, Device, Result, Tensor};
+use candle_core as candle;
+
+fn ops(lhs: &Tensor, rhs: &Tensor) -> Result<Vec<Tensor>> {
+    let mask = lhs.ge(rhs)?;
+    Ok(vec![
+        mask.where_cond(lhs, rhs)?,
+        lhs.matmul(&rhs.t()?)?,
+        (lhs + rhs)?.exp()?,
+        lhs.sum_keepdim(1)?,
+        lhs.to_dtype(DType::F64)?.to_dtype(DType::F32)?,
+    ])
+}
+
+// The fallback flag is process wide so everything is checked from a single test.
+fn fallback(device: &Device) -> Result<()> {
+    let lhs = Tensor::randn(0f32, 1., (3, 4), &Device::Cpu)?;
+    let rhs = Tensor::randn(0f32, 1., (3, 4), &Device::Cpu)?;
+    let expected = ops(&lhs, &rhs)?;
+    let lhs = lhs.to_device(device)?;
+    let rhs = rhs.to_device(device)?;
+
+    let check = |res: Vec<Tensor>, op: &str| -> Result<()> {
+        for (res, expected) in res.iter().zip(expected.iter()) {
+            assert!(res.device().same_device(device), "{op}");
+            let diff = (res.to_device(&Device::Cpu)? - expected)?
+                .abs()?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>()?;
+            assert!(diff < 1e-4, "{op}: {diff}");
+        }
+        Ok(())
+    };
+    for op in ["where-cond", "matmul", "add", "exp", "sum", "to-dtype"] {
+        candle::simulate_missing_kernel(Some(op));
+
+        // With the fallback disabled the ops go straight to the backend.
+        candle::set_fallback_to_cpu(false);
+        let count = candle::fallback_count();
+        check(ops(&lhs, &rhs)?, op)?;
+        assert_eq!(candle::fallback_count(), count, "{op}");
+
+        // Each op is only called once by `ops`, the to-dtype one twice.
+        candle::set_fallback_to_cpu(true);
+        check(ops(&lhs, &rhs)?, op)?;
+        let expected = if op == "to-dtype" { 2 } else { 1 };
+        assert_eq!(candle::fallback_count(), count + expected, "{op}");
+    }
+
+    // Without a missing kernel, nothing falls back.
+    candle::simulate_missing_kernel(None);
+    let count = candle::fallback_count();
+    check(ops(&lhs, &rhs)?, "none")?;
+    assert_eq!(candle::fallback_count(), count);
+    candle::simulate_missing_kernel(None);
+    candle::set_fallback_to_cpu(false);
+    Ok(())
+}
+
+test_device!(fallback, fallback_cpu, fallback_gpu, fallback_metal);
@@ -63,6 +63,35 @@ fn matmul_bf16(device: &Device) -> Result<()> {
     Ok(())
 }
 
+// With the blas features the f16 and bf16 matmuls on cpu use the f32 gemm, the products are
+// accumulated in f32 and only rounded once.
+#[cfg(any(feature = "mkl", feature = "accelerate"))]
+#[test]
+fn matmul_half_blas() -> Result<()> {
+    let device = &Device::Cpu;
+    // The attention products of a 7b model with 32 heads of size 128, for a prompt of 16 tokens
+    // over a context of 40 positions: q @ k^t, then the scores @ v.
+    let q = Tensor::randn(0f32, 1., (32, 16, 128), device)?;
+    let k = Tensor::randn(0f32, 1., (32, 40, 128), device)?;
+    let v = Tensor::randn(0f32, 1., (32, 40, 128), device)?;
+    for (dtype, eps) in [(DType::F16, 1. / 1024.), (DType::BF16, 1. / 128.)] {
+        let (q, k, v) = (q.to_dtype(dtype)?, k.to_dtype(dtype)?, v.to_dtype(dtype)?);
+        let scores = q.matmul(&k.t()?)?;
+        let out = scores.matmul(&v)?;
+        let f32 = |t: &Tensor| t.to_dtype(DType::F32);
+        let expected_scores = f32(&q)?.matmul(&f32(&k)?.t()?)?;
+        let expected_out = f32(&scores)?.matmul(&f32(&v)?)?;
+        for (t, expected) in [(scores, expected_scores), (out, expected_out)] {
+            let t = f32(&t)?.flatten_all()?.to_vec1::<f32>()?;
+            let expected = expected.flatten_all()?.to_vec1::<f32>()?;
+            for (v, e) in t.iter().zip(expected.iter()) {
+                assert!((v - e).abs() <= e.abs() * eps + 1e-3, "{dtype:?} {v} {e}")
+            }
+        }
+    }
+    Ok(())
+}
+
 fn broadcast_matmul(device: &Device) -> Result<()> {
     let lhs = Tensor::randn(0f32, 1f32, (3, 1, 4, 5), device)?;
     let rhs = Tensor::randn(0f32, 1f32, (6, 5, 2), device)?;
@@ -0,0 +1,128 @@
+use candle_core::cpu::numa::{self, NumaPolicy, Placement, Topology};
+use candle_core::quantized::{ggml_file, GgmlDType};
+use candle_core::{Device, Result};
+use std::sync::{Arc, Mutex};
+
+fn two_nodes() -> Topology {
+    Topology::new(vec![(1, vec![4, 5, 6, 7]), (0, vec![0, 1, 2, 3])])
+}
+
+#[test]
+fn cpulist() -> Result<()> {
+    assert_eq!(
+        numa::parse_cpulist("0-3,8-11\n")?,
+        [0, 1, 2, 3, 8, 9, 10, 11]
+    );
+    assert_eq!(numa::parse_cpulist("5")?, [5]);
+    assert_eq!(numa::parse_cpulist("")?, Vec::<usize>::new());
+    assert!(numa::parse_cpulist("0-x").is_err());
+    Ok(())
+}
+
+#[test]
+fn policy_parsing() -> Result<()> {
+    assert_eq!("distribute".parse::<NumaPolicy>()?, NumaPolicy::Interleave);
+    assert_eq!("numactl".parse::<NumaPolicy>()?, NumaPolicy::Auto);
+    assert_eq!("node=1".parse::<NumaPolicy>()?, NumaPolicy::BindNode(1));
+    assert_eq!("disabled".parse::<NumaPolicy>()?, NumaPolicy::Disabled);
+    assert!(matches!(
+        "isolate".parse::<NumaPolicy>()?,
+        NumaPolicy::BindNode(_)
+    ));
+    assert!("mirror".parse::<NumaPolicy>().is_err());
+    Ok(())
+}
+
+#[test]
+fn placement() {
+    let all = [0, 1, 2, 3, 4, 5, 6, 7];
+    let topology = two_nodes();
+    assert_eq!(topology.node_of_cpu(5), Some(1));
+    assert_eq!(
+        NumaPolicy::Interleave.placement(&topology, &all),
+        Some(Placement::Interleave(vec![0, 1]))
+    );
+    assert_eq!(
+        NumaPolicy::BindNode(1).placement(&topology, &all),
+        Some(Placement::Bind(1))
+    );
+    assert_eq!(NumaPolicy::Disabled.placement(&topology, &all), None);
+    // Auto follows the process affinity.
+    assert_eq!(
+        NumaPolicy::Auto.placement(&topology, &[4, 6]),
+        Some(Placement::Bind(1))
+    );
+    assert_eq!(
+        NumaPolicy::Auto.placement(&topology, &[2, 6]),
+        Some(Placement::Interleave(vec![0, 1]))
+    );
+
+    // Everything is a no-op with a single node.
+    let single = Topology::new(vec![(0, vec![0, 1, 2, 3])]);
+    for policy in [
+        NumaPolicy::Interleave,
+        NumaPolicy::BindNode(0),
+        NumaPolicy::Auto,
+    ] {
+        assert_eq!(policy.placement(&single, &[0, 1, 2, 3]), None)
+    }
+}
+
+#[test]
+fn worker_cpus() {
+    let topology = two_nodes();
+    let interleave = Placement::Interleave(vec![0, 1]);
+    assert_eq!(interleave.worker_cpus(&topology, 0), [0, 1, 2, 3]);
+    assert_eq!(interleave.worker_cpus(&topology, 1), [4, 5, 6, 7]);
+    assert_eq!(interleave.worker_cpus(&topology, 2), [0, 1, 2, 3]);
+    assert_eq!(interleave.n_cpus(&topology), 8);
+    let bind = Placement::Bind(1);
+    assert_eq!(bind.worker_cpus(&topology, 3), [4, 5, 6, 7]);
+    assert_eq!(bind.n_cpus(&topology), 4);
+}
+
+// The policy is global, so all the steps that change it run in this single test.
+#[test]
+fn allocation_hints() -> Result<()> {
+    let hints = Arc::new(Mutex::new(vec![]));
+    let observed = hints.clone();
+    numa::set_allocation_observer(Some(Box::new(move |hint| {
+        observed.lock().unwrap().push(hint.clone())
+    })));
+    let load = |dims: Vec<usize>| {
+        let raw_data = vec![0u8; dims.iter().product::<usize>() * 4];
+        ggml_file::qtensor_from_ggml(GgmlDType::F32, &raw_data, dims, &Device::Cpu)
+    };
+
+    assert!(numa::set_numa_policy_with_topology(NumaPolicy::BindNode(2), two_nodes()).is_err());
+    numa::set_numa_policy_with_topology(NumaPolicy::Interleave, two_nodes())?;
+    assert_eq!(numa::numa_policy(), NumaPolicy::Interleave);
+    // 1MiB is placed, smaller storages are not.
+    load(vec![512, 512])?;
+    load(vec![16, 16])?;
+    numa::set_numa_policy_with_topology(NumaPolicy::BindNode(1), two_nodes())?;
+    load(vec![1024, 512])?;
+    numa::set_numa_policy_with_topology(NumaPolicy::Disabled, two_nodes())?;
+    load(vec![512, 512])?;
+    let single = Topology::new(vec![(0, vec![0, 1])]);
+    numa::set_numa_policy_with_topology(NumaPolicy::Interleave, single)?;
+    assert_eq!(numa::placement(), None);
+    load(vec![512, 512])?;
+    // Nothing to pin without a placement.
+    numa::pin_worker_threads()?;
+
+    let hints = hints.lock().unwrap();
+    let hints = hints
+        .iter()
+        .map(|h| (h.bytes, h.placement.clone()))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        hints,
+        [
+            (1 << 20, Placement::Interleave(vec![0, 1])),
+            (2 << 20, Placement::Bind(1)),
+        ]
+    );
+    numa::set_allocation_observer(None);
+    Ok(())
+}
@@ -215,9 +215,68 @@ fn qmm_batch(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+fn qmm_backward(dev: &Device) -> Result<()> {
+    let (lhs, rhs, _mm) = get_random_tensors(6, 256, 8, dev)?;
+    let qrhs = quantized::QTensor::quantize(&rhs, GgmlDType::Q4_0)?;
+    let rhs = qrhs.dequantize(dev)?;
+    let qmm = quantized::QMatMul::from_qtensor(qrhs)?;
+    // No graph is recorded for inputs that do not require a gradient.
+    assert!(!qmm.forward(&lhs)?.track_op());
+
+    let lhs = candle_core::Var::from_tensor(&lhs.reshape((2, 3, 256))?)?;
+    let coeffs = Tensor::arange(0f32, 48., dev)?.reshape((2, 3, 8))?.cos()?;
+    // The gradient of a weighted sum does not depend on the forward result, so it matches the
+    // gradient of a matmul with the dequantized weight.
+    let loss = (qmm.forward(&lhs)? * &coeffs)?.sum_all()?;
+    let grads = loss.backward()?;
+    let grad = grads.get(&lhs).unwrap();
+    assert_eq!(grad.dims(), [2, 3, 256]);
+    let expected = coeffs.reshape((6, 8))?.matmul(&rhs)?.reshape((2, 3, 256))?;
+    let diff = (grad - expected)?.abs()?.flatten_all()?.max(0)?;
+    assert!(diff.to_vec0::<f32>()? < 1e-5);
+    Ok(())
+}
+
+fn qmm_kernels(dev: &Device) -> Result<()> {
+    use quantized::{KernelSelection, QMatMulKernel};
+
+    let (lhs, rhs, _mm) = get_random_tensors(16, 256, 8, dev)?;
+    let qmm =
+        quantized::QMatMul::from_qtensor(quantized::QTensor::quantize(&rhs, GgmlDType::Q4_0)?)?;
+    assert_eq!(qmm.last_kernel(), None);
+    let (matvec, batched) = if dev.is_cuda() {
+        (QMatMulKernel::CudaMmvq, QMatMulKernel::CudaMmq)
+    } else if dev.is_metal() {
+        (QMatMulKernel::MetalMatVec, QMatMulKernel::MetalMatVec)
+    } else {
+        (QMatMulKernel::CpuVecDot, QMatMulKernel::CpuVecDot)
+    };
+    qmm.forward(&lhs.i(..1)?)?;
+    assert_eq!(qmm.last_kernel(), Some(matvec));
+    qmm.forward(&lhs)?;
+    assert_eq!(qmm.last_kernel(), Some(batched));
+    let expected = KernelSelection {
+        matvec: Some(matvec),
+        batched: Some(batched),
+    };
+    assert_eq!(qmm.kernels(), expected);
+
+    let dense =
+        quantized::QMatMul::from_qtensor(quantized::QTensor::quantize(&rhs, GgmlDType::F32)?)?;
+    assert_eq!(dense.last_kernel(), Some(QMatMulKernel::Dequantized));
+    Ok(())
+}
+
 test_device!(quantized_matmul, qmm_cpu, qmm_cuda, qmm_metal);
+test_device!(
+    qmm_kernels,
+    qmm_kernels_cpu,
+    qmm_kernels_cuda,
+    qmm_kernels_metal
+);
 test_device!(quantized_matmul_neg, qmm_n_cpu, qmm_n_cuda, qmm_n_metal);
 test_device!(qmm_batch, qmm_b_cpu, qmm_b_cuda, qmm_b_metal);
+test_device!(qmm_backward, qmm_bwd_cpu, qmm_bwd_cuda, qmm_bwd_metal);
 
 fn quantize_q4_0(device: &Device) -> Result<()> {
     let src = (0..32 * 4).map(|v| v as f32).collect::<Vec<_>>();
@@ -777,6 +836,115 @@ test_device!(
     quantize_q8k_metal
 );
 
+fn gguf_bf16(device: &Device) -> Result<()> {
+    use quantized::gguf_file;
+
+    let (n, k) = (8, 64);
+    let reference = (0..n * k)
+        .map(|v| (v as f32 * 0.37).sin() * 3.)
+        .collect::<Vec<_>>();
+    let reference = Tensor::from_vec(reference, (n, k), &Device::Cpu)?;
+    let bf16 = quantized::QTensor::quantize(&reference, GgmlDType::BF16)?;
+    let f32 = quantized::QTensor::quantize(&reference, GgmlDType::F32)?;
+    let mut buffer = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &[], &[("w.bf16", &bf16), ("w.f32", &f32)])?;
+
+    buffer.set_position(0);
+    let content = gguf_file::Content::read(&mut buffer)?;
+    assert_eq!(content.tensor_infos["w.bf16"].ggml_dtype, GgmlDType::BF16);
+    let bf16 = content.tensor(&mut buffer, "w.bf16", device)?;
+    let f32 = content.tensor(&mut buffer, "w.f32", device)?;
+    assert_eq!(bf16.dtype(), GgmlDType::BF16);
+    assert_eq!(bf16.storage_size_in_bytes(), n * k * 2);
+
+    // bf16 has 8 bits of mantissa.
+    let tolerance = 3. / 256.;
+    let reference = f32.dequantize(device)?;
+    let max_rel_error = |t: &Tensor| -> Result<f32> {
+        let t = t.to_dtype(DType::F32)?;
+        ((t - &reference)?.abs()? / (reference.abs()? + 1e-3)?)?
+            .max_all()?
+            .to_scalar::<f32>()
+    };
+    let tensor = bf16.to_bf16_tensor()?;
+    assert_eq!(tensor.dtype(), DType::BF16);
+    assert!(max_rel_error(&tensor)? < tolerance);
+    assert!(max_rel_error(&bf16.dequantize(device)?)? < tolerance);
+    assert!(f32.to_bf16_tensor().is_err());
+
+    // bf16 weights are not dequantized, the output keeps the input dtype.
+    let xs = Tensor::arange(0f32, (3 * k) as f32, device)?
+        .reshape((3, k))?
+        .affine(0.01, -0.5)?;
+    let expected = xs.matmul(&reference.t()?)?;
+    let matmul = quantized::QMatMul::from_qtensor(bf16)?;
+    match &matmul {
+        quantized::QMatMul::QTensor(w) => {
+            assert!(device.is_cpu());
+            assert_eq!(w.dtype(), GgmlDType::BF16)
+        }
+        quantized::QMatMul::Tensor(w) => assert_eq!(w.dtype(), DType::BF16),
+        quantized::QMatMul::TensorF16(_) => bail!("unexpected f16 weights"),
+    }
+    let ys = matmul.forward(&xs)?;
+    assert_eq!(ys.dtype(), DType::F32);
+    let error = ((ys - &expected)?.abs()? / (expected.abs()? + 1.)?)?
+        .max_all()?
+        .to_scalar::<f32>()?;
+    assert!(error < 0.05, "{error}");
+    Ok(())
+}
+
+test_device!(gguf_bf16, gguf_bf16_cpu, gguf_bf16_cuda, gguf_bf16_metal);
+
+fn qtensor_embedding(device: &Device) -> Result<()> {
+    if device.is_metal() {
+        return Ok(());
+    }
+    let (n, k) = (16, 512);
+    let table = (0..n * k)
+        .map(|v| (v as f32 * 0.13).cos() * 2.)
+        .collect::<Vec<_>>();
+    let table = Tensor::from_vec(table, (n, k), device)?;
+    let ids = Tensor::new(&[[3u32, 0, 15], [3, 7, 1]], device)?;
+    for ggml_dtype in [
+        GgmlDType::Q4_0,
+        GgmlDType::Q8_0,
+        GgmlDType::Q4K,
+        GgmlDType::F16,
+    ] {
+        let qtensor = quantized::QTensor::quantize(&table, ggml_dtype)?;
+        let full = qtensor.dequantize(device)?;
+        let full_f16 = qtensor.dequantize_f16(device)?;
+        for dtype in [DType::F32, DType::F16, DType::BF16] {
+            let rows = qtensor.embedding(&ids, dtype)?;
+            assert_eq!(rows.dims(), [2, 3, k]);
+            assert_eq!(rows.dtype(), dtype);
+            let full = match dtype {
+                DType::F16 => full_f16.clone(),
+                _ => full.to_dtype(dtype)?,
+            };
+            let expected = full.embedding(&ids.flatten_all()?)?.reshape((2, 3, k))?;
+            let rows = rows.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+            let expected = expected.to_dtype(DType::F32)?.flatten_all()?;
+            assert_eq!(rows, expected.to_vec1::<f32>()?, "{ggml_dtype:?} {dtype:?}");
+        }
+    }
+    let qtensor = quantized::QTensor::quantize(&table, GgmlDType::Q4_0)?;
+    let empty = Tensor::zeros(0, DType::U32, device)?;
+    assert_eq!(qtensor.embedding(&empty, DType::F32)?.dims(), [0, k]);
+    let out_of_range = Tensor::new(&[2u32, 16], device)?;
+    assert!(qtensor.embedding(&out_of_range, DType::F32).is_err());
+    Ok(())
+}
+
+test_device!(
+    qtensor_embedding,
+    qtensor_embedding_cpu,
+    qtensor_embedding_cuda,
+    qtensor_embedding_metal
+);
+
 /// Very simple dot product implementation
 fn vec_dot_reference(a: &[f32], b: &[f32]) -> f32 {
     a.iter().zip(b).map(|(a, b)| a * b).sum()
@@ -0,0 +1,323 @@
+// Malformed gguf and ggml files have to result in errors, not in panics or huge allocations.
+//
+// The files are mutated with a fixed seed so that failures can be reproduced. A wrapper around
+// the system allocator records the largest allocation made on the current thread to check the
+// memory budget.
+use candle::quantized::{ggml_file, gguf_file, GgmlDType, QTensor};
+use candle::{Device, Result, Tensor};
+use candle_core as candle;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+struct TrackingAllocator;
+
+thread_local! {
+    static LARGEST_ALLOC: Cell<usize> = const { Cell::new(0) };
+}
+
+fn track(size: usize) {
+    let _ = LARGEST_ALLOC.try_with(|l| l.set(l.get().max(size)));
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size());
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        track(layout.size());
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track(new_size);
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+const MEMORY_BUDGET: usize = 16 << 20;
+const TIME_BUDGET: Duration = Duration::from_secs(2);
+
+// Runs `f` and checks that it does not panic and stays within the time and memory budgets.
+fn within_budget<T>(what: &str, f: impl FnOnce() -> Result<T>) -> Option<Result<T>> {
+    LARGEST_ALLOC.with(|l| l.set(0));
+    let start = Instant::now();
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(f));
+    let elapsed = start.elapsed();
+    let largest = LARGEST_ALLOC.with(|l| l.get());
+    assert!(res.is_ok(), "{what}: panicked");
+    assert!(elapsed < TIME_BUDGET, "{what}: took {elapsed:?}");
+    assert!(largest < MEMORY_BUDGET, "{what}: allocated {largest} bytes");
+    res.ok()
+}
+
+// A small deterministic generator, splitmix64.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+// Mutates a copy of `bytes`, the mutations target the first `header_len` bytes where the lengths
+// and offsets live.
+fn mutate(rng: &mut Rng, bytes: &[u8], header_len: usize) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+    for _ in 0..1 + rng.below(4) {
+        let pos = rng.below(header_len.min(bytes.len()));
+        match rng.below(5) {
+            0 => bytes[pos] ^= 1 << rng.below(8),
+            1 => bytes[pos] = rng.next() as u8,
+            2 => {
+                let end = (pos + 8).min(bytes.len());
+                bytes[pos..end].fill(0xff)
+            }
+            3 => {
+                let end = (pos + 4).min(bytes.len());
+                bytes[pos..end].fill(0)
+            }
+            _ => bytes.truncate(pos),
+        }
+        if bytes.is_empty() {
+            break;
+        }
+    }
+    bytes
+}
+
+fn read_gguf(bytes: &[u8]) -> Result<Vec<QTensor>> {
+    let mut reader = Cursor::new(bytes);
+    let content = gguf_file::Content::read(&mut reader)?;
+    let mut names = content.tensor_infos.keys().collect::<Vec<_>>();
+    names.sort();
+    names
+        .iter()
+        .map(|name| content.tensor(&mut reader, name, &Device::Cpu))
+        .collect()
+}
+
+fn gguf_fixture() -> Result<(Vec<u8>, usize)> {
+    use gguf_file::Value;
+
+    let f32 = QTensor::quantize(
+        &Tensor::arange(0f32, 64., &Device::Cpu)?.reshape((2, 32))?,
+        GgmlDType::F32,
+    )?;
+    let q8 = QTensor::quantize(
+        &Tensor::arange(0f32, 64., &Device::Cpu)?.reshape((2, 32))?,
+        GgmlDType::Q8_0,
+    )?;
+    let tokens = ["a", "b", "<s>"].map(|t| Value::String(t.to_string()));
+    let nested = Value::Array(vec![
+        Value::Array(vec![Value::U32(1), Value::U32(2)]),
+        Value::Array(vec![Value::U32(3)]),
+    ]);
+    let metadata = [
+        ("general.architecture", Value::String("llama".to_string())),
+        ("general.alignment", Value::U32(32)),
+        ("tokenizer.ggml.tokens", Value::Array(tokens.to_vec())),
+        ("nested", nested),
+    ];
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let mut buffer = Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &metadata, &[("a", &f32), ("b", &q8)])?;
+    let bytes = buffer.into_inner();
+    let content = gguf_file::Content::read(&mut Cursor::new(&bytes))?;
+    Ok((bytes, content.tensor_data_offset as usize))
+}
+
+// The start of a gguf v3 file.
+fn gguf_header(tensor_count: u64, kv_count: u64) -> Vec<u8> {
+    let mut bytes = b"GGUF".to_vec();
+    bytes.extend(3u32.to_le_bytes());
+    bytes.extend(tensor_count.to_le_bytes());
+    bytes.extend(kv_count.to_le_bytes());
+    bytes
+}
+
+fn push_string(bytes: &mut Vec<u8>, s: &str) {
+    bytes.extend((s.len() as u64).to_le_bytes());
+    bytes.extend(s.as_bytes());
+}
+
+#[test]
+fn gguf_malformed() -> Result<()> {
+    let mut cases = vec![];
+
+    // A metadata array claiming 2^60 elements.
+    for elem_type in [0u32, 8, 9] {
+        let mut bytes = gguf_header(0, 1);
+        push_string(&mut bytes, "array");
+        bytes.extend(9u32.to_le_bytes());
+        bytes.extend(elem_type.to_le_bytes());
+        bytes.extend((1u64 << 60).to_le_bytes());
+        bytes.extend([0; 64]);
+        cases.push((format!("huge array of type {elem_type}"), bytes));
+    }
+
+    let mut bytes = gguf_header(0, 1);
+    bytes.extend((1u64 << 60).to_le_bytes());
+    cases.push(("huge key".to_string(), bytes));
+
+    let mut bytes = gguf_header(0, 1);
+    push_string(&mut bytes, "key");
+    bytes.extend(42u32.to_le_bytes());
+    cases.push(("unknown value type".to_string(), bytes));
+
+    // Arrays of arrays nested far enough to overflow the stack when read recursively.
+    let mut bytes = gguf_header(0, 1);
+    push_string(&mut bytes, "nested");
+    bytes.extend(9u32.to_le_bytes());
+    for _ in 0..100_000 {
+        bytes.extend(9u32.to_le_bytes());
+        bytes.extend(1u64.to_le_bytes());
+    }
+    cases.push(("nested arrays".to_string(), bytes));
+
+    let mut bytes = gguf_header(0, 1);
+    push_string(&mut bytes, "general.alignment");
+    bytes.extend(4u32.to_le_bytes());
+    bytes.extend(0u32.to_le_bytes());
+    cases.push(("zero alignment".to_string(), bytes));
+
+    let tensor = |dims: &[u64], offset: u64| {
+        let mut bytes = gguf_header(1, 0);
+        push_string(&mut bytes, "t");
+        bytes.extend((dims.len() as u32).to_le_bytes());
+        for d in dims {
+            bytes.extend(d.to_le_bytes());
+        }
+        bytes.extend(0u32.to_le_bytes());
+        bytes.extend(offset.to_le_bytes());
+        bytes.extend([0; 64]);
+        bytes
+    };
+    let mut bytes = gguf_header(1, 0);
+    push_string(&mut bytes, "t");
+    bytes.extend(u32::MAX.to_le_bytes());
+    cases.push(("huge number of dimensions".to_string(), bytes));
+    cases.push((
+        "overflowing shape".to_string(),
+        tensor(&[1 << 40, 1 << 40], 0),
+    ));
+    cases.push(("huge tensor".to_string(), tensor(&[1 << 40], 0)));
+    cases.push((
+        "offset past the end".to_string(),
+        tensor(&[4], u64::MAX - 8),
+    ));
+
+    for (what, bytes) in cases {
+        let res = within_budget(&what, || read_gguf(&bytes)).unwrap();
+        assert!(res.is_err(), "{what}: no error");
+    }
+    Ok(())
+}
+
+#[test]
+fn gguf_fuzz() -> Result<()> {
+    let (bytes, header_len) = gguf_fixture()?;
+    let expected = read_gguf(&bytes)?;
+    assert_eq!(expected.len(), 2);
+    let mut rng = Rng(299792458);
+    for index in 0..2000 {
+        let mutated = mutate(&mut rng, &bytes, header_len);
+        within_budget(&format!("gguf mutation {index}"), || read_gguf(&mutated));
+    }
+    Ok(())
+}
+
+// A ggjt v3 file with a 3 tokens vocabulary and the given tensors.
+// The tensor dtypes are the ggml type ids, 0 for f32 and 8 for q8_0.
+fn ggml_file(tensors: &[(&str, u32, &[u32], &[u8])]) -> Vec<u8> {
+    let mut bytes = 0x67676a74u32.to_le_bytes().to_vec();
+    bytes.extend(3u32.to_le_bytes());
+    // n_vocab, n_embd, n_mult, n_head, n_layer, n_rot, ftype
+    for v in [3u32, 32, 1, 1, 1, 32, 0] {
+        bytes.extend(v.to_le_bytes());
+    }
+    for (token, score) in [("a", 0f32), ("b", -1.), ("<s>", -2.)] {
+        bytes.extend((token.len() as u32).to_le_bytes());
+        bytes.extend(token.as_bytes());
+        bytes.extend(score.to_le_bytes());
+    }
+    for (name, dtype, dims, data) in tensors {
+        bytes.extend((dims.len() as u32).to_le_bytes());
+        bytes.extend((name.len() as u32).to_le_bytes());
+        bytes.extend(dtype.to_le_bytes());
+        for d in dims.iter().rev() {
+            bytes.extend(d.to_le_bytes());
+        }
+        bytes.extend(name.as_bytes());
+        bytes.resize(bytes.len().div_ceil(32) * 32, 0);
+        bytes.extend(*data);
+    }
+    bytes
+}
+
+fn read_ggml(bytes: &[u8]) -> Result<usize> {
+    let content = ggml_file::Content::read(&mut Cursor::new(bytes), &Device::Cpu)?;
+    Ok(content.tensors.len())
+}
+
+#[test]
+fn ggml_fuzz() -> Result<()> {
+    let f32 = (0..64)
+        .flat_map(|v| (v as f32).to_le_bytes())
+        .collect::<Vec<_>>();
+    let bytes = ggml_file(&[("a", 0, &[2, 32], &f32), ("b", 0, &[64], &f32)]);
+    assert_eq!(read_ggml(&bytes)?, 2);
+
+    let mut cases = vec![
+        (
+            "truncated data",
+            ggml_file(&[("a", 0, &[2, 32], &f32[..100])]),
+        ),
+        (
+            "huge tensor",
+            ggml_file(&[("a", 0, &[1 << 31, 1 << 31], &f32)]),
+        ),
+        ("wrong block size", ggml_file(&[("a", 8, &[3], &f32)])),
+    ];
+    let mut huge_vocab = bytes.clone();
+    huge_vocab[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+    cases.push(("huge vocabulary", huge_vocab));
+    let mut huge_token = bytes.clone();
+    huge_token[36..40].copy_from_slice(&u32::MAX.to_le_bytes());
+    cases.push(("huge token", huge_token));
+    for (what, bytes) in cases {
+        let res = within_budget(what, || read_ggml(&bytes)).unwrap();
+        assert!(res.is_err(), "{what}: no error");
+    }
+
+    // Everything up to the data of the first tensor.
+    let header_len = bytes.len() - 2 * f32.len();
+    let mut rng = Rng(1618033988);
+    for index in 0..2000 {
+        let mutated = mutate(&mut rng, &bytes, header_len);
+        within_budget(&format!("ggml mutation {index}"), || read_ggml(&mutated));
+    }
+
+    // The raw data has to cover the whole tensor.
+    let res = ggml_file::qtensor_from_ggml(GgmlDType::F32, &f32[..8], vec![64], &Device::Cpu);
+    assert!(res.is_err());
+    Ok(())
+}
@@ -0,0 +1,183 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use candle::quantized::gguf_file::{self, MetadataEdit, Value};
+use clap::Parser;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The gguf file to edit.
+    input: PathBuf,
+
+    /// Where to write the edited file, defaults to editing the input in place.
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// Sets a metadata value, `key=value` or `key=<type>:value` with type one of u8, i8, u16,
+    /// i16, u32, i32, u64, i64, f32, f64, bool, or str. Without a type the value keeps the type
+    /// of the existing key, new keys are strings.
+    #[arg(long)]
+    set: Vec<String>,
+
+    /// Sets a string metadata value to the content of a file, `key=path`, e.g. for a chat
+    /// template.
+    #[arg(long)]
+    set_from_file: Vec<String>,
+
+    /// Removes a metadata key.
+    #[arg(long)]
+    delete: Vec<String>,
+
+    /// Renames a tensor, `old=new`.
+    #[arg(long)]
+    rename_tensor: Vec<String>,
+
+    /// Prints the metadata and the tensors of the file and exits, long arrays are elided.
+    #[arg(long)]
+    list: bool,
+}
+
+fn split(arg: &str) -> Result<(String, &str)> {
+    match arg.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value)),
+        _ => bail!("expected key=value, got {arg:?}"),
+    }
+}
+
+fn parse_value(value: &str, existing: Option<&Value>) -> Result<Value> {
+    let (value_type, value) = match value.split_once(':') {
+        Some((t, v)) if VALUE_TYPES.contains(&t) => (t, v),
+        _ => match existing {
+            None | Some(Value::String(_)) => return Ok(Value::String(value.to_string())),
+            Some(Value::Array(_)) => bail!("arrays cannot be set from the command line"),
+            Some(existing) => (value_type_name(existing), value),
+        },
+    };
+    let value = match value_type {
+        "u8" => Value::U8(value.parse()?),
+        "i8" => Value::I8(value.parse()?),
+        "u16" => Value::U16(value.parse()?),
+        "i16" => Value::I16(value.parse()?),
+        "u32" => Value::U32(value.parse()?),
+        "i32" => Value::I32(value.parse()?),
+        "u64" => Value::U64(value.parse()?),
+        "i64" => Value::I64(value.parse()?),
+        "f32" => Value::F32(value.parse()?),
+        "f64" => Value::F64(value.parse()?),
+        "bool" => Value::Bool(value.parse()?),
+        _ => Value::String(value.to_string()),
+    };
+    Ok(value)
+}
+
+const VALUE_TYPES: [&str; 12] = [
+    "u8", "i8", "u16", "i16", "u32", "i32", "u64", "i64", "f32", "f64", "bool", "str",
+];
+
+fn value_type_name(value: &Value) -> &'static str {
+    match value {
+        Value::U8(_) => "u8",
+        Value::I8(_) => "i8",
+        Value::U16(_) => "u16",
+        Value::I16(_) => "i16",
+        Value::U32(_) => "u32",
+        Value::I32(_) => "i32",
+        Value::U64(_) => "u64",
+        Value::I64(_) => "i64",
+        Value::F32(_) => "f32",
+        Value::F64(_) => "f64",
+        Value::Bool(_) => "bool",
+        Value::String(_) => "str",
+        Value::Array(_) => "array",
+    }
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Array(vs) if vs.len() > 8 => format!("[{} values]", vs.len()),
+        Value::Array(vs) => format!(
+            "[{}]",
+            vs.iter().map(format_value).collect::<Vec<_>>().join(", ")
+        ),
+        Value::String(s) if s.chars().count() > 80 => {
+            format!("{:?}...", s.chars().take(80).collect::<String>())
+        }
+        Value::String(s) => format!("{s:?}"),
+        Value::U8(v) => v.to_string(),
+        Value::I8(v) => v.to_string(),
+        Value::U16(v) => v.to_string(),
+        Value::I16(v) => v.to_string(),
+        Value::U32(v) => v.to_string(),
+        Value::I32(v) => v.to_string(),
+        Value::U64(v) => v.to_string(),
+        Value::I64(v) => v.to_string(),
+        Value::F32(v) => v.to_string(),
+        Value::F64(v) => v.to_string(),
+        Value::Bool(v) => v.to_string(),
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut file = std::fs::File::open(&args.input)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&args.input))?;
+    if args.list {
+        let mut metadata = content.metadata.iter().collect::<Vec<_>>();
+        metadata.sort_by_key(|(k, _)| k.as_str());
+        for (key, value) in metadata {
+            println!(
+                "{key}: {} = {}",
+                value_type_name(value),
+                format_value(value)
+            )
+        }
+        let mut tensors = content.tensor_infos.iter().collect::<Vec<_>>();
+        tensors.sort_by_key(|(_, info)| info.offset);
+        for (name, info) in tensors {
+            println!("{name}: {:?} {:?}", info.ggml_dtype, info.shape.dims())
+        }
+        return Ok(());
+    }
+
+    let mut edits = vec![];
+    for arg in args.set.iter() {
+        let (key, value) = split(arg)?;
+        let value = parse_value(value, content.metadata.get(&key))
+            .with_context(|| format!("invalid value for {key}"))?;
+        edits.push(MetadataEdit::Set(key, value))
+    }
+    for arg in args.set_from_file.iter() {
+        let (key, path) = split(arg)?;
+        let value = std::fs::read_to_string(path).with_context(|| format!("reading {path}"))?;
+        edits.push(MetadataEdit::Set(key, Value::String(value)))
+    }
+    for key in args.delete.iter() {
+        edits.push(MetadataEdit::Delete(key.clone()))
+    }
+    for arg in args.rename_tensor.iter() {
+        let (old, new) = split(arg)?;
+        edits.push(MetadataEdit::RenameTensor(old, new.to_string()))
+    }
+    if edits.is_empty() {
+        bail!("nothing to do, use --set, --set-from-file, --delete, --rename-tensor, or --list")
+    }
+    drop(file);
+
+    let output = args.output.as_ref().unwrap_or(&args.input);
+    let n_edits = edits.len();
+    let start = std::time::Instant::now();
+    gguf_file::edit(&args.input, output, edits)?;
+    println!(
+        "applied {n_edits} edits to {} in {:.2}s",
+        output.display(),
+        start.elapsed().as_secs_f64()
+    );
+    Ok(())
+}
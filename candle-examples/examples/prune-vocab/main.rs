@@ -0,0 +1,119 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use candle::quantized::gguf_file;
+use candle_transformers::vocab_pruning::prune_vocab;
+use clap::Parser;
+use tokenizers::Tokenizer;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The gguf model to prune.
+    #[arg(long)]
+    model: PathBuf,
+
+    /// Where to write the pruned gguf model.
+    #[arg(long)]
+    out_file: PathBuf,
+
+    /// A file with one token to keep per line.
+    #[arg(long)]
+    keep_file: PathBuf,
+
+    /// The lines of the keep file are token ids rather than token strings.
+    #[arg(long)]
+    ids: bool,
+
+    /// The tokenizer used to resolve the token strings, when not set the tokens from the gguf
+    /// metadata are used.
+    #[arg(long)]
+    tokenizer: Option<PathBuf>,
+
+    /// Also keep the added tokens of the tokenizer, e.g. the bos and eos tokens.
+    #[arg(long)]
+    keep_added_tokens: bool,
+}
+
+fn format_size(size_in_bytes: usize) -> String {
+    if size_in_bytes < 1_000 {
+        format!("{}B", size_in_bytes)
+    } else if size_in_bytes < 1_000_000 {
+        format!("{:.2}KB", size_in_bytes as f64 / 1e3)
+    } else if size_in_bytes < 1_000_000_000 {
+        format!("{:.2}MB", size_in_bytes as f64 / 1e6)
+    } else {
+        format!("{:.2}GB", size_in_bytes as f64 / 1e9)
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut file = std::fs::File::open(&args.model)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&args.model))?;
+    let tokenizer = match &args.tokenizer {
+        None => None,
+        Some(path) => Some(Tokenizer::from_file(path).map_err(anyhow::Error::msg)?),
+    };
+
+    let keep_file = std::fs::read_to_string(&args.keep_file)?;
+    let lines = keep_file.lines().filter(|line| !line.is_empty());
+    let mut keep = vec![];
+    if args.ids {
+        for line in lines {
+            keep.push(line.trim().parse::<u32>()?)
+        }
+    } else {
+        let vocab: std::collections::HashMap<String, u32> = match &tokenizer {
+            Some(tokenizer) => tokenizer.get_vocab(true),
+            None => match content.metadata.get("tokenizer.ggml.tokens") {
+                None => bail!("no tokenizer.ggml.tokens in the gguf metadata, use --tokenizer"),
+                Some(tokens) => tokens
+                    .to_vec()?
+                    .iter()
+                    .enumerate()
+                    .map(|(id, token)| Ok((token.to_string()?.clone(), id as u32)))
+                    .collect::<Result<_>>()?,
+            },
+        };
+        let mut unknown = vec![];
+        for line in lines {
+            match vocab.get(line) {
+                Some(&id) => keep.push(id),
+                None => unknown.push(line),
+            }
+        }
+        if !unknown.is_empty() {
+            bail!(
+                "{} unknown tokens in the keep file: {unknown:?}",
+                unknown.len()
+            )
+        }
+    }
+    if args.keep_added_tokens {
+        match &tokenizer {
+            None => bail!("--keep-added-tokens requires --tokenizer"),
+            Some(tokenizer) => keep.extend(tokenizer.get_added_tokens_decoder().keys()),
+        }
+    }
+
+    let mut out_file = std::fs::File::create(&args.out_file)?;
+    let summary = prune_vocab(&content, &mut file, &keep, &mut out_file)?;
+    println!(
+        "vocabulary: {} -> {} tokens",
+        summary.original_vocab_size, summary.pruned_vocab_size
+    );
+    println!(
+        "weights: {} -> {} ({:.1}% smaller)",
+        format_size(summary.original_bytes),
+        format_size(summary.pruned_bytes),
+        100. * (1. - summary.pruned_bytes as f64 / summary.original_bytes as f64)
+    );
+    Ok(())
+}
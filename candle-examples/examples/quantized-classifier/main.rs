@@ -0,0 +1,212 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::{Error as E, Result};
+use clap::Parser;
+use tokenizers::Tokenizer;
+
+use candle::{DType, Device, Tensor, D};
+use candle_nn::{loss, ops, Module, Optimizer, VarBuilder, VarMap};
+use candle_transformers::bucketing::{last_positions, pad_batch, plan_buckets, BucketConfig};
+use candle_transformers::models::quantized_llama::ModelWeights;
+
+const LABELS: [&str; 2] = ["negative", "positive"];
+
+const TRAIN: [(&str, usize); 16] = [
+    ("I loved this movie, it was wonderful.", 1),
+    ("What a great day, everything went well.", 1),
+    ("The food was delicious and the staff were friendly.", 1),
+    ("This is the best book I have read this year.", 1),
+    ("I am so happy with my new phone.", 1),
+    ("The concert was amazing, I would go again.", 1),
+    ("Such a pleasant and relaxing holiday.", 1),
+    ("Thank you, this helped me a lot!", 1),
+    ("I hated this movie, it was boring.", 0),
+    ("What a terrible day, everything went wrong.", 0),
+    ("The food was cold and the staff were rude.", 0),
+    ("This is the worst book I have read this year.", 0),
+    ("I am so disappointed with my new phone.", 0),
+    ("The concert was awful, I left early.", 0),
+    ("Such a stressful and exhausting holiday.", 0),
+    ("This did not help at all, what a waste of time.", 0),
+];
+
+const TEST: [&str; 4] = [
+    "The hotel was lovely and the view was stunning.",
+    "The service was slow and the room was dirty.",
+    "My friends and I had a fantastic evening.",
+    "I regret buying this, it broke after a day.",
+];
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// GGUF file to load, defaults to SmolLM2 360M from the hub.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// The tokenizer config in json format.
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+
+    #[arg(long, default_value_t = 200)]
+    epochs: usize,
+
+    #[arg(long, default_value_t = 0.01)]
+    learning_rate: f64,
+
+    /// The features are extracted for groups of sentences of similar lengths, padded to the
+    /// longest one, the padding of a group stays below this share of its size.
+    #[arg(long, default_value_t = 0.25)]
+    bucket_pad_ratio: f64,
+
+    /// The largest number of sentences in a group.
+    #[arg(long, default_value_t = 8)]
+    bucket_max_size: usize,
+}
+
+fn load(args: &Args, device: &Device) -> Result<(ModelWeights, Tokenizer)> {
+    let api = hf_hub::api::sync::Api::new()?;
+    let model_path = match &args.model {
+        Some(model) => std::path::PathBuf::from(model),
+        None => api
+            .model("HuggingFaceTB/SmolLM2-360M-Instruct-GGUF".to_string())
+            .get("smollm2-360m-instruct-q8_0.gguf")?,
+    };
+    let tokenizer_path = match &args.tokenizer {
+        Some(tokenizer) => std::path::PathBuf::from(tokenizer),
+        None => api
+            .model("HuggingFaceTB/SmolLM2-360M-Instruct".to_string())
+            .get("tokenizer.json")?,
+    };
+    let mut file = std::fs::File::open(&model_path)?;
+    let content = candle::quantized::gguf_file::Content::read(&mut file)
+        .map_err(|e| e.with_path(&model_path))?;
+    let model = ModelWeights::from_gguf(content, &mut file, device)?;
+    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(E::msg)?;
+    Ok((model, tokenizer))
+}
+
+fn encode(tokenizer: &Tokenizer, text: &str, device: &Device) -> Result<Tensor> {
+    let tokens = tokenizer.encode(text, true).map_err(E::msg)?;
+    Ok(Tensor::new(tokens.get_ids(), device)?.unsqueeze(0)?)
+}
+
+// The final hidden state of the last token of each text, with shape `(texts, hidden_size)`.
+fn extract_features(
+    model: &mut ModelWeights,
+    tokenizer: &Tokenizer,
+    texts: &[&str],
+    config: &BucketConfig,
+    device: &Device,
+) -> Result<Tensor> {
+    let tokens = texts
+        .iter()
+        .map(|text| {
+            Ok(tokenizer
+                .encode(*text, true)
+                .map_err(E::msg)?
+                .get_ids()
+                .to_vec())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let lengths = tokens.iter().map(|t| t.len()).collect::<Vec<_>>();
+    let buckets = plan_buckets(&lengths, config);
+    let mut features = vec![None; texts.len()];
+    for bucket in buckets.iter() {
+        let sequences = bucket
+            .items
+            .iter()
+            .map(|&i| tokens[i].as_slice())
+            .collect::<Vec<_>>();
+        let (input, lengths) = pad_batch(&sequences, 0, device)?;
+        let hidden = model.forward_hidden_padded(&input, &lengths)?;
+        let last = last_positions(&hidden, &lengths)?.to_dtype(DType::F32)?;
+        for (row, &i) in bucket.items.iter().enumerate() {
+            features[i] = Some(last.get(row)?)
+        }
+    }
+    let padding = buckets.iter().map(|b| b.padding).sum::<usize>();
+    println!(
+        "{} forward passes for {} texts, {padding} padding tokens",
+        buckets.len(),
+        texts.len()
+    );
+    let features = features.into_iter().flatten().collect::<Vec<_>>();
+    Ok(Tensor::stack(&features, 0)?)
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+    let (mut model, tokenizer) = load(&args, &device)?;
+
+    // The backbone is frozen: the quantized weights are not variables so the features are
+    // computed once and only the head gets trained.
+    let config = BucketConfig {
+        max_pad_ratio: args.bucket_pad_ratio,
+        max_batch_size: args.bucket_max_size,
+    };
+    let texts = TRAIN.iter().map(|(text, _)| *text).collect::<Vec<_>>();
+    let features = extract_features(&mut model, &tokenizer, &texts, &config, &device)?;
+    let labels = TRAIN.iter().map(|(_, l)| *l as u32).collect::<Vec<_>>();
+    let labels = Tensor::new(labels, &device)?;
+    let hidden_size = features.dim(1)?;
+    println!("extracted features for {} samples", TRAIN.len());
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    let head = candle_nn::linear(hidden_size, LABELS.len(), vb.pp("head"))?;
+    let mut opt = candle_nn::AdamW::new_lr(varmap.all_vars(), args.learning_rate)?;
+    for epoch in 1..=args.epochs {
+        let logits = head.forward(&features)?;
+        let loss = loss::cross_entropy(&logits, &labels)?;
+        let grads = loss.backward()?;
+        if epoch == 1 {
+            // Only the head receives gradients, nothing flows back into the backbone.
+            let with_grads = grads.get_ids().count();
+            let vars = varmap.all_vars();
+            anyhow::ensure!(grads.get(&features).is_none(), "gradient on the features");
+            anyhow::ensure!(vars.iter().all(|v| grads.get(v).is_some()));
+            println!(
+                "{with_grads} tensors with gradients, {} head variables",
+                vars.len()
+            );
+        }
+        opt.step(&grads)?;
+        if epoch % 20 == 0 {
+            let accuracy = logits
+                .argmax(D::Minus1)?
+                .eq(&labels)?
+                .to_dtype(DType::F32)?
+                .mean_all()?
+                .to_scalar::<f32>()?;
+            println!(
+                "epoch {epoch:4} loss {:8.5} train accuracy {:5.1}%",
+                loss.to_scalar::<f32>()?,
+                100. * accuracy
+            );
+        }
+    }
+
+    // Use the trained head in place of the language modeling head, which is dropped.
+    model.set_output_head(Some(Box::new(head)));
+    drop(model.take_output());
+    for text in TEST.iter() {
+        let input = encode(&tokenizer, text, &device)?;
+        let logits = model.forward(&input, 0)?.to_dtype(DType::F32)?;
+        let probs = ops::softmax(&logits, D::Minus1)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?;
+        let label = if probs[1] > probs[0] { 1 } else { 0 };
+        println!("{:>8} ({:.2}) {text}", LABELS[label], probs[label]);
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use candle_transformers::generation::{GenerationParams, Preset};
+
+/// The command line flags for [`GenerationParams`]. The flags that are not set come from the
+/// preset when one is selected and from `GenerationParams::default()` otherwise.
+#[derive(clap::Args, Debug, Clone)]
+pub struct GenerationArgs {
+    /// Start from a named set of sampling parameters: greedy, llama-cpp-default, creative, or
+    /// precise. The sampling flags that are set explicitly override the preset.
+    #[arg(long)]
+    pub preset: Option<Preset>,
+
+    /// The length of the sample to generate (in tokens) [default: 1000].
+    #[arg(short = 'n', long)]
+    pub sample_len: Option<usize>,
+
+    /// The temperature used to generate samples, use 0 for greedy sampling among the tokens
+    /// selected by --top-k and --top-p [default: 0.8].
+    #[arg(long)]
+    pub temperature: Option<f64>,
+
+    /// Nucleus sampling probability cutoff.
+    #[arg(long)]
+    pub top_p: Option<f64>,
+
+    /// Only sample among the top K samples.
+    #[arg(long)]
+    pub top_k: Option<usize>,
+
+    /// The seed to use when generating random samples [default: 299792458].
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Penalty to be applied for repeating tokens, 1. means no penalty [default: 1.1].
+    #[arg(long)]
+    pub repeat_penalty: Option<f32>,
+
+    /// The context size to consider for the repeat penalty [default: 64].
+    #[arg(long)]
+    pub repeat_last_n: Option<usize>,
+
+    /// Stop the generation on any added token of the tokenizer matching this regex, e.g.
+    /// `^<\|tool_call\|>$`, the stop token is not printed. Can be specified multiple times.
+    #[arg(long)]
+    pub stop_token_pattern: Vec<String>,
+}
+
+impl GenerationArgs {
+    pub fn params(&self) -> GenerationParams {
+        let params = match self.preset {
+            Some(preset) => preset.params(),
+            None => GenerationParams::default(),
+        };
+        let mut stop_token_patterns = params.stop_token_patterns;
+        stop_token_patterns.extend(self.stop_token_pattern.iter().cloned());
+        GenerationParams {
+            seed: self.seed.unwrap_or(params.seed),
+            max_tokens: self.sample_len.unwrap_or(params.max_tokens),
+            temperature: self.temperature.unwrap_or(params.temperature),
+            top_k: self.top_k.or(params.top_k),
+            top_p: self.top_p.or(params.top_p),
+            repeat_penalty: self.repeat_penalty.unwrap_or(params.repeat_penalty),
+            repeat_last_n: self.repeat_last_n.unwrap_or(params.repeat_last_n),
+            stop_token_patterns,
+            ..params
+        }
+    }
+}
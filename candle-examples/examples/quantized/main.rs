@@ -8,16 +8,49 @@ use clap::{Parser, ValueEnum};
 use std::io::Write;
 use tokenizers::Tokenizer;
 
-use candle::quantized::{ggml_file, gguf_file};
-use candle::Tensor;
-use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle::quantized::{ggml_file, gguf_file, GgmlDType};
+use candle::{DType, Device, Tensor};
+use candle_transformers::generation::batch::{
+    BatchError, BatchErrorKind, BatchGenerator, BatchResult,
+};
+use candle_transformers::generation::chat::{
+    ChatFormat, ChatHistory, ChatMessage, ChatSession, DropOldestTurns, Role, SummarizeTurns,
+};
+use candle_transformers::generation::compare::{CompareConfig, Comparison, GenerationRun};
+use candle_transformers::generation::constraint::{ConstraintSchedule, Phase};
+use candle_transformers::generation::eval::{with_gemm_precision, GemmPrecision, NllAccumulator};
+use candle_transformers::generation::guardrail::RegexGuardrail;
+use candle_transformers::generation::regression;
+use candle_transformers::generation::{
+    CausalLm, EnsembleModel, GenerationParams, LogitsProcessor, Sampling, StopConditions,
+    StopCriteria, StopReason, TextGeneration, TokenBudget, TokenBudgetError,
+};
 
+use candle_examples::hub_cache::{self, EvictionPolicy};
 use candle_examples::token_output_stream::TokenOutputStream;
+use candle_transformers::kv_budget::{
+    self, ActivationConfig, ContextBudget, MemoryEstimate, PrefillStrategy,
+};
+use candle_transformers::layer_dtype::{layer_index, LayerDTypeOverride};
 use candle_transformers::models::quantized_llama as model;
+use candle_transformers::prompt_lint::{self, LintConfig, ModelFamily};
+use candle_transformers::validation::{self, validate_configuration, Check, ValidationConfig};
+use generation_args::GenerationArgs;
 use model::ModelWeights;
 
+mod generation_args;
+
 const DEFAULT_PROMPT: &str = "My favorite theorem is ";
 
+/// The prompts and the number of greedy steps of `--record-baseline` and `--check-baseline`.
+const BASELINE_PROMPTS: [&str; 4] = [
+    DEFAULT_PROMPT,
+    "The capital of France is",
+    "def fibonacci(n):",
+    "Once upon a time, in a small village,",
+];
+const BASELINE_STEPS: usize = 32;
+
 #[derive(Debug)]
 enum Prompt {
     Interactive,
@@ -25,6 +58,18 @@ enum Prompt {
     One(String),
 }
 
+#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
+enum HistoryPolicy {
+    Drop,
+    Summarize,
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
+enum ContextOverflow {
+    Refuse,
+    Truncate,
+}
+
 #[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
 enum Which {
     #[value(name = "7b")]
@@ -77,6 +122,8 @@ enum Which {
     SmolLM2_1BInstruct,
     #[value(name = "deepseekr1-llama8b")]
     DeepseekR1Llama8b,
+    #[value(name = "falcon-7b-instruct")]
+    Falcon7bInstruct,
 }
 
 impl Which {
@@ -97,7 +144,8 @@ impl Which {
             | Self::Phi3
             | Self::SmolLM2_1BInstruct
             | Self::SmolLM2_360MInstruct
-            | Self::DeepseekR1Llama8b => false,
+            | Self::DeepseekR1Llama8b
+            | Self::Falcon7bInstruct => false,
             // Zephyr and OpenChat are fine tuned versions of mistral and should be treated in the
             // same way. Starling is a fine tuned version of OpenChat.
             Self::OpenChat35
@@ -136,7 +184,8 @@ impl Which {
             | Self::SmolLM2_1BInstruct
             | Self::SmolLM2_360MInstruct
             | Self::Phi3
-            | Self::DeepseekR1Llama8b => false,
+            | Self::DeepseekR1Llama8b
+            | Self::Falcon7bInstruct => false,
             Self::Zephyr7bAlpha | Self::Zephyr7bBeta => true,
         }
     }
@@ -165,7 +214,8 @@ impl Which {
             | Self::SmolLM2_1BInstruct
             | Self::SmolLM2_360MInstruct
             | Self::Phi3
-            | Self::DeepseekR1Llama8b => false,
+            | Self::DeepseekR1Llama8b
+            | Self::Falcon7bInstruct => false,
             Self::OpenChat35 | Self::Starling7bAlpha => true,
         }
     }
@@ -195,10 +245,43 @@ impl Which {
             | Self::SmolLM2_360MInstruct
             | Self::Phi3
             | Self::OpenChat35
-            | Self::Starling7bAlpha => false,
+            | Self::Starling7bAlpha
+            | Self::Falcon7bInstruct => false,
             Self::DeepseekR1Llama8b => true,
         }
     }
+
+    /// The chat template of the instruct models, `None` for the base models.
+    fn prompt_family(&self) -> Option<ModelFamily> {
+        match self {
+            Self::Zephyr7bAlpha | Self::Zephyr7bBeta => Some(ModelFamily::Falcon3),
+            Self::OpenChat35 | Self::Starling7bAlpha => Some(ModelFamily::OpenChat),
+            Self::DeepseekR1Llama8b => Some(ModelFamily::DeepSeek),
+            Self::SmolLM2_1BInstruct | Self::SmolLM2_360MInstruct => Some(ModelFamily::ChatMl),
+            Self::L7bChat
+            | Self::L13bChat
+            | Self::L70bChat
+            | Self::MixtralInstruct
+            | Self::Mistral7bInstruct
+            | Self::Mistral7bInstructV02 => Some(ModelFamily::Mistral),
+            _ => None,
+        }
+    }
+
+    fn eos_token(&self) -> &'static str {
+        match self {
+            Self::SmolLM2_360MInstruct | Self::SmolLM2_1BInstruct | Self::Falcon7bInstruct => {
+                "<|endoftext|>"
+            }
+            Self::L8b => "<|end_of_text|>",
+            Self::DeepseekR1Llama8b => "<｜end▁of▁sentence｜>",
+            _ => match self.is_open_chat() {
+                true => "<|end_of_turn|>",
+                false => "</s>",
+            },
+        }
+    }
+
     fn tokenizer_repo(&self) -> &'static str {
         match self {
             Self::L7b
@@ -226,14 +309,17 @@ impl Which {
             Self::SmolLM2_360MInstruct => "HuggingFaceTB/SmolLM2-360M-Instruct",
             Self::SmolLM2_1BInstruct => "HuggingFaceTB/SmolLM2-1.7B-Instruct",
             Self::DeepseekR1Llama8b => "deepseek-ai/DeepSeek-R1-Distill-Llama-8B",
+            Self::Falcon7bInstruct => "tiiuae/falcon-7b-instruct",
         }
     }
 }
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// GGML/GGUF file to load, typically a .bin/.gguf file generated by the quantize command from llama.cpp
+    /// This can also be a `file://`, `https://` or, with the s3 feature, `s3://` uri, optionally
+    /// followed by `#sha256=<hex digest>`, the file is then downloaded to the hub cache.
     #[arg(long)]
     model: Option<String>,
 
@@ -243,68 +329,240 @@ struct Args {
     #[arg(long)]
     prompt: Option<String>,
 
-    /// The length of the sample to generate (in tokens).
-    #[arg(short = 'n', long, default_value_t = 1000)]
-    sample_len: usize,
-
     /// The tokenizer config in json format.
     #[arg(long)]
     tokenizer: Option<String>,
 
-    /// The temperature used to generate samples, use 0 for greedy sampling.
-    #[arg(long, default_value_t = 0.8)]
-    temperature: f64,
+    /// Enable tracing (generates a trace-timestamp.json file).
+    #[arg(long)]
+    tracing: bool,
 
-    /// Nucleus sampling probability cutoff.
+    /// Display the token for the specified prompt.
     #[arg(long)]
-    top_p: Option<f64>,
+    verbose_prompt: bool,
 
-    /// Only sample among the top K samples.
+    /// Print the quantized matmul kernel used by each weight after processing the prompt, and
+    /// again once the generation is done.
     #[arg(long)]
-    top_k: Option<usize>,
+    debug_kernels: bool,
 
-    /// The seed to use when generating random samples.
-    #[arg(long, default_value_t = 299792458)]
-    seed: u64,
+    /// Do not warn about prompts that miss the chat template of an instruct model, contain
+    /// several BOS tokens, or do not end with the assistant header.
+    #[arg(long)]
+    no_prompt_lint: bool,
 
-    /// Enable tracing (generates a trace-timestamp.json file).
+    /// Print the disk usage of each repo in the hub cache and exit.
     #[arg(long)]
-    tracing: bool,
+    cache_report: bool,
 
-    /// Display the token for the specified prompt.
+    /// Remove the least recently used gguf and bin files from the hub cache and exit, either
+    /// `max-total-bytes=<size>`, e.g. `max-total-bytes=40GB`, or `older-than-days=<days>`.
+    /// Files used within the last hour or being downloaded are kept.
     #[arg(long)]
-    verbose_prompt: bool,
+    cache_evict: Option<EvictionPolicy>,
 
-    /// Process prompt elements separately.
+    /// Process prompt elements separately. By default the prompt is processed in a single
+    /// forward pass, or in chunks when the activations of a single pass would not fit in the free
+    /// memory of the device.
     #[arg(long)]
     split_prompt: bool,
 
+    /// Process the prompt in chunks of this many tokens rather than picking the chunk size from
+    /// the free memory.
+    #[arg(long, conflicts_with = "split_prompt")]
+    prefill_chunk_size: Option<usize>,
+
     /// Run on CPU rather than GPU even if a GPU is available.
     #[arg(long)]
     cpu: bool,
 
-    /// Penalty to be applied for repeating tokens, 1. means no penalty.
-    #[arg(long, default_value_t = 1.1)]
-    repeat_penalty: f32,
-
-    /// The context size to consider for the repeat penalty.
-    #[arg(long, default_value_t = 64)]
-    repeat_last_n: usize,
+    /// How to place the weights over the NUMA nodes of a multi-socket machine, as in llama.cpp:
+    /// `distribute`, `isolate`, `numactl`, or `node=<n>`. Pages are only placed and threads
+    /// pinned when built with the `numa` feature on linux.
+    #[arg(long)]
+    numa: Option<candle::cpu::NumaPolicy>,
 
     /// The model size to use.
     #[arg(long, default_value = "7b")]
     which: Which,
 
+    /// The system prompt of the chat mode.
+    #[arg(long)]
+    system_prompt: Option<String>,
+
+    /// What to do in chat mode when the history and the answer do not fit in the context:
+    /// drop the oldest turns, or replace them with a summary written by the model.
+    #[arg(long, value_enum, default_value_t = HistoryPolicy::Drop)]
+    history_policy: HistoryPolicy,
+
     /// Group-Query Attention, use 8 for the 70B version of LLaMAv2.
     #[arg(long)]
     gqa: Option<usize>,
 
+    /// Do not share the storage of the tensors that are duplicated in gguf files.
+    #[arg(long)]
+    no_dedup: bool,
+
+    /// Keep the token embeddings of a gguf model quantized and only dequantize the rows looked
+    /// up, rather than the whole table when loading. Defaults to true for large tables.
+    #[arg(long)]
+    quantized_embeddings: Option<bool>,
+
+    /// Convert some weights of a gguf model to another dtype when loading it, as a comma
+    /// separated list of selection:dtype where a selection is a layer range or a glob on the
+    /// tensor names, e.g. "0-1:f16,30-31:f16" or "blk.*.ffn_down.weight:q8_0". The last
+    /// matching rule wins.
+    #[arg(long)]
+    layer_dtype: Option<LayerDTypeOverride>,
+
     /// Use the slower dmmv cuda kernel.
     #[arg(long)]
     force_dmmv: bool,
+
+    /// Check the kernels of the device against a cpu reference before loading the model, the
+    /// quantized matmuls are checked for the dtypes of the gguf file.
+    #[arg(long)]
+    self_test: bool,
+
+    /// Load the model even when the self-test fails.
+    #[arg(long)]
+    force: bool,
+
+    #[command(flatten)]
+    generation: GenerationArgs,
+
+    /// A second GGML/GGUF file to compare against, the same prompt and seed are run through
+    /// both models (without repeat penalty) and the generations are printed side by side.
+    #[arg(long)]
+    compare_model: Option<String>,
+
+    /// Load the model passed via --compare-model on the CPU.
+    #[arg(long)]
+    compare_cpu: bool,
+
+    /// Only load the model passed via --compare-model once the first model has completed its
+    /// generation and has been dropped, useful when both models do not fit in memory.
+    #[arg(long)]
+    compare_sequential: bool,
+
+    /// Report the perplexity of the prompt rather than sampling from it.
+    #[arg(long)]
+    eval: bool,
+
+    /// Run a fixed set of prompts with greedy decoding and record the argmax token and logit of
+    /// each step to this file, to be checked later with `--check-baseline`.
+    #[arg(long)]
+    record_baseline: Option<String>,
+
+    /// Run the prompts of `--record-baseline` and compare with the recording in this file. Fails
+    /// when an argmax token changes or when a logit moves by more than `--baseline-tolerance`.
+    #[arg(long)]
+    check_baseline: Option<String>,
+
+    /// The largest logit delta accepted by `--check-baseline`.
+    #[arg(long, default_value_t = 1e-3)]
+    baseline_tolerance: f32,
+
+    /// Use full precision GEMM kernels for --eval, this is slower but removes the reduced
+    /// precision noise when comparing quantizations.
+    #[arg(long)]
+    high_precision_eval: bool,
+
+    /// Run the prompts from a jsonl file, one `{"id": .., "prompt": .., "max_tokens": ..}`
+    /// object per line, rather than a single prompt.
+    #[arg(long)]
+    batch_file: Option<String>,
+
+    /// Where to write the batch results, one json object per line, defaults to stdout.
+    #[arg(long)]
+    batch_output: Option<String>,
+
+    /// Groups the prompts of `--batch-file` by token length and processes each group with a
+    /// single padded forward pass, the padding of a group stays below this share of its size.
+    #[arg(long)]
+    bucket_pad_ratio: Option<f64>,
+
+    /// The largest number of prompts in a group, see `--bucket-pad-ratio`.
+    #[arg(long, default_value_t = 8)]
+    bucket_max_size: usize,
+
+    /// Tool calling demo, the file contains a json list of `{"name": .., "description": ..}`
+    /// objects. The model has to pick one of the tools for the prompt and then generates its
+    /// arguments.
+    #[arg(long)]
+    tools: Option<String>,
+
+    /// Decodes from a weighted mixture of this model and other models sharing its vocabulary,
+    /// `path:weight`, e.g. `--ensemble small.gguf:0.3`. The model gets the weight that brings the
+    /// total to one, a negative weight contrasts a model, e.g. `--ensemble base.gguf:-0.5` with
+    /// a fine-tuned model.
+    #[arg(long)]
+    ensemble: Vec<String>,
+
+    /// Guardrail demo: a sampled token that makes the generated text match this regex is
+    /// sampled again with the token banned, e.g. `(?i)\bpassword\b`. Supported with a single
+    /// prompt, `--ensemble`, and the chat mode.
+    #[arg(long)]
+    block_regex: Option<String>,
+
+    /// The number of times a token rejected by `--block-regex` is resampled before the
+    /// generation is aborted.
+    #[arg(long, default_value_t = 4)]
+    guardrail_max_resamples: usize,
+
+    /// Write a json manifest of the run to this file: model hash and metadata, build info,
+    /// device and generation settings. Only supported for gguf models.
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// The context length to use, defaults to the one of the model. It is capped so that the kv
+    /// cache fits in the memory left once the weights are loaded.
+    #[arg(long)]
+    context_length: Option<usize>,
+
+    /// The memory available to the kv cache in MiB, defaults to the free memory of the device
+    /// minus the weights and a reserve. Only supported for gguf models.
+    #[arg(long)]
+    kv_budget_mb: Option<u64>,
+
+    /// The number of context positions kept free after the prompt and the generated tokens,
+    /// e.g. as a safety margin or for the end of a chat template.
+    #[arg(long, default_value_t = 0)]
+    context_reserve: usize,
+
+    /// What to do with a prompt when the requested tokens do not fit in the context after it:
+    /// drop tokens from the start of the prompt, or refuse it.
+    #[arg(long, value_enum, default_value_t = ContextOverflow::Truncate)]
+    context_overflow: ContextOverflow,
+
+    /// Validate the model, the tokenizer, the chat template, the stop criteria, the tools, the
+    /// guardrail regex and the memory budget without loading the weights. The report is printed
+    /// and the exit code is 1 when the configuration is invalid.
+    #[arg(long)]
+    dry_run: bool,
 }
 
 impl Args {
+    fn sampling(&self) -> Sampling {
+        self.sampling_with_temperature(self.generation_params().temperature)
+    }
+
+    fn sampling_with_temperature(&self, temperature: f64) -> Sampling {
+        let params = GenerationParams {
+            temperature,
+            ..self.generation_params()
+        };
+        params.sampling()
+    }
+
+    fn generation_params(&self) -> GenerationParams {
+        self.generation.params()
+    }
+
+    fn stop_criteria(&self) -> Vec<StopCriteria> {
+        self.generation_params().stop_criteria()
+    }
+
     fn tokenizer(&self) -> anyhow::Result<Tokenizer> {
         let tokenizer_path = match &self.tokenizer {
             Some(config) => std::path::PathBuf::from(config),
@@ -320,6 +578,9 @@ impl Args {
 
     fn model(&self) -> anyhow::Result<std::path::PathBuf> {
         let model_path = match &self.model {
+            Some(uri) if candle_examples::fetch::is_uri(uri) => {
+                candle_examples::fetch::fetch_to_cache(uri)?
+            }
             Some(config) => std::path::PathBuf::from(config),
             None => {
                 let (repo, filename) = match self.which {
@@ -402,6 +663,10 @@ impl Args {
                         "unsloth/DeepSeek-R1-Distill-Llama-8B-GGUF",
                         "DeepSeek-R1-Distill-Llama-8B-Q4_K_M.gguf",
                     ),
+                    Which::Falcon7bInstruct => (
+                        "maddes8cht/tiiuae-falcon-7b-instruct-gguf",
+                        "tiiuae-falcon-7b-instruct-Q4_K_M.gguf",
+                    ),
                 };
                 let revision = if self.which == Which::Phi3 {
                     "5eef2ce24766d31909c0b269fe90c817a8f263fb"
@@ -433,63 +698,143 @@ fn format_size(size_in_bytes: usize) -> String {
     }
 }
 
-fn main() -> anyhow::Result<()> {
-    use tracing_chrome::ChromeLayerBuilder;
-    use tracing_subscriber::prelude::*;
-
-    let args = Args::parse();
-
-    #[cfg(feature = "cuda")]
-    candle::quantized::cuda::set_force_dmmv(args.force_dmmv);
-
-    candle::cuda::set_gemm_reduced_precision_f16(true);
-    candle::cuda::set_gemm_reduced_precision_bf16(true);
+/// Runs the `--cache-report` and `--cache-evict` commands.
+fn run_cache_commands(args: &Args) -> anyhow::Result<()> {
+    if let Some(policy) = args.cache_evict {
+        let report = hub_cache::hub_cache_evict(policy)?;
+        for file in report.removed.iter() {
+            let size = format_size(file.size as usize);
+            println!("removed {}/{} ({size})", file.repo_id, file.filename);
+        }
+        for file in report.in_use.iter() {
+            println!("kept {}/{}, in use", file.repo_id, file.filename);
+        }
+        println!("freed {}", format_size(report.freed as usize));
+    }
+    if args.cache_report {
+        let usage = hub_cache::hub_cache_usage()?;
+        println!("{:>10}  repo", "size");
+        for repo in usage.iter() {
+            println!("{:>10}  {}", format_size(repo.size as usize), repo.repo_id);
+            for file in repo.weights.iter() {
+                let size = format_size(file.size as usize);
+                println!("{size:>10}    {}", file.filename);
+            }
+        }
+        let total = usage.iter().map(|r| r.size).sum::<u64>();
+        println!("{:>10}  total", format_size(total as usize));
+    }
+    Ok(())
+}
 
-    let _guard = if args.tracing {
-        let (chrome_layer, guard) = ChromeLayerBuilder::new().build();
-        tracing_subscriber::registry().with(chrome_layer).init();
-        Some(guard)
-    } else {
-        None
+/// Prints the kernels used by each weight matrix so far.
+fn print_kernel_table(model: &ModelWeights) {
+    let kernel = |k: Option<candle::quantized::QMatMulKernel>| match k {
+        None => "-".to_string(),
+        Some(k) => format!("{k:?}"),
     };
-
     println!(
-        "avx: {}, neon: {}, simd128: {}, f16c: {}",
-        candle::utils::with_avx(),
-        candle::utils::with_neon(),
-        candle::utils::with_simd128(),
-        candle::utils::with_f16c()
+        "{:<28} {:<16} {:<8} {:<16} batched",
+        "weight", "shape", "dtype", "matvec"
     );
+    for w in model.kernel_report() {
+        println!(
+            "{:<28} {:<16} {:<8} {:<16} {}",
+            w.name,
+            format!("{:?}", w.shape.dims()),
+            format!("{:?}", w.dtype),
+            kernel(w.kernels.matvec),
+            kernel(w.kernels.batched),
+        );
+    }
+}
+
+/// Prints the dtypes of the weights of each layer, and of the weights outside of the layers.
+fn print_dtype_table(model: &ModelWeights) {
+    use std::collections::BTreeMap;
+
+    let mut layers: BTreeMap<usize, BTreeMap<String, usize>> = BTreeMap::new();
+    let mut others = vec![];
+    for (name, dtype) in model.tensor_dtypes() {
+        match layer_index(name) {
+            Some(layer) => {
+                *layers
+                    .entry(layer)
+                    .or_default()
+                    .entry(format!("{dtype:?}"))
+                    .or_default() += 1
+            }
+            None => others.push((name, dtype)),
+        }
+    }
     println!(
-        "temp: {:.2} repeat-penalty: {:.2} repeat-last-n: {}",
-        args.temperature, args.repeat_penalty, args.repeat_last_n
+        "{} weights converted to another dtype",
+        model.load_summary().overridden
     );
+    println!("{:<20} dtypes", "layer");
+    for (layer, dtypes) in layers {
+        let dtypes = dtypes
+            .iter()
+            .map(|(dtype, count)| format!("{dtype} x{count}"))
+            .collect::<Vec<_>>();
+        println!("{layer:<20} {}", dtypes.join(", "));
+    }
+    for (name, dtype) in others {
+        println!("{name:<20} {dtype:?}");
+    }
+}
 
-    let model_path = args.model()?;
-    let mut file = std::fs::File::open(&model_path)?;
+/// Loads a GGML/GGUF model, returning the model together with the size of its weights.
+fn load_model(
+    model_path: &std::path::Path,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<(ModelWeights, usize)> {
+    let mut file = std::fs::File::open(model_path)?;
     let start = std::time::Instant::now();
-    let device = candle_examples::device(args.cpu)?;
 
-    let mut model = match model_path.extension().and_then(|v| v.to_str()) {
+    let model = match model_path.extension().and_then(|v| v.to_str()) {
         Some("gguf") => {
             let model = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
-            let mut total_size_in_bytes = 0;
-            for (_, tensor) in model.tensor_infos.iter() {
-                let elem_count = tensor.shape.elem_count();
-                total_size_in_bytes +=
-                    elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
-            }
+            let total_size_in_bytes = kv_budget::weights_bytes(&model) as usize;
             println!(
                 "loaded {:?} tensors ({}) in {:.2}s",
                 model.tensor_infos.len(),
                 &format_size(total_size_in_bytes),
                 start.elapsed().as_secs_f32(),
             );
-            ModelWeights::from_gguf(model, &mut file, &device)?
+            let overrides = args.layer_dtype.clone().unwrap_or_default();
+            let dedup = !args.no_dedup;
+            let model = ModelWeights::from_gguf_with_embeddings(
+                model,
+                &mut file,
+                device,
+                dedup,
+                &overrides,
+                args.quantized_embeddings,
+            )?;
+            if !overrides.is_empty() {
+                print_dtype_table(&model);
+            }
+            let summary = model.load_summary();
+            if summary.deduplicated > 0 {
+                println!(
+                    "shared {} duplicated tensors ({})",
+                    summary.deduplicated,
+                    &format_size(summary.deduplicated_bytes),
+                );
+            }
+            if model.quantized_embeddings() {
+                println!(
+                    "kept the token embeddings quantized ({})",
+                    &format_size(model.embeddings_bytes()),
+                );
+            }
+            (model, total_size_in_bytes)
         }
         Some("ggml" | "bin") | Some(_) | None => {
-            let model = ggml_file::Content::read(&mut file, &device)
-                .map_err(|e| e.with_path(model_path))?;
+            let model =
+                ggml_file::Content::read(&mut file, device).map_err(|e| e.with_path(model_path))?;
             let mut total_size_in_bytes = 0;
             for (_, tensor) in model.tensors.iter() {
                 let elem_count = tensor.shape().elem_count();
@@ -517,6 +862,7 @@ fn main() -> anyhow::Result<()> {
                 | Which::SmolLM2_1BInstruct
                 | Which::SmolLM2_360MInstruct
                 | Which::DeepseekR1Llama8b
+                | Which::Falcon7bInstruct
                 | Which::Phi3 => 1,
                 Which::Mixtral
                 | Which::MixtralInstruct
@@ -530,122 +876,1396 @@ fn main() -> anyhow::Result<()> {
                 | Which::OpenChat35
                 | Which::Starling7bAlpha => 8,
             };
-            ModelWeights::from_ggml(model, args.gqa.unwrap_or(default_gqa))?
+            (
+                ModelWeights::from_ggml(model, args.gqa.unwrap_or(default_gqa))?,
+                total_size_in_bytes,
+            )
         }
     };
-    println!("model built");
+    Ok(model)
+}
 
-    let tokenizer = args.tokenizer()?;
-    let mut tos = TokenOutputStream::new(tokenizer);
+/// The model information used by the prompt lint, read from the gguf metadata when available
+/// and derived from --which otherwise.
+fn prompt_lint_config(
+    model_path: &std::path::Path,
+    args: &Args,
+    tokenizer: &Tokenizer,
+) -> anyhow::Result<LintConfig> {
+    if model_path.extension().and_then(|v| v.to_str()) == Some("gguf") {
+        let mut file = std::fs::File::open(model_path)?;
+        let ct = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
+        let mut config = LintConfig::from_gguf(&ct)?;
+        if config.bos_token_id.is_none() {
+            config.bos_token_id = tokenizer.token_to_id("<s>");
+        }
+        return Ok(config);
+    }
+    let family = args.which.prompt_family();
+    Ok(LintConfig::new(
+        family,
+        family.is_some(),
+        tokenizer.token_to_id("<s>"),
+    ))
+}
+
+fn run_comparison(
+    (mut model, model_path, model_size): (ModelWeights, &std::path::Path, usize),
+    compare_path: &std::path::Path,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
     let prompt = match args.prompt.as_deref() {
-        Some("chat") => Prompt::Chat,
-        Some("interactive") => Prompt::Interactive,
-        Some(s) => Prompt::One(s.to_string()),
-        None => Prompt::One(DEFAULT_PROMPT.to_string()),
+        Some("chat") | Some("interactive") => {
+            anyhow::bail!("--compare-model only supports a single prompt")
+        }
+        Some(prompt) => prompt,
+        None => DEFAULT_PROMPT,
+    };
+    let prompt_tokens = tokenizer
+        .encode(prompt, true)
+        .map_err(anyhow::Error::msg)?
+        .get_ids()
+        .to_vec();
+    let config = CompareConfig {
+        sample_len: args.generation_params().max_tokens,
+        eos_token: tokenizer
+            .get_vocab(true)
+            .get(args.which.eos_token())
+            .copied(),
+        seed: args.generation_params().seed,
+        sampling: args.sampling(),
+    };
+    let compare_device = if args.compare_cpu {
+        Device::Cpu
+    } else {
+        device.clone()
+    };
+    let (comparison, compare_size) = if args.compare_sequential {
+        let lhs = config.run(&mut model, &prompt_tokens, device)?;
+        drop(model);
+        let (mut compare_model, compare_size) = load_model(compare_path, args, &compare_device)?;
+        let rhs = config.run(&mut compare_model, &prompt_tokens, &compare_device)?;
+        (Comparison::new(lhs, rhs), compare_size)
+    } else {
+        let (mut compare_model, compare_size) = load_model(compare_path, args, &compare_device)?;
+        let comparison = config.compare(
+            (&mut model, device),
+            (&mut compare_model, &compare_device),
+            &prompt_tokens,
+        )?;
+        (comparison, compare_size)
     };
 
-    let mut pre_prompt_tokens = vec![];
-    for prompt_index in 0.. {
-        let prompt_str = match &prompt {
-            Prompt::One(prompt) => prompt.clone(),
-            Prompt::Interactive | Prompt::Chat => {
-                let is_interactive = matches!(prompt, Prompt::Interactive);
-                print!("> ");
-                std::io::stdout().flush()?;
-                let mut prompt = String::new();
-                std::io::stdin().read_line(&mut prompt)?;
-                if prompt.ends_with('\n') {
-                    prompt.pop();
-                    if prompt.ends_with('\r') {
-                        prompt.pop();
-                    }
-                }
-                if args.which.is_open_chat() {
-                    format!("GPT4 Correct User: {prompt}<|end_of_turn|>GPT4 Correct Assistant:")
-                } else if args.which.is_zephyr() {
-                    if prompt_index == 0 || is_interactive {
-                        format!("<|system|>\n</s>\n<|user|>\n{prompt}</s>\n<|assistant|>",)
-                    } else {
-                        format!("<|user|>\n{prompt}</s>\n<|assistant|>")
-                    }
-                } else if args.which.is_mistral() {
-                    format!("[INST] {prompt} [/INST]")
-                } else if args.which.is_deepseek() {
-                    format!("<｜User｜>{prompt}<｜Assistant｜>")
-                } else {
-                    prompt
-                }
-            }
+    let decode = |tokens: &[u32]| tokenizer.decode(tokens, true).map_err(anyhow::Error::msg);
+    for (path, run) in [
+        (model_path, &comparison.lhs),
+        (compare_path, &comparison.rhs),
+    ] {
+        println!("\n==== {} ====", path.display());
+        println!("{prompt}{}", decode(&run.tokens)?);
+    }
+    println!("\n{:>12} {:>12} {:>12}  model", "tokens", "token/s", "size");
+    for (path, run, size) in [
+        (model_path, &comparison.lhs, model_size),
+        (compare_path, &comparison.rhs, compare_size),
+    ] {
+        println!(
+            "{:>12} {:>12.2} {:>12}  {}",
+            run.tokens.len(),
+            run.tokens_per_sec(),
+            format_size(size),
+            path.display()
+        );
+    }
+    match comparison.divergence {
+        None => println!("generations are identical"),
+        Some(index) => {
+            let common = decode(&comparison.lhs.tokens[..index])?;
+            println!("generations diverge at generated token {index}, common prefix: {common:?}")
+        }
+    }
+    Ok(())
+}
+
+fn run_baseline(
+    mut model: ModelWeights,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let prompts = BASELINE_PROMPTS
+        .iter()
+        .map(|prompt| {
+            let tokens = tokenizer
+                .encode(*prompt, true)
+                .map_err(anyhow::Error::msg)?;
+            Ok(tokens.get_ids().to_vec())
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let start = std::time::Instant::now();
+    let current = regression::record(&mut model, &prompts, BASELINE_STEPS, device)?;
+    println!(
+        "ran {} prompts for {BASELINE_STEPS} steps in {:.2}s",
+        prompts.len(),
+        start.elapsed().as_secs_f32()
+    );
+    if let Some(path) = args.record_baseline.as_deref() {
+        current.save(path)?;
+        println!("recorded baseline to {path}");
+    }
+    if let Some(path) = args.check_baseline.as_deref() {
+        let baseline = regression::Recording::load(path)?;
+        let report = regression::compare(&baseline, &current, args.baseline_tolerance)?;
+        println!("{report}");
+        if !report.passed() {
+            anyhow::bail!("the outputs diverge from the baseline {path}")
+        }
+    }
+    Ok(())
+}
+
+fn run_eval(
+    mut model: ModelWeights,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let prompt = match args.prompt.as_deref() {
+        Some("chat") | Some("interactive") => {
+            anyhow::bail!("--eval only supports a single prompt")
+        }
+        Some(prompt) => prompt,
+        None => DEFAULT_PROMPT,
+    };
+    let tokens = tokenizer
+        .encode(prompt, true)
+        .map_err(anyhow::Error::msg)?
+        .get_ids()
+        .to_vec();
+    if tokens.len() < 2 || tokens.len() > model.max_seq_len() {
+        anyhow::bail!(
+            "--eval requires between 2 and {} prompt tokens, got {}",
+            model.max_seq_len(),
+            tokens.len()
+        )
+    }
+    let precision = if args.high_precision_eval {
+        GemmPrecision::full()
+    } else {
+        GemmPrecision::current()
+    };
+    let start = std::time::Instant::now();
+    let nll = with_gemm_precision(precision, || {
+        let input = Tensor::new(&tokens[..tokens.len() - 1], device)?.unsqueeze(0)?;
+        let targets = Tensor::new(&tokens[1..], device)?.unsqueeze(0)?;
+        let mut nll = NllAccumulator::new();
+        nll.add(&model.forward_nll(&input, &targets, 0)?)?;
+        Ok(nll)
+    })?;
+    println!(
+        "{:4} tokens evaluated in {:.2}s, nll: {:.6} ppl: {:.6}",
+        nll.count(),
+        start.elapsed().as_secs_f64(),
+        nll.mean(),
+        nll.perplexity()
+    );
+    Ok(())
+}
+
+struct Batch<'a> {
+    model: ModelWeights,
+    tokenizer: &'a Tokenizer,
+    args: &'a Args,
+    device: &'a Device,
+}
+
+impl Batch<'_> {
+    // The tokens of a prompt that fits in the context with `max_tokens` more tokens, truncated
+    // from the start with `--context-overflow truncate`.
+    fn prompt_tokens(&self, prompt: &str, max_tokens: usize) -> Result<Vec<u32>, BatchError> {
+        let tokens = self
+            .tokenizer
+            .encode(prompt, true)
+            .map_err(|e| BatchError::new(BatchErrorKind::TokenizationError, e))?;
+        let tokens = tokens.get_ids();
+        let budget =
+            TokenBudget::new(self.model.max_seq_len()).with_reserve(self.args.context_reserve);
+        let plan = match self.args.context_overflow {
+            ContextOverflow::Refuse => budget.plan(tokens.len(), max_tokens),
+            ContextOverflow::Truncate => budget.plan_truncated(tokens.len(), max_tokens),
         };
-        print!("{}", &prompt_str);
-        let tokens = tos
-            .tokenizer()
-            .encode(prompt_str, true)
-            .map_err(anyhow::Error::msg)?;
-        if args.verbose_prompt {
-            for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
-                let token = token.replace('▁', " ").replace("<0x0A>", "\n");
-                println!("{id:7} -> '{token}'");
+        match plan {
+            // Without truncation, `plan.truncate` is 0 when all the requested tokens fit.
+            Ok(plan) if plan.new_tokens == max_tokens => Ok(tokens[plan.truncate..].to_vec()),
+            _ => {
+                let msg = format!(
+                    "{} prompt tokens + {max_tokens} max tokens exceed the {} available tokens of the context",
+                    tokens.len(),
+                    budget.available()
+                );
+                Err(BatchError::new(BatchErrorKind::ContextOverflow, msg))
             }
         }
+    }
 
-        let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
-        let to_sample = args.sample_len.saturating_sub(1);
-        let prompt_tokens = if prompt_tokens.len() + to_sample > model::MAX_SEQ_LEN - 10 {
-            let to_remove = prompt_tokens.len() + to_sample + 10 - model::MAX_SEQ_LEN;
-            prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..].to_vec()
-        } else {
-            prompt_tokens
+    fn eos_token(&self) -> Option<u32> {
+        self.tokenizer
+            .get_vocab(true)
+            .get(self.args.which.eos_token())
+            .copied()
+    }
+
+    fn logits_processor(&self) -> LogitsProcessor {
+        LogitsProcessor::from_sampling(self.args.generation_params().seed, self.args.sampling())
+    }
+
+    fn result(
+        &self,
+        run: candle::Result<GenerationRun>,
+        max_tokens: usize,
+    ) -> Result<BatchResult, BatchError> {
+        let run = run.map_err(|e| BatchError::from_model_error(&e))?;
+        let text = self
+            .tokenizer
+            .decode(&run.tokens, true)
+            .map_err(|e| BatchError::new(BatchErrorKind::TokenizationError, e))?;
+        Ok(BatchResult {
+            text,
+            generated_tokens: run.tokens.len(),
+            max_tokens,
+        })
+    }
+}
+
+impl BatchGenerator for Batch<'_> {
+    fn generate(&mut self, prompt: &str, max_tokens: usize) -> Result<BatchResult, BatchError> {
+        let tokens = self.prompt_tokens(prompt, max_tokens)?;
+        let (eos_token, mut logits_processor) = (self.eos_token(), self.logits_processor());
+        let run = candle_transformers::generation::compare::generate(
+            &mut self.model,
+            &tokens,
+            max_tokens,
+            eos_token,
+            &mut logits_processor,
+            self.device,
+        );
+        self.result(run, max_tokens)
+    }
+
+    fn clear_cache(&mut self) {
+        self.model.clear_kv_cache()
+    }
+
+    fn prompt_len(&mut self, prompt: &str) -> Option<usize> {
+        let tokens = self.tokenizer.encode(prompt, true).ok()?;
+        Some(tokens.get_ids().len())
+    }
+
+    // One padded forward pass over the prompts, then each sequence continues on its own copy
+    // of the model holding its row of the kv cache.
+    fn generate_bucket(
+        &mut self,
+        requests: &[(&str, usize)],
+    ) -> Vec<Result<BatchResult, BatchError>> {
+        let prompts = requests
+            .iter()
+            .map(|&(prompt, max_tokens)| self.prompt_tokens(prompt, max_tokens))
+            .collect::<Vec<_>>();
+        let sequences = prompts
+            .iter()
+            .flatten()
+            .map(|t| t.as_slice())
+            .collect::<Vec<_>>();
+        if sequences.is_empty() {
+            return prompts
+                .into_iter()
+                .filter_map(Result::err)
+                .map(Err)
+                .collect();
+        }
+        let logits = candle_transformers::bucketing::pad_batch(&sequences, 0, self.device)
+            .and_then(|(xs, lengths)| self.model.forward_padded(&xs, &lengths));
+        let logits = match logits {
+            Ok(logits) => logits,
+            Err(err) => {
+                self.model.clear_kv_cache();
+                let err = BatchError::from_model_error(&err);
+                let failed =
+                    |tokens: Result<_, _>| Err(tokens.err().unwrap_or_else(|| err.clone()));
+                return prompts.into_iter().map(failed).collect();
+            }
         };
-        let mut all_tokens = vec![];
-        let mut logits_processor = {
-            let temperature = args.temperature;
-            let sampling = if temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (args.top_k, args.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        let mut row = 0;
+        let mut results = vec![];
+        for (tokens, &(_, max_tokens)) in prompts.into_iter().zip(requests.iter()) {
+            let tokens = match tokens {
+                Ok(tokens) => tokens,
+                Err(err) => {
+                    results.push(Err(err));
+                    continue;
                 }
             };
-            LogitsProcessor::from_sampling(args.seed, sampling)
-        };
+            let mut model = self.model.clone();
+            let run = model
+                .select_kv_cache_row(row, tokens.len())
+                .and_then(|()| logits.get(row))
+                .and_then(|logits| {
+                    candle_transformers::generation::compare::continue_generation(
+                        &mut model,
+                        &logits,
+                        tokens.len(),
+                        max_tokens,
+                        self.eos_token(),
+                        &mut self.logits_processor(),
+                        self.device,
+                    )
+                });
+            row += 1;
+            results.push(self.result(run, max_tokens))
+        }
+        self.model.clear_kv_cache();
+        results
+    }
+}
 
-        let start_prompt_processing = std::time::Instant::now();
-        let mut next_token = if !args.split_prompt {
-            let input = Tensor::new(prompt_tokens.as_slice(), &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            logits_processor.sample(&logits)?
-        } else {
-            let mut next_token = 0;
-            for (pos, token) in prompt_tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &device)?.unsqueeze(0)?;
-                let logits = model.forward(&input, pos)?;
-                let logits = logits.squeeze(0)?;
-                next_token = logits_processor.sample(&logits)?
-            }
-            next_token
-        };
-        let prompt_dt = start_prompt_processing.elapsed();
-        all_tokens.push(next_token);
-        if let Some(t) = tos.next_token(next_token)? {
-            print!("{t}");
-            std::io::stdout().flush()?;
+fn run_batch(
+    model: ModelWeights,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let batch_file = args.batch_file.as_deref().unwrap_or_default();
+    let input = std::io::BufReader::new(std::fs::File::open(batch_file)?);
+    let output: Box<dyn Write> = match args.batch_output.as_deref() {
+        Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let mut batch = Batch {
+        model,
+        tokenizer,
+        args,
+        device,
+    };
+    let max_tokens = args.generation_params().max_tokens;
+    let summary = match args.bucket_pad_ratio {
+        None => candle_transformers::generation::batch::run_batch(
+            &mut batch, input, output, max_tokens,
+        )?,
+        Some(max_pad_ratio) => {
+            let config = candle_transformers::bucketing::BucketConfig {
+                max_pad_ratio,
+                max_batch_size: args.bucket_max_size,
+            };
+            candle_transformers::generation::batch::run_batch_bucketed(
+                &mut batch, input, output, max_tokens, &config,
+            )?
         }
+    };
+    eprintln!(
+        "{} prompts succeeded, {} failed",
+        summary.succeeded, summary.failed
+    );
+    std::process::exit(summary.exit_code())
+}
 
-        let eos_token = match args.which {
-            Which::SmolLM2_360MInstruct | Which::SmolLM2_1BInstruct => "<|endoftext|>",
-            Which::L8b => "<|end_of_text|>",
-            Which::DeepseekR1Llama8b => "<｜end▁of▁sentence｜>",
-            _ => match args.which.is_open_chat() {
-                true => "<|end_of_turn|>",
-                false => "</s>",
+#[derive(serde::Deserialize)]
+struct Tool {
+    name: String,
+    description: String,
+}
+
+fn read_tools(path: &str) -> anyhow::Result<Vec<Tool>> {
+    let file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("{path}: {e}"))?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+// Pick a tool name, then generate the arguments up to the closing parenthesis. The names end
+// with the opening parenthesis when the tokenizer has it, so that `search` can be picked over
+// `search_web`.
+fn tool_schedule(tools: &[Tool], tokenizer: Option<&Tokenizer>) -> ConstraintSchedule {
+    let schedule = ConstraintSchedule::new(vec![
+        Phase::OneOf {
+            options: tools.iter().map(|t| t.name.clone()).collect(),
+        },
+        Phase::Free {
+            until: StopCriteria::Sequence(")".to_string()),
+        },
+    ]);
+    match tokenizer.and_then(|t| t.token_to_id("(")) {
+        Some(token) => schedule.with_option_end(token),
+        None => schedule,
+    }
+}
+
+fn run_tools(
+    model: ModelWeights,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let tools = read_tools(args.tools.as_deref().unwrap_or_default())?;
+    let request = match args.prompt.as_deref() {
+        Some("chat") | Some("interactive") | None => {
+            anyhow::bail!("--tools requires a single prompt")
+        }
+        Some(prompt) => prompt,
+    };
+    let mut prompt = "Available tools:\n".to_string();
+    for tool in tools.iter() {
+        prompt.push_str(&format!("- {}: {}\n", tool.name, tool.description));
+    }
+    let prompt = format_prompt(
+        args.which,
+        &format!("{prompt}Pick a tool for this request: {request}\nAnswer with tool(arguments)."),
+        true,
+    );
+    let prompt_tokens = tokenizer.encode(prompt, true).map_err(anyhow::Error::msg)?;
+
+    let schedule = tool_schedule(&tools, Some(tokenizer));
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    let added_tokens = added_tokens.iter().map(|(&id, t)| (id, t.content.as_str()));
+    let schedule = schedule.resolve(added_tokens, |option| {
+        let tokens = tokenizer
+            .encode(option, false)
+            .map_err(candle::Error::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    })?;
+    let mut generation = TextGeneration::from_params(model, &args.generation_params(), device)?;
+    generation.push_prompt(prompt_tokens.get_ids())?;
+    let outputs =
+        generation.run_schedule(&schedule, args.generation_params().max_tokens, |tokens| {
+            tokenizer.decode(tokens, true).map_err(candle::Error::msg)
+        })?;
+    let tokens = outputs
+        .iter()
+        .flat_map(|o| o.tokens.iter().copied())
+        .collect::<Vec<_>>();
+    let call = tokenizer
+        .decode(&tokens, true)
+        .map_err(anyhow::Error::msg)?;
+    println!("tool: {}", schedule.choices(&outputs)[0]);
+    println!("call: {call}");
+    Ok(())
+}
+
+/// Parses `path:weight`, the path can be an uri.
+fn parse_ensemble_member(arg: &str) -> anyhow::Result<(std::path::PathBuf, f64)> {
+    let (path, weight) = match arg.rsplit_once(':') {
+        Some((path, weight)) if !path.is_empty() => (path, weight),
+        _ => anyhow::bail!("expected path:weight, got {arg:?}"),
+    };
+    let weight = weight
+        .parse::<f64>()
+        .map_err(|e| anyhow::anyhow!("invalid weight in {arg:?}: {e}"))?;
+    let path = match candle_examples::fetch::is_uri(path) {
+        true => candle_examples::fetch::fetch_to_cache(path)?,
+        false => std::path::PathBuf::from(path),
+    };
+    Ok((path, weight))
+}
+
+fn run_ensemble(
+    model: ModelWeights,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let prompt = match args.prompt.as_deref() {
+        Some("chat") | Some("interactive") => {
+            anyhow::bail!("--ensemble requires a single prompt")
+        }
+        Some(prompt) => prompt,
+        None => DEFAULT_PROMPT,
+    };
+    let mut members = vec![];
+    for arg in args.ensemble.iter() {
+        let (path, weight) = parse_ensemble_member(arg)?;
+        let (member, _) = load_model(&path, args, device)?;
+        println!("ensemble: {} with weight {weight}", path.display());
+        members.push((member, weight));
+    }
+    let weight = 1. - members.iter().map(|(_, w)| w).sum::<f64>();
+    println!("ensemble: main model with weight {weight}");
+    let model = EnsembleModel::new(std::iter::once((model, weight)).chain(members))?;
+    run_text_generation(model, prompt, tokenizer, args, device)
+}
+
+fn with_guardrail<M: CausalLm>(
+    generation: TextGeneration<M>,
+    args: &Args,
+) -> anyhow::Result<TextGeneration<M>> {
+    match args.block_regex.as_deref() {
+        None => Ok(generation),
+        Some(pattern) => {
+            Ok(generation
+                .with_guardrail(RegexGuardrail::new(pattern)?, args.guardrail_max_resamples))
+        }
+    }
+}
+
+fn print_stop_reason(reason: Option<&StopReason>) {
+    match reason {
+        Some(StopReason::Length) => print!("\n[the context size of the model has been reached]"),
+        Some(StopReason::Guardrail(reason)) => print!("\n[aborted by the guardrail: {reason}]"),
+        _ => {}
+    }
+}
+
+// Generates from a single prompt with `TextGeneration`, for the modes that need it.
+fn run_text_generation<M: CausalLm>(
+    model: M,
+    prompt: &str,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let prompt_tokens = tokenizer.encode(prompt, true).map_err(anyhow::Error::msg)?;
+    let prompt_tokens = prompt_tokens.get_ids();
+    let max_context = model.max_seq_len().unwrap_or(usize::MAX);
+    let budget = TokenBudget::new(max_context).with_reserve(args.context_reserve);
+    let max_tokens = args.generation_params().max_tokens;
+    let (prompt_tokens, new_tokens) =
+        plan_prompt(&budget, prompt_tokens, max_tokens, args.context_overflow)?;
+    let stop_conditions = {
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let added_tokens = added_tokens.iter().map(|(&id, t)| (id, t.content.as_str()));
+        StopConditions::new(&args.stop_criteria(), added_tokens)?
+    };
+    let generation = TextGeneration::from_params(model, &args.generation_params(), device)?;
+    let mut generation = with_guardrail(generation, args)?;
+    print!("{prompt}");
+    let start = std::time::Instant::now();
+    generation.push_prompt(prompt_tokens)?;
+    let generated = generation.generate_text(
+        new_tokens,
+        &stop_conditions,
+        |tokens| tokenizer.decode(tokens, true).map_err(candle::Error::msg),
+        |text| {
+            print!("{text}");
+            std::io::stdout().flush()?;
+            Ok(())
+        },
+    )?;
+    let dt = start.elapsed();
+    print_stop_reason(generation.stop_reason());
+    println!(
+        "\n\n{:4} tokens generated: {:.2} token/s",
+        generated.len(),
+        generated.len() as f64 / dt.as_secs_f64(),
+    );
+    if let Some(stats) = generation.guardrail_stats() {
+        println!(
+            "guardrail: {} checks, {} resampled tokens",
+            stats.checks, stats.resamples
+        );
+    }
+    Ok(())
+}
+
+/// Plans the generation after `tokens` with `--context-overflow`, returns the prompt tokens to
+/// process and the number of tokens to generate. A notice is printed when the prompt is truncated
+/// or when fewer tokens than requested fit.
+fn plan_prompt<'a>(
+    budget: &TokenBudget,
+    tokens: &'a [u32],
+    max_tokens: usize,
+    overflow: ContextOverflow,
+) -> Result<(&'a [u32], usize), TokenBudgetError> {
+    let (tokens, new_tokens) = match overflow {
+        ContextOverflow::Refuse => (tokens, budget.plan(tokens.len(), max_tokens)?.new_tokens),
+        ContextOverflow::Truncate => {
+            let plan = budget.plan_truncated(tokens.len(), max_tokens)?;
+            if plan.truncate > 0 {
+                eprintln!(
+                    "note: dropped the first {} of the {} prompt tokens to fit in the context",
+                    plan.truncate,
+                    tokens.len()
+                )
+            }
+            (&tokens[plan.truncate..], plan.new_tokens)
+        }
+    };
+    if new_tokens < max_tokens {
+        eprintln!("note: only {new_tokens} of the {max_tokens} requested tokens fit in the context")
+    }
+    Ok((tokens, new_tokens))
+}
+
+/// Memory kept aside for the activations and the allocator when the kv cache budget is derived
+/// from the free memory.
+const MEMORY_RESERVE_BYTES: u64 = 512 * 1024 * 1024;
+
+struct TokenizerInfo<'a>(&'a Tokenizer);
+
+impl validation::TokenizerInfo for TokenizerInfo<'_> {
+    fn vocab_size(&self) -> usize {
+        self.0.get_vocab_size(true)
+    }
+
+    fn added_tokens(&self) -> Vec<(u32, String)> {
+        let added_tokens = self.0.get_added_tokens_decoder();
+        added_tokens
+            .into_iter()
+            .map(|(id, t)| (id, t.content))
+            .collect()
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> candle::Result<Vec<u32>> {
+        let tokens = self
+            .0
+            .encode(text, add_special_tokens)
+            .map_err(candle::Error::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> candle::Result<String> {
+        self.0.decode(tokens, false).map_err(candle::Error::msg)
+    }
+}
+
+// Runs the checks of `--dry-run` on the configuration given by the command line, the model and
+// the tokenizer are fetched but the weights are not loaded.
+fn run_dry_run(args: &Args, device: &Device) -> anyhow::Result<()> {
+    let mut fetch_errors = vec![];
+    let model_path = match args.model() {
+        Ok(model_path) => model_path,
+        Err(err) => {
+            fetch_errors.push((Check::Model, format!("cannot fetch the model: {err}")));
+            std::path::PathBuf::from(args.model.clone().unwrap_or_default())
+        }
+    };
+    let tokenizer = match args.tokenizer() {
+        Ok(tokenizer) => Some(tokenizer),
+        Err(err) => {
+            fetch_errors.push((
+                Check::Tokenizer,
+                format!("cannot load the tokenizer: {err}"),
+            ));
+            None
+        }
+    };
+    let schedule = match args.tools.as_deref().map(read_tools) {
+        None => None,
+        Some(Ok(tools)) => Some(tool_schedule(&tools, tokenizer.as_ref())),
+        Some(Err(err)) => {
+            fetch_errors.push((Check::Schedule, format!("cannot read the tools: {err}")));
+            None
+        }
+    };
+    let tokenizer_info = tokenizer.as_ref().map(TokenizerInfo);
+    let chat_format = tokenizer.as_ref().map(|tokenizer| QuantizedChatFormat {
+        which: args.which,
+        tokenizer: tokenizer.clone(),
+    });
+    let free_bytes = candle_examples::free_memory(device)?;
+    let config = ValidationConfig {
+        tokenizer: tokenizer_info
+            .as_ref()
+            .map(|t| t as &dyn validation::TokenizerInfo),
+        chat_format: chat_format.as_ref().map(|f| f as &dyn ChatFormat),
+        params: args.generation_params(),
+        schedule,
+        patterns: args.block_regex.iter().cloned().collect(),
+        context_length: args.context_length,
+        context_reserve: args.context_reserve,
+        free_bytes,
+        reserve_bytes: MEMORY_RESERVE_BYTES,
+        kv_budget_bytes: args.kv_budget_mb.map(|mb| mb * 1024 * 1024),
+        quantized_embeddings: args.quantized_embeddings,
+        ..ValidationConfig::new(&model_path, device)
+    };
+    let mut report = validate_configuration(&config)?;
+    for (check, message) in fetch_errors {
+        report.error(check, message)
+    }
+    println!("{report}");
+    std::process::exit(if report.is_valid() { 0 } else { 1 })
+}
+
+// The quantized dtypes of a gguf file, read from its header.
+fn gguf_dtypes(model_path: &std::path::Path) -> anyhow::Result<Vec<GgmlDType>> {
+    let mut file = std::fs::File::open(model_path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
+    let mut dtypes = vec![];
+    for info in content.tensor_infos.values() {
+        if !dtypes.contains(&info.ggml_dtype) {
+            dtypes.push(info.ggml_dtype)
+        }
+    }
+    // From the largest to the smallest number of bits per weight.
+    dtypes.sort_by_key(|dtype| std::cmp::Reverse(dtype.type_size() * 256 / dtype.block_size()));
+    Ok(dtypes)
+}
+
+fn run_self_test(args: &Args, device: &Device) -> anyhow::Result<()> {
+    let model_path = args.model()?;
+    let dtypes = match model_path.extension().and_then(|v| v.to_str()) {
+        Some("gguf") => gguf_dtypes(&model_path)?,
+        _ => {
+            println!("self-test: the quantized matmuls are only checked for gguf models");
+            vec![]
+        }
+    };
+    let report = candle_transformers::self_test::run(device, &dtypes)?;
+    println!("{report}");
+    if !report.passed() {
+        if !args.force {
+            anyhow::bail!("the self-test failed, use --force to load the model anyway")
+        }
+        eprintln!("the self-test failed, loading the model anyway");
+    }
+    Ok(())
+}
+
+/// Loads the model, checking beforehand that its weights fit in the free memory of the device
+/// (only a warning on cpu), then caps its context length so that the kv cache fits in the memory
+/// left or in `--kv-budget-mb`. The activation sizes are returned to plan the prefills.
+fn load_model_with_budget(
+    model_path: &std::path::Path,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<(
+    ModelWeights,
+    usize,
+    Option<ContextBudget>,
+    Option<ActivationConfig>,
+)> {
+    if model_path.extension().and_then(|v| v.to_str()) != Some("gguf") {
+        if args.context_length.is_some() || args.kv_budget_mb.is_some() {
+            anyhow::bail!("--context-length and --kv-budget-mb are only supported for gguf models")
+        }
+        let (model, model_size) = load_model(model_path, args, device)?;
+        return Ok((model, model_size, None, None));
+    }
+    let mut file = std::fs::File::open(model_path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
+    // The kv cache holds the f32 outputs of the key and value projections.
+    let estimate = MemoryEstimate::from_gguf(&content, DType::F32, device)?;
+    let estimate = estimate.with_quantized_embeddings(kv_budget::quantized_embeddings(
+        estimate.dense_embeddings_bytes,
+        args.quantized_embeddings,
+        device,
+    ));
+    if estimate.quantized_embeddings {
+        println!(
+            "quantized token embeddings save {}",
+            format_size(estimate.saved_embeddings_bytes() as usize)
+        );
+    }
+    let activations = ActivationConfig::from_gguf(&content)?;
+    let mut free = candle_examples::free_memory(device)?;
+    if let Some(bytes) = free {
+        if let Err(err) = estimate.check(bytes, MEMORY_RESERVE_BYTES) {
+            // The free memory of the cpu leaves out the swap and the overcommit.
+            if !device.is_cpu() {
+                return Err(err.into());
+            }
+            println!("warning: {err}, loading anyway");
+            // Nothing would be left for the kv cache, so the free memory does not cap the context.
+            free = None;
+        }
+    }
+    let budget = match args.kv_budget_mb {
+        Some(mb) => Some(mb * 1024 * 1024),
+        None => free.map(|free| estimate.kv_budget(free, MEMORY_RESERVE_BYTES)),
+    };
+    let (mut model, model_size) = load_model(model_path, args, device)?;
+    let requested = match args.context_length {
+        None => model.max_seq_len(),
+        Some(len) if len > model.max_seq_len() => {
+            println!(
+                "--context-length {len} is above the {} positions supported by the model",
+                model.max_seq_len()
+            );
+            model.max_seq_len()
+        }
+        Some(len) => len,
+    };
+    let context = ContextBudget::negotiate(&estimate.kv, requested, budget)?;
+    println!("{context}");
+    model.limit_max_seq_len(context.effective);
+    Ok((model, model_size, Some(context), Some(activations)))
+}
+
+/// Picks how to process a prompt of `prompt_len` tokens, `--split-prompt` and
+/// `--prefill-chunk-size` override the plan derived from the free memory of the device. The
+/// decision is printed.
+fn prefill_strategy(
+    args: &Args,
+    activations: Option<&ActivationConfig>,
+    context: Option<&ContextBudget>,
+    prompt_len: usize,
+    device: &Device,
+) -> anyhow::Result<PrefillStrategy> {
+    let forced = if args.split_prompt {
+        Some((PrefillStrategy::TokenByToken, "--split-prompt"))
+    } else {
+        let chunked = |chunk_size| PrefillStrategy::Chunked { chunk_size };
+        args.prefill_chunk_size
+            .map(|chunk_size| (chunked(chunk_size), "--prefill-chunk-size"))
+    };
+    if let Some((strategy, flag)) = forced {
+        println!("prefill {strategy} ({flag})");
+        return Ok(strategy);
+    }
+    let activations = match activations {
+        Some(activations) => activations,
+        None => return Ok(PrefillStrategy::SingleShot),
+    };
+    // The kv cache of the prompt is allocated during the prefill, on top of the activations.
+    let kv_bytes = context.map_or(0, |c| c.kv_bytes_per_token * prompt_len as u64);
+    let free = candle_examples::free_memory(device)?.map(|free| free.saturating_sub(kv_bytes));
+    let plan = activations.plan_prefill(prompt_len, free);
+    println!("{plan}");
+    Ok(plan.strategy)
+}
+
+fn write_manifest(
+    model_path: &std::path::Path,
+    args: &Args,
+    device: &Device,
+    context: Option<ContextBudget>,
+) -> anyhow::Result<()> {
+    use candle_transformers::manifest::{self, BuildInfo, CheckpointInfo};
+
+    let manifest_path = args.manifest.as_deref().unwrap_or_default();
+    if model_path.extension().and_then(|v| v.to_str()) != Some("gguf") {
+        anyhow::bail!("--manifest is only supported for gguf models")
+    }
+    let mut file = std::fs::File::open(model_path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
+    let model = CheckpointInfo::from_path(model_path, device)?;
+    let mut manifest = manifest::build(
+        &content,
+        &model,
+        &BuildInfo::current(),
+        &args.generation_params(),
+    );
+    manifest.context = context;
+    std::fs::write(manifest_path, serde_json::to_string_pretty(&manifest)?)?;
+    println!("manifest written to {manifest_path}");
+    Ok(())
+}
+
+fn read_prompt() -> anyhow::Result<String> {
+    print!("> ");
+    std::io::stdout().flush()?;
+    let mut prompt = String::new();
+    std::io::stdin().read_line(&mut prompt)?;
+    if prompt.ends_with('\n') {
+        prompt.pop();
+        if prompt.ends_with('\r') {
+            prompt.pop();
+        }
+    }
+    Ok(prompt)
+}
+
+fn format_prompt(which: Which, prompt: &str, first_turn: bool) -> String {
+    if which.is_open_chat() {
+        format!("GPT4 Correct User: {prompt}<|end_of_turn|>GPT4 Correct Assistant:")
+    } else if which.is_zephyr() {
+        if first_turn {
+            format!("<|system|>\n</s>\n<|user|>\n{prompt}</s>\n<|assistant|>",)
+        } else {
+            format!("<|user|>\n{prompt}</s>\n<|assistant|>")
+        }
+    } else if which.is_mistral() {
+        format!("[INST] {prompt} [/INST]")
+    } else if which.is_deepseek() {
+        format!("<｜User｜>{prompt}<｜Assistant｜>")
+    } else {
+        prompt.to_string()
+    }
+}
+
+/// Parses `/regen [--temp X]`, returns `None` if the line is not a regen command.
+fn parse_regen(line: &str) -> Option<anyhow::Result<Option<f64>>> {
+    let mut args = line.split_whitespace();
+    if args.next() != Some("/regen") {
+        return None;
+    }
+    let temperature = match (args.next(), args.next(), args.next()) {
+        (None, _, _) => Ok(None),
+        (Some("--temp"), Some(t), None) => t.parse().map(Some).map_err(anyhow::Error::msg),
+        _ => Err(anyhow::anyhow!("usage: /regen [--temp X]")),
+    };
+    Some(temperature)
+}
+
+// The chat template of the instruct models, a system message is only rendered as such by the
+// zephyr template and is a plain prefix for the others.
+struct QuantizedChatFormat {
+    which: Which,
+    tokenizer: Tokenizer,
+}
+
+impl ChatFormat for QuantizedChatFormat {
+    fn render(&self, messages: &[ChatMessage]) -> String {
+        let which = self.which;
+        let mut text = String::new();
+        if which.is_zephyr() && messages.first().map(|m| m.role) != Some(Role::System) {
+            text.push_str("<|system|>\n</s>\n")
+        }
+        for ChatMessage { role, content } in messages {
+            let message = match role {
+                Role::System if which.is_zephyr() => format!("<|system|>\n{content}</s>\n"),
+                Role::System => format!("{content}\n\n"),
+                Role::User => format_prompt(which, content, false),
+                Role::Assistant if which.is_open_chat() => format!("{content}<|end_of_turn|>"),
+                Role::Assistant if which.is_zephyr() => format!("{content}</s>\n"),
+                Role::Assistant if which.is_mistral() => format!("{content}</s>"),
+                Role::Assistant if which.is_deepseek() => {
+                    format!("{content}<｜end▁of▁sentence｜>")
+                }
+                Role::Assistant => content.to_string(),
+            };
+            text.push_str(&message)
+        }
+        text
+    }
+
+    fn encode(&self, text: &str) -> candle::Result<Vec<u32>> {
+        let tokens = self
+            .tokenizer
+            .encode(text, true)
+            .map_err(candle::Error::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> candle::Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(candle::Error::msg)
+    }
+}
+
+// The maximum number of tokens of the summaries of `--history-policy summarize`, at most a
+// quarter of the context is used.
+const SUMMARY_MAX_TOKENS: usize = 128;
+
+// In chat mode the whole history is rendered before each answer, the kv cache is kept for the
+// tokens shared with the previous turn. The oldest turns are dropped or summarized when the
+// history does not fit, and the state before each answer is checkpointed so that `/regen` can
+// resample the last answer.
+fn run_chat(
+    model: ModelWeights,
+    tokenizer: Tokenizer,
+    args: &Args,
+    device: &Device,
+) -> anyhow::Result<()> {
+    let stop_conditions = {
+        let eos_token = args.which.eos_token();
+        let eos_token = *tokenizer.get_vocab(true).get(eos_token).unwrap();
+        let mut criteria = args.stop_criteria();
+        criteria.push(StopCriteria::StopTokens([eos_token].into()));
+        let added_tokens = tokenizer.get_added_tokens_decoder();
+        let added_tokens = added_tokens.iter().map(|(&id, t)| (id, t.content.as_str()));
+        StopConditions::new(&criteria, added_tokens)?
+    };
+    let budget = TokenBudget::new(model.max_seq_len()).with_reserve(args.context_reserve);
+    let generation = TextGeneration::from_params(model, &args.generation_params(), device)?;
+    let generation = with_guardrail(generation, args)?;
+    let format = QuantizedChatFormat {
+        which: args.which,
+        tokenizer: tokenizer.clone(),
+    };
+    let mut session = ChatSession::new(generation, format, stop_conditions, budget)
+        .with_history(ChatHistory::new(args.system_prompt.clone()));
+    session = match args.history_policy {
+        HistoryPolicy::Drop => session.with_summarizer(DropOldestTurns),
+        HistoryPolicy::Summarize => {
+            let max_tokens = SUMMARY_MAX_TOKENS.min(budget.available() / 4);
+            session.with_summarizer(SummarizeTurns::new(max_tokens))
+        }
+    };
+    let max_tokens = args.generation_params().max_tokens;
+    let mut regen_count = 0;
+    loop {
+        let line = read_prompt()?;
+        let start_prompt_processing = std::time::Instant::now();
+        let prompt_len = match parse_regen(&line) {
+            None => {
+                let prompt = match session.push_user(&line, max_tokens) {
+                    Ok(prompt) => prompt,
+                    Err(err) => {
+                        println!("{err}");
+                        continue;
+                    }
+                };
+                if prompt.compactions > 0 {
+                    println!(
+                        "[the history has been compacted {} times to fit the context]",
+                        prompt.compactions
+                    );
+                }
+                if args.verbose_prompt {
+                    let prompt_str = session.format().render(&session.history().to_messages());
+                    let tokens = tokenizer
+                        .encode(prompt_str, true)
+                        .map_err(anyhow::Error::msg)?;
+                    for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
+                        let token = token.replace('▁', " ").replace("<0x0A>", "\n");
+                        println!("{id:7} -> '{token}'");
+                    }
+                }
+                prompt.processed_tokens
+            }
+            Some(temperature) => {
+                let temperature = match temperature {
+                    Ok(temperature) => temperature,
+                    Err(err) => {
+                        println!("{err}");
+                        continue;
+                    }
+                };
+                if let Err(err) = session.rollback_answer() {
+                    println!("{err}");
+                    continue;
+                }
+                regen_count += 1;
+                let sampling = args.sampling_with_temperature(
+                    temperature.unwrap_or(args.generation_params().temperature),
+                );
+                let seed = args.generation_params().seed.wrapping_add(regen_count);
+                session
+                    .generation_mut()
+                    .set_logits_processor(LogitsProcessor::from_sampling(seed, sampling));
+                0
+            }
+        };
+        let prompt_dt = start_prompt_processing.elapsed();
+
+        let start_post_prompt = std::time::Instant::now();
+        let generated = session.answer(|text| {
+            print!("{text}");
+            std::io::stdout().flush()?;
+            Ok(())
+        })?;
+        print_stop_reason(session.generation().stop_reason());
+        std::io::stdout().flush()?;
+        let dt = start_post_prompt.elapsed();
+        println!(
+            "\n\n{prompt_len:4} prompt tokens processed: {:.2} token/s",
+            prompt_len as f64 / prompt_dt.as_secs_f64(),
+        );
+        println!(
+            "{:4} tokens generated: {:.2} token/s",
+            generated.len(),
+            generated.len() as f64 / dt.as_secs_f64(),
+        );
+    }
+}
+
+// Names the `custom-op` spans after their op and the shapes of its arguments, e.g.
+// `rms-norm [1, 9, 4096] [4096]`, rather than all of them `custom-op` in the chrome trace.
+struct CustomOpNames;
+
+struct CustomOpName(String);
+
+impl<S> tracing_subscriber::Layer<S> for CustomOpNames
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        struct Visitor(String);
+        impl tracing::field::Visit for Visitor {
+            fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+                if field.name() == "op" || field.name() == "shapes" {
+                    self.0.push(' ');
+                    self.0.push_str(value)
+                }
+            }
+
+            fn record_debug(&mut self, _: &tracing::field::Field, _: &dyn std::fmt::Debug) {}
+        }
+        if attrs.metadata().name() != "custom-op" {
+            return;
+        }
+        let mut visitor = Visitor(String::new());
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            let name = visitor.0.trim().to_string();
+            span.extensions_mut().insert(CustomOpName(name))
+        }
+    }
+}
+
+fn chrome_trace_name<S>(event_or_span: &tracing_chrome::EventOrSpan<'_, '_, S>) -> String
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a> + Send + Sync,
+{
+    match event_or_span {
+        tracing_chrome::EventOrSpan::Event(event) => event.metadata().name().to_string(),
+        tracing_chrome::EventOrSpan::Span(span) => match span.extensions().get::<CustomOpName>() {
+            Some(name) => name.0.clone(),
+            None => span.name().to_string(),
+        },
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    use tracing_chrome::ChromeLayerBuilder;
+    use tracing_subscriber::prelude::*;
+
+    let args = Args::parse();
+    if args.cache_report || args.cache_evict.is_some() {
+        return run_cache_commands(&args);
+    }
+
+    #[cfg(feature = "cuda")]
+    candle::quantized::cuda::set_force_dmmv(args.force_dmmv);
+
+    candle::cuda::set_gemm_reduced_precision_f16(true);
+    candle::cuda::set_gemm_reduced_precision_bf16(true);
+
+    if let Some(policy) = args.numa {
+        use candle::cpu::numa;
+        // The policy has to be set before loading the weights and before any op starts the
+        // thread pool.
+        numa::set_numa_policy(policy)?;
+        numa::pin_worker_threads()?;
+        match numa::placement() {
+            None => println!("numa: single node, {policy:?} has no effect"),
+            Some(placement) if !numa::is_supported() => eprintln!(
+                "numa: {placement:?} is not applied, build with the numa feature on linux"
+            ),
+            Some(placement) => println!("numa: {placement:?}"),
+        }
+    }
+
+    let _guard = if args.tracing {
+        candle_transformers::register_custom_ops()?;
+        let (chrome_layer, guard) = ChromeLayerBuilder::new()
+            .include_args(true)
+            .name_fn(Box::new(chrome_trace_name))
+            .build();
+        tracing_subscriber::registry()
+            .with(CustomOpNames)
+            .with(chrome_layer)
+            .init();
+        Some(guard)
+    } else {
+        None
+    };
+
+    println!(
+        "avx: {}, neon: {}, simd128: {}, f16c: {}",
+        candle::utils::with_avx(),
+        candle::utils::with_neon(),
+        candle::utils::with_simd128(),
+        candle::utils::with_f16c()
+    );
+    let params = args.generation_params();
+    println!(
+        "preset: {} temp: {:.2} top-k: {:?} top-p: {:?} repeat-penalty: {:.2} repeat-last-n: {}",
+        args.generation.preset.map_or("none", |p| p.name()),
+        params.temperature,
+        params.top_k,
+        params.top_p,
+        params.repeat_penalty,
+        params.repeat_last_n
+    );
+
+    let device = candle_examples::device(args.cpu)?;
+    if args.dry_run {
+        return run_dry_run(&args, &device);
+    }
+    params.validate()?;
+    if args.self_test {
+        run_self_test(&args, &device)?;
+    }
+    // Fetch the tokenizer and encode a one-shot prompt while the model is being loaded.
+    let one_shot_prompt = match args.prompt.as_deref() {
+        Some("chat") | Some("interactive") => None,
+        Some(prompt) => Some(prompt.to_string()),
+        None => Some(DEFAULT_PROMPT.to_string()),
+    };
+    let (
+        (model_path, mut model, model_size, context, activations),
+        (tokenizer, mut prompt_encoding),
+    ) = {
+        let (model_args, tokenizer_args) = (args.clone(), args.clone());
+        let device = device.clone();
+        candle_examples::join_concurrently(
+            move |cancelled| {
+                let check_cancelled = || {
+                    if cancelled.load(std::sync::atomic::Ordering::Relaxed) {
+                        anyhow::bail!("cancelled")
+                    }
+                    Ok(())
+                };
+                // The tokenizer may already have failed, e.g. on a missing file, before the
+                // weights are fetched and again before they are loaded.
+                check_cancelled()?;
+                let model_path = model_args.model()?;
+                check_cancelled()?;
+                let (model, model_size, context, activations) =
+                    load_model_with_budget(&model_path, &model_args, &device)?;
+                Ok::<_, anyhow::Error>((model_path, model, model_size, context, activations))
+            },
+            move |_| {
+                let tokenizer = tokenizer_args.tokenizer()?;
+                let tokens = match one_shot_prompt {
+                    None => None,
+                    Some(prompt) => {
+                        Some(tokenizer.encode(prompt, true).map_err(anyhow::Error::msg)?)
+                    }
+                };
+                Ok((tokenizer, tokens))
             },
+        )?
+    };
+    println!("model built");
+    if args.manifest.is_some() {
+        write_manifest(&model_path, &args, &device, context)?;
+    }
+
+    if let Some(compare_model) = args.compare_model.as_deref() {
+        let compare_model = std::path::PathBuf::from(compare_model);
+        return run_comparison(
+            (model, &model_path, model_size),
+            &compare_model,
+            &tokenizer,
+            &args,
+            &device,
+        );
+    }
+    if args.eval {
+        return run_eval(model, &tokenizer, &args, &device);
+    }
+    if args.record_baseline.is_some() || args.check_baseline.is_some() {
+        return run_baseline(model, &tokenizer, &args, &device);
+    }
+    if args.batch_file.is_some() {
+        return run_batch(model, &tokenizer, &args, &device);
+    }
+    if args.tools.is_some() {
+        return run_tools(model, &tokenizer, &args, &device);
+    }
+    if !args.ensemble.is_empty() {
+        return run_ensemble(model, &tokenizer, &args, &device);
+    }
+    let prompt = match args.prompt.as_deref() {
+        Some("chat") => Prompt::Chat,
+        Some("interactive") => Prompt::Interactive,
+        Some(s) => Prompt::One(s.to_string()),
+        None => Prompt::One(DEFAULT_PROMPT.to_string()),
+    };
+    if let Prompt::Chat = prompt {
+        return run_chat(model, tokenizer, &args, &device);
+    }
+    if args.block_regex.is_some() {
+        let Prompt::One(prompt) = prompt else {
+            anyhow::bail!("--block-regex requires a single prompt or the chat mode")
         };
+        return run_text_generation(model, &prompt, &tokenizer, &args, &device);
+    }
+    let lint_config = match args.no_prompt_lint {
+        true => None,
+        false => Some(prompt_lint_config(&model_path, &args, &tokenizer)?),
+    };
+    let mut tos = TokenOutputStream::new(tokenizer);
+    let stop_conditions = {
+        let added_tokens = tos.tokenizer().get_added_tokens_decoder();
+        let added_tokens = added_tokens.iter().map(|(&id, t)| (id, t.content.as_str()));
+        StopConditions::new(&args.stop_criteria(), added_tokens)?
+    };
 
+    loop {
+        let prompt_str = match &prompt {
+            Prompt::One(prompt) => prompt.clone(),
+            Prompt::Interactive | Prompt::Chat => format_prompt(args.which, &read_prompt()?, true),
+        };
+        let tokens = match prompt_encoding.take() {
+            Some(tokens) => tokens,
+            None => tos
+                .tokenizer()
+                .encode(prompt_str.as_str(), true)
+                .map_err(anyhow::Error::msg)?,
+        };
+        // Prompts that do not fit in the context are truncated or refused depending on
+        // `--context-overflow`, and the generation stops when the context is full.
+        let budget = TokenBudget::new(model.max_seq_len()).with_reserve(args.context_reserve);
+        let max_tokens = params.max_tokens;
+        let (prompt_tokens, new_tokens) =
+            match plan_prompt(&budget, tokens.get_ids(), max_tokens, args.context_overflow) {
+                Ok((prompt_tokens, new_tokens)) => (prompt_tokens.to_vec(), new_tokens),
+                Err(err) => match prompt {
+                    Prompt::One(_) => anyhow::bail!(err),
+                    Prompt::Interactive | Prompt::Chat => {
+                        eprintln!("\n{err}");
+                        continue;
+                    }
+                },
+            };
+        // The prefill decision is printed before the prompt that the generated text continues.
+        let strategy = prefill_strategy(
+            &args,
+            activations.as_ref(),
+            context.as_ref(),
+            prompt_tokens.len(),
+            &device,
+        )?;
+        print!("{}", &prompt_str);
+        if args.verbose_prompt {
+            for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
+                let token = token.replace('▁', " ").replace("<0x0A>", "\n");
+                println!("{id:7} -> '{token}'");
+            }
+        }
+
+        // The first token is sampled from the logits of the prompt.
+        let to_sample = new_tokens.saturating_sub(1);
+        if let Some(config) = lint_config.as_ref() {
+            let decode = |ids: &[u32]| {
+                tos.tokenizer()
+                    .decode(ids, false)
+                    .map_err(candle::Error::msg)
+            };
+            for warning in prompt_lint::check(&prompt_tokens, config, decode)? {
+                eprintln!("warning: {warning} (silence with --no-prompt-lint)")
+            }
+        }
+        let mut all_tokens = vec![];
+        let mut logits_processor = LogitsProcessor::from_sampling(params.seed, args.sampling());
+
+        let start_prompt_processing = std::time::Instant::now();
+        let chunk_size = strategy.chunk_size(prompt_tokens.len());
+        let mut next_token = 0;
+        for (index, chunk) in prompt_tokens.chunks(chunk_size).enumerate() {
+            let input = Tensor::new(chunk, &device)?.unsqueeze(0)?;
+            let logits = model.forward(&input, index * chunk_size)?;
+            // Only the logits of the last position of the prompt are sampled.
+            if (index + 1) * chunk_size >= prompt_tokens.len() {
+                next_token = logits_processor.sample(&logits.squeeze(0)?)?
+            }
+        }
+        let prompt_dt = start_prompt_processing.elapsed();
+        if args.debug_kernels {
+            println!();
+            print_kernel_table(&model);
+        }
+        all_tokens.push(next_token);
+        // Stop tokens are never emitted.
+        let to_sample = if stop_conditions.check_token(next_token).is_some() {
+            0
+        } else {
+            if let Some(t) = tos.next_token(next_token)? {
+                print!("{t}");
+                std::io::stdout().flush()?;
+            }
+            to_sample
+        };
+
+        let eos_token = args.which.eos_token();
         let eos_token = *tos.tokenizer().get_vocab(true).get(eos_token).unwrap();
         let start_post_prompt = std::time::Instant::now();
         let mut sampled = 0;
@@ -653,18 +2273,21 @@ fn main() -> anyhow::Result<()> {
             let input = Tensor::new(&[next_token], &device)?.unsqueeze(0)?;
             let logits = model.forward(&input, prompt_tokens.len() + index)?;
             let logits = logits.squeeze(0)?;
-            let logits = if args.repeat_penalty == 1. {
-                logits
+            next_token = if params.repeat_penalty == 1. {
+                logits_processor.sample(&logits)?
             } else {
-                let start_at = all_tokens.len().saturating_sub(args.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
+                let start_at = all_tokens.len().saturating_sub(params.repeat_last_n);
+                let penalized = candle_transformers::utils::apply_repeat_penalty(
                     &logits,
-                    args.repeat_penalty,
+                    params.repeat_penalty,
                     &all_tokens[start_at..],
-                )?
+                )?;
+                logits_processor.sample_with_unfiltered(&penalized, &logits)?
             };
-            next_token = logits_processor.sample(&logits)?;
             all_tokens.push(next_token);
+            if stop_conditions.check_token(next_token).is_some() {
+                break;
+            }
             if let Some(t) = tos.next_token(next_token)? {
                 print!("{t}");
                 std::io::stdout().flush()?;
@@ -688,13 +2311,18 @@ fn main() -> anyhow::Result<()> {
             "{sampled:4} tokens generated: {:.2} token/s",
             sampled as f64 / dt.as_secs_f64(),
         );
+        if args.debug_kernels {
+            print_kernel_table(&model);
+        }
+        if logits_processor.degenerate_count() > 0 {
+            println!(
+                "{:4} sampling steps had a degenerate distribution",
+                logits_processor.degenerate_count()
+            );
+        }
 
-        match prompt {
-            Prompt::One(_) => break,
-            Prompt::Interactive => {}
-            Prompt::Chat => {
-                pre_prompt_tokens = [prompt_tokens.as_slice(), all_tokens.as_slice()].concat()
-            }
+        if let Prompt::One(_) = prompt {
+            break;
         }
     }
 
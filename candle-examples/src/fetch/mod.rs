@@ -0,0 +1,561 @@
+//! Fetches model files from URIs rather than from the hub.
+//!
+//! [`fetch_to_cache`] accepts `file://` URIs, which are used in place, `http://` and `https://`
+//! URLs with the `http` feature, which is enabled by default, and `s3://<bucket>/<key>` URIs
+//! with the `s3` feature. The downloads go to the hub cache
+//! directory, in a `urls--<scheme>--<host>` directory per host, so that a second run finds the
+//! file there and does not download it again. An interrupted download is resumed from where it
+//! stopped when the server supports range requests.
+//!
+//! A URI can end with a `#sha256=<hex digest>` fragment, the download is then checked against
+//! this digest. The digest of a verified file is recorded next to it so that a cache hit does not
+//! hash the file again.
+//!
+//! Other schemes can be supported by implementing [`Scheme`] and registering it with
+//! [`Fetcher::register`].
+#[cfg(feature = "s3")]
+pub mod s3;
+
+use candle::Result;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// A parsed URI, `<scheme>://<authority><path>[#sha256=<hex digest>]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Uri {
+    /// The lowercase scheme, e.g. `https`.
+    pub scheme: String,
+    /// The host and port for urls, the bucket for `s3://`, empty for local files.
+    pub authority: String,
+    /// The path starting with `/`, including the query if any.
+    pub path: String,
+    /// The expected lowercase hex sha256 digest of the content.
+    pub sha256: Option<String>,
+}
+
+impl std::str::FromStr for Uri {
+    type Err = candle::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (uri, fragment) = match s.split_once('#') {
+            Some((uri, fragment)) => (uri, Some(fragment)),
+            None => (s, None),
+        };
+        let (scheme, rest) = match uri.split_once("://") {
+            Some((scheme, rest)) if !scheme.is_empty() => (scheme.to_lowercase(), rest),
+            _ => candle::bail!("{s:?} is not a uri, expected <scheme>://<location>"),
+        };
+        let valid_scheme = |c: char| c.is_ascii_alphanumeric() || "+-.".contains(c);
+        if !scheme.chars().all(valid_scheme) {
+            candle::bail!("invalid scheme {scheme:?} in {s:?}")
+        }
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, ""),
+        };
+        let sha256 = match fragment {
+            None => None,
+            Some(fragment) => match fragment.strip_prefix("sha256=") {
+                Some(digest)
+                    if digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()) =>
+                {
+                    Some(digest.to_lowercase())
+                }
+                _ => candle::bail!("invalid fragment in {s:?}, expected #sha256=<hex digest>"),
+            },
+        };
+        let uri = Self {
+            scheme,
+            authority: authority.to_string(),
+            path: path.to_string(),
+            sha256,
+        };
+        if uri.filename().is_empty() {
+            candle::bail!("no file name in {s:?}")
+        }
+        Ok(uri)
+    }
+}
+
+impl std::fmt::Display for Uri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}://{}{}", self.scheme, self.authority, self.path)?;
+        if let Some(sha256) = &self.sha256 {
+            write!(f, "#sha256={sha256}")?
+        }
+        Ok(())
+    }
+}
+
+impl Uri {
+    /// The path without the query.
+    pub fn path_without_query(&self) -> &str {
+        self.path.split('?').next().unwrap_or("")
+    }
+
+    /// The last segment of the path.
+    pub fn filename(&self) -> &str {
+        self.path_without_query().rsplit('/').next().unwrap_or("")
+    }
+
+    /// The uri without the checksum fragment.
+    pub fn url(&self) -> String {
+        format!("{}://{}{}", self.scheme, self.authority, self.path)
+    }
+}
+
+/// The content of a resource being downloaded.
+pub struct Download {
+    pub reader: Box<dyn Read + Send>,
+    /// The position in the resource of the first byte of `reader`. This is `0` when the backend
+    /// cannot resume downloads, regardless of the requested offset.
+    pub offset: u64,
+}
+
+/// A backend for a uri scheme.
+pub trait Scheme: Send + Sync {
+    /// Opens the resource, starting at byte `offset` if the backend supports it.
+    fn open(&self, uri: &Uri, offset: u64) -> Result<Download>;
+
+    /// The local path of the resource, for resources that are used in place rather than copied
+    /// to the cache.
+    fn local_path(&self, _uri: &Uri) -> Result<Option<PathBuf>> {
+        Ok(None)
+    }
+}
+
+/// `file://` uris, the files are used in place.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FileScheme;
+
+impl Scheme for FileScheme {
+    fn open(&self, uri: &Uri, offset: u64) -> Result<Download> {
+        use std::io::Seek;
+        let path = self.local_path(uri)?.unwrap_or_default();
+        let mut file = std::fs::File::open(&path)
+            .map_err(|e| candle::Error::from(e).context(format!("opening {uri}")))?;
+        file.seek(std::io::SeekFrom::Start(offset))?;
+        Ok(Download {
+            reader: Box::new(file),
+            offset,
+        })
+    }
+
+    fn local_path(&self, uri: &Uri) -> Result<Option<PathBuf>> {
+        if !uri.authority.is_empty() && uri.authority != "localhost" {
+            candle::bail!("{uri} is not a local file, expected file:///<absolute path>")
+        }
+        Ok(Some(PathBuf::from(uri.path_without_query())))
+    }
+}
+
+/// `http://` and `https://` urls, downloads are resumed with range requests.
+#[cfg(feature = "http")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HttpScheme;
+
+#[cfg(feature = "http")]
+impl HttpScheme {
+    /// Sends a GET request with some extra headers, asking for the content after `offset`.
+    pub fn get(url: &str, headers: &[(String, String)], offset: u64) -> Result<Download> {
+        let mut request = ureq::get(url);
+        for (name, value) in headers {
+            request = request.set(name, value)
+        }
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={offset}-"))
+        }
+        let response = request
+            .call()
+            .map_err(|e| candle::Error::wrap(e).context(format!("fetching {url}")))?;
+        // A server that does not support range requests sends the whole content.
+        let offset = if response.status() == 206 { offset } else { 0 };
+        Ok(Download {
+            reader: Box::new(response.into_reader()),
+            offset,
+        })
+    }
+}
+
+#[cfg(feature = "http")]
+impl Scheme for HttpScheme {
+    fn open(&self, uri: &Uri, offset: u64) -> Result<Download> {
+        Self::get(&uri.url(), &[], offset)
+    }
+}
+
+/// Downloads uris to a cache directory.
+#[derive(Clone)]
+pub struct Fetcher {
+    cache_dir: PathBuf,
+    schemes: HashMap<String, Arc<dyn Scheme>>,
+}
+
+impl Default for Fetcher {
+    /// A fetcher using the hub cache directory.
+    fn default() -> Self {
+        Self::new(hf_hub::Cache::default().path().clone())
+    }
+}
+
+impl Fetcher {
+    /// Creates a fetcher with the `file` scheme, the `http` and `https` ones with the `http`
+    /// feature, and the `s3` one with the `s3` feature.
+    pub fn new<P: Into<PathBuf>>(cache_dir: P) -> Self {
+        let mut fetcher = Self {
+            cache_dir: cache_dir.into(),
+            schemes: HashMap::new(),
+        };
+        fetcher.register("file", FileScheme);
+        #[cfg(feature = "http")]
+        {
+            fetcher.register("http", HttpScheme);
+            fetcher.register("https", HttpScheme);
+        }
+        #[cfg(feature = "s3")]
+        fetcher.register("s3", s3::S3Scheme::from_env());
+        fetcher
+    }
+
+    /// Registers the backend for a scheme, replacing the previous one if any.
+    pub fn register<S: Scheme + 'static>(&mut self, scheme: &str, backend: S) {
+        self.schemes
+            .insert(scheme.to_lowercase(), Arc::new(backend));
+    }
+
+    pub fn cache_dir(&self) -> &Path {
+        &self.cache_dir
+    }
+
+    /// The path where the content of `uri` is cached. The query is not part of the path so that
+    /// e.g. a presigned url that is regenerated for each run still hits the cache.
+    pub fn cache_path(&self, uri: &Uri) -> Result<PathBuf> {
+        let mut path = self.cache_dir.join(format!(
+            "urls--{}--{}",
+            uri.scheme,
+            uri.authority.replace(':', "--")
+        ));
+        for segment in uri.path_without_query().split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => candle::bail!("unexpected .. in {uri}"),
+                segment => path.push(segment),
+            }
+        }
+        Ok(path)
+    }
+
+    /// Returns a local path holding the content of `uri`, downloading it to the cache if it is
+    /// not already there.
+    pub fn fetch(&self, uri: &str) -> Result<PathBuf> {
+        let uri = uri.parse::<Uri>()?;
+        let scheme = match self.schemes.get(&uri.scheme) {
+            Some(scheme) => scheme,
+            None => candle::bail!("unsupported scheme {:?} in {uri}", uri.scheme),
+        };
+        if let Some(path) = scheme.local_path(&uri)? {
+            if !path.is_file() {
+                candle::bail!("{uri} does not exist")
+            }
+            if let Some(expected) = &uri.sha256 {
+                check_sha256(&path, expected)?
+            }
+            return Ok(path);
+        }
+        let path = self.cache_path(&uri)?;
+        if is_cached(&path, uri.sha256.as_deref())? {
+            return Ok(path);
+        }
+        std::fs::create_dir_all(path.parent().unwrap_or(&self.cache_dir))?;
+        // Another process downloading the same file holds the lock, wait for it to finish.
+        let lock = std::fs::File::create(with_suffix(&path, ".lock"))?;
+        lock.lock()?;
+        if is_cached(&path, uri.sha256.as_deref())? {
+            return Ok(path);
+        }
+        let part = with_suffix(&path, ".part");
+        download(scheme.as_ref(), &uri, &part)?;
+        if let Some(expected) = &uri.sha256 {
+            if let Err(err) = check_sha256(&part, expected) {
+                std::fs::remove_file(&part)?;
+                return Err(err);
+            }
+            std::fs::write(with_suffix(&path, ".sha256"), expected)?;
+        }
+        std::fs::rename(&part, &path)?;
+        drop(lock);
+        Ok(path)
+    }
+}
+
+/// Returns a local path holding the content of `uri`, using the default [`Fetcher`].
+pub fn fetch_to_cache(uri: &str) -> Result<PathBuf> {
+    Fetcher::default().fetch(uri)
+}
+
+/// Whether `uri` should be fetched rather than being a local path or a hub file.
+pub fn is_uri(s: &str) -> bool {
+    s.contains("://")
+}
+
+/// The lowercase hex sha256 digest of a file.
+pub fn sha256_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n])
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+fn check_sha256(path: &Path, expected: &str) -> Result<()> {
+    let digest = sha256_file(path)?;
+    if digest != expected {
+        candle::bail!("sha256 mismatch for {path:?}, expected {expected}, got {digest}")
+    }
+    Ok(())
+}
+
+// A cached file is only used when its recorded digest matches the expected one.
+fn is_cached(path: &Path, sha256: Option<&str>) -> Result<bool> {
+    if !path.is_file() {
+        return Ok(false);
+    }
+    match sha256 {
+        None => Ok(true),
+        Some(expected) => match std::fs::read_to_string(with_suffix(path, ".sha256")) {
+            Ok(recorded) => Ok(recorded.trim() == expected),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err)?,
+        },
+    }
+}
+
+fn download(scheme: &dyn Scheme, uri: &Uri, part: &Path) -> Result<()> {
+    let offset = match std::fs::metadata(part) {
+        Ok(metadata) => metadata.len(),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(err) => Err(err)?,
+    };
+    let mut download = match scheme.open(uri, offset) {
+        Ok(download) => download,
+        // The partial file may be complete or stale, e.g. a 416 response, start over.
+        Err(_) if offset > 0 => scheme.open(uri, 0)?,
+        Err(err) => return Err(err),
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(part)?;
+    file.set_len(download.offset.min(offset))?;
+    std::io::Seek::seek(&mut file, std::io::SeekFrom::End(0))?;
+    let mut file = std::io::BufWriter::new(file);
+    std::io::copy(&mut download.reader, &mut file)
+        .map_err(|e| candle::Error::from(e).context(format!("downloading {uri}")))?;
+    file.flush()?;
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_os_string();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("candle-fetch-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Serves some content, counting the requests and optionally failing after some bytes.
+    struct MemScheme {
+        content: Vec<u8>,
+        opened: Arc<AtomicUsize>,
+        offsets: Arc<Mutex<Vec<u64>>>,
+        fail_after: Option<usize>,
+    }
+
+    impl MemScheme {
+        fn new(content: &[u8]) -> Self {
+            Self {
+                content: content.to_vec(),
+                opened: Arc::new(AtomicUsize::new(0)),
+                offsets: Arc::new(Mutex::new(vec![])),
+                fail_after: None,
+            }
+        }
+    }
+
+    struct Failing(std::io::Cursor<Vec<u8>>);
+
+    impl Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(std::io::Error::other("connection reset")),
+                n => Ok(n),
+            }
+        }
+    }
+
+    impl Scheme for MemScheme {
+        fn open(&self, _uri: &Uri, offset: u64) -> Result<Download> {
+            self.opened.fetch_add(1, Ordering::SeqCst);
+            self.offsets.lock().unwrap().push(offset);
+            let content = self.content[offset as usize..].to_vec();
+            let reader: Box<dyn Read + Send> = match self.fail_after {
+                Some(n) => Box::new(Failing(std::io::Cursor::new(content[..n].to_vec()))),
+                None => Box::new(std::io::Cursor::new(content)),
+            };
+            Ok(Download { reader, offset })
+        }
+    }
+
+    #[test]
+    fn uri_parse() -> Result<()> {
+        let uri: Uri = "HTTPS://example.com:8080/models/tiny.gguf?sig=abc".parse()?;
+        assert_eq!(uri.scheme, "https");
+        assert_eq!(uri.authority, "example.com:8080");
+        assert_eq!(uri.path, "/models/tiny.gguf?sig=abc");
+        assert_eq!(uri.filename(), "tiny.gguf");
+        assert_eq!(uri.sha256, None);
+
+        let digest = "AB".repeat(32);
+        let uri: Uri = format!("s3://bucket/dir/model.bin#sha256={digest}").parse()?;
+        assert_eq!(
+            (uri.authority.as_str(), uri.path.as_str()),
+            ("bucket", "/dir/model.bin")
+        );
+        assert_eq!(uri.sha256, Some(digest.to_lowercase()));
+        assert_eq!(
+            uri.to_string(),
+            format!("s3://bucket/dir/model.bin#sha256={}", digest.to_lowercase())
+        );
+
+        let uri: Uri = "file:///tmp/model.gguf".parse()?;
+        assert_eq!(
+            (uri.authority.as_str(), uri.path.as_str()),
+            ("", "/tmp/model.gguf")
+        );
+
+        for invalid in [
+            "model.gguf",
+            "://host/model.gguf",
+            "https://host/",
+            "https://host",
+            "ht tp://host/model.gguf",
+            "https://host/model.gguf#md5=abc",
+            "https://host/model.gguf#sha256=abc",
+        ] {
+            assert!(invalid.parse::<Uri>().is_err(), "{invalid}")
+        }
+        assert!(is_uri("file:///tmp/model.gguf"));
+        assert!(!is_uri("/tmp/model.gguf"));
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_file_uri() -> Result<()> {
+        let dir = TempDir::new("file");
+        let path = dir.0.join("model.gguf");
+        std::fs::write(&path, b"weights")?;
+        let fetcher = Fetcher::new(dir.0.join("cache"));
+        // Local files are used in place, nothing is copied to the cache.
+        let fetched = fetcher.fetch(&format!("file://{}", path.display()))?;
+        assert_eq!(fetched, path);
+        assert!(!dir.0.join("cache").exists());
+
+        let digest = hex(&sha2::Sha256::digest(b"weights"));
+        let uri = format!("file://{}#sha256={digest}", path.display());
+        assert_eq!(fetcher.fetch(&uri)?, path);
+        let uri = format!("file://{}#sha256={}", path.display(), "0".repeat(64));
+        assert!(fetcher.fetch(&uri).is_err());
+        assert!(fetcher
+            .fetch(&format!("file://{}/missing.gguf", dir.0.display()))
+            .is_err());
+        assert!(fetcher.fetch("file://host/model.gguf").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_cache_hit() -> Result<()> {
+        let dir = TempDir::new("cache-hit");
+        let mut fetcher = Fetcher::new(&dir.0);
+        let scheme = MemScheme::new(b"some weights");
+        let opened = scheme.opened.clone();
+        fetcher.register("mem", scheme);
+        let path = fetcher.fetch("mem://host/dir/model.gguf")?;
+        assert_eq!(path, dir.0.join("urls--mem--host/dir/model.gguf"));
+        assert_eq!(std::fs::read(&path)?, b"some weights");
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        // The second fetch is served from the cache, even with a different query.
+        assert_eq!(fetcher.fetch("mem://host/dir/model.gguf?v=2")?, path);
+        assert_eq!(opened.load(Ordering::SeqCst), 1);
+        // A checksum that has not been verified yet triggers a download.
+        let digest = hex(&sha2::Sha256::digest(b"some weights"));
+        let uri = format!("mem://host/dir/model.gguf#sha256={digest}");
+        assert_eq!(fetcher.fetch(&uri)?, path);
+        assert_eq!(fetcher.fetch(&uri)?, path);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        let uri = format!("mem://host/dir/other.gguf#sha256={}", "0".repeat(64));
+        assert!(fetcher.fetch(&uri).is_err());
+        assert!(!dir.0.join("urls--mem--host/dir/other.gguf").exists());
+        assert!(!dir.0.join("urls--mem--host/dir/other.gguf.part").exists());
+        assert!(fetcher.fetch("unknown://host/model.gguf").is_err());
+        assert!(fetcher.fetch("mem://host/../model.gguf").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn fetch_resume() -> Result<()> {
+        let dir = TempDir::new("resume");
+        let content = (0..100u8).collect::<Vec<_>>();
+        let mut fetcher = Fetcher::new(&dir.0);
+        let mut scheme = MemScheme::new(&content);
+        scheme.fail_after = Some(30);
+        fetcher.register("mem", scheme);
+        assert!(fetcher.fetch("mem://host/model.gguf").is_err());
+        let part = dir.0.join("urls--mem--host/model.gguf.part");
+        assert_eq!(std::fs::read(&part)?, &content[..30]);
+
+        let scheme = MemScheme::new(&content);
+        let offsets = scheme.offsets.clone();
+        fetcher.register("mem", scheme);
+        let path = fetcher.fetch("mem://host/model.gguf")?;
+        assert_eq!(std::fs::read(path)?, content);
+        assert_eq!(*offsets.lock().unwrap(), [30]);
+        assert!(!part.exists());
+        Ok(())
+    }
+}
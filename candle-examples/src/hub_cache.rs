@@ -0,0 +1,496 @@
+//! Reports and trims the hugging face hub cache that the examples download their weights to.
+//!
+//! The cache has one `models--<org>--<name>` directory per repo. The downloaded files are stored
+//! under `blobs/` and `snapshots/<commit>/<filename>` links to them. Only the weight files, i.e.
+//! `.gguf` and `.bin` files, are ever evicted, tokenizers and configs are small and removing them
+//! would break the repos for other tools.
+//!
+//! Eviction has to be safe while another example is running or downloading. A weight file is
+//! kept when it has been read or written within [`HubCache::min_idle`], or when the lock that
+//! `hf-hub` takes while downloading a blob is held by another process.
+use candle::Result;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+const WEIGHT_EXTENSIONS: [&str; 2] = ["gguf", "bin"];
+
+/// A weight file from the cache.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CachedFile {
+    /// The repo id, e.g. `TheBloke/Llama-2-7B-GGML`.
+    pub repo_id: String,
+    /// The filename in the repo.
+    pub filename: String,
+    /// The file holding the data, removing it frees `size` bytes.
+    pub blob: PathBuf,
+    pub size: u64,
+    /// The most recent of the access and modification times of the blob.
+    pub last_used: SystemTime,
+}
+
+/// The disk usage of a repo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RepoUsage {
+    pub repo_id: String,
+    /// The size of all the files of the repo, including the ones that are never evicted.
+    pub size: u64,
+    /// The weight files of the repo, the least recently used first.
+    pub weights: Vec<CachedFile>,
+}
+
+/// Which weight files to evict.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Evicts the least recently used weight files until the whole cache fits in this many bytes.
+    MaxTotalBytes(u64),
+    /// Evicts the weight files that have not been used for this many days.
+    OlderThanDays(u64),
+}
+
+impl std::str::FromStr for EvictionPolicy {
+    type Err = String;
+
+    /// Parses `max-total-bytes=<size>`, where the size can use a `KB`, `MB`, `GB` or `TB` suffix,
+    /// or `older-than-days=<days>`.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "invalid eviction policy {s:?}, expected max-total-bytes=<size> or \
+                 older-than-days=<days>"
+            )
+        };
+        match s.split_once('=') {
+            Some(("max-total-bytes", size)) => {
+                let size = size.trim().to_uppercase();
+                let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit()) {
+                    Some(pos) => size.split_at(pos),
+                    None => (size.as_str(), ""),
+                };
+                let unit = match unit.trim() {
+                    "" | "B" => 1,
+                    "KB" => 1_000,
+                    "MB" => 1_000_000,
+                    "GB" => 1_000_000_000,
+                    "TB" => 1_000_000_000_000,
+                    _ => return Err(err()),
+                };
+                let size = digits.parse::<u64>().map_err(|_| err())?;
+                Ok(Self::MaxTotalBytes(size * unit))
+            }
+            Some(("older-than-days", days)) => {
+                let days = days.trim().parse().map_err(|_| err())?;
+                Ok(Self::OlderThanDays(days))
+            }
+            _ => Err(err()),
+        }
+    }
+}
+
+/// The outcome of [`HubCache::evict`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EvictionReport {
+    pub removed: Vec<CachedFile>,
+    /// The files that the policy selected but that are recently used or being downloaded.
+    pub in_use: Vec<CachedFile>,
+    /// The number of bytes freed by the removed files.
+    pub freed: u64,
+}
+
+/// A hub cache directory.
+#[derive(Debug, Clone)]
+pub struct HubCache {
+    path: PathBuf,
+    min_idle: Duration,
+}
+
+impl Default for HubCache {
+    /// The cache used by `hf_hub::api::sync::Api::new`, which the examples download to.
+    fn default() -> Self {
+        Self::new(hf_hub::Cache::default().path().clone())
+    }
+}
+
+impl HubCache {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            min_idle: Duration::from_secs(3600),
+        }
+    }
+
+    /// Files used more recently than this are never evicted, one hour by default.
+    pub fn with_min_idle(self, min_idle: Duration) -> Self {
+        Self { min_idle, ..self }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn min_idle(&self) -> Duration {
+        self.min_idle
+    }
+
+    /// Returns the usage of each model repo, the largest first. A missing cache directory is
+    /// empty.
+    pub fn usage(&self) -> Result<Vec<RepoUsage>> {
+        let mut repos = vec![];
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(repos),
+            Err(err) => Err(err)?,
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name();
+            let repo_id = match name.to_str().and_then(|n| n.strip_prefix("models--")) {
+                Some(repo_id) => repo_id.replace("--", "/"),
+                None => continue,
+            };
+            if entry.file_type()?.is_dir() {
+                repos.push(repo_usage(repo_id, &entry.path())?)
+            }
+        }
+        repos.sort_by(|a, b| b.size.cmp(&a.size).then(a.repo_id.cmp(&b.repo_id)));
+        Ok(repos)
+    }
+
+    /// Removes the weight files selected by `policy`, skipping the ones in use.
+    pub fn evict(&self, policy: EvictionPolicy) -> Result<EvictionReport> {
+        let repos = self.usage()?;
+        let mut weights = repos
+            .iter()
+            .flat_map(|r| r.weights.iter().cloned())
+            .collect::<Vec<_>>();
+        weights.sort_by_key(|w| w.last_used);
+        let now = SystemTime::now();
+        let mut report = EvictionReport::default();
+        match policy {
+            EvictionPolicy::OlderThanDays(days) => {
+                let max_age = Duration::from_secs(days * 24 * 3600);
+                for file in weights {
+                    let age = now.duration_since(file.last_used).unwrap_or_default();
+                    if age > max_age {
+                        self.evict_file(file, now, &mut report)?;
+                    }
+                }
+            }
+            EvictionPolicy::MaxTotalBytes(max_bytes) => {
+                let mut total = repos.iter().map(|r| r.size).sum::<u64>();
+                for file in weights {
+                    if total <= max_bytes {
+                        break;
+                    }
+                    let size = file.size;
+                    if self.evict_file(file, now, &mut report)? {
+                        total = total.saturating_sub(size)
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Removes `file` unless it is in use, returns whether it has been removed.
+    fn evict_file(
+        &self,
+        file: CachedFile,
+        now: SystemTime,
+        report: &mut EvictionReport,
+    ) -> Result<bool> {
+        let idle = now.duration_since(file.last_used).unwrap_or_default();
+        if idle < self.min_idle || file.blob.with_extension("sync.part").exists() {
+            report.in_use.push(file);
+            return Ok(false);
+        }
+        // Hold the download lock of the blob while removing it so that an `hf-hub` download of
+        // the same file waits for the removal to complete.
+        let lock = std::fs::File::create(file.blob.with_extension("lock"))?;
+        match lock.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                report.in_use.push(file);
+                return Ok(false);
+            }
+            Err(std::fs::TryLockError::Error(err)) => Err(err)?,
+        }
+        let repo_dir = self
+            .path
+            .join(format!("models--{}", file.repo_id.replace('/', "--")));
+        for pointer in pointers_to(&repo_dir.join("snapshots"), &file.blob)? {
+            std::fs::remove_file(pointer)?
+        }
+        match std::fs::remove_file(&file.blob) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => Err(err)?,
+        }
+        drop(lock);
+        report.freed += file.size;
+        report.removed.push(file);
+        Ok(true)
+    }
+}
+
+/// Returns the usage of the model repos in the default cache, see [`HubCache::usage`].
+pub fn hub_cache_usage() -> Result<Vec<RepoUsage>> {
+    HubCache::default().usage()
+}
+
+/// Evicts weight files from the default cache, see [`HubCache::evict`].
+pub fn hub_cache_evict(policy: EvictionPolicy) -> Result<EvictionReport> {
+    HubCache::default().evict(policy)
+}
+
+fn repo_usage(repo_id: String, repo_dir: &Path) -> Result<RepoUsage> {
+    let mut size = 0;
+    for file in files(&repo_dir.join("blobs"))? {
+        size += std::fs::symlink_metadata(&file)?.len()
+    }
+    let snapshots = repo_dir.join("snapshots");
+    let mut weights: Vec<CachedFile> = vec![];
+    for pointer in files(&snapshots)? {
+        let is_link = std::fs::symlink_metadata(&pointer)?
+            .file_type()
+            .is_symlink();
+        let blob = match std::fs::canonicalize(&pointer) {
+            Ok(blob) => blob,
+            // A dangling link, its blob has been removed.
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => Err(err)?,
+        };
+        let metadata = std::fs::metadata(&blob)?;
+        if !is_link {
+            // `hf-hub` moves the blob to the snapshot when symlinks are not supported.
+            size += metadata.len()
+        }
+        let is_weight = pointer
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| WEIGHT_EXTENSIONS.contains(&e));
+        // Several revisions can share the same blob, it is listed once.
+        if !is_weight || weights.iter().any(|w| w.blob == blob) {
+            continue;
+        }
+        // The path within the snapshot, after the commit directory.
+        let filename = pointer
+            .strip_prefix(&snapshots)
+            .ok()
+            .and_then(|p| {
+                p.iter()
+                    .skip(1)
+                    .map(|c| c.to_str())
+                    .collect::<Option<Vec<_>>>()
+            })
+            .map_or_else(|| pointer.display().to_string(), |p| p.join("/"));
+        let last_used = match (metadata.accessed(), metadata.modified()) {
+            (Ok(a), Ok(m)) => a.max(m),
+            (Ok(t), Err(_)) | (Err(_), Ok(t)) => t,
+            (Err(err), Err(_)) => Err(err)?,
+        };
+        weights.push(CachedFile {
+            repo_id: repo_id.clone(),
+            filename,
+            blob,
+            size: metadata.len(),
+            last_used,
+        })
+    }
+    weights.sort_by_key(|w| w.last_used);
+    Ok(RepoUsage {
+        repo_id,
+        size,
+        weights,
+    })
+}
+
+/// The files below `dir`, recursively, without following symlinks.
+fn files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => Err(err)?,
+        };
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path())
+            } else {
+                files.push(entry.path())
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The snapshot entries that resolve to `blob`, including `blob` itself when it lives in the
+/// snapshots.
+fn pointers_to(snapshots: &Path, blob: &Path) -> Result<Vec<PathBuf>> {
+    let mut pointers = vec![];
+    for file in files(snapshots)? {
+        if std::fs::canonicalize(&file).is_ok_and(|f| f == blob) {
+            pointers.push(file)
+        }
+    }
+    Ok(pointers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::FileTimes;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    struct TempCache(PathBuf);
+
+    impl TempCache {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("candle-hub-cache-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir.canonicalize().unwrap())
+        }
+
+        /// Adds a file to a repo, with a blob last used `age` ago.
+        fn add(&self, repo_id: &str, filename: &str, size: usize, age: Duration) -> PathBuf {
+            let repo_dir = self
+                .0
+                .join(format!("models--{}", repo_id.replace('/', "--")));
+            let blob = repo_dir
+                .join("blobs")
+                .join(format!("{repo_id}-{filename}").replace('/', "-"));
+            std::fs::create_dir_all(blob.parent().unwrap()).unwrap();
+            std::fs::write(&blob, vec![0u8; size]).unwrap();
+            let time = SystemTime::now() - age;
+            let times = FileTimes::new().set_accessed(time).set_modified(time);
+            std::fs::File::options()
+                .write(true)
+                .open(&blob)
+                .unwrap()
+                .set_times(times)
+                .unwrap();
+            let pointer = repo_dir.join("snapshots/0123abcd").join(filename);
+            std::fs::create_dir_all(pointer.parent().unwrap()).unwrap();
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(&blob, &pointer).unwrap();
+            #[cfg(not(unix))]
+            std::fs::hard_link(&blob, &pointer).unwrap();
+            blob
+        }
+    }
+
+    impl Drop for TempCache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn removed(report: &EvictionReport) -> Vec<&str> {
+        report.removed.iter().map(|f| f.filename.as_str()).collect()
+    }
+
+    #[test]
+    fn hub_cache_usage_per_repo() {
+        let cache = TempCache::new("usage");
+        cache.add("org/small", "model.gguf", 100, 3 * DAY);
+        cache.add("org/small", "tokenizer.json", 10, 3 * DAY);
+        cache.add("org/large", "q4/model.bin", 1000, DAY);
+        let usage = HubCache::new(&cache.0).usage().unwrap();
+        let sizes = usage
+            .iter()
+            .map(|r| (r.repo_id.as_str(), r.size, r.weights.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, [("org/large", 1000, 1), ("org/small", 110, 1)]);
+        assert_eq!(usage[0].weights[0].filename, "q4/model.bin");
+        assert!(HubCache::new(cache.0.join("missing"))
+            .usage()
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn hub_cache_evict_older_than() {
+        let cache = TempCache::new("older");
+        let old = cache.add("org/a", "old.gguf", 100, 40 * DAY);
+        cache.add("org/a", "old.json", 10, 40 * DAY);
+        cache.add("org/b", "recent.gguf", 100, 2 * DAY);
+        let hub = HubCache::new(&cache.0);
+        let report = hub.evict(EvictionPolicy::OlderThanDays(30)).unwrap();
+        assert_eq!(removed(&report), ["old.gguf"]);
+        assert_eq!(report.freed, 100);
+        assert!(!old.exists());
+        assert!(!cache
+            .0
+            .join("models--org--a/snapshots/0123abcd/old.gguf")
+            .exists());
+        // The config is not a weight file and is kept.
+        assert!(cache
+            .0
+            .join("models--org--a/snapshots/0123abcd/old.json")
+            .exists());
+        let usage = hub.usage().unwrap();
+        assert_eq!(usage.iter().map(|r| r.size).sum::<u64>(), 110);
+    }
+
+    #[test]
+    fn hub_cache_evict_max_total() {
+        let cache = TempCache::new("max-total");
+        cache.add("org/a", "a.gguf", 300, 10 * DAY);
+        cache.add("org/b", "b.bin", 300, 5 * DAY);
+        cache.add("org/c", "c.gguf", 300, 2 * DAY);
+        let hub = HubCache::new(&cache.0);
+        // The least recently used files go first, until the cache fits.
+        let report = hub.evict(EvictionPolicy::MaxTotalBytes(400)).unwrap();
+        assert_eq!(removed(&report), ["a.gguf", "b.bin"]);
+        let report = hub.evict(EvictionPolicy::MaxTotalBytes(400)).unwrap();
+        assert!(report.removed.is_empty());
+    }
+
+    #[test]
+    fn hub_cache_evict_skips_in_use() {
+        let cache = TempCache::new("in-use");
+        let recent = cache.add("org/a", "recent.gguf", 100, Duration::from_secs(60));
+        let locked = cache.add("org/a", "locked.gguf", 100, 40 * DAY);
+        let downloading = cache.add("org/a", "downloading.gguf", 100, 40 * DAY);
+        std::fs::write(downloading.with_extension("sync.part"), b"").unwrap();
+        let lock = std::fs::File::create(locked.with_extension("lock")).unwrap();
+        lock.lock().unwrap();
+        let hub = HubCache::new(&cache.0);
+        let report = hub.evict(EvictionPolicy::MaxTotalBytes(0)).unwrap();
+        assert!(report.removed.is_empty());
+        assert_eq!(report.in_use.len(), 3);
+        assert!(recent.exists() && locked.exists() && downloading.exists());
+        drop(lock);
+        std::fs::remove_file(downloading.with_extension("sync.part")).unwrap();
+        let report = hub.evict(EvictionPolicy::MaxTotalBytes(0)).unwrap();
+        assert_eq!(removed(&report), ["locked.gguf", "downloading.gguf"]);
+        let hub = hub.with_min_idle(Duration::ZERO);
+        let report = hub.evict(EvictionPolicy::MaxTotalBytes(0)).unwrap();
+        assert_eq!(removed(&report), ["recent.gguf"]);
+    }
+
+    #[test]
+    fn eviction_policy_from_str() {
+        let policy = |s: &str| s.parse::<EvictionPolicy>();
+        assert_eq!(
+            policy("max-total-bytes=40GB"),
+            Ok(EvictionPolicy::MaxTotalBytes(40_000_000_000))
+        );
+        assert_eq!(
+            policy("max-total-bytes=1234"),
+            Ok(EvictionPolicy::MaxTotalBytes(1234))
+        );
+        assert_eq!(
+            policy("older-than-days=30"),
+            Ok(EvictionPolicy::OlderThanDays(30))
+        );
+        assert!(policy("max-total-bytes=40XB").is_err());
+        assert!(policy("newer-than-days=3").is_err());
+    }
+}
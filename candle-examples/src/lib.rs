@@ -1,6 +1,8 @@
 pub mod audio;
 pub mod bs1770;
 pub mod coco_classes;
+pub mod fetch;
+pub mod hub_cache;
 pub mod imagenet;
 pub mod token_output_stream;
 pub mod wav;
@@ -29,6 +31,39 @@ pub fn device(cpu: bool) -> Result<Device> {
     }
 }
 
+/// The memory currently free on `device` in bytes, `None` when it cannot be queried. On cpu this
+/// is `MemAvailable` from `/proc/meminfo`.
+pub fn free_memory(device: &Device) -> Result<Option<u64>> {
+    match device {
+        Device::Cpu => {
+            let meminfo = match std::fs::read_to_string("/proc/meminfo") {
+                Ok(meminfo) => meminfo,
+                Err(_) => return Ok(None),
+            };
+            let available = meminfo.lines().find_map(|line| {
+                let kb = line
+                    .strip_prefix("MemAvailable:")?
+                    .trim()
+                    .strip_suffix("kB")?;
+                kb.trim().parse::<u64>().ok()
+            });
+            Ok(available.map(|kb| kb * 1024))
+        }
+        #[cfg(feature = "cuda")]
+        Device::Cuda(device) => {
+            use candle::cuda_backend::cudarc::driver::result;
+            let stream = device.cuda_stream();
+            stream
+                .context()
+                .bind_to_thread()
+                .map_err(candle::Error::wrap)?;
+            let (free, _total) = result::mem_get_info().map_err(candle::Error::wrap)?;
+            Ok(Some(free as u64))
+        }
+        _ => Ok(None),
+    }
+}
+
 pub fn load_image<P: AsRef<std::path::Path>>(
     p: P,
     resize_longest: Option<usize>,
@@ -171,3 +206,114 @@ pub fn hub_load_local_safetensors<P: AsRef<std::path::Path>>(
         .collect();
     Ok(safetensors_files)
 }
+
+/// Runs `a` and `b` concurrently on two threads, e.g. to load the tokenizer while the model
+/// weights are being loaded, and returns both results.
+///
+/// On the first error the other task gets its flag set and the error is returned right away,
+/// without waiting for that task which may be stuck in a blocking call such as a download. The
+/// task is detached, it should poll the flag between its steps to stop and release its memory.
+pub fn join_concurrently<A, B, E, FA, FB>(a: FA, b: FB) -> std::result::Result<(A, B), E>
+where
+    A: Send + 'static,
+    B: Send + 'static,
+    E: Send + 'static,
+    FA: FnOnce(&std::sync::atomic::AtomicBool) -> std::result::Result<A, E> + Send + 'static,
+    FB: FnOnce(&std::sync::atomic::AtomicBool) -> std::result::Result<B, E> + Send + 'static,
+{
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    enum Done<A, B> {
+        A(A),
+        B(B),
+    }
+
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = std::sync::mpsc::channel();
+    let handle_a = {
+        let (tx, cancelled) = (tx.clone(), cancelled.clone());
+        std::thread::spawn(move || {
+            let _ = tx.send(a(&cancelled).map(Done::A));
+        })
+    };
+    let handle_b = {
+        let cancelled = cancelled.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(b(&cancelled).map(Done::B));
+        })
+    };
+    let (mut res_a, mut res_b) = (None, None);
+    while res_a.is_none() || res_b.is_none() {
+        match rx.recv() {
+            Ok(Ok(Done::A(a))) => res_a = Some(a),
+            Ok(Ok(Done::B(b))) => res_b = Some(b),
+            Ok(Err(err)) => {
+                cancelled.store(true, Ordering::Relaxed);
+                return Err(err);
+            }
+            // Both senders have been dropped without a result, one of the tasks panicked.
+            Err(_) => {
+                for handle in [handle_a, handle_b] {
+                    if let Err(panic) = handle.join() {
+                        std::panic::resume_unwind(panic)
+                    }
+                }
+                unreachable!("a task ended without sending its result")
+            }
+        }
+    }
+    Ok((res_a.unwrap(), res_b.unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, Barrier};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn join_concurrently_overlaps() {
+        // Each task waits for the other one, this would deadlock if they ran one after the other.
+        let barrier = Arc::new(Barrier::new(2));
+        let barrier_b = barrier.clone();
+        let (a, b) = join_concurrently(
+            move |_| {
+                barrier.wait();
+                Ok::<_, String>(1)
+            },
+            move |_| {
+                barrier_b.wait();
+                Ok("tokenizer")
+            },
+        )
+        .unwrap();
+        assert_eq!((a, b), (1, "tokenizer"));
+    }
+
+    #[test]
+    fn join_concurrently_first_error() {
+        // The model task is stuck in a download that does not check the flag.
+        let start = Instant::now();
+        let (release, download) = std::sync::mpsc::channel::<()>();
+        let (ended_tx, ended) = std::sync::mpsc::channel();
+        let res = join_concurrently(
+            move |cancelled| {
+                let _ = download.recv_timeout(Duration::from_secs(10));
+                let _ = ended_tx.send(cancelled.load(Ordering::Relaxed));
+                Err::<(), _>("cancelled".to_string())
+            },
+            |_| {
+                std::thread::sleep(Duration::from_millis(20));
+                Err::<(), _>("no tokenizer".to_string())
+            },
+        );
+        assert_eq!(res, Err("no tokenizer".to_string()));
+        // The error comes back without waiting for the download.
+        assert!(start.elapsed() < Duration::from_secs(5));
+        release.send(()).unwrap();
+        // Once the download ends, the task sees that it has been cancelled.
+        assert_eq!(ended.recv_timeout(Duration::from_secs(5)), Ok(true));
+    }
+}
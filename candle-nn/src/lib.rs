@@ -29,6 +29,7 @@ pub mod linear;
 pub mod loss;
 pub mod ops;
 pub mod optim;
+pub mod quantized;
 pub mod rnn;
 pub mod rotary_emb;
 pub mod sampling;
@@ -59,3 +60,9 @@ pub use var_builder::VarBuilder;
 pub use var_map::VarMap;
 
 pub use candle::{Module, ModuleT};
+
+/// Registers the custom ops of this crate with [`candle::op_registry`].
+pub fn register_custom_ops() -> candle::Result<()> {
+    candle::op_registry::register_all(&ops::CUSTOM_OPS)?;
+    candle::op_registry::register_all(&rotary_emb::CUSTOM_OPS)
+}
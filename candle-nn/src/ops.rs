@@ -1,9 +1,39 @@
 //! Tensor ops.
 //!
 
+use candle::op_registry::CustomOpDescriptor;
 use candle::{CpuStorage, DType, Layout, Module, Result, Shape, Tensor, D};
 use rayon::prelude::*;
 
+/// The custom ops defined in this module.
+pub const CUSTOM_OPS: [CustomOpDescriptor; 5] = [
+    CustomOpDescriptor {
+        name: "sigmoid",
+        params: "",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "softmax-last-dim",
+        params: "",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "rms-norm",
+        params: "eps: f32",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "layer-norm",
+        params: "eps: f32",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "metal-sdpa",
+        params: "scale: f32, softcapping: f32",
+        source: module_path!(),
+    },
+];
+
 /// Applies the softmax function to the input tensor, rescaling the element so that elements on
 /// a slice of fixed index on dimension `dim` are between 0 and 1 and sum to 1.
 ///
@@ -451,6 +481,10 @@ impl candle::CustomOp2 for RmsNorm {
         "rms-norm"
     }
 
+    fn params(&self) -> String {
+        format!("eps={:e}", self.eps)
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -665,6 +699,10 @@ impl candle::CustomOp3 for LayerNorm {
         "layer-norm"
     }
 
+    fn params(&self) -> String {
+        format!("eps={:e}", self.eps)
+    }
+
     fn cpu_fwd(
         &self,
         s1: &CpuStorage,
@@ -981,6 +1019,10 @@ impl candle::CustomOp3 for Sdpa {
         "metal-sdpa"
     }
 
+    fn params(&self) -> String {
+        format!("scale={}, softcapping={}", self.scale, self.softcapping)
+    }
+
     fn cpu_fwd(
         &self,
         _s1: &CpuStorage,
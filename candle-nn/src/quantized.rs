@@ -0,0 +1,153 @@
+//! Layers using quantized weights
+//!
+//! [`QLinear`] is the quantized counterpart of [`crate::Linear`], `y = x@w.t() + b` where the
+//! weight is a [`QMatMul`] and the optional bias is a regular tensor. The input can come with
+//! or without batch dimensions. The output has the dtype of the input, the quantized matmul runs
+//! in f32 and the bias is converted to the output dtype when needed.
+//!
+//! ```rust
+//! use candle::quantized::{GgmlDType, QTensor};
+//! use candle::{Device::Cpu, Tensor};
+//! use candle_nn::{quantized::QLinear, Module};
+//! # fn main() -> candle::Result<()> {
+//!
+//! let w = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &Cpu)?;
+//! let w = QTensor::quantize(&w, GgmlDType::F32)?;
+//! let b = Tensor::new(&[1f32, 1., 1.], &Cpu)?;
+//! let layer = QLinear::new(w, Some(b))?;
+//! let xs = Tensor::new(&[[10f32, 100.]], &Cpu)?;
+//! let ys = layer.forward(&xs)?;
+//! assert_eq!(ys.to_vec2::<f32>()?, &[[211.0, 431.0, 651.0]]);
+//! # Ok(()) }
+//! ```
+//!
+//! [`QLoraLinear`] adds a trainable low-rank adapter on top of a frozen [`QLinear`] for LoRA
+//! fine-tuning. Gradients flow through the quantized matmul to its input, the quantized weight
+//! itself never gets a gradient.
+use crate::{Init, Linear, VarBuilder};
+use candle::quantized::{QMatMul, QTensor};
+use candle::{DType, Result, Tensor};
+use std::sync::Arc;
+
+#[derive(Clone, Debug)]
+pub struct QLinear {
+    weight: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl QLinear {
+    /// Creates the layer from a weight of shape `(out_dim, in_dim)`.
+    pub fn new(weight: QTensor, bias: Option<Tensor>) -> Result<Self> {
+        Self::from_arc(Arc::new(weight), bias)
+    }
+
+    pub fn from_arc(weight: Arc<QTensor>, bias: Option<Tensor>) -> Result<Self> {
+        let weight = QMatMul::from_arc(weight)?;
+        Ok(Self::from_qmatmul(weight, bias))
+    }
+
+    pub fn from_qmatmul(weight: QMatMul, bias: Option<Tensor>) -> Self {
+        Self { weight, bias }
+    }
+
+    pub fn weight(&self) -> &QMatMul {
+        &self.weight
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+
+    /// The `(out_dim, in_dim)` dimensions of the weight.
+    pub fn dims(&self) -> Result<(usize, usize)> {
+        match &self.weight {
+            QMatMul::QTensor(w) => w.shape().dims2(),
+            QMatMul::Tensor(w) | QMatMul::TensorF16(w) => w.dims2(),
+        }
+    }
+}
+
+impl super::Module for QLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        if x.rank() == 1 {
+            return self.forward(&x.unsqueeze(0)?)?.squeeze(0);
+        }
+        let x = match &self.weight {
+            // The quantized matmul only supports contiguous f32 inputs.
+            QMatMul::QTensor(_) => {
+                let in_dtype = x.dtype();
+                let x = x.to_dtype(DType::F32)?.contiguous()?;
+                x.apply(&self.weight)?.to_dtype(in_dtype)?
+            }
+            QMatMul::Tensor(_) | QMatMul::TensorF16(_) => x.apply(&self.weight)?,
+        };
+        match &self.bias {
+            None => Ok(x),
+            Some(bias) if bias.dtype() == x.dtype() => x.broadcast_add(bias),
+            Some(bias) => x.broadcast_add(&bias.to_dtype(x.dtype())?),
+        }
+    }
+}
+
+/// A frozen [`QLinear`] layer with a trainable low-rank adapter, `y = base(x) + s * x@a.t()@b.t()`
+/// where `a` has shape `(rank, in_dim)`, `b` has shape `(out_dim, rank)` and `s = alpha / rank`.
+/// `b` is initialized to zero so the layer starts out matching the base layer.
+#[derive(Clone, Debug)]
+pub struct QLoraLinear {
+    base: QLinear,
+    lora_a: Linear,
+    lora_b: Linear,
+    scale: f64,
+}
+
+impl QLoraLinear {
+    /// Creates the adapter variables `lora_a` and `lora_b` in `vb`, use a [`crate::VarMap`] backed
+    /// builder to train them.
+    pub fn new(base: QLinear, rank: usize, alpha: f64, vb: VarBuilder) -> Result<Self> {
+        if rank == 0 {
+            candle::bail!("the rank of a lora adapter must be positive")
+        }
+        let (out_dim, in_dim) = base.dims()?;
+        let a = vb.get_with_hints(
+            (rank, in_dim),
+            "lora_a",
+            crate::init::DEFAULT_KAIMING_NORMAL,
+        )?;
+        let b = vb.get_with_hints((out_dim, rank), "lora_b", Init::Const(0.))?;
+        Ok(Self::from_weights(base, a, b, alpha / rank as f64))
+    }
+
+    pub fn from_weights(base: QLinear, lora_a: Tensor, lora_b: Tensor, scale: f64) -> Self {
+        Self {
+            base,
+            lora_a: Linear::new(lora_a, None),
+            lora_b: Linear::new(lora_b, None),
+            scale,
+        }
+    }
+
+    pub fn base(&self) -> &QLinear {
+        &self.base
+    }
+
+    pub fn lora_a(&self) -> &Tensor {
+        self.lora_a.weight()
+    }
+
+    pub fn lora_b(&self) -> &Tensor {
+        self.lora_b.weight()
+    }
+}
+
+impl super::Module for QLoraLinear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        if x.rank() == 1 {
+            return self.forward(&x.unsqueeze(0)?)?.squeeze(0);
+        }
+        let ys = self.base.forward(x)?;
+        let adapter_x = x.to_dtype(self.lora_a.weight().dtype())?;
+        let delta = (adapter_x.apply(&self.lora_a)?.apply(&self.lora_b)? * self.scale)?;
+        let delta = delta.to_dtype(ys.dtype())?;
+        ys + delta
+    }
+}
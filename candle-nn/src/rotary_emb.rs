@@ -1,8 +1,28 @@
 //! Rotary Embeddings
 //!
+use candle::op_registry::CustomOpDescriptor;
 use candle::{CpuStorage, Layout, Result, Shape, Tensor, D};
 use rayon::prelude::*;
 
+/// The custom ops defined in this module.
+pub const CUSTOM_OPS: [CustomOpDescriptor; 3] = [
+    CustomOpDescriptor {
+        name: "rotary-emb-int",
+        params: "",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "rotary-emb",
+        params: "",
+        source: module_path!(),
+    },
+    CustomOpDescriptor {
+        name: "rotary-emb-thd",
+        params: "",
+        source: module_path!(),
+    },
+];
+
 /// Interleaved variant of rotary embeddings.
 /// The x0 and x1 value are interleaved on the n_embd (= head_dim) dimension.
 /// The resulting y0 and y1 are also interleaved with:
@@ -559,7 +579,7 @@ struct RotaryEmbThd;
 
 impl candle::CustomOp3 for RotaryEmbThd {
     fn name(&self) -> &'static str {
-        "rotary-emb"
+        "rotary-emb-thd"
     }
 
     fn cpu_fwd(
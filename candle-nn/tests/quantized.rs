@@ -0,0 +1,202 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::quantized::{GgmlDType, QMatMul, QTensor};
+use candle::{DType, Device, Tensor, Var};
+use candle_nn::quantized::{QLinear, QLoraLinear};
+use candle_nn::{Module, Optimizer, VarBuilder, VarMap};
+use std::sync::Arc;
+
+fn weight(dtype: GgmlDType) -> Result<QTensor> {
+    let w = Tensor::arange(0f32, 8. * 64., &Device::Cpu)?;
+    let w = ((w.reshape((8, 64))? / 100.)?.sin()? * 0.5)?;
+    Ok(QTensor::quantize(&w, dtype)?)
+}
+
+fn input(shape: &[usize]) -> Result<Tensor> {
+    let elem_count = shape.iter().product::<usize>();
+    let xs = Tensor::arange(0f32, elem_count as f32, &Device::Cpu)?;
+    Ok((xs.reshape(shape)? / 10.)?.cos()?)
+}
+
+#[test]
+fn qlinear_matches_qmatmul() -> Result<()> {
+    let bias = Tensor::arange(0f32, 8., &Device::Cpu)?;
+    for dtype in [
+        GgmlDType::Q4_0,
+        GgmlDType::Q8_0,
+        GgmlDType::F16,
+        GgmlDType::F32,
+    ] {
+        let layer = QLinear::new(weight(dtype)?, Some(bias.clone()))?;
+        let matmul = QMatMul::from_qtensor(weight(dtype)?)?;
+        for shape in [&[3, 64][..], &[2, 3, 64], &[2, 2, 3, 64]] {
+            let xs = input(shape)?;
+            let ys = layer.forward(&xs)?;
+            let mut out_shape = shape.to_vec();
+            *out_shape.last_mut().unwrap() = 8;
+            assert_eq!(ys.dims(), out_shape);
+            let expected = matmul.forward(&xs)?.broadcast_add(&bias)?;
+            let diff = (ys - expected)?.abs()?.flatten_all()?.max(0)?;
+            assert_eq!(diff.to_scalar::<f32>()?, 0., "{dtype:?} {shape:?}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn qlinear_bias() -> Result<()> {
+    let layer = QLinear::new(weight(GgmlDType::Q8_0)?, None)?;
+    let with_bias = QLinear::new(
+        weight(GgmlDType::Q8_0)?,
+        Some(Tensor::ones(8, DType::F32, &Device::Cpu)?),
+    )?;
+    assert!(layer.bias().is_none());
+    let xs = input(&[2, 3, 64])?;
+    let ys = (layer.forward(&xs)? + 1.)?;
+    assert_eq!(
+        with_bias.forward(&xs)?.to_vec3::<f32>()?,
+        ys.to_vec3::<f32>()?
+    );
+    // Inputs without a batch dimension.
+    let xs = input(&[64])?;
+    let ys = with_bias.forward(&xs)?;
+    assert_eq!(ys.dims(), [8]);
+    assert_eq!(
+        ys.to_vec1::<f32>()?,
+        with_bias
+            .forward(&xs.unsqueeze(0)?)?
+            .squeeze(0)?
+            .to_vec1::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn qlinear_dtypes() -> Result<()> {
+    let bias = Tensor::arange(0f32, 8., &Device::Cpu)?;
+    let xs = input(&[2, 3, 64])?;
+    for dtype in [GgmlDType::Q4_1, GgmlDType::F16, GgmlDType::F32] {
+        let layer = QLinear::new(weight(dtype)?, Some(bias.clone()))?;
+        let expected = layer.forward(&xs)?;
+        // The output has the input dtype, whatever the dtypes of the weight and the bias.
+        for (x_dtype, b_dtype) in [
+            (DType::F16, DType::F32),
+            (DType::BF16, DType::F32),
+            (DType::F32, DType::F16),
+            (DType::F16, DType::F16),
+        ] {
+            let layer = QLinear::new(weight(dtype)?, Some(bias.to_dtype(b_dtype)?))?;
+            let ys = layer.forward(&xs.to_dtype(x_dtype)?)?;
+            assert_eq!(ys.dtype(), x_dtype);
+            let diff = (ys.to_dtype(DType::F32)? - &expected)?.abs()?;
+            let diff = diff.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+            assert!(diff < 0.1, "{dtype:?} {x_dtype:?} {b_dtype:?}: {diff}");
+        }
+    }
+    Ok(())
+}
+
+fn vector(len: usize, offset: f32) -> Result<Tensor> {
+    let xs = Tensor::arange(0f32, len as f32, &Device::Cpu)?;
+    Ok(((xs * 0.37)? + offset as f64)?.sin()?)
+}
+
+// A quantized layer with a lora adapter followed by a frozen quantized layer, the gradient of
+// the adapter goes through the quantized matmul of the second layer.
+fn lora_loss(
+    base: &QLinear,
+    head: &QLinear,
+    a: &Tensor,
+    b: &Tensor,
+    xs: &Tensor,
+) -> Result<Tensor> {
+    let layer = QLoraLinear::from_weights(base.clone(), a.clone(), b.clone(), 0.5);
+    let ys = layer.forward(xs)?.tanh()?.apply(head)?;
+    Ok(ys.sqr()?.sum_all()?)
+}
+
+#[test]
+fn qlora_gradient_check() -> Result<()> {
+    // Use f32 weights so that the quantized matmul is exact and the loss smooth, the weights
+    // are kept as quantized tensors rather than being dequantized.
+    let qlinear = |out_dim: usize, in_dim: usize, offset: f32| -> Result<QLinear> {
+        let w = (vector(out_dim * in_dim, offset)?.reshape((out_dim, in_dim))? * 0.3)?;
+        let w = QTensor::quantize(&w, GgmlDType::F32)?;
+        Ok(QLinear::from_qmatmul(QMatMul::QTensor(Arc::new(w)), None))
+    };
+    let base = qlinear(16, 32, 0.)?;
+    let head = qlinear(8, 16, 1.)?;
+    let xs = vector(3 * 32, 2.)?.reshape((3, 32))?;
+    let a = Var::from_tensor(&(vector(4 * 32, 3.)?.reshape((4, 32))? * 0.2)?)?;
+    let b = Var::from_tensor(&(vector(16 * 4, 4.)?.reshape((16, 4))? * 0.2)?)?;
+    let grads = lora_loss(&base, &head, &a, &b, &xs)?.backward()?;
+    for var in [&a, &b] {
+        let grad = grads.get(var).unwrap().flatten_all()?.to_vec1::<f32>()?;
+        let values = var.flatten_all()?.to_vec1::<f32>()?;
+        for (i, grad) in grad.iter().enumerate() {
+            let eps = 1e-2;
+            let loss = |delta: f32| -> Result<f32> {
+                let mut values = values.clone();
+                values[i] += delta;
+                let v = Tensor::from_vec(values, var.shape(), &Device::Cpu)?;
+                let (a, b) = if std::ptr::eq(var, &a) {
+                    (&v, b.as_tensor())
+                } else {
+                    (a.as_tensor(), &v)
+                };
+                Ok(lora_loss(&base, &head, a, b, &xs)?.to_scalar::<f32>()?)
+            };
+            let numerical = (loss(eps)? - loss(-eps)?) / (2. * eps);
+            let tol = 1e-2 * (1. + numerical.abs());
+            assert!((grad - numerical).abs() < tol, "{i} {grad} {numerical}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn qlora_fine_tune() -> Result<()> {
+    let dev = &Device::Cpu;
+    let qlinear = |out_dim: usize, in_dim: usize, offset: f32, dtype| -> Result<QLinear> {
+        let w = (vector(out_dim * in_dim, offset)?.reshape((out_dim, in_dim))? * 0.3)?;
+        Ok(QLinear::new(QTensor::quantize(&w, dtype)?, None)?)
+    };
+    let base = qlinear(32, 64, 0., GgmlDType::Q4_0)?;
+    let head = qlinear(4, 32, 5., GgmlDType::Q8_0)?;
+    let xs = input(&[16, 64])?;
+    // The targets come from an adapter of the same shape that we try to recover.
+    let target_a = (vector(2 * 64, 6.)?.reshape((2, 64))? * 0.1)?;
+    let target_b = (vector(32 * 2, 7.)?.reshape((32, 2))? * 0.5)?;
+    let target = QLoraLinear::from_weights(base.clone(), target_a, target_b, 1.);
+    let target = target.forward(&xs)?.tanh()?.apply(&head)?;
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let layer = QLoraLinear::new(base, 2, 2., vb.pp("lora"))?;
+    // Only the adapter is trainable.
+    assert_eq!(varmap.all_vars().len(), 2);
+    let params = candle_nn::ParamsAdamW {
+        lr: 0.02,
+        ..Default::default()
+    };
+    let mut opt = candle_nn::AdamW::new(varmap.all_vars(), params)?;
+    let loss = |layer: &QLoraLinear| -> Result<Tensor> {
+        let ys = layer.forward(&xs)?.tanh()?.apply(&head)?;
+        Ok(candle_nn::loss::mse(&ys, &target)?)
+    };
+    let initial_loss = loss(&layer)?.to_scalar::<f32>()?;
+    for _ in 0..100 {
+        opt.backward_step(&loss(&layer)?)?;
+    }
+    let final_loss = loss(&layer)?.to_scalar::<f32>()?;
+    assert!(
+        final_loss < initial_loss / 10.,
+        "{initial_loss} {final_loss}"
+    );
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Generates the tiny llama fixture used by the integration tests, together with its tokenizer
+//! and the golden outputs.
+//!
+//! The model has 2 layers, a hidden size of 64, and a vocabulary of 256 tokens. Its weights are
+//! ternary, i.e. in `{-s, 0, s}` with one scale `s` per tensor, and stored as q8_0 which
+//! represents them exactly. The tokenizer is `tests/fixtures/tiny-llama-tokenizer.json`, a word
+//! level tokenizer with a few words and chat tokens, padded to the model vocabulary.
+//!
+//! The fixture is deterministic, regenerate the goldens only when a change to the model code is
+//! expected to change the outputs:
+//!
+//! ```bash
+//! cargo run -p candle-transformers --example tiny_llama_fixture
+//! ```
+use anyhow::Result;
+use candle::Device;
+use candle_transformers::generation::{LogitsProcessor, Sampling, StopConditions, TextGeneration};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_transformers::test_support::{tiny_test_gguf, VOCAB_SIZE};
+use std::path::Path;
+
+const SEED: u64 = 20240607;
+const SAMPLE_LEN: usize = 16;
+
+const PROMPT: &str = "the cat sat on the mat";
+const CHAT_PROMPT: &str = "<|im_start|> user hello world <|im_end|> <|im_start|> assistant";
+
+// The fixture tokenizer with its vocabulary padded to the model vocabulary.
+fn tokenizer(fixtures: &Path) -> Result<serde_json::Value> {
+    let tokenizer = std::fs::read_to_string(fixtures.join("tiny-llama-tokenizer.json"))?;
+    let mut tokenizer: serde_json::Value = serde_json::from_str(&tokenizer)?;
+    let vocab = tokenizer["model"]["vocab"]
+        .as_object_mut()
+        .ok_or_else(|| anyhow::anyhow!("no vocab in the fixture tokenizer"))?;
+    for id in vocab.len()..VOCAB_SIZE {
+        vocab.insert(format!("tok{id}"), id.into());
+    }
+    Ok(tokenizer)
+}
+
+fn greedy(model: ModelWeights, prompt: &[u32]) -> Result<Vec<u32>> {
+    let device = Device::Cpu;
+    let logits_processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    let mut generation = TextGeneration::new(model, logits_processor, &device);
+    generation.push_prompt(prompt)?;
+    let tokens = generation.generate(SAMPLE_LEN, &StopConditions::default(), |_| Ok(()))?;
+    Ok(tokens)
+}
+
+fn main() -> Result<()> {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let gguf = tiny_test_gguf(SEED)?;
+    let tokenizer = tokenizer(&fixtures)?;
+    let tokenizer_json = serde_json::to_string_pretty(&tokenizer)?;
+    let tok =
+        tokenizers::Tokenizer::from_bytes(tokenizer_json.as_bytes()).map_err(anyhow::Error::msg)?;
+
+    let mut goldens = serde_json::Map::new();
+    for (name, prompt) in [("prompt", PROMPT), ("chat", CHAT_PROMPT)] {
+        let prompt_tokens = tok
+            .encode(prompt, true)
+            .map_err(anyhow::Error::msg)?
+            .get_ids()
+            .to_vec();
+        let model = ModelWeights::from_gguf_bytes(&gguf, &Device::Cpu)?;
+        let tokens = greedy(model, &prompt_tokens)?;
+        goldens.insert(
+            name.to_string(),
+            serde_json::json!({
+                "text": prompt,
+                "prompt_tokens": prompt_tokens,
+                "tokens": tokens,
+            }),
+        );
+    }
+
+    std::fs::write(fixtures.join("tiny-llama.gguf"), &gguf)?;
+    std::fs::write(
+        fixtures.join("tiny-llama-tokenizer.json"),
+        tokenizer_json + "\n",
+    )?;
+    let goldens = serde_json::to_string_pretty(&goldens)?;
+    std::fs::write(fixtures.join("tiny-llama-goldens.json"), goldens + "\n")?;
+    println!("wrote the fixtures to {}", fixtures.display());
+    Ok(())
+}
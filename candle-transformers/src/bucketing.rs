@@ -0,0 +1,144 @@
+//! Length bucketing for batched forward passes.
+//!
+//! A batch is padded to its longest sequence, the compute spent on the padding positions is
+//! wasted. [`plan_buckets`] sorts the sequences by length and groups them so that the padding of
+//! each bucket stays below a ratio of its padded size. [`pad_batch`] builds the right padded
+//! `(batch, seq_len)` input of a bucket along with the valid length of each row, which the
+//! padded forward passes of the models use to mask the padding, e.g. `forward_padded` in
+//! `quantized_llama`. [`unpad`] and [`last_positions`] split the outputs back per sequence.
+use candle::{Device, Result, Tensor};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BucketConfig {
+    /// The largest share of padding positions in a bucket, between 0 and 1.
+    pub max_pad_ratio: f64,
+    /// The largest number of sequences in a bucket.
+    pub max_batch_size: usize,
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            max_pad_ratio: 0.25,
+            max_batch_size: 8,
+        }
+    }
+}
+
+/// Sequences processed together, see [`plan_buckets`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bucket {
+    /// The indexes of the sequences, from the longest to the shortest.
+    pub items: Vec<usize>,
+    /// The length of the longest sequence, all of them are padded to it.
+    pub seq_len: usize,
+    /// The number of padding positions.
+    pub padding: usize,
+}
+
+impl Bucket {
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    /// The share of the padded positions that are padding.
+    pub fn pad_ratio(&self) -> f64 {
+        match self.items.len() * self.seq_len {
+            0 => 0.,
+            size => self.padding as f64 / size as f64,
+        }
+    }
+}
+
+/// Groups the sequences of the given lengths into buckets whose padding ratio is at most
+/// `config.max_pad_ratio`, the buckets go from the longest sequences to the shortest ones.
+///
+/// Each bucket starts with the longest remaining sequence and takes the next ones by decreasing
+/// length while the ratio holds. A sequence that does not fit would only leave more padding if
+/// a shorter one was taken in its place, so it starts the next bucket.
+pub fn plan_buckets(lengths: &[usize], config: &BucketConfig) -> Vec<Bucket> {
+    let mut order = (0..lengths.len()).collect::<Vec<_>>();
+    // Stable so that sequences of the same length keep their order.
+    order.sort_by_key(|&i| std::cmp::Reverse(lengths[i]));
+    let max_batch_size = config.max_batch_size.max(1);
+    let mut buckets: Vec<Bucket> = vec![];
+    for index in order {
+        let len = lengths[index];
+        let fits = buckets.last().is_some_and(|bucket| {
+            let padding = bucket.padding + bucket.seq_len - len;
+            let size = (bucket.len() + 1) * bucket.seq_len;
+            bucket.len() < max_batch_size && padding as f64 <= config.max_pad_ratio * size as f64
+        });
+        match buckets.last_mut() {
+            Some(bucket) if fits => {
+                bucket.padding += bucket.seq_len - len;
+                bucket.items.push(index)
+            }
+            _ => buckets.push(Bucket {
+                items: vec![index],
+                seq_len: len,
+                padding: 0,
+            }),
+        }
+    }
+    buckets
+}
+
+/// Stacks the sequences into a `(batch, seq_len)` tensor, padded on the right with `pad_id` to
+/// the longest one, and returns it with the length of each sequence.
+pub fn pad_batch(
+    sequences: &[&[u32]],
+    pad_id: u32,
+    device: &Device,
+) -> Result<(Tensor, Vec<usize>)> {
+    let lengths = sequences.iter().map(|s| s.len()).collect::<Vec<_>>();
+    let seq_len = lengths.iter().copied().max().unwrap_or(0);
+    if seq_len == 0 || lengths.contains(&0) {
+        candle::bail!("cannot pad a batch with empty sequences, lengths {lengths:?}")
+    }
+    let mut data = Vec::with_capacity(sequences.len() * seq_len);
+    for sequence in sequences.iter() {
+        data.extend_from_slice(sequence);
+        data.resize(data.len() + seq_len - sequence.len(), pad_id)
+    }
+    let xs = Tensor::from_vec(data, (sequences.len(), seq_len), device)?;
+    Ok((xs, lengths))
+}
+
+/// Splits a `(batch, seq_len, ...)` output of a padded batch into one `(length, ...)` tensor per
+/// sequence.
+pub fn unpad(xs: &Tensor, lengths: &[usize]) -> Result<Vec<Tensor>> {
+    check_lengths(xs, lengths)?;
+    lengths
+        .iter()
+        .enumerate()
+        .map(|(row, &len)| xs.get(row)?.narrow(0, 0, len))
+        .collect()
+}
+
+/// The values at the last valid position of each sequence of a `(batch, seq_len, ...)` output,
+/// with shape `(batch, ...)`.
+pub fn last_positions(xs: &Tensor, lengths: &[usize]) -> Result<Tensor> {
+    check_lengths(xs, lengths)?;
+    let rows = lengths
+        .iter()
+        .enumerate()
+        .map(|(row, &len)| xs.get(row)?.get(len - 1))
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::stack(&rows, 0)
+}
+
+pub(crate) fn check_lengths(xs: &Tensor, lengths: &[usize]) -> Result<()> {
+    let (b_sz, seq_len) = (xs.dim(0)?, xs.dim(1)?);
+    if lengths.len() != b_sz {
+        candle::bail!("{} lengths for a batch of {b_sz}", lengths.len())
+    }
+    if let Some(len) = lengths.iter().find(|&&len| len == 0 || len > seq_len) {
+        candle::bail!("invalid length {len} for sequences of {seq_len} positions")
+    }
+    Ok(())
+}
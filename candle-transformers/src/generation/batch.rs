@@ -0,0 +1,301 @@
+//! Line oriented batch generation.
+//!
+//! Each input line is a json object with a `prompt` field and optional `id` and `max_tokens`
+//! fields. For each non-empty input line, a json line is written to the output with the input
+//! line number, the id, and either a `result` or an `error` object. A failing item does not stop
+//! the run, and out of memory errors are retried once with a reduced `max_tokens` after the
+//! generator has cleared its caches.
+//!
+//! [`run_batch_bucketed`] reads all the requests first and generates the prompts of similar
+//! lengths together, see [`crate::bucketing`], the output stays in the order of the input.
+use crate::bucketing::{plan_buckets, BucketConfig};
+use candle::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchRequest {
+    #[serde(default)]
+    pub id: Option<String>,
+    pub prompt: String,
+    #[serde(default)]
+    pub max_tokens: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchErrorKind {
+    /// The input line is not a valid request.
+    InvalidRequest,
+    TokenizationError,
+    /// The prompt and the requested tokens do not fit in the model context.
+    ContextOverflow,
+    Oom,
+    ModelError,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchError {
+    pub kind: BatchErrorKind,
+    pub message: String,
+    /// Whether submitting the same request again may succeed.
+    pub retriable: bool,
+}
+
+impl BatchError {
+    pub fn new(kind: BatchErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+            retriable: kind == BatchErrorKind::Oom,
+        }
+    }
+
+    /// Classifies an error returned by the model, allocation failures are reported as
+    /// [`BatchErrorKind::Oom`].
+    pub fn from_model_error(err: &Error) -> Self {
+        let message = err.to_string();
+        let lower = message.to_lowercase();
+        let kind = if lower.contains("out of memory") || lower.contains("out_of_memory") {
+            BatchErrorKind::Oom
+        } else {
+            BatchErrorKind::ModelError
+        };
+        Self::new(kind, message)
+    }
+}
+
+impl std::fmt::Display for BatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub text: String,
+    pub generated_tokens: usize,
+    /// The token limit used for this generation, lower than the requested one after an out of
+    /// memory retry.
+    pub max_tokens: usize,
+}
+
+/// One line of the batch output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BatchOutput {
+    pub line: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<BatchResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+pub trait BatchGenerator {
+    fn generate(
+        &mut self,
+        prompt: &str,
+        max_tokens: usize,
+    ) -> std::result::Result<BatchResult, BatchError>;
+
+    /// Releases the caches held by the generator, called after an out of memory error.
+    fn clear_cache(&mut self);
+
+    /// The number of tokens of `prompt`, used by [`run_batch_bucketed`] to group the prompts by
+    /// length. `None` generates the prompt on its own, e.g. when it cannot be tokenized.
+    fn prompt_len(&mut self, _prompt: &str) -> Option<usize> {
+        None
+    }
+
+    /// Generates prompts of similar lengths together, each of them with its own `max_tokens`,
+    /// and returns the results in the same order. This generates them one by one by default.
+    fn generate_bucket(
+        &mut self,
+        requests: &[(&str, usize)],
+    ) -> Vec<std::result::Result<BatchResult, BatchError>> {
+        requests
+            .iter()
+            .map(|&(prompt, max_tokens)| self.generate(prompt, max_tokens))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+impl BatchSummary {
+    /// The process exit code for this run, non-zero if any item failed.
+    pub fn exit_code(&self) -> i32 {
+        if self.failed == 0 {
+            0
+        } else {
+            1
+        }
+    }
+}
+
+fn generate_item<G: BatchGenerator>(
+    generator: &mut G,
+    request: &BatchRequest,
+    max_tokens: usize,
+) -> std::result::Result<BatchResult, BatchError> {
+    match generator.generate(&request.prompt, max_tokens) {
+        Err(err) if err.kind == BatchErrorKind::Oom => {
+            generator.clear_cache();
+            generator.generate(&request.prompt, (max_tokens / 2).max(1))
+        }
+        res => res,
+    }
+}
+
+fn parse_line(line: &str) -> std::result::Result<BatchRequest, BatchError> {
+    serde_json::from_str::<BatchRequest>(line)
+        .map_err(|err| BatchError::new(BatchErrorKind::InvalidRequest, err))
+}
+
+fn write_output<W: Write>(
+    output: &mut W,
+    summary: &mut BatchSummary,
+    line: usize,
+    id: Option<String>,
+    outcome: std::result::Result<BatchResult, BatchError>,
+) -> Result<()> {
+    let (result, error) = match outcome {
+        Ok(result) => {
+            summary.succeeded += 1;
+            (Some(result), None)
+        }
+        Err(err) => {
+            summary.failed += 1;
+            (None, Some(err))
+        }
+    };
+    let out = BatchOutput {
+        line,
+        id,
+        result,
+        error,
+    };
+    serde_json::to_writer(&mut *output, &out).map_err(Error::wrap)?;
+    writeln!(output)?;
+    Ok(())
+}
+
+/// Runs all the requests from `input`, writing one output line per request to `output`.
+/// Only io errors on the output abort the run.
+pub fn run_batch<G: BatchGenerator, R: BufRead, W: Write>(
+    generator: &mut G,
+    input: R,
+    mut output: W,
+    default_max_tokens: usize,
+) -> Result<BatchSummary> {
+    let mut summary = BatchSummary::default();
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, outcome) = match parse_line(&line) {
+            Err(err) => (None, Err(err)),
+            Ok(request) => {
+                let max_tokens = request.max_tokens.unwrap_or(default_max_tokens);
+                let outcome = generate_item(generator, &request, max_tokens);
+                (request.id, outcome)
+            }
+        };
+        write_output(&mut output, &mut summary, index + 1, id, outcome)?;
+    }
+    output.flush()?;
+    Ok(summary)
+}
+
+/// Like [`run_batch`] with the prompts grouped in buckets of similar lengths, each bucket is
+/// passed to [`BatchGenerator::generate_bucket`]. The items of a bucket that run out of memory
+/// are generated again one by one with the usual retry. The output is written once all the
+/// requests have been generated, in the order of the input.
+pub fn run_batch_bucketed<G: BatchGenerator, R: BufRead, W: Write>(
+    generator: &mut G,
+    input: R,
+    mut output: W,
+    default_max_tokens: usize,
+    config: &BucketConfig,
+) -> Result<BatchSummary> {
+    let mut items = vec![];
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            items.push((index + 1, parse_line(&line)));
+        }
+    }
+    let mut outcomes = items.iter().map(|_| None).collect::<Vec<_>>();
+    let mut bucketed = vec![];
+    let mut lengths = vec![];
+    for (item, (_, request)) in items.iter().enumerate() {
+        match request {
+            Err(err) => outcomes[item] = Some(Err(err.clone())),
+            Ok(request) => match generator.prompt_len(&request.prompt) {
+                Some(len) => {
+                    bucketed.push(item);
+                    lengths.push(len)
+                }
+                None => {
+                    let max_tokens = request.max_tokens.unwrap_or(default_max_tokens);
+                    outcomes[item] = Some(generate_item(generator, request, max_tokens))
+                }
+            },
+        }
+    }
+    let request = |item: usize| match &items[item].1 {
+        Ok(request) => request,
+        Err(_) => unreachable!("only the valid requests are bucketed"),
+    };
+    for bucket in plan_buckets(&lengths, config) {
+        let bucket = bucket
+            .items
+            .iter()
+            .map(|&i| bucketed[i])
+            .collect::<Vec<_>>();
+        let requests = bucket
+            .iter()
+            .map(|&item| {
+                let request = request(item);
+                let max_tokens = request.max_tokens.unwrap_or(default_max_tokens);
+                (request.prompt.as_str(), max_tokens)
+            })
+            .collect::<Vec<_>>();
+        let results = generator.generate_bucket(&requests);
+        if results.len() != bucket.len() {
+            candle::bail!(
+                "generate_bucket returned {} results for {} prompts",
+                results.len(),
+                bucket.len()
+            )
+        }
+        let mut cleared = false;
+        for ((&item, &(_, max_tokens)), result) in bucket.iter().zip(requests.iter()).zip(results) {
+            let outcome = match result {
+                Err(err) if err.kind == BatchErrorKind::Oom => {
+                    if !std::mem::replace(&mut cleared, true) {
+                        generator.clear_cache()
+                    }
+                    generate_item(generator, request(item), max_tokens)
+                }
+                result => result,
+            };
+            outcomes[item] = Some(outcome)
+        }
+    }
+    let mut summary = BatchSummary::default();
+    for ((line, request), outcome) in items.into_iter().zip(outcomes) {
+        let id = request.ok().and_then(|request| request.id);
+        let outcome = outcome.unwrap_or_else(|| unreachable!("all the requests are generated"));
+        write_output(&mut output, &mut summary, line, id, outcome)?;
+    }
+    output.flush()?;
+    Ok(summary)
+}
@@ -0,0 +1,135 @@
+//! Best-of-n generation.
+//!
+//! [`generate_best_of`] samples `n` completions of a prompt with [`super::generate_forked`] and
+//! keeps the one with the highest length normalized log-probability. The score of a completion
+//! with log-probabilities `lp_1..lp_len` is `sum(lp_i) / len^alpha`: with `alpha = 0` this is
+//! the plain sum, which favors short completions, and with `alpha = 1` this is the mean.
+//!
+//! The log-probabilities are the ones of the tokens that were actually sampled, computed from the
+//! logits of the model before the repeat penalty, the temperature, and the top-k/top-p filters.
+//! The stop token that ends a completion is scored too, so that stopping early is not free.
+use super::{slot::decode_forked, CausalLm, GenerationParams, SamplerSlot};
+use candle::{DType, Device, Result, Tensor};
+
+/// The length normalized score of a completion, `sum(logprobs) / len^alpha`. An empty completion
+/// scores 0.
+pub fn length_normalized_score(logprobs: &[f32], alpha: f64) -> f64 {
+    let sum = logprobs.iter().map(|&lp| lp as f64).sum::<f64>();
+    let len = logprobs.len().max(1) as f64;
+    sum / len.powf(alpha)
+}
+
+/// One of the sampled completions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// The generated tokens, the stop token is not included.
+    pub tokens: Vec<u32>,
+    /// The log-probability of each sampled token, this includes the stop token when the
+    /// completion ended with one so there can be one more entry than in `tokens`.
+    pub logprobs: Vec<f32>,
+    pub score: f64,
+}
+
+impl Candidate {
+    pub fn new(tokens: Vec<u32>, logprobs: Vec<f32>, alpha: f64) -> Self {
+        let score = length_normalized_score(&logprobs, alpha);
+        Self {
+            tokens,
+            logprobs,
+            score,
+        }
+    }
+}
+
+/// All the candidates of a best-of-n generation, in sampling order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestOf {
+    pub candidates: Vec<Candidate>,
+    /// The index of the candidate with the highest score, the first one on ties.
+    pub best: usize,
+}
+
+impl BestOf {
+    pub fn new(candidates: Vec<Candidate>) -> Result<Self> {
+        let mut best: Option<usize> = None;
+        for (index, candidate) in candidates.iter().enumerate() {
+            match best {
+                Some(b) if candidates[b].score.total_cmp(&candidate.score).is_ge() => {}
+                _ => best = Some(index),
+            }
+        }
+        match best {
+            None => candle::bail!("no candidates to select from"),
+            Some(best) => Ok(Self { candidates, best }),
+        }
+    }
+
+    pub fn best(&self) -> &Candidate {
+        &self.candidates[self.best]
+    }
+}
+
+/// The log-probability of `token` under `logits`, a 1d tensor over the vocabulary.
+pub fn token_logprob(logits: &Tensor, token: u32) -> Result<f32> {
+    let logits = logits.to_dtype(DType::F32)?;
+    let logprobs = candle_nn::ops::log_softmax(&logits, candle::D::Minus1)?;
+    logprobs.get(token as usize)?.to_scalar::<f32>()
+}
+
+/// Samples `n` completions of `prompt` and selects the one with the best length normalized
+/// score, see the module documentation. The completions are decoded together from a forked kv
+/// cache, candidate `i` is sampled with the seed `params.seed + i`. `params.max_tokens` bounds the
+/// length of each completion and `added_tokens` is used to resolve the stop token patterns, see
+/// [`SamplerSlot::from_params`].
+///
+/// The `n` and `alpha` of a request are usually [`GenerationParams::best_of`] and
+/// [`GenerationParams::length_penalty`].
+pub fn generate_best_of<'a, M, I>(
+    model: &mut M,
+    prompt: &[u32],
+    params: &GenerationParams,
+    n: usize,
+    alpha: f64,
+    added_tokens: I,
+    device: &Device,
+) -> Result<BestOf>
+where
+    M: CausalLm,
+    I: IntoIterator<Item = (u32, &'a str)>,
+{
+    if n == 0 {
+        candle::bail!("best-of-n requires at least one candidate")
+    }
+    if !alpha.is_finite() {
+        candle::bail!("length_penalty must be finite, got {alpha}")
+    }
+    let slot = SamplerSlot::from_params(params, added_tokens)?;
+    let mut slots = (0..n as u64)
+        .map(|i| {
+            let params = params.clone().with_seed(params.seed.wrapping_add(i));
+            SamplerSlot::new(
+                params.logits_processor(),
+                slot.penalty().clone(),
+                slot.stop_conditions().clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut logprobs = vec![vec![]; n];
+    let tokens = decode_forked(
+        model,
+        prompt,
+        &mut slots,
+        params.max_tokens,
+        device,
+        |index, logits, token| {
+            logprobs[index].push(token_logprob(logits, token)?);
+            Ok(())
+        },
+    )?;
+    let candidates = tokens
+        .into_iter()
+        .zip(logprobs)
+        .map(|(tokens, logprobs)| Candidate::new(tokens, logprobs, alpha))
+        .collect();
+    BestOf::new(candidates)
+}
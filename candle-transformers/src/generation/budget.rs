@@ -0,0 +1,135 @@
+//! How a prompt and the tokens generated after it fit in the context of a model.
+//!
+//! Each prompt token and each generated token takes one position in the context. A
+//! [`TokenBudget`] keeps `reserved` positions free, e.g. for the suffix of a chat template or as
+//! a safety margin, and [`TokenBudget::plan`] splits the other positions between the prompt and
+//! the new tokens. [`TokenBudget::plan_truncated`] drops the start of the prompt instead when
+//! the requested tokens do not fit after it.
+
+/// The context of a model minus the reserved positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBudget {
+    pub max_context: usize,
+    pub reserved: usize,
+}
+
+/// What fits in a [`TokenBudget`] for a given request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenPlan {
+    /// The number of tokens that can be generated after the whole prompt, at most the requested
+    /// number.
+    pub new_tokens: usize,
+    /// The number of prompt tokens to drop from the start for all the requested tokens to fit,
+    /// the prompt is never dropped entirely so this is less than the prompt length.
+    pub truncate: usize,
+}
+
+/// The error returned when not a single token can be generated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenBudgetError {
+    /// The reserved positions take the whole context.
+    NoContext { max_context: usize, reserved: usize },
+    /// The prompt takes all the available positions, dropping `truncate` tokens from its start
+    /// leaves room for one new token.
+    PromptTooLong {
+        prompt_len: usize,
+        available: usize,
+        truncate: usize,
+    },
+}
+
+impl std::fmt::Display for TokenBudgetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NoContext {
+                max_context,
+                reserved,
+            } => write!(
+                f,
+                "no room left in a context of {max_context} tokens with {reserved} reserved tokens"
+            ),
+            Self::PromptTooLong {
+                prompt_len,
+                available,
+                ..
+            } => write!(
+                f,
+                "the prompt has {prompt_len} tokens, only {available} tokens of the context are available"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TokenBudgetError {}
+
+impl TokenBudget {
+    pub fn new(max_context: usize) -> Self {
+        Self {
+            max_context,
+            reserved: 0,
+        }
+    }
+
+    /// Keeps `reserved` positions of the context free.
+    pub fn with_reserve(self, reserved: usize) -> Self {
+        Self { reserved, ..self }
+    }
+
+    /// The number of positions for the prompt and the new tokens.
+    pub fn available(&self) -> usize {
+        self.max_context.saturating_sub(self.reserved)
+    }
+
+    /// Plans the generation of up to `max_new_tokens` tokens after a prompt of `prompt_len`
+    /// tokens. This fails when no new token fits after the prompt, when `max_new_tokens` is 0
+    /// the prompt alone has to fit.
+    pub fn plan(
+        &self,
+        prompt_len: usize,
+        max_new_tokens: usize,
+    ) -> Result<TokenPlan, TokenBudgetError> {
+        let available = self.available();
+        if available == 0 {
+            return Err(TokenBudgetError::NoContext {
+                max_context: self.max_context,
+                reserved: self.reserved,
+            });
+        }
+        let required = prompt_len + max_new_tokens.min(1);
+        if required > available {
+            return Err(TokenBudgetError::PromptTooLong {
+                prompt_len,
+                available,
+                truncate: required - available,
+            });
+        }
+        let new_tokens = max_new_tokens.min(available - prompt_len);
+        Ok(TokenPlan {
+            new_tokens,
+            truncate: self.truncate(prompt_len, max_new_tokens),
+        })
+    }
+
+    /// Plans the generation after the prompt with its first [`TokenPlan::truncate`] tokens
+    /// dropped so that all the requested tokens fit. `new_tokens` is then counted after the
+    /// truncated prompt, it is only below `max_new_tokens` when a single prompt token is kept.
+    /// This fails with the error of [`TokenBudget::plan`] when not a single new token fits.
+    pub fn plan_truncated(
+        &self,
+        prompt_len: usize,
+        max_new_tokens: usize,
+    ) -> Result<TokenPlan, TokenBudgetError> {
+        let truncate = self.truncate(prompt_len, max_new_tokens);
+        match self.plan(prompt_len - truncate, max_new_tokens) {
+            Ok(plan) => Ok(TokenPlan { truncate, ..plan }),
+            // The whole prompt does not fit either, report it rather than the truncated one.
+            Err(_) => self.plan(prompt_len, max_new_tokens),
+        }
+    }
+
+    fn truncate(&self, prompt_len: usize, max_new_tokens: usize) -> usize {
+        (prompt_len + max_new_tokens)
+            .saturating_sub(self.available())
+            .min(prompt_len.saturating_sub(1))
+    }
+}
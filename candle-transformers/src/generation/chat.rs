@@ -0,0 +1,355 @@
+//! Multi-turn chats that stay within the context of the model.
+//!
+//! A [`ChatSession`] keeps the messages of a chat and renders the whole history with the chat
+//! template of the model before each answer, the tokens shared with the previous rendering stay
+//! in the kv cache. When the rendered history and the answer do not fit in the [`TokenBudget`],
+//! the session asks its [`Summarizer`] to compact the history and renders it again. The system
+//! prompt is never part of what gets compacted.
+use super::compare::first_divergence;
+use super::{CausalLm, StopConditions, TextGeneration, TokenBudget, TurnCheckpoint};
+use candle::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: Role, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+/// The messages of a chat.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatHistory {
+    /// The system prompt, it is kept by the compactions.
+    pub system: Option<String>,
+    /// A summary of the turns dropped so far, rendered as a second system message.
+    pub summary: Option<String>,
+    /// The user and assistant messages, alternating and starting with a user message. The last
+    /// message is a user message while its answer is being generated.
+    pub messages: Vec<ChatMessage>,
+}
+
+impl ChatHistory {
+    pub fn new(system: Option<String>) -> Self {
+        Self {
+            system,
+            ..Default::default()
+        }
+    }
+
+    /// The number of answered turns, i.e. of user and assistant message pairs.
+    pub fn turns(&self) -> usize {
+        self.messages.len() / 2
+    }
+
+    /// Removes the `n` oldest turns, at most the answered ones, and returns their messages.
+    pub fn evict(&mut self, n: usize) -> Vec<ChatMessage> {
+        let n = n.min(self.turns());
+        self.messages.drain(..2 * n).collect()
+    }
+
+    /// The messages to pass to a chat template: the system prompt, the summary, then the turns.
+    pub fn to_messages(&self) -> Vec<ChatMessage> {
+        let system = self
+            .system
+            .iter()
+            .map(|s| ChatMessage::new(Role::System, s));
+        let summary = self.summary.iter().map(|s| {
+            ChatMessage::new(
+                Role::System,
+                format!("Summary of the earlier conversation: {s}"),
+            )
+        });
+        system
+            .chain(summary)
+            .chain(self.messages.iter().cloned())
+            .collect()
+    }
+}
+
+/// The chat template and the tokenizer of a model.
+pub trait ChatFormat {
+    /// Renders the messages with the chat template, the result ends with the header of the
+    /// assistant answer.
+    fn render(&self, messages: &[ChatMessage]) -> String;
+
+    /// Tokenizes a rendered chat, including the special tokens that start a sequence.
+    fn encode(&self, text: &str) -> Result<Vec<u32>>;
+
+    fn decode(&self, tokens: &[u32]) -> Result<String>;
+}
+
+/// Generates the answer to some messages with at most the given number of tokens, this is how a
+/// [`Summarizer`] can use the model. The oldest tokens of the rendered messages are dropped when
+/// they do not fit in the context together with the answer.
+pub type Respond<'a> = dyn FnMut(&[ChatMessage], usize) -> Result<String> + 'a;
+
+/// Makes a chat history shorter when it does not fit in the context anymore.
+pub trait Summarizer {
+    /// Compacts `history`, the system prompt and the unanswered user message have to be kept.
+    /// Returns `false` when there is nothing left to compact, the session then generates as
+    /// many tokens as fit. Using `respond` resets the kv cache of the session.
+    fn compact(&mut self, history: &mut ChatHistory, respond: &mut Respond<'_>) -> Result<bool>;
+}
+
+/// Drops the oldest turn, the user message together with its answer, then the summary once no
+/// turn is left.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DropOldestTurns;
+
+impl Summarizer for DropOldestTurns {
+    fn compact(&mut self, history: &mut ChatHistory, _: &mut Respond<'_>) -> Result<bool> {
+        if history.turns() > 0 {
+            history.evict(1);
+            Ok(true)
+        } else {
+            Ok(history.summary.take().is_some())
+        }
+    }
+}
+
+/// Replaces the oldest half of the turns with a summary written by the model, the previous
+/// summary is summarized along with them.
+#[derive(Debug, Clone)]
+pub struct SummarizeTurns {
+    /// The maximum number of tokens of a summary.
+    pub max_tokens: usize,
+    /// The request that follows the evicted turns in the summarization prompt.
+    pub instruction: String,
+}
+
+impl SummarizeTurns {
+    pub fn new(max_tokens: usize) -> Self {
+        Self {
+            max_tokens,
+            instruction: "Summarize the conversation above in a few sentences.".to_string(),
+        }
+    }
+}
+
+impl Summarizer for SummarizeTurns {
+    fn compact(&mut self, history: &mut ChatHistory, respond: &mut Respond<'_>) -> Result<bool> {
+        if history.turns() == 0 {
+            return Ok(history.summary.take().is_some());
+        }
+        let evicted = history.evict(history.turns().div_ceil(2));
+        // The instruction comes last so that it survives when the prompt has to be truncated.
+        let mut prompt = String::new();
+        if let Some(summary) = history.summary.as_ref() {
+            prompt.push_str(&format!("Earlier: {summary}\n"));
+        }
+        for message in evicted.iter() {
+            let speaker = match message.role {
+                Role::System => "System",
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+            };
+            prompt.push_str(&format!("{speaker}: {}\n", message.content));
+        }
+        prompt.push('\n');
+        prompt.push_str(&self.instruction);
+        let summary = respond(&[ChatMessage::new(Role::User, prompt)], self.max_tokens)?;
+        let summary = summary.trim();
+        history.summary = (!summary.is_empty()).then(|| summary.to_string());
+        Ok(true)
+    }
+}
+
+/// How a user message has been processed by [`ChatSession::push_user`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChatPrompt {
+    /// The number of tokens of the rendered history.
+    pub prompt_tokens: usize,
+    /// The number of these tokens that were not in the kv cache.
+    pub processed_tokens: usize,
+    /// The number of tokens that the answer can have.
+    pub new_tokens: usize,
+    /// The number of times the history has been compacted to fit.
+    pub compactions: usize,
+}
+
+/// A chat with a model, see the [module documentation](self).
+pub struct ChatSession<M: CausalLm> {
+    generation: TextGeneration<M>,
+    format: Box<dyn ChatFormat>,
+    stop: StopConditions,
+    budget: TokenBudget,
+    summarizer: Box<dyn Summarizer>,
+    history: ChatHistory,
+    checkpoint: Option<(TurnCheckpoint, usize)>,
+}
+
+impl<M: CausalLm> ChatSession<M> {
+    /// A session that drops the oldest turns when the history does not fit.
+    pub fn new(
+        generation: TextGeneration<M>,
+        format: impl ChatFormat + 'static,
+        stop: StopConditions,
+        budget: TokenBudget,
+    ) -> Self {
+        Self {
+            generation,
+            format: Box::new(format),
+            stop,
+            budget,
+            summarizer: Box::new(DropOldestTurns),
+            history: ChatHistory::default(),
+            checkpoint: None,
+        }
+    }
+
+    pub fn with_summarizer(mut self, summarizer: impl Summarizer + 'static) -> Self {
+        self.summarizer = Box::new(summarizer);
+        self
+    }
+
+    pub fn with_history(mut self, history: ChatHistory) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn history(&self) -> &ChatHistory {
+        &self.history
+    }
+
+    pub fn format(&self) -> &dyn ChatFormat {
+        self.format.as_ref()
+    }
+
+    pub fn generation(&self) -> &TextGeneration<M> {
+        &self.generation
+    }
+
+    pub fn generation_mut(&mut self) -> &mut TextGeneration<M> {
+        &mut self.generation
+    }
+
+    /// Adds a user message and processes the history up to the header of the answer, leaving
+    /// room for `max_new_tokens` tokens. The history is compacted until it fits, if it still
+    /// does not fit after the last compaction the answer gets fewer tokens.
+    pub fn push_user(&mut self, content: &str, max_new_tokens: usize) -> Result<ChatPrompt> {
+        if self.history.messages.last().map(|m| m.role) == Some(Role::User) {
+            candle::bail!("the previous user message has not been answered")
+        }
+        self.history
+            .messages
+            .push(ChatMessage::new(Role::User, content));
+        self.checkpoint = None;
+        let res = self.fit(max_new_tokens);
+        if res.is_err() {
+            self.history.messages.pop();
+        }
+        res
+    }
+
+    fn fit(&mut self, max_new_tokens: usize) -> Result<ChatPrompt> {
+        let Self {
+            generation,
+            format,
+            stop,
+            budget,
+            summarizer,
+            history,
+            ..
+        } = self;
+        let mut compactions = 0;
+        let (tokens, plan) = loop {
+            let tokens = format.encode(&format.render(&history.to_messages()))?;
+            let plan = budget.plan(tokens.len(), max_new_tokens);
+            if matches!(plan, Ok(plan) if plan.truncate == 0) {
+                break (tokens, plan.map_err(Error::wrap)?);
+            }
+            let mut respond = |messages: &[ChatMessage], max_tokens: usize| {
+                let tokens = format.encode(&format.render(messages))?;
+                let skip = (tokens.len() + max_tokens).saturating_sub(budget.available());
+                let tokens = &tokens[skip.min(tokens.len().saturating_sub(1))..];
+                let plan = budget.plan(tokens.len(), max_tokens).map_err(Error::wrap)?;
+                generation.truncate(0)?;
+                generation.push_prompt(tokens)?;
+                let mut text = String::new();
+                let decode = |tokens: &[u32]| format.decode(tokens);
+                generation.generate_text(plan.new_tokens, stop, decode, |t| {
+                    text.push_str(t);
+                    Ok(())
+                })?;
+                Ok(text)
+            };
+            if !summarizer.compact(history, &mut respond)? {
+                break (tokens, plan.map_err(Error::wrap)?);
+            }
+            compactions += 1;
+        };
+        if tokens.is_empty() {
+            candle::bail!("the rendered chat is empty")
+        }
+        // The tokens shared with the conversation so far are kept, at least one token is
+        // processed again to get the logits of the answer.
+        let current = generation.tokens();
+        let shared = first_divergence(&current, &tokens).unwrap_or(tokens.len());
+        let shared = shared.min(tokens.len() - 1);
+        generation.truncate(shared)?;
+        let checkpoint = generation.push_prompt(&tokens[shared..])?;
+        self.checkpoint = Some((checkpoint, plan.new_tokens));
+        Ok(ChatPrompt {
+            prompt_tokens: tokens.len(),
+            processed_tokens: tokens.len() - shared,
+            new_tokens: plan.new_tokens,
+            compactions,
+        })
+    }
+
+    /// Generates the answer to the last user message, `on_text` gets the decoded text as it is
+    /// generated. The answer is added to the history, the generated tokens are returned.
+    pub fn answer(&mut self, on_text: impl FnMut(&str) -> Result<()>) -> Result<Vec<u32>> {
+        let new_tokens = match self.checkpoint.as_ref() {
+            Some((_, new_tokens)) => *new_tokens,
+            None => candle::bail!("no user message to answer"),
+        };
+        if self.history.messages.last().map(|m| m.role) != Some(Role::User) {
+            candle::bail!("the last user message has already been answered")
+        }
+        let mut on_text = on_text;
+        let mut answer = String::new();
+        let format = &self.format;
+        let tokens = self.generation.generate_text(
+            new_tokens,
+            &self.stop,
+            |tokens| format.decode(tokens),
+            |text| {
+                answer.push_str(text);
+                on_text(text)
+            },
+        )?;
+        self.history
+            .messages
+            .push(ChatMessage::new(Role::Assistant, answer));
+        Ok(tokens)
+    }
+
+    /// Drops the last answer so that it can be generated again with [`Self::answer`].
+    pub fn rollback_answer(&mut self) -> Result<()> {
+        let checkpoint = match self.checkpoint.as_ref() {
+            Some((checkpoint, _)) => checkpoint,
+            None => candle::bail!("nothing to regenerate"),
+        };
+        self.generation.rollback_to(checkpoint)?;
+        if self.history.messages.last().map(|m| m.role) == Some(Role::Assistant) {
+            self.history.messages.pop();
+        }
+        Ok(())
+    }
+}
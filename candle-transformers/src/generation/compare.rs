@@ -0,0 +1,171 @@
+//! Side by side generation with two models.
+//!
+//! This is mostly useful to compare two quantizations of the same model, or to bisect kernel
+//! regressions: both models are fed the same prompt and sampled with the same seed, and the
+//! index of the first generated token where they disagree is reported.
+use super::{CausalLm, LogitsProcessor, Sampling};
+use candle::{Device, Result, Tensor};
+
+/// The output of a single generation run.
+#[derive(Debug, Clone)]
+pub struct GenerationRun {
+    pub tokens: Vec<u32>,
+    pub prompt_dt: std::time::Duration,
+    pub sample_dt: std::time::Duration,
+}
+
+impl GenerationRun {
+    /// Number of generated tokens per second, the first token is accounted for in the prompt
+    /// processing time.
+    pub fn tokens_per_sec(&self) -> f64 {
+        let sampled = self.tokens.len().saturating_sub(1);
+        if sampled == 0 {
+            return 0.;
+        }
+        sampled as f64 / self.sample_dt.as_secs_f64()
+    }
+}
+
+/// Returns the index of the first position where the two token sequences differ, `None` if
+/// they are identical. When one sequence is a strict prefix of the other, the divergence is
+/// reported at the end of the shorter one.
+pub fn first_divergence(lhs: &[u32], rhs: &[u32]) -> Option<usize> {
+    match lhs.iter().zip(rhs.iter()).position(|(l, r)| l != r) {
+        Some(index) => Some(index),
+        None if lhs.len() == rhs.len() => None,
+        None => Some(lhs.len().min(rhs.len())),
+    }
+}
+
+/// Generates up to `sample_len` tokens for `prompt`, stopping early on `eos_token`.
+pub fn generate<M: CausalLm>(
+    model: &mut M,
+    prompt: &[u32],
+    sample_len: usize,
+    eos_token: Option<u32>,
+    logits_processor: &mut LogitsProcessor,
+    device: &Device,
+) -> Result<GenerationRun> {
+    let start = std::time::Instant::now();
+    let input = Tensor::new(prompt, device)?.unsqueeze(0)?;
+    let logits = model.forward(&input, 0)?.squeeze(0)?;
+    let forward_dt = start.elapsed();
+    let mut run = continue_generation(
+        model,
+        &logits,
+        prompt.len(),
+        sample_len,
+        eos_token,
+        logits_processor,
+        device,
+    )?;
+    run.prompt_dt += forward_dt;
+    Ok(run)
+}
+
+/// Like [`generate`] once the `prompt_len` tokens of the prompt are in the kv cache, `logits` are
+/// the ones of the last prompt position, e.g. a row of a batched forward pass over several
+/// prompts. The prompt time only covers the sampling of the first token.
+pub fn continue_generation<M: CausalLm>(
+    model: &mut M,
+    logits: &Tensor,
+    prompt_len: usize,
+    sample_len: usize,
+    eos_token: Option<u32>,
+    logits_processor: &mut LogitsProcessor,
+    device: &Device,
+) -> Result<GenerationRun> {
+    let mut tokens = Vec::with_capacity(sample_len);
+    let start = std::time::Instant::now();
+    let mut next_token = logits_processor.sample(logits)?;
+    tokens.push(next_token);
+    let prompt_dt = start.elapsed();
+
+    let start = std::time::Instant::now();
+    for index in 1..sample_len {
+        if Some(next_token) == eos_token {
+            break;
+        }
+        let input = Tensor::new(&[next_token], device)?.unsqueeze(0)?;
+        let logits = model.forward(&input, prompt_len + index - 1)?.squeeze(0)?;
+        next_token = logits_processor.sample(&logits)?;
+        tokens.push(next_token);
+    }
+    Ok(GenerationRun {
+        tokens,
+        prompt_dt,
+        sample_dt: start.elapsed(),
+    })
+}
+
+/// The result of running the same prompt through two models.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub lhs: GenerationRun,
+    pub rhs: GenerationRun,
+    /// Index in the generated tokens where the two runs first diverge.
+    pub divergence: Option<usize>,
+}
+
+impl Comparison {
+    pub fn new(lhs: GenerationRun, rhs: GenerationRun) -> Self {
+        let divergence = first_divergence(&lhs.tokens, &rhs.tokens);
+        Self {
+            lhs,
+            rhs,
+            divergence,
+        }
+    }
+}
+
+/// Settings shared by both sides of a comparison.
+#[derive(Debug, Clone)]
+pub struct CompareConfig {
+    pub sample_len: usize,
+    pub eos_token: Option<u32>,
+    pub seed: u64,
+    pub sampling: Sampling,
+}
+
+impl CompareConfig {
+    /// Greedy decoding, the divergence index is only really meaningful in this case.
+    pub fn greedy(sample_len: usize, eos_token: Option<u32>) -> Self {
+        Self {
+            sample_len,
+            eos_token,
+            seed: 0,
+            sampling: Sampling::ArgMax,
+        }
+    }
+
+    /// Runs a single side of the comparison, this can be used to run the two models one after
+    /// the other when they do not fit in memory at the same time.
+    pub fn run<M: CausalLm>(
+        &self,
+        model: &mut M,
+        prompt: &[u32],
+        device: &Device,
+    ) -> Result<GenerationRun> {
+        let mut logits_processor = LogitsProcessor::from_sampling(self.seed, self.sampling.clone());
+        generate(
+            model,
+            prompt,
+            self.sample_len,
+            self.eos_token,
+            &mut logits_processor,
+            device,
+        )
+    }
+
+    /// Runs both models on the same prompt, each model lives on its own device.
+    pub fn compare<L: CausalLm, R: CausalLm>(
+        &self,
+        (lhs, lhs_device): (&mut L, &Device),
+        (rhs, rhs_device): (&mut R, &Device),
+        prompt: &[u32],
+    ) -> Result<Comparison> {
+        let lhs = self.run(lhs, prompt, lhs_device)?;
+        let rhs = self.run(rhs, prompt, rhs_device)?;
+        Ok(Comparison::new(lhs, rhs))
+    }
+}
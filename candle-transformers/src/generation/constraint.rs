@@ -0,0 +1,280 @@
+//! Constrained decoding schedules, e.g. for tool calling.
+//!
+//! A [`ConstraintSchedule`] is a list of phases run one after the other on a
+//! [`TextGeneration`]. Free phases sample without constraints until their stop criteria fires,
+//! one-of phases restrict the sampling to the tokenizations of a fixed set of options so that
+//! the model has to pick one of them, e.g. the name of a tool. When an option is a prefix of
+//! another one, e.g. `search` and `search_web`, the options have to be followed by an end token,
+//! see [`ConstraintSchedule::with_option_end`], so that the model can pick the shorter one.
+use super::{CausalLm, StopConditions, StopCriteria, StopReason, TextGeneration};
+use candle::{DType, Result, Tensor};
+use std::collections::{BTreeMap, HashSet};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Phase {
+    /// Samples freely until `until` fires or the token limit of the schedule is reached.
+    Free { until: StopCriteria },
+    /// Forces the model to generate exactly one of the options.
+    OneOf { options: Vec<String> },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConstraintSchedule {
+    pub phases: Vec<Phase>,
+    /// The token that ends each option of the one-of phases, see [`Self::with_option_end`].
+    pub option_end: Option<u32>,
+}
+
+impl ConstraintSchedule {
+    pub fn new(phases: Vec<Phase>) -> Self {
+        Self {
+            phases,
+            option_end: None,
+        }
+    }
+
+    /// Appends `token` to the tokenization of each option, e.g. the end of sequence token or
+    /// the token of the opening parenthesis of a tool call. Where an option is a prefix of
+    /// another one, the model then chooses between this token and the continuations. The token
+    /// is part of the tokens of the one-of phases.
+    pub fn with_option_end(self, token: u32) -> Self {
+        Self {
+            option_end: Some(token),
+            ..self
+        }
+    }
+
+    /// Resolves the stop criteria and tokenizes the options. `added_tokens` is used for the
+    /// [`StopCriteria::StopTokenPattern`] criteria, see [`StopConditions::new`], and `encode`
+    /// should tokenize an option the way it would appear in the generated text.
+    pub fn resolve<'a, I, E>(&self, added_tokens: I, mut encode: E) -> Result<ResolvedSchedule>
+    where
+        I: IntoIterator<Item = (u32, &'a str)> + Clone,
+        E: FnMut(&str) -> Result<Vec<u32>>,
+    {
+        let mut phases = Vec::with_capacity(self.phases.len());
+        for phase in self.phases.iter() {
+            let phase = match phase {
+                Phase::Free { until } => {
+                    let stop =
+                        StopConditions::new(std::slice::from_ref(until), added_tokens.clone())?;
+                    ResolvedPhase::Free(stop)
+                }
+                Phase::OneOf { options } => {
+                    let mut tokens = options
+                        .iter()
+                        .map(|o| encode(o))
+                        .collect::<Result<Vec<_>>>()?;
+                    if let Some(end) = self.option_end {
+                        for tokens in tokens.iter_mut().filter(|t| !t.is_empty()) {
+                            tokens.push(end)
+                        }
+                    }
+                    let trie = TokenTrie::new(&tokens)?;
+                    ResolvedPhase::OneOf(options.clone(), trie)
+                }
+            };
+            phases.push(phase)
+        }
+        Ok(ResolvedSchedule { phases })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum ResolvedPhase {
+    Free(StopConditions),
+    OneOf(Vec<String>, TokenTrie),
+}
+
+/// A [`ConstraintSchedule`] with the stop criteria resolved and the options tokenized.
+#[derive(Debug, Clone)]
+pub struct ResolvedSchedule {
+    phases: Vec<ResolvedPhase>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseOutput {
+    /// The tokens generated in this phase, for free phases a stop token is not included.
+    pub tokens: Vec<u32>,
+    /// The index of the option picked in a one-of phase.
+    pub choice: Option<usize>,
+    /// Why a free phase ended, `None` if the token limit was reached.
+    pub stop: Option<StopReason>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: BTreeMap<u32, usize>,
+    option: Option<usize>,
+}
+
+/// A trie over the tokenizations of a set of options.
+#[derive(Debug, Clone)]
+pub struct TokenTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl TokenTrie {
+    /// Builds the trie, this fails if two options have the same tokenization or if the
+    /// tokenization of an option is a prefix of another one as the longer option could then
+    /// never be picked. Ending all the options with the same token, as done by
+    /// [`ConstraintSchedule::with_option_end`], avoids the latter.
+    pub fn new(options: &[Vec<u32>]) -> Result<Self> {
+        if options.is_empty() {
+            candle::bail!("no options to choose from")
+        }
+        let mut nodes = vec![TrieNode::default()];
+        for (index, tokens) in options.iter().enumerate() {
+            if tokens.is_empty() {
+                candle::bail!("option {index} has an empty tokenization")
+            }
+            let mut node = 0;
+            for &token in tokens.iter() {
+                if let Some(prev) = nodes[node].option {
+                    candle::bail!(
+                        "option {prev} is a prefix of option {index} once tokenized, end the options with a token"
+                    )
+                }
+                node = match nodes[node].children.get(&token) {
+                    Some(&child) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.insert(token, child);
+                        child
+                    }
+                }
+            }
+            if let Some(prev) = nodes[node].option {
+                candle::bail!("options {prev} and {index} have the same tokenization")
+            }
+            // Every path ends on an option, follow one to name the longer option.
+            let mut longer = node;
+            while let Some(&child) = nodes[longer].children.values().next() {
+                longer = child
+            }
+            if let Some(next) = nodes[longer].option.filter(|_| longer != node) {
+                candle::bail!(
+                    "option {index} is a prefix of option {next} once tokenized, end the options with a token"
+                )
+            }
+            nodes[node].option = Some(index)
+        }
+        Ok(Self { nodes })
+    }
+
+    /// Picks an option by walking the trie and returns its index together with its tokens.
+    ///
+    /// `sample` is only called when the model has to choose between several tokens, it gets
+    /// the tokens forced since the previous call and the allowed ones. Tokens are forced along
+    /// the path where there is a single continuation.
+    pub fn walk<F>(&self, mut sample: F) -> Result<(usize, Vec<u32>)>
+    where
+        F: FnMut(&[u32], &HashSet<u32>) -> Result<u32>,
+    {
+        let mut node = 0;
+        let mut tokens = vec![];
+        let mut forced_from = 0;
+        loop {
+            let n = &self.nodes[node];
+            if let Some(option) = n.option {
+                return Ok((option, tokens));
+            }
+            let (token, child) = if n.children.len() == 1 {
+                let (&token, &child) = n.children.iter().next().unwrap();
+                (token, child)
+            } else {
+                let allowed = n.children.keys().copied().collect::<HashSet<_>>();
+                let token = sample(&tokens[forced_from..], &allowed)?;
+                forced_from = tokens.len() + 1;
+                match n.children.get(&token) {
+                    Some(&child) => (token, child),
+                    None => candle::bail!("sampled token {token} is not a valid continuation"),
+                }
+            };
+            tokens.push(token);
+            node = child;
+        }
+    }
+}
+
+/// Sets the logits of the tokens that are not in `allowed` to minus infinity.
+pub fn mask_logits(logits: &Tensor, allowed: &HashSet<u32>) -> Result<Tensor> {
+    let mut logits_v = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for (token, v) in logits_v.iter_mut().enumerate() {
+        if !allowed.contains(&(token as u32)) {
+            *v = f32::NEG_INFINITY
+        }
+    }
+    Tensor::new(logits_v, logits.device())
+}
+
+impl<M: CausalLm> TextGeneration<M> {
+    /// Runs all the phases of `schedule` following the last prompt. Free phases generate at
+    /// most `max_free_tokens` tokens, `decode` is used to check the text based stop criteria.
+    pub fn run_schedule(
+        &mut self,
+        schedule: &ResolvedSchedule,
+        max_free_tokens: usize,
+        mut decode: impl FnMut(&[u32]) -> Result<String>,
+    ) -> Result<Vec<PhaseOutput>> {
+        let mut outputs = Vec::with_capacity(schedule.phases.len());
+        for phase in schedule.phases.iter() {
+            let output = match phase {
+                ResolvedPhase::Free(stop) => {
+                    let mut tokens = vec![];
+                    let mut reason = None;
+                    while tokens.len() < max_free_tokens {
+                        let token = self.sample_next(None)?;
+                        if let Some(r) = stop.check_token(token) {
+                            reason = Some(r);
+                            break;
+                        }
+                        tokens.push(token);
+                        if !stop.sequences().is_empty() {
+                            if let Some(r) = stop.check_text(&decode(&tokens)?) {
+                                reason = Some(r);
+                                break;
+                            }
+                        }
+                    }
+                    PhaseOutput {
+                        tokens,
+                        choice: None,
+                        stop: reason,
+                    }
+                }
+                ResolvedPhase::OneOf(_, trie) => {
+                    let mut processed = 0;
+                    let (choice, tokens) = trie.walk(|forced, allowed| {
+                        self.force_tokens(forced);
+                        processed += forced.len() + 1;
+                        self.sample_next(Some(allowed))
+                    })?;
+                    self.force_tokens(&tokens[processed..]);
+                    PhaseOutput {
+                        tokens,
+                        choice: Some(choice),
+                        stop: None,
+                    }
+                }
+            };
+            outputs.push(output)
+        }
+        Ok(outputs)
+    }
+}
+
+impl ResolvedSchedule {
+    /// The option picked in each one-of phase of `outputs`.
+    pub fn choices<'a>(&'a self, outputs: &[PhaseOutput]) -> Vec<&'a str> {
+        self.phases
+            .iter()
+            .zip(outputs.iter())
+            .filter_map(|(phase, output)| match (phase, output.choice) {
+                (ResolvedPhase::OneOf(options, _), Some(choice)) => Some(options[choice].as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,100 @@
+//! Decoding from a weighted mixture of models.
+//!
+//! An [`EnsembleModel`] runs the same tokens through each of its members, each with its own kv
+//! cache, and returns `sum_i w_i * log_softmax(logits_i)` as its logits. With positive weights
+//! adding up to one this is a geometric mixture of the distributions of the members, negative
+//! weights contrast the models, e.g. `1.5 * finetuned - 0.5 * base` amplifies what the fine-tuning
+//! changed. The sampler normalizes the combined logits.
+use super::CausalLm;
+use candle::{DType, Result, Tensor, D};
+
+/// A model and its weight in an [`EnsembleModel`].
+#[derive(Debug, Clone)]
+pub struct EnsembleMember<M> {
+    pub model: M,
+    pub weight: f64,
+}
+
+/// A [`CausalLm`] whose logits combine the log-probabilities of several models, the models have
+/// to share the same vocabulary.
+#[derive(Debug, Clone)]
+pub struct EnsembleModel<M> {
+    members: Vec<EnsembleMember<M>>,
+}
+
+impl<M: CausalLm> EnsembleModel<M> {
+    /// Builds an ensemble from `(model, weight)` pairs, the weights have to be finite.
+    pub fn new(members: impl IntoIterator<Item = (M, f64)>) -> Result<Self> {
+        let members = members
+            .into_iter()
+            .map(|(model, weight)| EnsembleMember { model, weight })
+            .collect::<Vec<_>>();
+        if members.is_empty() {
+            candle::bail!("an ensemble needs at least one model")
+        }
+        if let Some(m) = members.iter().find(|m| !m.weight.is_finite()) {
+            candle::bail!("invalid ensemble weight {}", m.weight)
+        }
+        Ok(Self { members })
+    }
+
+    pub fn members(&self) -> &[EnsembleMember<M>] {
+        &self.members
+    }
+
+    pub fn members_mut(&mut self) -> &mut [EnsembleMember<M>] {
+        &mut self.members
+    }
+
+    pub fn into_members(self) -> Vec<EnsembleMember<M>> {
+        self.members
+    }
+}
+
+impl<M: CausalLm> CausalLm for EnsembleModel<M> {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut combined: Option<Tensor> = None;
+        for (i, member) in self.members.iter_mut().enumerate() {
+            let logits = member.model.forward(input, index_pos)?;
+            if let Some(combined) = combined.as_ref() {
+                if logits.dims() != combined.dims() {
+                    candle::bail!(
+                        "ensemble model {i} returns logits of shape {:?}, expected {:?}, the models must share the same vocabulary",
+                        logits.dims(),
+                        combined.dims()
+                    )
+                }
+            }
+            let logprobs = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+            let logprobs = logprobs.affine(member.weight, 0.)?;
+            combined = Some(match combined {
+                None => logprobs,
+                Some(combined) => (combined + logprobs)?,
+            });
+        }
+        // The ensemble cannot be empty.
+        Ok(combined.unwrap())
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.model.truncate_kv_cache(len)?
+        }
+        Ok(())
+    }
+
+    fn fork_kv_cache(&mut self, n: usize) -> Result<()> {
+        for member in self.members.iter_mut() {
+            member.model.fork_kv_cache(n)?
+        }
+        Ok(())
+    }
+
+    /// The smallest context of the members.
+    fn max_seq_len(&self) -> Option<usize> {
+        self.members
+            .iter()
+            .filter_map(|m| m.model.max_seq_len())
+            .min()
+    }
+}
@@ -0,0 +1,136 @@
+//! Helpers for perplexity and log-probability evaluation.
+//!
+//! The per-token log-likelihoods are computed on device in f32, but summing thousands of them
+//! in f32 loses enough precision to blur perplexity differences below 0.01, so the sums are
+//! accumulated on the host in f64.
+use candle::{DType, Result, Tensor};
+
+/// Accumulates per-token negative log-likelihoods in f64.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct NllAccumulator {
+    sum: f64,
+    count: usize,
+}
+
+impl NllAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds all the values of `nll`, whatever its shape and float dtype.
+    pub fn add(&mut self, nll: &Tensor) -> Result<()> {
+        let nll = nll.flatten_all()?.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        for v in nll {
+            self.add_value(v)
+        }
+        Ok(())
+    }
+
+    pub fn add_value(&mut self, nll: f32) {
+        self.sum += nll as f64;
+        self.count += 1;
+    }
+
+    /// The total negative log-likelihood.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// The mean negative log-likelihood per token, NaN if nothing has been accumulated.
+    pub fn mean(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+
+    pub fn perplexity(&self) -> f64 {
+        self.mean().exp()
+    }
+}
+
+/// The reduced precision settings used by the cuda GEMM kernels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GemmPrecision {
+    pub reduced_f16: bool,
+    pub reduced_bf16: bool,
+    pub reduced_f32: bool,
+}
+
+impl GemmPrecision {
+    pub fn current() -> Self {
+        Self {
+            reduced_f16: candle::cuda::gemm_reduced_precision_f16(),
+            reduced_bf16: candle::cuda::gemm_reduced_precision_bf16(),
+            reduced_f32: candle::cuda::gemm_reduced_precision_f32(),
+        }
+    }
+
+    /// Full precision for all the dtypes.
+    pub fn full() -> Self {
+        Self {
+            reduced_f16: false,
+            reduced_bf16: false,
+            reduced_f32: false,
+        }
+    }
+
+    pub fn apply(&self) {
+        candle::cuda::set_gemm_reduced_precision_f16(self.reduced_f16);
+        candle::cuda::set_gemm_reduced_precision_bf16(self.reduced_bf16);
+        candle::cuda::set_gemm_reduced_precision_f32(self.reduced_f32);
+    }
+}
+
+/// Where the GEMM precision settings are stored.
+pub trait GemmSettings {
+    fn get(&self) -> GemmPrecision;
+    fn set(&self, precision: GemmPrecision);
+}
+
+/// The process wide settings of the cuda backend, setting them does nothing without the `cuda`
+/// feature.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CudaGemmSettings;
+
+impl GemmSettings for CudaGemmSettings {
+    fn get(&self) -> GemmPrecision {
+        GemmPrecision::current()
+    }
+
+    fn set(&self, precision: GemmPrecision) {
+        precision.apply()
+    }
+}
+
+// Restores the previous settings on drop so that they also get restored on errors and panics.
+struct GemmPrecisionGuard<'a, S: GemmSettings>(&'a S, GemmPrecision);
+
+impl<S: GemmSettings> Drop for GemmPrecisionGuard<'_, S> {
+    fn drop(&mut self) {
+        self.0.set(self.1)
+    }
+}
+
+/// Runs `f` with the given GEMM precision, the previous settings are restored afterwards.
+///
+/// These settings are process wide so this should not be used while other threads are running
+/// computations.
+pub fn with_gemm_precision<T>(
+    precision: GemmPrecision,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    with_gemm_precision_in(&CudaGemmSettings, precision, f)
+}
+
+/// Like [`with_gemm_precision`] with the settings stored in `settings`.
+pub fn with_gemm_precision_in<S: GemmSettings, T>(
+    settings: &S,
+    precision: GemmPrecision,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let _guard = GemmPrecisionGuard(settings, settings.get());
+    settings.set(precision);
+    f()
+}
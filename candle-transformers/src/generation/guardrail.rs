@@ -0,0 +1,80 @@
+//! Guardrails checking the generated text as it is sampled.
+//!
+//! A [`GuardrailHook`] set on a [`super::TextGeneration`] sees each sampled token together with
+//! the decoded text generated so far, before the token is emitted. It can let the token through,
+//! ask for another sample with some tokens banned for this step, or end the generation with
+//! [`super::StopReason::Guardrail`]. The number of samples per step is bounded, the generation is
+//! aborted once it is reached so that a hook cannot stall it.
+use candle::{DType, Error, Result, Tensor};
+use std::collections::HashSet;
+
+/// What to do with a sampled token, see [`GuardrailHook::check`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GuardrailDecision {
+    Allow,
+    /// Samples again with these tokens banned, on top of the ones banned by the previous
+    /// attempts of the same step.
+    Resample(HashSet<u32>),
+    /// Ends the generation without emitting the token.
+    Abort(String),
+}
+
+pub trait GuardrailHook {
+    /// Checks the sampled `token`, `text` is the decoded text of the tokens generated so far
+    /// including this one.
+    fn check(&mut self, token: u32, text: &str) -> Result<GuardrailDecision>;
+}
+
+impl<F: FnMut(u32, &str) -> Result<GuardrailDecision>> GuardrailHook for F {
+    fn check(&mut self, token: u32, text: &str) -> Result<GuardrailDecision> {
+        self(token, text)
+    }
+}
+
+/// Resamples the tokens that make the generated text match a regex, e.g. `(?i)\bpassword\b`.
+/// The text before the token has already been accepted, so a match involves the token.
+#[derive(Debug, Clone)]
+pub struct RegexGuardrail {
+    regex: fancy_regex::Regex,
+}
+
+impl RegexGuardrail {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = fancy_regex::Regex::new(pattern).map_err(Error::wrap)?;
+        Ok(Self { regex })
+    }
+}
+
+impl GuardrailHook for RegexGuardrail {
+    fn check(&mut self, token: u32, text: &str) -> Result<GuardrailDecision> {
+        match self.regex.is_match(text).map_err(Error::wrap)? {
+            true => Ok(GuardrailDecision::Resample([token].into())),
+            false => Ok(GuardrailDecision::Allow),
+        }
+    }
+}
+
+/// The number of calls to the hook and of resampled tokens, across generations.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GuardrailStats {
+    pub checks: usize,
+    pub resamples: usize,
+    pub aborts: usize,
+}
+
+pub(crate) struct Guardrail {
+    pub(crate) hook: Box<dyn GuardrailHook>,
+    pub(crate) max_resamples: usize,
+    pub(crate) stats: GuardrailStats,
+}
+
+/// Sets the logits of the `banned` tokens to minus infinity.
+pub fn ban_logits(logits: &Tensor, banned: &HashSet<u32>) -> Result<Tensor> {
+    let mut logits_v = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    for &token in banned.iter() {
+        if let Some(v) = logits_v.get_mut(token as usize) {
+            *v = f32::NEG_INFINITY
+        }
+    }
+    Tensor::new(logits_v, logits.device())
+}
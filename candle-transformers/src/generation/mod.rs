@@ -6,6 +6,83 @@
 use candle::{Context, DType, Error, Result, Tensor};
 use rand::{distr::Distribution, SeedableRng};
 
+pub mod batch;
+pub mod best_of;
+mod budget;
+pub mod chat;
+pub mod compare;
+pub mod constraint;
+pub mod ensemble;
+pub mod eval;
+pub mod guardrail;
+mod params;
+pub mod regression;
+pub mod slot;
+pub mod stop;
+pub mod stream;
+mod text_generation;
+
+pub use best_of::{generate_best_of, BestOf, Candidate};
+pub use budget::{TokenBudget, TokenBudgetError, TokenPlan};
+pub use ensemble::{EnsembleMember, EnsembleModel};
+pub use params::{GenerationParams, Preset};
+pub use slot::{
+    generate_forked, sample_batch, PenaltySnapshot, PenaltyState, SamplerSlot,
+    PENALTY_SNAPSHOT_VERSION,
+};
+pub use stop::{StopConditions, StopCriteria, StopReason, StopStringMatcher};
+pub use stream::TextStream;
+pub use text_generation::{ContextOverflow, TextGeneration, TurnCheckpoint};
+
+/// A causal language model that can be driven step by step by the generation helpers.
+///
+/// `input` has shape `(batch, seq_len)` and `index_pos` is the position of its first token in
+/// the sequence, the returned logits are the ones for the last position, shape `(batch, vocab)`.
+pub trait CausalLm {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor>;
+
+    /// Drops the kv cache entries past the first `len` positions.
+    fn truncate_kv_cache(&mut self, _len: usize) -> Result<()> {
+        candle::bail!("kv cache truncation is not supported by this model")
+    }
+    /// Shares the kv cache of the current sequence between `n` sequences, the next calls to
+    /// `forward` then use a batch size of `n`.
+    fn fork_kv_cache(&mut self, _n: usize) -> Result<()> {
+        candle::bail!("kv cache forking is not supported by this model")
+    }
+
+    /// The number of positions that the model can process, `None` if there is no limit.
+    fn max_seq_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+#[cfg(feature = "quantized-llama")]
+impl CausalLm for crate::models::quantized_llama::ModelWeights {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forward(input, index_pos)
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.truncate_kv_cache(len)
+    }
+    fn fork_kv_cache(&mut self, n: usize) -> Result<()> {
+        self.fork_kv_cache(n)
+    }
+
+    fn max_seq_len(&self) -> Option<usize> {
+        Some(self.max_seq_len())
+    }
+}
+
+/// The sampling strategy.
+///
+/// The top-k filter only keeps the `k` most likely tokens, the top-p filter only keeps the
+/// smallest set of most likely tokens whose probabilities add up to `p`. The filters select the
+/// candidate tokens and the next token is then sampled from the candidates. A temperature of 0
+/// means greedy decoding over the candidates, as the most likely token always survives the
+/// filters this is the same as [`Sampling::ArgMax`]. Negative temperatures, `k = 0`, and `p`
+/// outside of `(0, 1]` are rejected with an error when sampling, see [`Sampling::validate`].
 #[derive(Clone, PartialEq, Debug)]
 pub enum Sampling {
     ArgMax,
@@ -17,19 +94,121 @@ pub enum Sampling {
     GumbelSoftmax { temperature: f64 },
 }
 
+impl Sampling {
+    pub fn temperature(&self) -> Option<f64> {
+        match self {
+            Self::ArgMax => None,
+            Self::All { temperature }
+            | Self::TopK { temperature, .. }
+            | Self::TopP { temperature, .. }
+            | Self::TopKThenTopP { temperature, .. }
+            | Self::GumbelSoftmax { temperature } => Some(*temperature),
+        }
+    }
+
+    /// Checks that the parameters are in range.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(temperature) = self.temperature() {
+            if temperature.is_nan() || temperature < 0. {
+                candle::bail!("temperature must be non-negative, got {temperature}")
+            }
+        }
+        match self {
+            Self::TopK { k, .. } | Self::TopKThenTopP { k, .. } if *k == 0 => {
+                candle::bail!("top_k must be positive")
+            }
+            Self::TopP { p, .. } | Self::TopKThenTopP { p, .. } if !(*p > 0. && *p <= 1.) => {
+                candle::bail!("top_p must be in (0,1], got {p}")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What to do when the distribution to sample from is degenerate, i.e. when the logits are all
+/// `-inf` (typically after aggressive token banning) or contain NaN values.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum DegenerateHandling {
+    /// Sample from the unfiltered logits instead, these are the logits passed to
+    /// [`LogitsProcessor::sample_with_unfiltered`], or the logits before top-k/top-p filtering
+    /// otherwise. Non-finite values are ignored.
+    #[default]
+    Unfiltered,
+    /// Emit the given end of sequence token.
+    Eos(u32),
+    /// Return a [`DegenerateDistribution`] error.
+    Error,
+}
+
+/// The error returned when sampling from a degenerate distribution with
+/// [`DegenerateHandling::Error`], or when there is nothing left to fall back on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DegenerateDistribution {
+    pub vocab_size: usize,
+}
+
+impl std::fmt::Display for DegenerateDistribution {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "degenerate sampling distribution over {} tokens",
+            self.vocab_size
+        )
+    }
+}
+
+impl std::error::Error for DegenerateDistribution {}
+
+fn is_degenerate_logits(logits: &[f32]) -> bool {
+    logits.iter().any(|v| v.is_nan()) || logits.iter().all(|&v| v == f32::NEG_INFINITY)
+}
+
+fn is_degenerate_prs(prs: &[f32]) -> bool {
+    let sum = prs.iter().sum::<f32>();
+    prs.iter().any(|v| v.is_nan()) || !sum.is_finite() || sum <= 0.
+}
+
+#[derive(Clone)]
 pub struct LogitsProcessor {
     rng: rand::rngs::StdRng,
     sampling: Sampling,
+    degenerate_handling: DegenerateHandling,
+    degenerate_count: usize,
 }
 
 impl LogitsProcessor {
     pub fn from_sampling(seed: u64, sampling: Sampling) -> Self {
         let rng = rand::rngs::StdRng::seed_from_u64(seed);
-        Self { rng, sampling }
+        Self {
+            rng,
+            sampling,
+            degenerate_handling: DegenerateHandling::default(),
+            degenerate_count: 0,
+        }
+    }
+
+    pub fn set_degenerate_handling(&mut self, degenerate_handling: DegenerateHandling) {
+        self.degenerate_handling = degenerate_handling
+    }
+
+    pub fn degenerate_handling(&self) -> DegenerateHandling {
+        self.degenerate_handling
+    }
+
+    /// The number of sampling steps so far where the distribution was found to be degenerate.
+    pub fn degenerate_count(&self) -> usize {
+        self.degenerate_count
     }
 
     pub fn new(seed: u64, temperature: Option<f64>, top_p: Option<f64>) -> Self {
-        let temperature = temperature.and_then(|v| if v < 1e-7 { None } else { Some(v) });
+        // Negative temperatures are kept so that sampling reports them as errors.
+        let temperature = temperature.and_then(|v| {
+            if (0. ..1e-7).contains(&v) {
+                None
+            } else {
+                Some(v)
+            }
+        });
         let sampling = match temperature {
             None => Sampling::ArgMax,
             Some(temperature) => match top_p {
@@ -40,8 +219,7 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
-    fn sample_argmax(&mut self, logits: Tensor) -> Result<u32> {
-        let logits_v: Vec<f32> = logits.to_vec1()?;
+    fn sample_argmax(&mut self, logits_v: &[f32]) -> Result<u32> {
         let next_token = logits_v
             .iter()
             .enumerate()
@@ -62,10 +240,32 @@ impl LogitsProcessor {
         Ok(next_token)
     }
 
+    // Samples among the candidates, i.e. the tokens with a non-zero probability. When greedy,
+    // `greedy_logits` holds the logits of the candidates and the one with the largest logit is
+    // picked.
+    fn sample_candidates(&mut self, prs: &Vec<f32>, greedy_logits: Option<&[f32]>) -> Result<u32> {
+        match greedy_logits {
+            None => self.sample_multinomial(prs),
+            Some(logits) => prs
+                .iter()
+                .zip(logits.iter())
+                .enumerate()
+                .filter(|(_, (&p, _))| p > 0.)
+                .max_by(|(_, (_, u)), (_, (_, v))| u.total_cmp(v))
+                .map(|(i, _)| i as u32)
+                .context("empty candidate set"),
+        }
+    }
+
     /// top-p sampling (or "nucleus sampling") samples from the smallest set of tokens that exceed
     /// probability top_p. This way we never sample tokens that have very low probabilities and are
     /// less likely to go "off the rails".
-    fn sample_topp(&mut self, prs: &mut Vec<f32>, top_p: f32) -> Result<u32> {
+    fn sample_topp(
+        &mut self,
+        prs: &mut Vec<f32>,
+        top_p: f32,
+        greedy_logits: Option<&[f32]>,
+    ) -> Result<u32> {
         let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
@@ -81,38 +281,51 @@ impl LogitsProcessor {
             }
         }
         // Sample with clamped probabilities.
-        self.sample_multinomial(prs)
+        self.sample_candidates(prs, greedy_logits)
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
-    fn sample_topk(&mut self, prs: &mut Vec<f32>, top_k: usize) -> Result<u32> {
+    fn sample_topk(
+        &mut self,
+        prs: &mut Vec<f32>,
+        top_k: usize,
+        greedy_logits: Option<&[f32]>,
+    ) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_multinomial(prs)
+            self.sample_candidates(prs, greedy_logits)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
                 argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
             let prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
-            let index = self.sample_multinomial(&prs)?;
+            let logits = greedy_logits.map(|l| indices.iter().map(|&i| l[i]).collect::<Vec<_>>());
+            let index = self.sample_candidates(&prs, logits.as_deref())?;
             Ok(indices[index as usize] as u32)
         }
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
     // then top-p sampling.
-    fn sample_topk_topp(&mut self, prs: &mut Vec<f32>, top_k: usize, top_p: f32) -> Result<u32> {
+    fn sample_topk_topp(
+        &mut self,
+        prs: &mut Vec<f32>,
+        top_k: usize,
+        top_p: f32,
+        greedy_logits: Option<&[f32]>,
+    ) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_topp(prs, top_p)
+            self.sample_topp(prs, top_p, greedy_logits)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
                 argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
             let mut prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
+            let logits = greedy_logits.map(|l| indices.iter().map(|&i| l[i]).collect::<Vec<_>>());
             let sum_p = prs.iter().sum::<f32>();
             let index = if top_p <= 0.0 || top_p >= sum_p {
-                self.sample_multinomial(&prs)?
+                self.sample_candidates(&prs, logits.as_deref())?
             } else {
-                self.sample_topp(&mut prs, top_p)?
+                self.sample_topp(&mut prs, top_p, logits.as_deref())?
             };
             Ok(indices[index as usize] as u32)
         }
@@ -122,44 +335,112 @@ impl LogitsProcessor {
         self.sample_f(logits, |_| {})
     }
 
+    /// Samples from `logits`, `unfiltered` holds the logits before any penalty or token banning
+    /// was applied and are only used if `logits` turn out to be degenerate.
+    pub fn sample_with_unfiltered(&mut self, logits: &Tensor, unfiltered: &Tensor) -> Result<u32> {
+        let logits = logits.to_dtype(DType::F32)?;
+        match self.sample_or_degenerate(&logits, |_| {})? {
+            Some(next_token) => Ok(next_token),
+            None => self.sample_degenerate(unfiltered),
+        }
+    }
+
     pub fn sample_f(&mut self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
-        let prs = |temperature: f64| -> Result<Vec<f32>> {
-            let logits = (&logits / temperature)?;
+        match self.sample_or_degenerate(&logits, f)? {
+            Some(next_token) => Ok(next_token),
+            None => self.sample_degenerate(&logits),
+        }
+    }
+
+    fn sample_degenerate(&mut self, unfiltered: &Tensor) -> Result<u32> {
+        self.degenerate_count += 1;
+        let logits = unfiltered.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        let vocab_size = logits.len();
+        match self.degenerate_handling {
+            DegenerateHandling::Eos(eos_token) => return Ok(eos_token),
+            DegenerateHandling::Error => {
+                return Err(Error::wrap(DegenerateDistribution { vocab_size }))
+            }
+            DegenerateHandling::Unfiltered => {}
+        }
+        let finite = |v: &f32| v.is_finite();
+        let max = match logits.iter().copied().filter(finite).reduce(f32::max) {
+            Some(max) => max as f64,
+            None => return Err(Error::wrap(DegenerateDistribution { vocab_size })),
+        };
+        let temperature = self.sampling.temperature();
+        let prs = logits
+            .iter()
+            .map(|&v| match temperature {
+                _ if !v.is_finite() => 0.,
+                Some(t) if t > 0. => ((v as f64 - max) / t).exp() as f32,
+                // Greedy sampling, only the argmax has a non-zero probability.
+                _ => f32::from(v as f64 == max),
+            })
+            .collect::<Vec<_>>();
+        self.sample_multinomial(&prs)
+    }
+
+    // Returns `None` when the distribution to sample from is degenerate.
+    fn sample_or_degenerate(
+        &mut self,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+    ) -> Result<Option<u32>> {
+        self.sampling.validate()?;
+        let logits_v = logits.to_vec1::<f32>()?;
+        if is_degenerate_logits(&logits_v) {
+            return Ok(None);
+        }
+        // With a zero temperature the probabilities are only used to select the candidates.
+        let greedy_logits = match self.sampling.temperature() {
+            Some(0.) => Some(logits_v.as_slice()),
+            _ => None,
+        };
+        let prs = |temperature: f64| -> Result<Option<Vec<f32>>> {
+            let temperature = if temperature == 0. { 1. } else { temperature };
+            let logits = (logits / temperature)?;
             let prs = candle_nn::ops::softmax_last_dim(&logits)?;
             let mut prs = prs.to_vec1()?;
             f(&mut prs);
-            Ok(prs)
+            if is_degenerate_prs(&prs) {
+                Ok(None)
+            } else {
+                Ok(Some(prs))
+            }
         };
 
         let next_token = match &self.sampling {
-            Sampling::ArgMax => self.sample_argmax(logits)?,
+            Sampling::ArgMax => self.sample_argmax(&logits_v)?,
             Sampling::GumbelSoftmax { temperature } => {
-                self.sample_gumbel_softmax(&logits, *temperature)?
+                self.sample_gumbel_softmax(logits, *temperature)?
             }
-            Sampling::All { temperature } => {
-                let prs = prs(*temperature)?;
-                self.sample_multinomial(&prs)?
-            }
-            Sampling::TopP { p, temperature } => {
-                let mut prs = prs(*temperature)?;
-                if *p <= 0.0 || *p >= 1.0 {
-                    // simply sample from the predicted probability distribution
-                    self.sample_multinomial(&prs)?
-                } else {
-                    // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(&mut prs, *p as f32)?
+            Sampling::All { temperature } => match prs(*temperature)? {
+                None => return Ok(None),
+                Some(prs) => self.sample_candidates(&prs, greedy_logits)?,
+            },
+            Sampling::TopP { p, temperature } => match prs(*temperature)? {
+                None => return Ok(None),
+                Some(mut prs) => {
+                    if *p >= 1.0 {
+                        // simply sample from the predicted probability distribution
+                        self.sample_candidates(&prs, greedy_logits)?
+                    } else {
+                        // top-p (nucleus) sampling, clamping the least likely tokens to zero
+                        self.sample_topp(&mut prs, *p as f32, greedy_logits)?
+                    }
                 }
-            }
-            Sampling::TopK { k, temperature } => {
-                let mut prs = prs(*temperature)?;
-                self.sample_topk(&mut prs, *k)?
-            }
-            Sampling::TopKThenTopP { k, p, temperature } => {
-                let mut prs = prs(*temperature)?;
-                self.sample_topk_topp(&mut prs, *k, *p as f32)?
-            }
+            },
+            Sampling::TopK { k, temperature } => match prs(*temperature)? {
+                None => return Ok(None),
+                Some(mut prs) => self.sample_topk(&mut prs, *k, greedy_logits)?,
+            },
+            Sampling::TopKThenTopP { k, p, temperature } => match prs(*temperature)? {
+                None => return Ok(None),
+                Some(mut prs) => self.sample_topk_topp(&mut prs, *k, *p as f32, greedy_logits)?,
+            },
         };
-        Ok(next_token)
+        Ok(Some(next_token))
     }
 }
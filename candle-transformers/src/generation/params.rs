@@ -0,0 +1,239 @@
+use super::{LogitsProcessor, Sampling, StopCriteria};
+use candle::Result;
+use serde::{Deserialize, Serialize};
+
+/// The settings of a generation run.
+///
+/// This is shared by the library entry points, e.g. [`super::TextGeneration::from_params`] and
+/// [`super::SamplerSlot::from_params`], the command line flags of the examples, and the run
+/// manifests. The parameters can be built with the `with_*` methods and are checked by
+/// [`GenerationParams::validate`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GenerationParams {
+    pub seed: u64,
+    pub max_tokens: usize,
+    /// Use 0 for greedy sampling.
+    pub temperature: f64,
+    pub top_k: Option<usize>,
+    pub top_p: Option<f64>,
+    /// Penalty applied to the recently generated tokens, 1 means no penalty.
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    /// Regexes matched against the added tokens, see [`super::StopCriteria::StopTokenPattern`].
+    pub stop_token_patterns: Vec<String>,
+    /// The number of completions to sample, only the best one is returned, see
+    /// [`super::best_of`].
+    pub best_of: usize,
+    /// The exponent `alpha` of the length normalization used to rank the completions when
+    /// `best_of` is more than 1, 0 ranks by the sum of the log-probabilities and 1 by their mean.
+    pub length_penalty: f64,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            seed: 299792458,
+            max_tokens: 1000,
+            temperature: 0.8,
+            top_k: None,
+            top_p: None,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            stop_token_patterns: vec![],
+            best_of: 1,
+            length_penalty: 1.,
+        }
+    }
+}
+
+impl GenerationParams {
+    /// The parameters of a named preset, see [`Preset`] for the accepted names.
+    pub fn from_preset(name: &str) -> Result<Self> {
+        Ok(name.parse::<Preset>()?.params())
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub fn with_temperature(mut self, temperature: f64) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = Some(top_k);
+        self
+    }
+
+    pub fn with_top_p(mut self, top_p: f64) -> Self {
+        self.top_p = Some(top_p);
+        self
+    }
+
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    pub fn with_stop_token_pattern<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.stop_token_patterns.push(pattern.into());
+        self
+    }
+
+    pub fn with_best_of(mut self, best_of: usize, length_penalty: f64) -> Self {
+        self.best_of = best_of;
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    /// Checks that the parameters are in range and that the stop token patterns are valid
+    /// regexes.
+    pub fn validate(&self) -> Result<()> {
+        if !self.temperature.is_finite() || self.temperature < 0. {
+            candle::bail!("temperature must be non-negative, got {}", self.temperature)
+        }
+        if self.top_k == Some(0) {
+            candle::bail!("top_k must be positive")
+        }
+        if let Some(p) = self.top_p {
+            if !(p > 0. && p <= 1.) {
+                candle::bail!("top_p must be in (0,1], got {p}")
+            }
+        }
+        if !self.repeat_penalty.is_finite() || self.repeat_penalty <= 0. {
+            candle::bail!(
+                "repeat_penalty must be positive, got {}",
+                self.repeat_penalty
+            )
+        }
+        if self.best_of == 0 {
+            candle::bail!("best_of must be positive")
+        }
+        if !self.length_penalty.is_finite() {
+            candle::bail!("length_penalty must be finite, got {}", self.length_penalty)
+        }
+        for pattern in self.stop_token_patterns.iter() {
+            if let Err(err) = fancy_regex::Regex::new(pattern) {
+                candle::bail!("invalid stop token pattern {pattern:?}: {err}")
+            }
+        }
+        Ok(())
+    }
+
+    /// The sampling strategy, see [`Sampling`] for the semantics of a zero temperature combined
+    /// with top-k or top-p.
+    pub fn sampling(&self) -> Sampling {
+        let temperature = self.temperature;
+        match (self.top_k, self.top_p) {
+            (None, None) if temperature == 0. => Sampling::ArgMax,
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+
+    /// A logits processor seeded with [`GenerationParams::seed`].
+    pub fn logits_processor(&self) -> LogitsProcessor {
+        LogitsProcessor::from_sampling(self.seed, self.sampling())
+    }
+
+    pub fn stop_criteria(&self) -> Vec<StopCriteria> {
+        let patterns = self.stop_token_patterns.iter();
+        patterns
+            .map(|p| StopCriteria::StopTokenPattern(p.clone()))
+            .collect()
+    }
+}
+
+/// Named sampling settings, these only set the sampling parameters and the other fields keep
+/// their default value.
+///
+/// | preset                | temperature | top_k | top_p | repeat_penalty | repeat_last_n |
+/// |-----------------------|-------------|-------|-------|----------------|---------------|
+/// | `greedy`              | 0           | -     | -     | 1              | 64            |
+/// | `llama-cpp-default`   | 0.8         | 40    | 0.95  | 1              | 64            |
+/// | `creative`            | 1.0         | -     | 0.95  | 1.1            | 64            |
+/// | `precise`             | 0.2         | 40    | 0.9   | 1.1            | 64            |
+///
+/// `llama-cpp-default` matches the defaults of the llama.cpp cli, except for its min-p filter
+/// which is not supported here, and is close to the ollama defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    Greedy,
+    LlamaCppDefault,
+    Creative,
+    Precise,
+}
+
+impl Preset {
+    pub const ALL: [Preset; 4] = [
+        Self::Greedy,
+        Self::LlamaCppDefault,
+        Self::Creative,
+        Self::Precise,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Greedy => "greedy",
+            Self::LlamaCppDefault => "llama-cpp-default",
+            Self::Creative => "creative",
+            Self::Precise => "precise",
+        }
+    }
+
+    pub fn params(&self) -> GenerationParams {
+        let params = GenerationParams {
+            top_k: None,
+            top_p: None,
+            ..GenerationParams::default()
+        };
+        match self {
+            Self::Greedy => params.with_temperature(0.).with_repeat_penalty(1., 64),
+            Self::LlamaCppDefault => params
+                .with_temperature(0.8)
+                .with_top_k(40)
+                .with_top_p(0.95)
+                .with_repeat_penalty(1., 64),
+            Self::Creative => params
+                .with_temperature(1.)
+                .with_top_p(0.95)
+                .with_repeat_penalty(1.1, 64),
+            Self::Precise => params
+                .with_temperature(0.2)
+                .with_top_k(40)
+                .with_top_p(0.9)
+                .with_repeat_penalty(1.1, 64),
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Preset {
+    type Err = candle::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match Self::ALL.iter().find(|p| p.name() == s) {
+            Some(preset) => Ok(*preset),
+            None => {
+                let names = Self::ALL.map(|p| p.name()).join(", ");
+                candle::bail!("unknown preset {s:?}, expected one of {names}")
+            }
+        }
+    }
+}
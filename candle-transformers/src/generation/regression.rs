@@ -0,0 +1,269 @@
+//! Run to run drift detection on the model outputs.
+//!
+//! [`record`] runs a fixed set of prompts through a model with greedy decoding and keeps, for
+//! each step, the argmax token and its logit. [`compare`] then checks a later run against such a
+//! recording, e.g. after a kernel or quantization change: it reports the first step where the
+//! argmax token changes, and the largest logit delta over the steps before that, where both runs
+//! still process the same tokens.
+//!
+//! Recordings are stored in a compact little endian binary file, see [`Recording::write`].
+use super::CausalLm;
+use candle::{DType, Device, Result, Tensor};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"CNDLREG1";
+
+/// One greedy decoding step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// The argmax token.
+    pub token: u32,
+    /// The logit of the argmax token.
+    pub logit: f32,
+}
+
+/// The greedy decoding of a prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptRecording {
+    pub prompt: Vec<u32>,
+    pub steps: Vec<Step>,
+}
+
+/// The greedy decodings of a set of prompts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Recording {
+    pub prompts: Vec<PromptRecording>,
+}
+
+impl Recording {
+    /// Writes the recording: the magic `CNDLREG1`, the number of prompts, then for each prompt the
+    /// number of prompt tokens, the tokens, the number of steps, and the `(token, logit)` pairs.
+    /// All the values are stored as 4 bytes little endian.
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<()> {
+        let len = |l: usize| (l as u32).to_le_bytes();
+        w.write_all(MAGIC)?;
+        w.write_all(&len(self.prompts.len()))?;
+        for prompt in self.prompts.iter() {
+            w.write_all(&len(prompt.prompt.len()))?;
+            for token in prompt.prompt.iter() {
+                w.write_all(&token.to_le_bytes())?
+            }
+            w.write_all(&len(prompt.steps.len()))?;
+            for step in prompt.steps.iter() {
+                w.write_all(&step.token.to_le_bytes())?;
+                w.write_all(&step.logit.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Self> {
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            candle::bail!("not a logits recording, unexpected magic {magic:?}")
+        }
+        let mut u32 = || -> Result<u32> {
+            let mut bytes = [0u8; 4];
+            r.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let n_prompts = u32()?;
+        let mut prompts = vec![];
+        for _ in 0..n_prompts {
+            let n_tokens = u32()?;
+            let prompt = (0..n_tokens).map(|_| u32()).collect::<Result<Vec<_>>>()?;
+            let n_steps = u32()?;
+            let mut steps = vec![];
+            for _ in 0..n_steps {
+                let token = u32()?;
+                let logit = f32::from_bits(u32()?);
+                steps.push(Step { token, logit })
+            }
+            prompts.push(PromptRecording { prompt, steps })
+        }
+        Ok(Self { prompts })
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        self.write(&mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = std::fs::File::open(path)?;
+        Self::read(&mut std::io::BufReader::new(file))
+            .map_err(|e| e.context(format!("reading {path:?}")))
+    }
+}
+
+/// Runs each prompt through `model` and decodes `steps` tokens greedily, the kv cache is reset
+/// between prompts.
+pub fn record<M: CausalLm>(
+    model: &mut M,
+    prompts: &[Vec<u32>],
+    steps: usize,
+    device: &Device,
+) -> Result<Recording> {
+    let mut recording = Recording::default();
+    for prompt in prompts.iter() {
+        if prompt.is_empty() {
+            candle::bail!("empty prompt")
+        }
+        model.truncate_kv_cache(0)?;
+        let mut input = prompt.clone();
+        let mut pos = 0;
+        let mut recorded = vec![];
+        for _ in 0..steps {
+            let xs = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+            let logits = model.forward(&xs, pos)?.squeeze(0)?.to_dtype(DType::F32)?;
+            let logits = logits.to_vec1::<f32>()?;
+            let (token, &logit) = logits
+                .iter()
+                .enumerate()
+                .max_by(|(_, u), (_, v)| u.total_cmp(v))
+                .ok_or_else(|| candle::Error::Msg("empty logits".to_string()))?;
+            recorded.push(Step {
+                token: token as u32,
+                logit,
+            });
+            pos += input.len();
+            input = vec![token as u32];
+        }
+        recording.prompts.push(PromptRecording {
+            prompt: prompt.clone(),
+            steps: recorded,
+        })
+    }
+    model.truncate_kv_cache(0)?;
+    Ok(recording)
+}
+
+/// The first step where the argmax token differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenChange {
+    pub step: usize,
+    pub baseline: u32,
+    pub current: u32,
+}
+
+/// How the run of a prompt differs from its recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PromptDiff {
+    pub token_change: Option<TokenChange>,
+    /// The largest absolute delta of the argmax logit over the steps before the token change,
+    /// and the step where it happens.
+    pub max_logit_delta: f32,
+    pub max_logit_delta_step: Option<usize>,
+}
+
+/// The outcome of [`compare`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub prompts: Vec<PromptDiff>,
+    /// The largest logit delta that is not reported as a divergence.
+    pub tolerance: f32,
+}
+
+impl Report {
+    /// Whether no argmax token changed and all the logit deltas are within the tolerance.
+    pub fn passed(&self) -> bool {
+        self.prompts
+            .iter()
+            .all(|p| p.token_change.is_none() && p.max_logit_delta <= self.tolerance)
+    }
+
+    /// The largest logit delta over all the prompts.
+    pub fn max_logit_delta(&self) -> f32 {
+        self.prompts
+            .iter()
+            .map(|p| p.max_logit_delta)
+            .fold(0., f32::max)
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, prompt) in self.prompts.iter().enumerate() {
+            write!(f, "prompt {index}: ")?;
+            match prompt.token_change {
+                None => write!(f, "same tokens")?,
+                Some(change) => write!(
+                    f,
+                    "token changed at step {}, {} -> {}",
+                    change.step, change.baseline, change.current
+                )?,
+            }
+            write!(f, ", max logit delta {:.6}", prompt.max_logit_delta)?;
+            if let Some(step) = prompt.max_logit_delta_step {
+                write!(f, " at step {step}")?
+            }
+            if prompt.max_logit_delta > self.tolerance {
+                write!(f, " (above the {} tolerance)", self.tolerance)?
+            }
+            writeln!(f)?
+        }
+        let status = if self.passed() { "passed" } else { "FAILED" };
+        write!(f, "{status}, max logit delta {:.6}", self.max_logit_delta())
+    }
+}
+
+/// Compares a run against a baseline recorded with the same prompts and number of steps.
+pub fn compare(baseline: &Recording, current: &Recording, tolerance: f32) -> Result<Report> {
+    if baseline.prompts.len() != current.prompts.len() {
+        candle::bail!(
+            "the baseline has {} prompts, the current run has {}",
+            baseline.prompts.len(),
+            current.prompts.len()
+        )
+    }
+    let mut prompts = vec![];
+    for (index, (b, c)) in baseline
+        .prompts
+        .iter()
+        .zip(current.prompts.iter())
+        .enumerate()
+    {
+        if b.prompt != c.prompt {
+            candle::bail!(
+                "prompt {index} differs from the baseline, the tokenizer may have changed"
+            )
+        }
+        if b.steps.len() != c.steps.len() {
+            candle::bail!(
+                "prompt {index} has {} steps in the baseline, {} in the current run",
+                b.steps.len(),
+                c.steps.len()
+            )
+        }
+        let mut diff = PromptDiff {
+            token_change: None,
+            max_logit_delta: 0.,
+            max_logit_delta_step: None,
+        };
+        for (step, (b, c)) in b.steps.iter().zip(c.steps.iter()).enumerate() {
+            if b.token != c.token {
+                diff.token_change = Some(TokenChange {
+                    step,
+                    baseline: b.token,
+                    current: c.token,
+                });
+                break;
+            }
+            // A NaN on either side is always above the tolerance.
+            let delta = match (b.logit - c.logit).abs() {
+                delta if delta.is_nan() => f32::INFINITY,
+                delta => delta,
+            };
+            if delta > diff.max_logit_delta {
+                diff.max_logit_delta = delta;
+                diff.max_logit_delta_step = Some(step)
+            }
+        }
+        prompts.push(diff)
+    }
+    Ok(Report { prompts, tolerance })
+}
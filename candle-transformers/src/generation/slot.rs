@@ -0,0 +1,355 @@
+//! Per-sequence sampling for batched generation.
+//!
+//! When several sequences are decoded together, each of them can come with its own sampling
+//! parameters. A [`SamplerSlot`] bundles the state needed to sample one sequence: the
+//! [`LogitsProcessor`] holding the sampling strategy and the random number generator, the
+//! repeat penalty state, and the stop conditions. [`sample_batch`] samples each row of the
+//! `(batch, vocab)` logits with the matching slot, the rows are extracted on device and the
+//! penalties and filters are then applied per row.
+//!
+//! [`generate_forked`] samples several completions of the same prompt, the prompt is only
+//! processed once and its kv cache is shared by the sequences.
+use super::{CausalLm, GenerationParams, LogitsProcessor, StopConditions, StopReason};
+use candle::{Device, Result, Tensor};
+use serde::{Deserialize, Serialize};
+
+/// The version of the [`PenaltySnapshot`] format, snapshots with another version are not
+/// restored.
+pub const PENALTY_SNAPSHOT_VERSION: u32 = 1;
+
+/// The recent tokens of a sequence, used to apply the repeat penalty.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PenaltyState {
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    tokens: Vec<u32>,
+}
+
+impl PenaltyState {
+    pub fn new(repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        Self {
+            repeat_penalty,
+            repeat_last_n,
+            tokens: vec![],
+        }
+    }
+
+    pub fn repeat_penalty(&self) -> f32 {
+        self.repeat_penalty
+    }
+
+    pub fn repeat_last_n(&self) -> usize {
+        self.repeat_last_n
+    }
+
+    /// The tokens in the penalty window, oldest first.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// The state after the tokens of `history`, only its last `repeat_last_n` tokens are read.
+    pub fn from_history(repeat_penalty: f32, repeat_last_n: usize, history: &[u32]) -> Self {
+        let mut state = Self::new(repeat_penalty, repeat_last_n);
+        state.extend(history);
+        state
+    }
+
+    /// The state to store next to a saved kv cache, see [`PenaltyState::restore`].
+    pub fn snapshot(&self) -> PenaltySnapshot {
+        PenaltySnapshot {
+            version: PENALTY_SNAPSHOT_VERSION,
+            repeat_penalty: self.repeat_penalty,
+            repeat_last_n: self.repeat_last_n,
+            tokens: self.tokens.clone(),
+        }
+    }
+
+    /// Restores the state saved after the tokens of `history` when it was taken with the same
+    /// version and penalty configuration, the saved tokens are used as is. Otherwise the state
+    /// is reconstructed from `history` and the reason is returned as a warning.
+    pub fn restore(
+        snapshot: &PenaltySnapshot,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        history: &[u32],
+    ) -> (Self, Option<String>) {
+        let mismatch = if snapshot.version != PENALTY_SNAPSHOT_VERSION {
+            Some(format!(
+                "penalty state version {} instead of {PENALTY_SNAPSHOT_VERSION}",
+                snapshot.version
+            ))
+        } else if snapshot.repeat_penalty != repeat_penalty
+            || snapshot.repeat_last_n != repeat_last_n
+        {
+            Some(format!(
+                "penalty state saved with repeat-penalty {} and repeat-last-n {}, now {repeat_penalty} and {repeat_last_n}",
+                snapshot.repeat_penalty, snapshot.repeat_last_n
+            ))
+        } else if snapshot.tokens.len() != history.len().min(repeat_last_n) {
+            Some(format!(
+                "penalty state with {} tokens for a history of {} tokens",
+                snapshot.tokens.len(),
+                history.len()
+            ))
+        } else {
+            None
+        };
+        match mismatch {
+            None => {
+                let state = Self {
+                    repeat_penalty,
+                    repeat_last_n,
+                    tokens: snapshot.tokens.clone(),
+                };
+                (state, None)
+            }
+            Some(mismatch) => {
+                let state = Self::from_history(repeat_penalty, repeat_last_n, history);
+                (
+                    state,
+                    Some(format!("{mismatch}, reconstructed from the history")),
+                )
+            }
+        }
+    }
+
+    pub fn push(&mut self, token: u32) {
+        self.extend(&[token])
+    }
+
+    pub fn extend(&mut self, tokens: &[u32]) {
+        // Only the tail of a long sequence can end up in the window.
+        let tokens = &tokens[tokens.len().saturating_sub(self.repeat_last_n)..];
+        self.tokens.extend_from_slice(tokens);
+        let len = self.tokens.len();
+        if len > self.repeat_last_n {
+            self.tokens.drain(..len - self.repeat_last_n);
+        }
+    }
+
+    /// Returns the penalized logits, or `None` if there is no penalty to apply.
+    pub fn apply(&self, logits: &Tensor) -> Result<Option<Tensor>> {
+        if self.repeat_penalty == 1. || self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let logits = crate::utils::apply_repeat_penalty(logits, self.repeat_penalty, &self.tokens)?;
+        Ok(Some(logits))
+    }
+}
+
+/// A serialized [`PenaltyState`], e.g. saved with a session so that resuming it does not scan
+/// the whole token history again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PenaltySnapshot {
+    pub version: u32,
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+    pub tokens: Vec<u32>,
+}
+
+/// The sampling state of one sequence in a batch.
+#[derive(Clone)]
+pub struct SamplerSlot {
+    logits_processor: LogitsProcessor,
+    penalty: PenaltyState,
+    stop: StopConditions,
+    stop_reason: Option<StopReason>,
+}
+
+impl SamplerSlot {
+    pub fn new(
+        logits_processor: LogitsProcessor,
+        penalty: PenaltyState,
+        stop: StopConditions,
+    ) -> Self {
+        Self {
+            logits_processor,
+            penalty,
+            stop,
+            stop_reason: None,
+        }
+    }
+
+    /// Builds a slot from the parameters of a request, `added_tokens` is used to resolve the
+    /// stop token patterns, see [`StopConditions::new`].
+    pub fn from_params<'a, I>(params: &GenerationParams, added_tokens: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (u32, &'a str)>,
+    {
+        params.validate()?;
+        let stop = StopConditions::new(&params.stop_criteria(), added_tokens)?;
+        Ok(Self::new(
+            params.logits_processor(),
+            PenaltyState::new(params.repeat_penalty, params.repeat_last_n),
+            stop,
+        ))
+    }
+
+    pub fn logits_processor(&self) -> &LogitsProcessor {
+        &self.logits_processor
+    }
+
+    pub fn penalty(&self) -> &PenaltyState {
+        &self.penalty
+    }
+
+    pub fn stop_conditions(&self) -> &StopConditions {
+        &self.stop
+    }
+
+    /// Adds tokens that were not sampled by this slot, e.g. the prompt, to the penalty window.
+    pub fn push_tokens(&mut self, tokens: &[u32]) {
+        self.penalty.extend(tokens)
+    }
+
+    /// Why the sequence stopped, `None` while it is still running.
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.stop_reason.is_some()
+    }
+
+    /// Checks the text based stop conditions on the text generated so far, returns true if the
+    /// sequence is finished.
+    pub fn check_text(&mut self, text: &str) -> bool {
+        if self.stop_reason.is_none() {
+            self.stop_reason = self.stop.check_text(text);
+        }
+        self.is_finished()
+    }
+
+    /// Samples the next token from the logits of this sequence, a 1d tensor over the vocabulary.
+    /// Returns `None` when the sampled token is a stop token, the slot is then finished.
+    pub fn sample(&mut self, logits: &Tensor) -> Result<Option<u32>> {
+        if self.is_finished() {
+            candle::bail!("sampling from a finished slot")
+        }
+        let token = match self.penalty.apply(logits)? {
+            None => self.logits_processor.sample(logits)?,
+            Some(penalized) => self
+                .logits_processor
+                .sample_with_unfiltered(&penalized, logits)?,
+        };
+        if let Some(reason) = self.stop.check_token(token) {
+            self.stop_reason = Some(reason);
+            return Ok(None);
+        }
+        self.penalty.push(token);
+        Ok(Some(token))
+    }
+}
+
+/// Samples one token per row of `logits`, a `(batch, vocab)` tensor, using the slot with the
+/// same index. Finished slots are skipped and get `None`, as do the slots that sample a stop
+/// token.
+pub fn sample_batch(logits: &Tensor, slots: &mut [SamplerSlot]) -> Result<Vec<Option<u32>>> {
+    let (batch, _vocab) = logits.dims2()?;
+    if batch != slots.len() {
+        candle::bail!(
+            "got logits for {batch} sequences but {} sampler slots",
+            slots.len()
+        )
+    }
+    slots
+        .iter_mut()
+        .enumerate()
+        .map(|(index, slot)| {
+            if slot.is_finished() {
+                Ok(None)
+            } else {
+                slot.sample(&logits.get(index)?)
+            }
+        })
+        .collect()
+}
+
+/// Generates one completion of `prompt` per slot, with up to `sample_len` tokens each. The prompt
+/// is processed once, the kv cache is then forked so that the completions share it and are
+/// decoded together in a batch. The first tokens are all sampled from the logits of the prompt.
+/// Returns the tokens generated for each slot, the stop tokens are not included.
+pub fn generate_forked<M: CausalLm>(
+    model: &mut M,
+    prompt: &[u32],
+    slots: &mut [SamplerSlot],
+    sample_len: usize,
+    device: &Device,
+) -> Result<Vec<Vec<u32>>> {
+    decode_forked(model, prompt, slots, sample_len, device, |_, _, _| Ok(()))
+}
+
+/// The loop of [`generate_forked`], `on_sample` is called with the slot index, the logits and
+/// the token for each sampled token, including the stop tokens.
+pub(super) fn decode_forked<M, F>(
+    model: &mut M,
+    prompt: &[u32],
+    slots: &mut [SamplerSlot],
+    sample_len: usize,
+    device: &Device,
+    mut on_sample: F,
+) -> Result<Vec<Vec<u32>>>
+where
+    M: CausalLm,
+    F: FnMut(usize, &Tensor, u32) -> Result<()>,
+{
+    let Some(&last_prompt_token) = prompt.last() else {
+        candle::bail!("empty prompt")
+    };
+    if slots.is_empty() {
+        candle::bail!("no sampler slots")
+    }
+    let mut generated = vec![vec![]; slots.len()];
+    if sample_len == 0 {
+        return Ok(generated);
+    }
+    let mut sample = |index: usize, slot: &mut SamplerSlot, logits: &Tensor| {
+        let token = slot.sample(logits)?;
+        let sampled = match (token, slot.stop_reason()) {
+            (Some(token), _) => Some(token),
+            (None, Some(StopReason::Token(token))) => Some(*token),
+            (None, _) => None,
+        };
+        if let Some(sampled) = sampled {
+            on_sample(index, logits, sampled)?
+        }
+        Ok::<_, candle::Error>(token)
+    };
+    let input = Tensor::new(prompt, device)?.unsqueeze(0)?;
+    let logits = model.forward(&input, 0)?.squeeze(0)?;
+    for (index, slot) in slots.iter_mut().enumerate() {
+        slot.push_tokens(prompt);
+        if let Some(token) = sample(index, slot, &logits)? {
+            generated[index].push(token)
+        }
+    }
+    model.fork_kv_cache(slots.len())?;
+    for index_pos in prompt.len()..prompt.len() + sample_len - 1 {
+        if slots.iter().all(|slot| slot.is_finished()) {
+            break;
+        }
+        // The finished sequences still need an input, their outputs are ignored.
+        let input = generated
+            .iter()
+            .map(|tokens| *tokens.last().unwrap_or(&last_prompt_token))
+            .collect::<Vec<_>>();
+        let input = Tensor::new(input, device)?.unsqueeze(1)?;
+        let logits = model.forward(&input, index_pos)?;
+        if logits.dim(0)? != slots.len() {
+            candle::bail!(
+                "got logits for {} sequences but {} sampler slots",
+                logits.dim(0)?,
+                slots.len()
+            )
+        }
+        for (index, slot) in slots.iter_mut().enumerate() {
+            if slot.is_finished() {
+                continue;
+            }
+            if let Some(token) = sample(index, slot, &logits.get(index)?)? {
+                generated[index].push(token)
+            }
+        }
+    }
+    Ok(generated)
+}
@@ -0,0 +1,209 @@
+//! Stopping criteria for text generation.
+//!
+//! Criteria are either based on the generated text, or on the generated token ids. Token based
+//! criteria are evaluated before the token gets detokenized so that the stopping token is never
+//! emitted, they take precedence over text based criteria when both would fire on the same step.
+use candle::{Error, Result};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopCriteria {
+    /// Stop when the generated text contains this string.
+    Sequence(String),
+    /// Stop when one of these token ids is sampled.
+    StopTokens(HashSet<u32>),
+    /// Stop on any added token whose content matches this regex, e.g. `^<\|tool_.*\|>$`. The
+    /// regex is resolved to token ids once when building [`StopConditions`].
+    StopTokenPattern(String),
+}
+
+/// Why the generation stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StopReason {
+    Token(u32),
+    Sequence(String),
+    /// The context size of the model has been reached.
+    Length,
+    /// The guardrail aborted the generation, with its reason, see [`super::guardrail`].
+    Guardrail(String),
+}
+
+/// A set of [`StopCriteria`] with the token patterns resolved to token ids.
+#[derive(Debug, Clone, Default)]
+pub struct StopConditions {
+    tokens: HashSet<u32>,
+    sequences: Vec<String>,
+}
+
+impl StopConditions {
+    /// Resolves the criteria, `added_tokens` lists the added tokens of the tokenizer together
+    /// with their content, these are the candidates for [`StopCriteria::StopTokenPattern`].
+    pub fn new<'a, I>(criteria: &[StopCriteria], added_tokens: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (u32, &'a str)>,
+    {
+        let mut tokens = HashSet::new();
+        let mut sequences = vec![];
+        let mut patterns = vec![];
+        for criteria in criteria.iter() {
+            match criteria {
+                StopCriteria::Sequence(s) => {
+                    if s.is_empty() {
+                        candle::bail!("empty stop sequence")
+                    }
+                    sequences.push(s.clone())
+                }
+                StopCriteria::StopTokens(ts) => tokens.extend(ts.iter().copied()),
+                StopCriteria::StopTokenPattern(p) => {
+                    let re = fancy_regex::Regex::new(p).map_err(Error::wrap)?;
+                    patterns.push(re)
+                }
+            }
+        }
+        if !patterns.is_empty() {
+            for (id, content) in added_tokens {
+                for re in patterns.iter() {
+                    if re.is_match(content).map_err(Error::wrap)? {
+                        tokens.insert(id);
+                    }
+                }
+            }
+        }
+        Ok(Self { tokens, sequences })
+    }
+
+    pub fn stop_tokens(&self) -> &HashSet<u32> {
+        &self.tokens
+    }
+
+    pub fn sequences(&self) -> &[String] {
+        &self.sequences
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty() && self.sequences.is_empty()
+    }
+
+    /// Checks the token based criteria, this should be called before `token` gets detokenized.
+    pub fn check_token(&self, token: u32) -> Option<StopReason> {
+        self.tokens
+            .contains(&token)
+            .then_some(StopReason::Token(token))
+    }
+
+    /// Checks the text based criteria on the text generated so far.
+    pub fn check_text(&self, text: &str) -> Option<StopReason> {
+        self.sequences
+            .iter()
+            .find(|s| text.contains(s.as_str()))
+            .map(|s| StopReason::Sequence(s.clone()))
+    }
+
+    /// Checks all the criteria for a newly sampled `token`, `text` is the generated text
+    /// including this token. Token based criteria take precedence.
+    pub fn check(&self, token: u32, text: &str) -> Option<StopReason> {
+        self.check_token(token).or_else(|| self.check_text(text))
+    }
+}
+
+/// Incremental matching of stop sequences on streamed text.
+///
+/// The text is pushed as it gets decoded and only the part that cannot be the start of a stop
+/// sequence is released, the rest is held back until it either completes a stop sequence or
+/// diverges from all of them. When a stop sequence matches, the released text ends right before
+/// the match. The result is the same as truncating the full text at the first occurrence of any
+/// of the stop sequences, however the text is split into chunks.
+#[derive(Debug, Clone)]
+pub struct StopStringMatcher {
+    sequences: Vec<String>,
+    // The text that has not been released yet, it starts at a position from which a stop
+    // sequence may still match.
+    pending: String,
+    stop_reason: Option<StopReason>,
+}
+
+impl StopStringMatcher {
+    pub fn new(sequences: &[String]) -> Result<Self> {
+        if sequences.iter().any(|s| s.is_empty()) {
+            candle::bail!("empty stop sequence")
+        }
+        Ok(Self {
+            sequences: sequences.to_vec(),
+            pending: String::new(),
+            stop_reason: None,
+        })
+    }
+
+    /// Why the text stopped, this is always a [`StopReason::Sequence`].
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    /// The text held back so far.
+    pub fn pending(&self) -> &str {
+        &self.pending
+    }
+
+    /// Adds some text and returns the part of the text that can be released. Once a stop
+    /// sequence has matched, the following calls return an empty string.
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stop_reason.is_some() {
+            return String::new();
+        }
+        self.pending.push_str(text);
+        // The earliest complete match, and the earliest position from which a stop sequence
+        // could still match with more text.
+        let mut matched: Option<(usize, &String)> = None;
+        let mut partial = None;
+        for (pos, _) in self.pending.char_indices() {
+            if matched.is_some_and(|(m, _)| m <= pos) {
+                break;
+            }
+            let rest = &self.pending[pos..];
+            for s in self.sequences.iter() {
+                if rest.starts_with(s.as_str()) {
+                    matched = Some((pos, s));
+                    break;
+                }
+                if partial.is_none() && s.starts_with(rest) {
+                    partial = Some(pos)
+                }
+            }
+        }
+        match (matched, partial) {
+            (Some((pos, s)), partial) if partial.is_none_or(|p| p >= pos) => {
+                self.stop_reason = Some(StopReason::Sequence(s.clone()));
+                let mut released = std::mem::take(&mut self.pending);
+                released.truncate(pos);
+                released
+            }
+            (matched, partial) => {
+                let keep_from = partial.or(matched.map(|(m, _)| m));
+                let keep_from = keep_from.unwrap_or(self.pending.len());
+                let pending = self.pending.split_off(keep_from);
+                std::mem::replace(&mut self.pending, pending)
+            }
+        }
+    }
+
+    /// Ends the text, returns the text that was still held back truncated at a stop sequence if
+    /// one has matched.
+    pub fn finish(&mut self) -> String {
+        if self.stop_reason.is_some() {
+            return String::new();
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let matched = self
+            .sequences
+            .iter()
+            .filter_map(|s| pending.find(s.as_str()).map(|pos| (pos, s)))
+            .min_by_key(|(pos, _)| *pos);
+        match matched {
+            None => pending,
+            Some((pos, s)) => {
+                self.stop_reason = Some(StopReason::Sequence(s.clone()));
+                pending[..pos].to_string()
+            }
+        }
+    }
+}
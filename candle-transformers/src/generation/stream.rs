@@ -0,0 +1,89 @@
+//! Streaming the generated text.
+//!
+//! Decoding the tokens one at a time does not work in general: a character can be split over
+//! several tokens and some decoders handle the spaces depending on the surrounding tokens.
+//! [`TextStream`] decodes a sliding window of tokens and only releases text once it ends with a
+//! complete character, the released text is then passed through a [`StopStringMatcher`] so that
+//! no text past a stop sequence is ever released.
+use super::{StopReason, StopStringMatcher};
+use candle::Result;
+
+/// Turns a stream of tokens into a stream of text, see the module documentation.
+pub struct TextStream<D> {
+    decode: D,
+    tokens: Vec<u32>,
+    prefix_index: usize,
+    read_index: usize,
+    matcher: StopStringMatcher,
+}
+
+impl<D: FnMut(&[u32]) -> Result<String>> TextStream<D> {
+    /// `decode` returns the text for some tokens, `stop_sequences` are the stop sequences to
+    /// look for in the text.
+    pub fn new(decode: D, stop_sequences: &[String]) -> Result<Self> {
+        Ok(Self {
+            decode,
+            tokens: vec![],
+            prefix_index: 0,
+            read_index: 0,
+            matcher: StopStringMatcher::new(stop_sequences)?,
+        })
+    }
+
+    /// The matched stop sequence, once the text has reached one.
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.matcher.stop_reason()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stop_reason().is_some()
+    }
+
+    // The text decoded since the last release, or `None` if it ends with an incomplete
+    // character.
+    fn new_text(&mut self) -> Result<Option<String>> {
+        let prefix = (self.decode)(&self.tokens[self.prefix_index..self.read_index])?;
+        let text = (self.decode)(&self.tokens[self.prefix_index..])?;
+        if text.ends_with(char::REPLACEMENT_CHARACTER) {
+            return Ok(None);
+        }
+        match text.get(prefix.len()..) {
+            Some(new_text) if !new_text.is_empty() => Ok(Some(new_text.to_string())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Adds a token, returns the text that can be released, possibly empty.
+    pub fn push(&mut self, token: u32) -> Result<String> {
+        if self.is_stopped() {
+            return Ok(String::new());
+        }
+        self.tokens.push(token);
+        match self.new_text()? {
+            None => Ok(String::new()),
+            Some(text) => {
+                self.prefix_index = self.read_index;
+                self.read_index = self.tokens.len();
+                Ok(self.matcher.push(&text))
+            }
+        }
+    }
+
+    /// Ends the stream and returns the remaining text. A trailing incomplete character is
+    /// released as is.
+    pub fn finish(&mut self) -> Result<String> {
+        if self.is_stopped() {
+            return Ok(String::new());
+        }
+        let prefix = (self.decode)(&self.tokens[self.prefix_index..self.read_index])?;
+        let text = (self.decode)(&self.tokens[self.prefix_index..])?;
+        self.prefix_index = self.tokens.len();
+        self.read_index = self.tokens.len();
+        let mut released = match text.get(prefix.len()..) {
+            Some(new_text) => self.matcher.push(new_text),
+            None => String::new(),
+        };
+        released.push_str(&self.matcher.finish());
+        Ok(released)
+    }
+}
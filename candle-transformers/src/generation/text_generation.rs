@@ -0,0 +1,468 @@
+//! Multi-turn text generation with checkpoints at the user turn boundaries.
+use super::guardrail::{Guardrail, GuardrailDecision, GuardrailHook, GuardrailStats};
+use super::{CausalLm, GenerationParams, LogitsProcessor, StopConditions, StopReason, TextStream};
+use candle::{Device, Result, Tensor};
+use std::collections::HashSet;
+
+/// The state of a [`TextGeneration`] right before the model starts answering a prompt.
+///
+/// Rolling back to a checkpoint truncates the kv cache to the positions it had at that point
+/// and restores the sampler, so that the answer can be regenerated, possibly with different
+/// sampling parameters. This is not possible anymore once [`ContextOverflow::Rotate`] has
+/// rotated the context past the checkpoint.
+#[derive(Clone)]
+pub struct TurnCheckpoint {
+    kv_len: usize,
+    // The number of context rotations before this checkpoint.
+    rotations: usize,
+    next_logits: Tensor,
+    logits_processor: LogitsProcessor,
+}
+
+impl TurnCheckpoint {
+    /// The number of tokens in the kv cache at this checkpoint.
+    pub fn kv_len(&self) -> usize {
+        self.kv_len
+    }
+}
+
+/// What to do when the conversation reaches the context size of the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ContextOverflow {
+    /// Stop the generation with [`StopReason::Length`].
+    #[default]
+    Stop,
+    /// Keep the first `sink_tokens` tokens of the conversation, drop the oldest half of the
+    /// following ones and process the remaining tokens again at their new positions. The
+    /// generation then continues over this sliding window.
+    Rotate { sink_tokens: usize },
+}
+
+// Returns the text of some tokens.
+type Decode<'a> = dyn FnMut(&[u32]) -> Result<String> + 'a;
+
+/// Drives a [`CausalLm`] over a multi-turn conversation, keeping the kv cache between turns.
+pub struct TextGeneration<M: CausalLm> {
+    model: M,
+    device: Device,
+    logits_processor: LogitsProcessor,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    // The tokens that have been processed by the model, i.e. the kv cache content.
+    tokens: Vec<u32>,
+    // Sampled or forced tokens, not yet processed by the model.
+    pending: Vec<u32>,
+    next_logits: Option<Tensor>,
+    context_overflow: ContextOverflow,
+    // The number of times the context has been rotated, the positions of the kv cache change
+    // on each rotation.
+    rotations: usize,
+    stop_reason: Option<StopReason>,
+    guardrail: Option<Guardrail>,
+}
+
+impl<M: CausalLm> TextGeneration<M> {
+    pub fn new(model: M, logits_processor: LogitsProcessor, device: &Device) -> Self {
+        Self {
+            model,
+            device: device.clone(),
+            logits_processor,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            tokens: vec![],
+            pending: vec![],
+            next_logits: None,
+            context_overflow: ContextOverflow::Stop,
+            rotations: 0,
+            stop_reason: None,
+            guardrail: None,
+        }
+    }
+
+    /// Builds the generation from validated parameters: the seed, the sampling strategy and the
+    /// repeat penalty. The token budget and the stop token patterns are not used here, they are
+    /// passed to [`TextGeneration::generate`].
+    pub fn from_params(model: M, params: &GenerationParams, device: &Device) -> Result<Self> {
+        params.validate()?;
+        let generation = Self::new(model, params.logits_processor(), device);
+        Ok(generation.with_repeat_penalty(params.repeat_penalty, params.repeat_last_n))
+    }
+
+    /// Penalizes the tokens that appear in the last `repeat_last_n` tokens of the conversation,
+    /// a penalty of 1 disables this.
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+
+    pub fn with_context_overflow(mut self, context_overflow: ContextOverflow) -> Self {
+        self.context_overflow = context_overflow;
+        self
+    }
+
+    /// Checks each token sampled by [`Self::generate_text`] with `hook`, a step gets at most
+    /// `max_resamples` more samples when the hook asks for them before the generation is
+    /// aborted, see [`super::guardrail`].
+    pub fn with_guardrail(
+        mut self,
+        hook: impl GuardrailHook + 'static,
+        max_resamples: usize,
+    ) -> Self {
+        self.guardrail = Some(Guardrail {
+            hook: Box::new(hook),
+            max_resamples,
+            stats: GuardrailStats::default(),
+        });
+        self
+    }
+
+    /// The activity of the guardrail so far, `None` if there is no guardrail.
+    pub fn guardrail_stats(&self) -> Option<GuardrailStats> {
+        self.guardrail.as_ref().map(|g| g.stats)
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn into_inner(self) -> M {
+        self.model
+    }
+
+    pub fn logits_processor(&self) -> &LogitsProcessor {
+        &self.logits_processor
+    }
+
+    pub fn set_logits_processor(&mut self, logits_processor: LogitsProcessor) {
+        self.logits_processor = logits_processor
+    }
+
+    /// Why the last call to [`Self::generate`] stopped, `None` if it produced all the requested
+    /// tokens.
+    pub fn stop_reason(&self) -> Option<&StopReason> {
+        self.stop_reason.as_ref()
+    }
+
+    /// The conversation so far, including the sampled and forced tokens that have not been
+    /// processed by the model yet. The tokens dropped by [`ContextOverflow::Rotate`] are not
+    /// included.
+    pub fn tokens(&self) -> Vec<u32> {
+        [self.tokens.as_slice(), self.pending.as_slice()].concat()
+    }
+
+    /// Processes the tokens of a new user turn and returns the checkpoint from which the answer
+    /// will be generated.
+    pub fn push_prompt(&mut self, prompt: &[u32]) -> Result<TurnCheckpoint> {
+        if prompt.is_empty() && self.pending.is_empty() {
+            candle::bail!("empty prompt")
+        }
+        self.pending.extend_from_slice(prompt);
+        let logits = self.logits()?;
+        Ok(TurnCheckpoint {
+            kv_len: self.tokens.len(),
+            rotations: self.rotations,
+            next_logits: logits,
+            logits_processor: self.logits_processor.clone(),
+        })
+    }
+
+    /// Appends tokens to the conversation without sampling them, they get processed by the
+    /// model together with the next sampling step.
+    pub fn force_tokens(&mut self, tokens: &[u32]) {
+        self.pending.extend_from_slice(tokens);
+        if !tokens.is_empty() {
+            self.next_logits = None
+        }
+    }
+
+    // Whether the pending tokens fit in the context of the model.
+    fn fits_context(&self) -> bool {
+        match self.model.max_seq_len() {
+            None => true,
+            Some(max_seq_len) => self.tokens.len() + self.pending.len() <= max_seq_len,
+        }
+    }
+
+    // Makes room in the context by dropping the oldest tokens past the sink tokens, the kept
+    // tokens are queued again so that they get processed at their new positions.
+    fn rotate(&mut self, sink_tokens: usize) -> Result<()> {
+        let max_seq_len = self.model.max_seq_len().unwrap_or(usize::MAX);
+        if sink_tokens >= max_seq_len / 2 {
+            candle::bail!(
+                "cannot rotate the context with {sink_tokens} sink tokens for a context of {max_seq_len}"
+            )
+        }
+        let tokens = self.tokens();
+        let sink_tokens = sink_tokens.min(tokens.len());
+        let window = tokens.len() - sink_tokens;
+        let keep_from = tokens.len() - window / 2;
+        let kv_len = sink_tokens.min(self.tokens.len());
+        self.model.truncate_kv_cache(kv_len)?;
+        self.tokens.truncate(kv_len);
+        self.pending = [&tokens[kv_len..sink_tokens], &tokens[keep_from..]].concat();
+        self.next_logits = None;
+        self.rotations += 1;
+        Ok(())
+    }
+
+    // Returns the logits for the next position, processing the pending tokens if needed.
+    fn logits(&mut self) -> Result<Tensor> {
+        if !self.fits_context() {
+            match self.context_overflow {
+                ContextOverflow::Stop => candle::bail!(
+                    "the context size of the model has been reached, {} tokens",
+                    self.tokens.len() + self.pending.len()
+                ),
+                ContextOverflow::Rotate { sink_tokens } => self.rotate(sink_tokens)?,
+            }
+        }
+        if !self.pending.is_empty() {
+            let xs = Tensor::new(self.pending.as_slice(), &self.device)?.unsqueeze(0)?;
+            let logits = self.model.forward(&xs, self.tokens.len())?.squeeze(0)?;
+            self.tokens.append(&mut self.pending);
+            self.next_logits = Some(logits);
+        }
+        match self.next_logits.as_ref() {
+            None => candle::bail!("no prompt to generate from"),
+            Some(logits) => Ok(logits.clone()),
+        }
+    }
+
+    /// Samples the next token, restricted to `allowed` when specified. The token is appended
+    /// to the conversation.
+    pub fn sample_next(&mut self, allowed: Option<&HashSet<u32>>) -> Result<u32> {
+        let next_token = self.sample_token(allowed, &HashSet::new())?;
+        self.force_tokens(&[next_token]);
+        Ok(next_token)
+    }
+
+    // Samples the next token without appending it to the conversation, so that it can be
+    // sampled again from the same logits.
+    fn sample_token(
+        &mut self,
+        allowed: Option<&HashSet<u32>>,
+        banned: &HashSet<u32>,
+    ) -> Result<u32> {
+        let logits = self.logits()?;
+        let logits = match allowed {
+            None => logits,
+            Some(allowed) => super::constraint::mask_logits(&logits, allowed)?,
+        };
+        let logits = match banned.is_empty() {
+            true => logits,
+            false => super::guardrail::ban_logits(&logits, banned)?,
+        };
+        let next_token = if self.repeat_penalty == 1. {
+            self.logits_processor.sample(&logits)?
+        } else {
+            let start_at = self.tokens.len().saturating_sub(self.repeat_last_n);
+            let penalized = crate::utils::apply_repeat_penalty(
+                &logits,
+                self.repeat_penalty,
+                &self.tokens[start_at..],
+            )?;
+            self.logits_processor
+                .sample_with_unfiltered(&penalized, &logits)?
+        };
+        Ok(next_token)
+    }
+
+    // Samples the next token and runs it through the guardrail, `decode` gives the text of the
+    // generated tokens. Returns the reason of the abort when no token is accepted.
+    fn sample_guarded(
+        &mut self,
+        guardrail: &mut Guardrail,
+        stop: &StopConditions,
+        generated: &[u32],
+        decode: &mut Decode<'_>,
+    ) -> Result<std::result::Result<u32, String>> {
+        let mut banned = HashSet::new();
+        let mut tokens = generated.to_vec();
+        for attempt in 0..=guardrail.max_resamples {
+            let token = self.sample_token(None, &banned)?;
+            // The stop tokens are never emitted.
+            if stop.check_token(token).is_some() {
+                return Ok(Ok(token));
+            }
+            tokens.push(token);
+            guardrail.stats.checks += 1;
+            match guardrail.hook.check(token, &decode(&tokens)?)? {
+                GuardrailDecision::Allow => return Ok(Ok(token)),
+                GuardrailDecision::Abort(reason) => return Ok(Err(reason)),
+                GuardrailDecision::Resample(ban) => {
+                    if attempt < guardrail.max_resamples {
+                        guardrail.stats.resamples += 1;
+                    }
+                    banned.extend(ban);
+                    tokens.pop();
+                }
+            }
+        }
+        Ok(Err(format!(
+            "the guardrail rejected {} samples in a row",
+            guardrail.max_resamples + 1
+        )))
+    }
+
+    /// Generates up to `sample_len` tokens following the last prompt, `on_token` is called on
+    /// each of them as soon as it is sampled. Returns the generated tokens, a token matching
+    /// the stop conditions ends the generation and is not included in the result. Reaching the
+    /// context size of the model either ends the generation with [`StopReason::Length`] or
+    /// rotates the context, depending on [`ContextOverflow`].
+    pub fn generate(
+        &mut self,
+        sample_len: usize,
+        stop: &StopConditions,
+        mut on_token: impl FnMut(u32) -> Result<()>,
+    ) -> Result<Vec<u32>> {
+        if self.guardrail.is_some() {
+            candle::bail!("the guardrail checks the decoded text, use generate_text")
+        }
+        self.generate_until(sample_len, stop, None, |token| {
+            on_token(token)?;
+            Ok(None)
+        })
+    }
+
+    // The loop of `generate`, `on_token` can end the generation by returning a stop reason. The
+    // guardrail, if any, is used when `decode` is provided.
+    fn generate_until(
+        &mut self,
+        sample_len: usize,
+        stop: &StopConditions,
+        decode: Option<&mut Decode<'_>>,
+        on_token: impl FnMut(u32) -> Result<Option<StopReason>>,
+    ) -> Result<Vec<u32>> {
+        // The guardrail is put back even when the generation fails.
+        let mut guardrail = self.guardrail.take();
+        let res = self.generate_loop(sample_len, stop, guardrail.as_mut().zip(decode), on_token);
+        self.guardrail = guardrail;
+        res
+    }
+
+    fn generate_loop(
+        &mut self,
+        sample_len: usize,
+        stop: &StopConditions,
+        mut guardrail: Option<(&mut Guardrail, &mut Decode<'_>)>,
+        mut on_token: impl FnMut(u32) -> Result<Option<StopReason>>,
+    ) -> Result<Vec<u32>> {
+        self.stop_reason = None;
+        let mut generated = Vec::with_capacity(sample_len);
+        while generated.len() < sample_len {
+            if !self.fits_context() && self.context_overflow == ContextOverflow::Stop {
+                self.stop_reason = Some(StopReason::Length);
+                break;
+            }
+            let next_token = match guardrail.as_mut() {
+                None => self.sample_token(None, &HashSet::new())?,
+                Some((guardrail, decode)) => {
+                    match self.sample_guarded(guardrail, stop, &generated, *decode)? {
+                        Ok(token) => token,
+                        Err(reason) => {
+                            guardrail.stats.aborts += 1;
+                            self.stop_reason = Some(StopReason::Guardrail(reason));
+                            break;
+                        }
+                    }
+                }
+            };
+            self.force_tokens(&[next_token]);
+            if let Some(reason) = stop.check_token(next_token) {
+                self.stop_reason = Some(reason);
+                break;
+            }
+            generated.push(next_token);
+            if let Some(reason) = on_token(next_token)? {
+                self.stop_reason = Some(reason);
+                break;
+            }
+        }
+        Ok(generated)
+    }
+
+    /// Generates like [`Self::generate`] and streams the decoded text to `on_text`, `decode`
+    /// returns the text of some tokens. The text based stop conditions are checked
+    /// incrementally, the text is released up to the start of the first stop sequence and the
+    /// generation then ends with [`StopReason::Sequence`]. Returns the generated tokens, these
+    /// include the tokens of the matched stop sequence.
+    pub fn generate_text<D>(
+        &mut self,
+        sample_len: usize,
+        stop: &StopConditions,
+        decode: D,
+        mut on_text: impl FnMut(&str) -> Result<()>,
+    ) -> Result<Vec<u32>>
+    where
+        D: FnMut(&[u32]) -> Result<String>,
+    {
+        // The stream and the guardrail decode at different times.
+        let decode = std::cell::RefCell::new(decode);
+        let mut stream = TextStream::new(|t: &[u32]| (decode.borrow_mut())(t), stop.sequences())?;
+        let mut guardrail_decode = |t: &[u32]| (decode.borrow_mut())(t);
+        let decode: Option<&mut Decode<'_>> = match self.guardrail {
+            None => None,
+            Some(_) => Some(&mut guardrail_decode),
+        };
+        let generated = self.generate_until(sample_len, stop, decode, |token| {
+            let text = stream.push(token)?;
+            if !text.is_empty() {
+                on_text(&text)?
+            }
+            Ok(stream.stop_reason().cloned())
+        })?;
+        // The text held back by the stream is released when the generation ends for another
+        // reason, it can still be truncated at a stop sequence.
+        if !matches!(self.stop_reason, Some(StopReason::Sequence(_))) {
+            let text = stream.finish()?;
+            if !text.is_empty() {
+                on_text(&text)?
+            }
+            if let Some(reason) = stream.stop_reason() {
+                self.stop_reason = Some(reason.clone())
+            }
+        }
+        Ok(generated)
+    }
+
+    /// Drops the conversation past its first `len` tokens, e.g. to reuse the kv cache for a new
+    /// prompt that shares a prefix with the conversation.
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.tokens.len() + self.pending.len() {
+            return Ok(());
+        }
+        let kv_len = len.min(self.tokens.len());
+        if kv_len < self.tokens.len() {
+            self.model.truncate_kv_cache(kv_len)?;
+            self.tokens.truncate(kv_len);
+        }
+        self.pending.truncate(len - kv_len);
+        self.next_logits = None;
+        Ok(())
+    }
+
+    /// Restores the state at `checkpoint`, dropping everything that has been generated since.
+    pub fn rollback_to(&mut self, checkpoint: &TurnCheckpoint) -> Result<()> {
+        if checkpoint.rotations != self.rotations {
+            candle::bail!("the context has been rotated since the checkpoint")
+        }
+        if checkpoint.kv_len > self.tokens.len() {
+            candle::bail!(
+                "checkpoint at {} is past the current position {}",
+                checkpoint.kv_len,
+                self.tokens.len()
+            )
+        }
+        self.model.truncate_kv_cache(checkpoint.kv_len)?;
+        self.tokens.truncate(checkpoint.kv_len);
+        self.pending.clear();
+        self.next_logits = Some(checkpoint.next_logits.clone());
+        self.logits_processor = checkpoint.logits_processor.clone();
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+//! Kv cache sizing, and the context length that fits in a memory budget.
+//!
+//! Each layer keeps a key and a value vector of `n_kv_head * head_dim` elements per position, so
+//! the kv cache grows linearly with the context length. [`KvCacheConfig`] reads these sizes from
+//! the gguf metadata and [`MemoryEstimate`] adds the size of the weights from the gguf header.
+//! The pre-load memory check and the [`ContextBudget`] negotiation both go through
+//! [`MemoryEstimate`] so that they agree on the numbers.
+//!
+//! The token embeddings are dequantized to f32 at load time unless they are kept quantized, see
+//! [`quantized_embeddings`]. For the 128256 x 4096 table of llama-3-8b, this is 2GiB on top of
+//! the weights.
+//!
+//! The activations of a forward pass are transient but can be much larger than the kv cache for
+//! a long prompt: the attention scores alone take `n_head * seq_len^2` elements per layer.
+//! [`ActivationConfig`] estimates their peak and [`ActivationConfig::plan_prefill`] picks how to
+//! process a prompt so that this peak fits in the free memory.
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Result};
+use serde::{Deserialize, Serialize};
+
+/// The shape of the kv cache of a model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvCacheConfig {
+    pub n_layer: usize,
+    pub n_kv_head: usize,
+    pub head_dim: usize,
+    /// The dtype of the cached keys and values.
+    pub dtype: DType,
+}
+
+impl KvCacheConfig {
+    /// Reads the layer and head counts from the `<arch>.*` metadata, `head_count_kv` defaults to
+    /// `head_count` and the head dimension to `embedding_length / head_count` when the gguf has no
+    /// `key_length`.
+    pub fn from_gguf(ct: &gguf_file::Content, dtype: DType) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        let arch = match ct.metadata.get("general.architecture") {
+            None => "llama",
+            Some(arch) => arch.to_string()?.as_str(),
+        };
+        let n_layer = md_get(&format!("{arch}.block_count"))?.to_u32()? as usize;
+        let head_count = md_get(&format!("{arch}.attention.head_count"))?.to_u32()? as usize;
+        let n_kv_head = match ct.metadata.get(&format!("{arch}.attention.head_count_kv")) {
+            None => head_count,
+            Some(v) => v.to_u32()? as usize,
+        };
+        let head_dim = match ct.metadata.get(&format!("{arch}.attention.key_length")) {
+            Some(v) => v.to_u32()? as usize,
+            None => {
+                let embedding_length =
+                    md_get(&format!("{arch}.embedding_length"))?.to_u32()? as usize;
+                embedding_length / head_count.max(1)
+            }
+        };
+        Ok(Self {
+            n_layer,
+            n_kv_head,
+            head_dim,
+            dtype,
+        })
+    }
+
+    /// The size of the keys and values of a single position over all the layers.
+    pub fn bytes_per_token(&self) -> u64 {
+        (2 * self.n_layer * self.n_kv_head * self.head_dim * self.dtype.size_in_bytes()) as u64
+    }
+
+    /// The size of the kv cache for `context_length` positions.
+    pub fn bytes(&self, context_length: usize) -> u64 {
+        self.bytes_per_token() * context_length as u64
+    }
+
+    /// The longest context whose kv cache fits in `budget_bytes`.
+    pub fn max_context_length(&self, budget_bytes: u64) -> usize {
+        match self.bytes_per_token() {
+            0 => usize::MAX,
+            per_token => (budget_bytes / per_token).try_into().unwrap_or(usize::MAX),
+        }
+    }
+}
+
+/// The size of the tensors listed in a gguf header, as stored in the file.
+pub fn weights_bytes(ct: &gguf_file::Content) -> u64 {
+    ct.tensor_infos
+        .values()
+        .map(|info| {
+            let dtype = info.ggml_dtype;
+            (info.shape.elem_count() * dtype.type_size() / dtype.block_size()) as u64
+        })
+        .sum()
+}
+
+/// The size of the dequantized token embeddings above which they are kept quantized by
+/// default, see [`quantized_embeddings`].
+pub const QUANTIZED_EMBEDDINGS_MIN_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The size of the token embeddings, `token_embd.weight`, once dequantized to f32. This is 0 when
+/// the gguf has no such tensor.
+pub fn dense_embeddings_bytes(ct: &gguf_file::Content) -> u64 {
+    match ct.tensor_infos.get("token_embd.weight") {
+        None => 0,
+        Some(info) => (info.shape.elem_count() * DType::F32.size_in_bytes()) as u64,
+    }
+}
+
+/// Whether the token embeddings are kept quantized, with the rows dequantized on lookup, rather
+/// than dequantized at load time. `requested` wins when set, otherwise the embeddings are kept
+/// quantized when their dequantization takes more than [`QUANTIZED_EMBEDDINGS_MIN_BYTES`]. The
+/// lookups are not supported on metal.
+pub fn quantized_embeddings(dense_bytes: u64, requested: Option<bool>, device: &Device) -> bool {
+    requested.unwrap_or(!device.is_metal() && dense_bytes > QUANTIZED_EMBEDDINGS_MIN_BYTES)
+}
+
+/// The memory taken by a model: its weights plus a kv cache that depends on the context length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryEstimate {
+    pub weights_bytes: u64,
+    /// The size of the token embeddings dequantized to f32, see [`dense_embeddings_bytes`].
+    pub dense_embeddings_bytes: u64,
+    /// Whether the token embeddings stay quantized, they only take their size in the file then.
+    pub quantized_embeddings: bool,
+    pub kv: KvCacheConfig,
+}
+
+impl MemoryEstimate {
+    /// The estimate for a model loaded on `device` with the default embeddings, see
+    /// [`quantized_embeddings`] and [`Self::with_quantized_embeddings`] to change it.
+    pub fn from_gguf(ct: &gguf_file::Content, kv_dtype: DType, device: &Device) -> Result<Self> {
+        let dense_embeddings_bytes = dense_embeddings_bytes(ct);
+        Ok(Self {
+            weights_bytes: weights_bytes(ct),
+            dense_embeddings_bytes,
+            quantized_embeddings: quantized_embeddings(dense_embeddings_bytes, None, device),
+            kv: KvCacheConfig::from_gguf(ct, kv_dtype)?,
+        })
+    }
+
+    pub fn with_quantized_embeddings(mut self, quantized_embeddings: bool) -> Self {
+        self.quantized_embeddings = quantized_embeddings;
+        self
+    }
+
+    /// The memory taken by the dequantized token embeddings on top of the weights, 0 when they
+    /// stay quantized.
+    pub fn embeddings_bytes(&self) -> u64 {
+        match self.quantized_embeddings {
+            true => 0,
+            false => self.dense_embeddings_bytes,
+        }
+    }
+
+    /// The memory saved by keeping the token embeddings quantized.
+    pub fn saved_embeddings_bytes(&self) -> u64 {
+        self.dense_embeddings_bytes - self.embeddings_bytes()
+    }
+
+    /// The memory used with a context of `context_length` positions.
+    pub fn total_bytes(&self, context_length: usize) -> u64 {
+        self.loaded_bytes() + self.kv.bytes(context_length)
+    }
+
+    /// What is left of `free_bytes` for the kv cache once the weights are loaded and
+    /// `reserve_bytes` are kept aside for the activations and the allocator.
+    pub fn kv_budget(&self, free_bytes: u64, reserve_bytes: u64) -> u64 {
+        free_bytes.saturating_sub(self.loaded_bytes() + reserve_bytes)
+    }
+
+    fn loaded_bytes(&self) -> u64 {
+        self.weights_bytes + self.embeddings_bytes()
+    }
+
+    /// Checks that the weights and the kv cache of a single position fit in `free_bytes`, this is
+    /// meant to run before the weights are loaded.
+    pub fn check(&self, free_bytes: u64, reserve_bytes: u64) -> Result<()> {
+        let needed = self.total_bytes(1) + reserve_bytes;
+        if needed > free_bytes {
+            candle::bail!(
+                "the model needs {} ({} of weights, {} reserved) but only {} of memory is free",
+                format_bytes(needed),
+                format_bytes(self.loaded_bytes()),
+                format_bytes(reserve_bytes),
+                format_bytes(free_bytes),
+            )
+        }
+        Ok(())
+    }
+}
+
+/// The sizes that drive the activation memory of a forward pass.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ActivationConfig {
+    pub embedding_length: usize,
+    pub feed_forward_length: usize,
+    pub n_head: usize,
+    pub n_kv_head: usize,
+    pub head_dim: usize,
+    pub vocab_size: usize,
+    /// The number of elements of the largest weight matrix, the gpu kernels dequantize a weight
+    /// to f32 when multiplying it with many rows.
+    pub max_weight_elems: usize,
+}
+
+impl ActivationConfig {
+    /// Reads the sizes from the `<arch>.*` metadata and the tensor shapes, with the same defaults
+    /// as [`KvCacheConfig::from_gguf`]. The vocabulary size is the number of rows of
+    /// `token_embd.weight`.
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        let arch = match ct.metadata.get("general.architecture") {
+            None => "llama",
+            Some(arch) => arch.to_string()?.as_str(),
+        };
+        let kv = KvCacheConfig::from_gguf(ct, DType::F32)?;
+        let embedding_length = md_get(&format!("{arch}.embedding_length"))?.to_u32()? as usize;
+        let feed_forward_length =
+            md_get(&format!("{arch}.feed_forward_length"))?.to_u32()? as usize;
+        let n_head = md_get(&format!("{arch}.attention.head_count"))?.to_u32()? as usize;
+        let vocab_size = match ct.tensor_infos.get("token_embd.weight") {
+            None => candle::bail!("cannot find token_embd.weight in the gguf tensors"),
+            Some(info) => info.shape.dims()[0],
+        };
+        let max_weight_elems = ct
+            .tensor_infos
+            .values()
+            .filter(|info| info.shape.rank() == 2)
+            .map(|info| info.shape.elem_count())
+            .max()
+            .unwrap_or(0);
+        Ok(Self {
+            embedding_length,
+            feed_forward_length,
+            n_head,
+            n_kv_head: kv.n_kv_head,
+            head_dim: kv.head_dim,
+            vocab_size,
+            max_weight_elems,
+        })
+    }
+
+    /// An upper bound of the transient memory of a forward pass on `seq_len` positions that
+    /// follow `past` cached ones, for a batch of one with f32 activations. The kv cache itself
+    /// is not included.
+    ///
+    /// The layers run one after the other, so the peak is the residual stream plus the largest
+    /// of the attention and the mlp stages of a single layer. The attention stage is dominated by
+    /// the scores of shape `(n_head, seq_len, past + seq_len)`, of which the matmul, the scaling,
+    /// the mask, and the softmax each make a copy.
+    pub fn prefill_bytes(&self, seq_len: usize, past: usize) -> u64 {
+        let (seq, total) = (seq_len as u64, (past + seq_len) as u64);
+        let emb = self.embedding_length as u64;
+        let ffn = self.feed_forward_length as u64;
+        let n_head = self.n_head as u64;
+        let q_dim = n_head * self.head_dim as u64;
+        let kv_dim = (self.n_kv_head * self.head_dim) as u64;
+        let residual = 2 * seq * emb;
+        let attention = seq * (q_dim + 2 * kv_dim) // q, k, and v
+            + seq * (q_dim + kv_dim) // the rotary embeddings
+            + 2 * total * kv_dim // the cache concatenation
+            + 3 * total * q_dim // the repeated and contiguous keys and values
+            + 2 * seq * emb // the attention output and its projection
+            + 4 * n_head * seq * total; // the scores
+        let mlp = seq * emb + 4 * seq * ffn + seq * emb;
+        let weights = match seq_len {
+            1 => 0,
+            _ => self.max_weight_elems as u64,
+        };
+        let elems = residual + attention.max(mlp) + weights + self.vocab_size as u64;
+        // The causal mask is one byte per score position.
+        4 * elems + seq * total
+    }
+
+    /// Picks how to process a prompt of `prompt_len` tokens given the free memory of the device,
+    /// `None` when it is unknown. The prompt is processed in a single forward pass when its peak
+    /// fits, otherwise in the largest chunks that fit, down to a token at a time. The last chunk
+    /// attends to the whole prompt so it is the one that gets checked.
+    pub fn plan_prefill(&self, prompt_len: usize, free_bytes: Option<u64>) -> PrefillPlan {
+        let single_shot_bytes = self.prefill_bytes(prompt_len, 0);
+        let fits = |chunk: usize| match free_bytes {
+            None => true,
+            Some(free) => self.prefill_bytes(chunk, prompt_len - chunk) <= free,
+        };
+        let strategy = if prompt_len <= 1 || fits(prompt_len) {
+            PrefillStrategy::SingleShot
+        } else if !fits(2) {
+            PrefillStrategy::TokenByToken
+        } else {
+            // The peak grows with the chunk size, find the largest chunk that fits.
+            let (mut lo, mut hi) = (2, prompt_len);
+            while hi - lo > 1 {
+                let mid = lo + (hi - lo) / 2;
+                if fits(mid) {
+                    lo = mid
+                } else {
+                    hi = mid
+                }
+            }
+            PrefillStrategy::Chunked { chunk_size: lo }
+        };
+        PrefillPlan {
+            prompt_len,
+            free_bytes,
+            single_shot_bytes,
+            strategy,
+        }
+    }
+}
+
+/// How the tokens of a prompt are fed to the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PrefillStrategy {
+    /// The whole prompt in one forward pass.
+    SingleShot,
+    /// Forward passes of `chunk_size` tokens, the last one can be shorter.
+    Chunked { chunk_size: usize },
+    /// One forward pass per token, with the smallest peak.
+    TokenByToken,
+}
+
+impl PrefillStrategy {
+    /// The number of tokens of each forward pass for a prompt of `prompt_len` tokens.
+    pub fn chunk_size(&self, prompt_len: usize) -> usize {
+        match self {
+            Self::SingleShot => prompt_len.max(1),
+            Self::Chunked { chunk_size } => (*chunk_size).max(1),
+            Self::TokenByToken => 1,
+        }
+    }
+}
+
+impl std::fmt::Display for PrefillStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SingleShot => write!(f, "single-shot"),
+            Self::Chunked { chunk_size } => write!(f, "in chunks of {chunk_size} tokens"),
+            Self::TokenByToken => write!(f, "token by token"),
+        }
+    }
+}
+
+/// The outcome of [`ActivationConfig::plan_prefill`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrefillPlan {
+    pub prompt_len: usize,
+    pub free_bytes: Option<u64>,
+    /// The estimated peak of a single-shot prefill.
+    pub single_shot_bytes: u64,
+    pub strategy: PrefillStrategy,
+}
+
+impl std::fmt::Display for PrefillPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "prefill {}: a single pass over the {} prompt tokens needs about {}",
+            self.strategy,
+            self.prompt_len,
+            format_bytes(self.single_shot_bytes)
+        )?;
+        match self.free_bytes {
+            None => write!(f, ", the free memory is unknown"),
+            Some(free) => write!(f, " and {} is free", format_bytes(free)),
+        }
+    }
+}
+
+/// The context length negotiated between what was requested and what the kv cache budget allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextBudget {
+    pub requested: usize,
+    /// The memory available to the kv cache, `None` when it is not limited.
+    pub budget_bytes: Option<u64>,
+    pub kv_bytes_per_token: u64,
+    /// The longest context that fits in the budget.
+    pub max_supported: Option<usize>,
+    /// The context length to use, the requested one capped to `max_supported`.
+    pub effective: usize,
+    /// The size of the kv cache for the effective context length.
+    pub kv_bytes: u64,
+}
+
+impl ContextBudget {
+    /// Caps `requested` so that the kv cache fits in `budget_bytes`, this fails if the budget
+    /// cannot hold a single position.
+    pub fn negotiate(
+        kv: &KvCacheConfig,
+        requested: usize,
+        budget_bytes: Option<u64>,
+    ) -> Result<Self> {
+        let max_supported = budget_bytes.map(|budget| kv.max_context_length(budget));
+        if max_supported == Some(0) {
+            candle::bail!(
+                "the kv cache budget of {} cannot hold a single position, each takes {}",
+                format_bytes(budget_bytes.unwrap_or_default()),
+                format_bytes(kv.bytes_per_token()),
+            )
+        }
+        let effective = requested.min(max_supported.unwrap_or(usize::MAX));
+        Ok(Self {
+            requested,
+            budget_bytes,
+            kv_bytes_per_token: kv.bytes_per_token(),
+            max_supported,
+            effective,
+            kv_bytes: kv.bytes(effective),
+        })
+    }
+
+    /// Whether the budget made the context shorter than requested.
+    pub fn is_capped(&self) -> bool {
+        self.effective < self.requested
+    }
+}
+
+impl std::fmt::Display for ContextBudget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_capped() {
+            write!(
+                f,
+                "context length capped from {} to {}: the kv cache takes {} per position and its budget is {}",
+                self.requested,
+                self.effective,
+                format_bytes(self.kv_bytes_per_token),
+                format_bytes(self.budget_bytes.unwrap_or_default()),
+            )
+        } else {
+            write!(
+                f,
+                "context length {}, kv cache of {}",
+                self.effective,
+                format_bytes(self.kv_bytes)
+            )
+        }
+    }
+}
+
+pub(crate) fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes}B");
+    }
+    let mut size = bytes as f64 / 1024.;
+    let mut unit = 0;
+    while size >= 1024. && unit + 1 < UNITS.len() {
+        size /= 1024.;
+        unit += 1;
+    }
+    format!("{size:.2}{}", UNITS[unit])
+}
@@ -0,0 +1,169 @@
+//! Changing the quantization type of some tensors when loading a model.
+//!
+//! This is meant for quality/speed experiments without regenerating the gguf files, e.g. keeping
+//! the first and last transformer blocks in a higher precision. A [`LayerDTypeOverride`] is a
+//! list of rules mapping a selection of tensors to a [`GgmlDType`], a selection is either a
+//! range of layers, matching the `blk.{layer}.*` tensors, or a glob on the tensor names where `*`
+//! matches any sequence of characters and `?` a single character. When several rules match a
+//! tensor the last one wins.
+//!
+//! The rules can be parsed from a comma separated list of `selection:dtype`, e.g.
+//! `*:q4_0,0-1:f16,30-31:f16,output.weight:q8_0`.
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{Device, Result};
+use std::sync::Arc;
+
+/// The tensors targeted by a rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TensorSelector {
+    /// The tensors of the layers in this inclusive range.
+    Layers { first: usize, last: usize },
+    /// The tensors whose name matches this glob.
+    Glob(String),
+}
+
+impl TensorSelector {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Self::Layers { first, last } => match layer_index(name) {
+                Some(layer) => *first <= layer && layer <= *last,
+                None => false,
+            },
+            Self::Glob(glob) => glob_match(glob.as_bytes(), name.as_bytes()),
+        }
+    }
+}
+
+impl std::str::FromStr for TensorSelector {
+    type Err = candle::Error;
+
+    /// Parses a layer index `3`, a layer range `0-1`, or otherwise a glob.
+    fn from_str(s: &str) -> Result<Self> {
+        let is_range = !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit() || b == b'-');
+        if !is_range {
+            if s.is_empty() {
+                candle::bail!("empty tensor selection")
+            }
+            return Ok(Self::Glob(s.to_string()));
+        }
+        let parse = |v: &str| match v.parse::<usize>() {
+            Ok(v) => Ok(v),
+            Err(_) => candle::bail!("invalid layer range {s:?}"),
+        };
+        let (first, last) = match s.split_once('-') {
+            None => (parse(s)?, parse(s)?),
+            Some((first, last)) => (parse(first)?, parse(last)?),
+        };
+        if first > last {
+            candle::bail!("invalid layer range {s:?}, {first} is after {last}")
+        }
+        Ok(Self::Layers { first, last })
+    }
+}
+
+/// The layer index of a `blk.{layer}.*` tensor.
+pub fn layer_index(name: &str) -> Option<usize> {
+    let rest = name.strip_prefix("blk.")?;
+    let (layer, _) = rest.split_once('.')?;
+    layer.parse().ok()
+}
+
+fn glob_match(glob: &[u8], name: &[u8]) -> bool {
+    match glob.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+        Some((&c, rest)) => match name.split_first() {
+            Some((&n, name)) if c == b'?' || c == n => glob_match(rest, name),
+            _ => false,
+        },
+    }
+}
+
+/// Parses a ggml dtype name such as `f16`, `q4_0`, or `q4k` (also accepted as `q4_k`), the case
+/// is ignored.
+pub fn parse_ggml_dtype(s: &str) -> Result<GgmlDType> {
+    let dtype = match s.to_lowercase().replace("_k", "k").as_str() {
+        "f32" => GgmlDType::F32,
+        "f16" => GgmlDType::F16,
+        "bf16" => GgmlDType::BF16,
+        "q4_0" => GgmlDType::Q4_0,
+        "q4_1" => GgmlDType::Q4_1,
+        "q5_0" => GgmlDType::Q5_0,
+        "q5_1" => GgmlDType::Q5_1,
+        "q8_0" => GgmlDType::Q8_0,
+        "q8_1" => GgmlDType::Q8_1,
+        "q2k" => GgmlDType::Q2K,
+        "q3k" => GgmlDType::Q3K,
+        "q4k" => GgmlDType::Q4K,
+        "q5k" => GgmlDType::Q5K,
+        "q6k" => GgmlDType::Q6K,
+        "q8k" => GgmlDType::Q8K,
+        _ => candle::bail!("unknown ggml dtype {s:?}"),
+    };
+    Ok(dtype)
+}
+
+/// The per-tensor dtype overrides applied when loading a model.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LayerDTypeOverride {
+    rules: Vec<(TensorSelector, GgmlDType)>,
+}
+
+impl LayerDTypeOverride {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, it takes precedence over the rules added before.
+    pub fn with_rule(mut self, selector: TensorSelector, dtype: GgmlDType) -> Self {
+        self.rules.push((selector, dtype));
+        self
+    }
+
+    pub fn rules(&self) -> &[(TensorSelector, GgmlDType)] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The dtype requested for the tensor `name`, `None` if no rule matches it.
+    pub fn dtype_for(&self, name: &str) -> Option<GgmlDType> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(selector, _)| selector.matches(name))
+            .map(|(_, dtype)| *dtype)
+    }
+
+    /// Returns the tensor `name` in the requested dtype, the tensor is dequantized and quantized
+    /// again when the dtype changes and returned as is otherwise.
+    pub fn apply(&self, name: &str, tensor: Arc<QTensor>, device: &Device) -> Result<Arc<QTensor>> {
+        match self.dtype_for(name) {
+            Some(dtype) if dtype != tensor.dtype() => {
+                let tensor = tensor.dequantize(device)?;
+                let tensor = QTensor::quantize(&tensor, dtype)
+                    .map_err(|e| e.context(format!("converting {name} to {dtype:?}")))?;
+                Ok(Arc::new(tensor))
+            }
+            _ => Ok(tensor),
+        }
+    }
+}
+
+impl std::str::FromStr for LayerDTypeOverride {
+    type Err = candle::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut overrides = Self::new();
+        for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+            let Some((selector, dtype)) = rule.rsplit_once(':') else {
+                candle::bail!("expected selection:dtype, got {rule:?}")
+            };
+            overrides =
+                overrides.with_rule(selector.trim().parse()?, parse_ggml_dtype(dtype.trim())?);
+        }
+        Ok(overrides)
+    }
+}
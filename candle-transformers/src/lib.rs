@@ -1,7 +1,40 @@
+pub mod bucketing;
+#[cfg(feature = "generation")]
 pub mod generation;
+pub mod kv_budget;
+pub mod layer_dtype;
+#[cfg(feature = "generation")]
+pub mod manifest;
 pub mod models;
 pub mod object_detection;
+#[cfg(feature = "models")]
 pub mod pipelines;
+pub mod prompt_lint;
+#[cfg(feature = "quantized-llama")]
 pub mod quantized_nn;
+#[cfg(feature = "quantized-llama")]
 pub mod quantized_var_builder;
+#[cfg(feature = "quantized-llama")]
+pub mod self_test;
+#[cfg(feature = "test-support")]
+pub mod test_support;
 pub mod utils;
+#[cfg(all(feature = "generation", feature = "quantized-llama"))]
+pub mod validation;
+pub mod vocab_pruning;
+
+/// Registers the custom ops of this crate and of `candle-nn` with [`candle::op_registry`], so
+/// that the `op` field of the `custom-op` tracing spans can be looked up.
+pub fn register_custom_ops() -> candle::Result<()> {
+    candle_nn::register_custom_ops()?;
+    #[cfg(feature = "models")]
+    {
+        use candle::op_registry::register_all;
+
+        register_all(&models::deepseek2::CUSTOM_OPS)?;
+        register_all(&models::encodec::CUSTOM_OPS)?;
+        register_all(&models::mimi::quantization::CUSTOM_OPS)?;
+        register_all(&models::segment_anything::image_encoder::CUSTOM_OPS)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,164 @@
+//! Machine readable description of a run, for reproducibility and auditing.
+//!
+//! A [`Manifest`] records the checkpoint that was loaded (hash, size, `general.*` metadata and
+//! tensor dtypes), how candle was built, the device, the generation settings,
+//! and the context length negotiated with the kv cache budget.
+use crate::generation::GenerationParams;
+use crate::kv_budget::ContextBudget;
+use candle::quantized::gguf_file;
+use candle::{Device, DeviceLocation, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The checkpoint file and the device it is loaded on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckpointInfo {
+    pub path: Option<String>,
+    pub file_size: u64,
+    pub sha256: String,
+    pub device: String,
+}
+
+impl CheckpointInfo {
+    /// Hashes the file at `path`, this reads the whole file.
+    pub fn from_path<P: AsRef<std::path::Path>>(path: P, device: &Device) -> Result<Self> {
+        use sha2::Digest;
+
+        let path = path.as_ref();
+        let mut file = std::fs::File::open(path).map_err(|e| Error::from(e).with_path(path))?;
+        let mut hasher = sha2::Sha256::new();
+        let file_size = std::io::copy(&mut file, &mut hasher)?;
+        let sha256 = hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        Ok(Self {
+            path: Some(path.display().to_string()),
+            file_size,
+            sha256,
+            device: device_description(device),
+        })
+    }
+}
+
+pub fn device_description(device: &Device) -> String {
+    match device.location() {
+        DeviceLocation::Cpu => "cpu".to_string(),
+        DeviceLocation::Cuda { gpu_id } => format!("cuda:{gpu_id}"),
+        DeviceLocation::Metal { gpu_id } => format!("metal:{gpu_id}"),
+    }
+}
+
+/// How candle was built.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub features: Vec<String>,
+    /// Set from the `CANDLE_GIT_HASH` environment variable at build time, if available.
+    pub git_hash: Option<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        let features = [
+            ("accelerate", cfg!(feature = "accelerate")),
+            ("cuda", cfg!(feature = "cuda")),
+            ("cudnn", cfg!(feature = "cudnn")),
+            ("flash-attn", cfg!(feature = "flash-attn")),
+            ("metal", cfg!(feature = "metal")),
+            ("mkl", cfg!(feature = "mkl")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            features: features
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| name.to_string())
+                .collect(),
+            git_hash: option_env!("CANDLE_GIT_HASH").map(|s| s.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModelManifest {
+    #[serde(flatten)]
+    pub checkpoint: CheckpointInfo,
+    pub gguf_version: u32,
+    /// The `general.*` metadata entries.
+    pub metadata: BTreeMap<String, serde_json::Value>,
+    pub tensor_count: usize,
+    pub parameter_count: usize,
+    /// Number of tensors per dtype.
+    pub tensor_dtypes: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub model: ModelManifest,
+    pub build: BuildInfo,
+    pub generation: GenerationParams,
+    /// The negotiated context length, when a kv cache budget was applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<ContextBudget>,
+}
+
+fn to_json(value: &gguf_file::Value) -> serde_json::Value {
+    use gguf_file::Value;
+    match value {
+        Value::U8(v) => (*v).into(),
+        Value::I8(v) => (*v).into(),
+        Value::U16(v) => (*v).into(),
+        Value::I16(v) => (*v).into(),
+        Value::U32(v) => (*v).into(),
+        Value::I32(v) => (*v).into(),
+        Value::U64(v) => (*v).into(),
+        Value::I64(v) => (*v).into(),
+        Value::F32(v) => (*v).into(),
+        Value::F64(v) => (*v).into(),
+        Value::Bool(v) => (*v).into(),
+        Value::String(v) => v.as_str().into(),
+        Value::Array(v) => v.iter().map(to_json).collect(),
+    }
+}
+
+pub fn build(
+    content: &gguf_file::Content,
+    model: &CheckpointInfo,
+    build: &BuildInfo,
+    generation: &GenerationParams,
+) -> Manifest {
+    let metadata = content
+        .metadata
+        .iter()
+        .filter(|(k, _)| k.starts_with("general."))
+        .map(|(k, v)| (k.clone(), to_json(v)))
+        .collect();
+    let mut tensor_dtypes = BTreeMap::new();
+    let mut parameter_count = 0;
+    for info in content.tensor_infos.values() {
+        *tensor_dtypes
+            .entry(format!("{:?}", info.ggml_dtype))
+            .or_default() += 1;
+        parameter_count += info.shape.elem_count();
+    }
+    let gguf_version = match content.magic {
+        gguf_file::VersionedMagic::GgufV1 => 1,
+        gguf_file::VersionedMagic::GgufV2 => 2,
+        gguf_file::VersionedMagic::GgufV3 => 3,
+    };
+    Manifest {
+        model: ModelManifest {
+            checkpoint: model.clone(),
+            gguf_version,
+            metadata,
+            tensor_count: content.tensor_infos.len(),
+            parameter_count,
+            tensor_dtypes,
+        },
+        build: build.clone(),
+        generation: generation.clone(),
+        context: None,
+    }
+}
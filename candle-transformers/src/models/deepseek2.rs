@@ -10,6 +10,13 @@ use candle_nn::{embedding, rms_norm, Activation, Embedding, Linear, Module, RmsN
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::Deserialize;
 
+pub(crate) const CUSTOM_OPS: [candle::op_registry::CustomOpDescriptor; 1] =
+    [candle::op_registry::CustomOpDescriptor {
+        name: "nonzero",
+        params: "",
+        source: module_path!(),
+    }];
+
 struct NonZero {}
 
 impl NonZero {
@@ -175,11 +175,18 @@ pub fn conv_transpose1d_weight_norm(
     Ok(ConvTranspose1d::new(weight, bias, config))
 }
 
+pub(crate) const CUSTOM_OPS: [candle::op_registry::CustomOpDescriptor; 1] =
+    [candle::op_registry::CustomOpDescriptor {
+        name: "encodec-codebook-encode",
+        params: "",
+        source: module_path!(),
+    }];
+
 struct CodebookEncode;
 
 impl candle::CustomOp2 for CodebookEncode {
     fn name(&self) -> &'static str {
-        "cb"
+        "encodec-codebook-encode"
     }
 
     fn cpu_fwd(
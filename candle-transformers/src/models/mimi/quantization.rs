@@ -5,11 +5,18 @@
 use candle::{IndexOp, Layout, Result, Shape, Tensor, D};
 use candle_nn::{linear, Linear, VarBuilder};
 
+pub(crate) const CUSTOM_OPS: [candle::op_registry::CustomOpDescriptor; 1] =
+    [candle::op_registry::CustomOpDescriptor {
+        name: "mimi-codebook-encode",
+        params: "",
+        source: module_path!(),
+    }];
+
 struct CodebookEncode;
 
 impl candle::CustomOp2 for CodebookEncode {
     fn name(&self) -> &'static str {
-        "cb"
+        "mimi-codebook-encode"
     }
 
     fn cpu_fwd(
@@ -14,112 +14,221 @@
 //! The implementations aim to be readable while maintaining good performance. For more information
 //! on each model see the model's module docs in the links below.
 
+#[cfg(feature = "models")]
 pub mod based;
+#[cfg(feature = "models")]
 pub mod beit;
+#[cfg(feature = "models")]
 pub mod bert;
+#[cfg(feature = "models")]
 pub mod bigcode;
+#[cfg(feature = "models")]
 pub mod blip;
+#[cfg(feature = "models")]
 pub mod blip_text;
+#[cfg(feature = "models")]
 pub mod chatglm;
+#[cfg(feature = "models")]
 pub mod chinese_clip;
+#[cfg(feature = "models")]
 pub mod clip;
+#[cfg(feature = "models")]
 pub mod codegeex4_9b;
+#[cfg(feature = "models")]
 pub mod colpali;
+#[cfg(feature = "models")]
 pub mod convmixer;
+#[cfg(feature = "models")]
 pub mod convnext;
+#[cfg(feature = "models")]
 pub mod csm;
+#[cfg(feature = "models")]
 pub mod dac;
+#[cfg(feature = "models")]
 pub mod debertav2;
+#[cfg(feature = "models")]
 pub mod deepseek2;
+#[cfg(feature = "models")]
 pub mod depth_anything_v2;
+#[cfg(feature = "models")]
 pub mod dinov2;
+#[cfg(feature = "models")]
 pub mod dinov2reg4;
+#[cfg(feature = "models")]
 pub mod distilbert;
+#[cfg(feature = "models")]
 pub mod efficientnet;
+#[cfg(feature = "models")]
 pub mod efficientvit;
+#[cfg(feature = "models")]
 pub mod encodec;
+#[cfg(feature = "models")]
 pub mod eva2;
+#[cfg(feature = "models")]
 pub mod falcon;
+#[cfg(feature = "models")]
 pub mod fastvit;
+#[cfg(feature = "models")]
 pub mod flux;
+#[cfg(feature = "models")]
 pub mod gemma;
+#[cfg(feature = "models")]
 pub mod gemma2;
+#[cfg(feature = "models")]
 pub mod gemma3;
+#[cfg(feature = "models")]
 pub mod glm4;
+#[cfg(feature = "models")]
 pub mod granite;
+#[cfg(feature = "models")]
 pub mod helium;
+#[cfg(feature = "models")]
 pub mod hiera;
+#[cfg(feature = "models")]
 pub mod jina_bert;
+#[cfg(feature = "models")]
 pub mod llama;
+#[cfg(feature = "models")]
 pub mod llama2_c;
+#[cfg(feature = "models")]
 pub mod llama2_c_weights;
+#[cfg(feature = "models")]
 pub mod llava;
+#[cfg(feature = "models")]
 pub mod mamba;
+#[cfg(feature = "models")]
 pub mod marian;
+#[cfg(feature = "models")]
 pub mod metavoice;
+#[cfg(feature = "models")]
 pub mod mimi;
+#[cfg(feature = "models")]
 pub mod mistral;
+#[cfg(feature = "models")]
 pub mod mixformer;
+#[cfg(feature = "models")]
 pub mod mixtral;
+#[cfg(feature = "models")]
 pub mod mmdit;
+#[cfg(feature = "models")]
 pub mod mobileclip;
+#[cfg(feature = "models")]
 pub mod mobilenetv4;
+#[cfg(feature = "models")]
 pub mod mobileone;
+#[cfg(feature = "models")]
 pub mod modernbert;
+#[cfg(feature = "models")]
 pub mod moondream;
+#[cfg(feature = "models")]
 pub mod mpt;
+#[cfg(feature = "models")]
 pub mod nvembed_v2;
+#[cfg(feature = "models")]
 pub mod olmo;
+#[cfg(feature = "models")]
 pub mod olmo2;
+#[cfg(feature = "models")]
 pub mod openclip;
+#[cfg(feature = "models")]
 pub mod paligemma;
+#[cfg(feature = "models")]
 pub mod parler_tts;
+#[cfg(feature = "models")]
 pub mod persimmon;
+#[cfg(feature = "models")]
 pub mod phi;
+#[cfg(feature = "models")]
 pub mod phi3;
+#[cfg(feature = "models")]
 pub mod pixtral;
+#[cfg(feature = "models")]
 pub mod quantized_blip;
+#[cfg(feature = "models")]
 pub mod quantized_blip_text;
+#[cfg(feature = "models")]
 pub mod quantized_gemma3;
+#[cfg(feature = "quantized-llama")]
 pub mod quantized_llama;
+#[cfg(feature = "models")]
 pub mod quantized_llama2_c;
+#[cfg(feature = "models")]
 pub mod quantized_metavoice;
+#[cfg(feature = "models")]
 pub mod quantized_mistral;
+#[cfg(feature = "models")]
 pub mod quantized_mixformer;
+#[cfg(feature = "models")]
 pub mod quantized_moondream;
+#[cfg(feature = "models")]
 pub mod quantized_mpt;
+#[cfg(feature = "models")]
 pub mod quantized_phi;
+#[cfg(feature = "models")]
 pub mod quantized_phi3;
+#[cfg(feature = "models")]
 pub mod quantized_qwen2;
+#[cfg(feature = "models")]
 pub mod quantized_qwen3;
+#[cfg(feature = "models")]
 pub mod quantized_recurrent_gemma;
+#[cfg(feature = "models")]
 pub mod quantized_rwkv_v5;
+#[cfg(feature = "models")]
 pub mod quantized_rwkv_v6;
+#[cfg(feature = "models")]
 pub mod quantized_stable_lm;
+#[cfg(feature = "models")]
 pub mod quantized_t5;
+#[cfg(feature = "models")]
 pub mod qwen2;
+#[cfg(feature = "models")]
 pub mod qwen2_moe;
+#[cfg(feature = "models")]
 pub mod qwen3;
+#[cfg(feature = "models")]
 pub mod qwen3_moe;
+#[cfg(feature = "models")]
 pub mod recurrent_gemma;
+#[cfg(feature = "models")]
 pub mod repvgg;
+#[cfg(feature = "models")]
 pub mod resnet;
+#[cfg(feature = "models")]
 pub mod rwkv_v5;
+#[cfg(feature = "models")]
 pub mod rwkv_v6;
+#[cfg(feature = "models")]
 pub mod segformer;
+#[cfg(feature = "models")]
 pub mod segment_anything;
+#[cfg(feature = "models")]
 pub mod siglip;
+#[cfg(feature = "models")]
 pub mod snac;
+#[cfg(feature = "models")]
 pub mod stable_diffusion;
+#[cfg(feature = "models")]
 pub mod stable_lm;
+#[cfg(feature = "models")]
 pub mod starcoder2;
+#[cfg(feature = "models")]
 pub mod stella_en_v5;
+#[cfg(feature = "models")]
 pub mod t5;
+#[cfg(feature = "models")]
 pub mod trocr;
+#[cfg(feature = "models")]
 pub mod vgg;
+#[cfg(feature = "models")]
 pub mod vit;
+#[cfg(feature = "models")]
 pub mod whisper;
+#[cfg(feature = "quantized-llama")]
 pub mod with_tracing;
+#[cfg(feature = "models")]
 pub mod wuerstchen;
+#[cfg(feature = "models")]
 pub mod xlm_roberta;
+#[cfg(feature = "models")]
 pub mod yi;
@@ -9,6 +9,9 @@
 //! - Support for 2/3/4/8-bit quantization
 //! - Optimized memory usage through quantization
 //! - Configurable model sizes and parameter counts
+//! - Falcon architecture files, with the parallel attention and MLP residual layout
+//! - Forking the kv cache after the prompt to sample several completions in a batch
+//! - Sharing the storage of the tensors whose data is duplicated in the gguf file
 //!
 //! - 💻 [GH Link](https://github.com/facebookresearch/llama)
 //! - 📝 [Paper](https://arxiv.org/abs/2302.13971)
@@ -16,13 +19,15 @@
 //! ![](https://raw.githubusercontent.com/huggingface/candle/main/candle-examples/examples/quantized/assets/aoc.gif)
 //!
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
+use crate::layer_dtype::LayerDTypeOverride;
 use crate::quantized_nn::RmsNorm;
-use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
-use candle::{DType, Device, IndexOp, Result, Tensor};
-use candle_nn::{Embedding, Module};
+use candle::quantized::{GgmlDType, KernelSelection, QTensor};
+use candle::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_nn::{Embedding, LayerNorm, Module};
 
 pub const MAX_SEQ_LEN: usize = 4096;
 
@@ -34,8 +39,8 @@ struct QMatMul {
 }
 
 impl QMatMul {
-    fn from_qtensor(qtensor: QTensor) -> Result<Self> {
-        let inner = candle::quantized::QMatMul::from_qtensor(qtensor)?;
+    fn from_arc(qtensor: Arc<QTensor>) -> Result<Self> {
+        let inner = candle::quantized::QMatMul::from_arc(qtensor)?;
         let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
         Ok(Self { inner, span })
     }
@@ -44,6 +49,36 @@ impl QMatMul {
         let _enter = self.span.enter();
         self.inner.forward(xs)
     }
+
+    fn kernel_use(&self, name: String) -> KernelUse {
+        let (shape, dtype) = match &self.inner {
+            candle::quantized::QMatMul::QTensor(t) => (t.shape().clone(), t.dtype()),
+            candle::quantized::QMatMul::Tensor(t) | candle::quantized::QMatMul::TensorF16(t) => {
+                let dtype = match t.dtype() {
+                    DType::F16 => GgmlDType::F16,
+                    DType::BF16 => GgmlDType::BF16,
+                    _ => GgmlDType::F32,
+                };
+                (t.shape().clone(), dtype)
+            }
+        };
+        KernelUse {
+            name,
+            shape,
+            dtype,
+            kernels: self.inner.kernels(),
+        }
+    }
+}
+
+/// The kernels used by a weight matrix, see [`ModelWeights::kernel_report`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelUse {
+    /// The name of the weight in gguf files.
+    pub name: String,
+    pub shape: candle::Shape,
+    pub dtype: GgmlDType,
+    pub kernels: KernelSelection,
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +88,20 @@ struct Mlp {
     feed_forward_w3: QMatMul,
 }
 
+impl Mlp {
+    fn new(
+        gate: impl Into<Arc<QTensor>>,
+        down: impl Into<Arc<QTensor>>,
+        up: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        Ok(Self {
+            feed_forward_w1: QMatMul::from_arc(gate.into())?,
+            feed_forward_w2: QMatMul::from_arc(down.into())?,
+            feed_forward_w3: QMatMul::from_arc(up.into())?,
+        })
+    }
+}
+
 impl Module for Mlp {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let w1 = self.feed_forward_w1.forward(xs)?;
@@ -65,6 +114,11 @@ impl Module for Mlp {
 #[derive(Debug, Clone)]
 enum MlpOrMoe {
     Mlp(Mlp),
+    // The falcon MLP, without gating.
+    Gelu {
+        up: QMatMul,
+        down: QMatMul,
+    },
     MoE {
         n_expert_used: usize,
         feed_forward_gate_inp: QMatMul,
@@ -138,26 +192,418 @@ impl Module for MlpOrMoe {
                 Ok(ys)
             }
             Self::Mlp(mlp) => mlp.forward(xs),
+            Self::Gelu { up, down } => down.forward(&up.forward(xs)?.gelu()?),
         }
     }
 }
 
+/// The norm applied before the attention and MLP blocks and to the final hidden states.
+#[doc(hidden)]
 #[derive(Debug, Clone)]
-struct LayerWeights {
-    attention_wq: QMatMul,
-    attention_wk: QMatMul,
-    attention_wv: QMatMul,
+pub enum Norm {
+    Rms(RmsNorm),
+    Layer(LayerNorm),
+}
+
+impl Norm {
+    pub fn rms(weight: QTensor, eps: f64) -> Result<Self> {
+        Ok(Self::Rms(RmsNorm::from_qtensor(weight, eps)?))
+    }
+
+    /// A layer norm, the weight and bias are dequantized on their device.
+    pub fn layer(weight: &QTensor, bias: &QTensor, eps: f64) -> Result<Self> {
+        let weight = weight.dequantize(&weight.device())?;
+        let bias = bias.dequantize(&bias.device())?;
+        Ok(Self::Layer(LayerNorm::new(weight, bias, eps)))
+    }
+
+    fn layer_norm<R: std::io::Seek + std::io::Read>(
+        tensors: &mut TensorReader<R>,
+        name: &str,
+        eps: f64,
+    ) -> Result<Self> {
+        let weight = tensors.get_unshared(&format!("{name}.weight"))?;
+        let bias = tensors.get_unshared(&format!("{name}.bias"))?;
+        Self::layer(&weight, &bias, eps)
+    }
+}
+
+impl Module for Norm {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Rms(norm) => norm.forward(xs),
+            Self::Layer(norm) => norm.forward(xs),
+        }
+    }
+}
+
+// The token embeddings, either dequantized at load time or kept quantized with the rows
+// dequantized on lookup, see `crate::kv_budget::quantized_embeddings`.
+#[derive(Debug, Clone)]
+enum TokenEmbeddings {
+    Dense(Embedding),
+    Quantized(Arc<QTensor>),
+}
+
+impl TokenEmbeddings {
+    fn new(embed: Arc<QTensor>, hidden_size: usize, quantized: Option<bool>) -> Result<Self> {
+        let device = embed.device();
+        let dense_bytes = (embed.shape().elem_count() * DType::F32.size_in_bytes()) as u64;
+        if crate::kv_budget::quantized_embeddings(dense_bytes, quantized, &device) {
+            if device.is_metal() {
+                candle::bail!("quantized embeddings are not supported on metal")
+            }
+            return Ok(Self::Quantized(embed));
+        }
+        let embeddings = embed.dequantize(&device)?;
+        Ok(Self::Dense(Embedding::new(embeddings, hidden_size)))
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        match self {
+            Self::Dense(embeddings) => {
+                let embeddings = embeddings.embeddings();
+                embeddings.elem_count() * embeddings.dtype().size_in_bytes()
+            }
+            Self::Quantized(embed) => embed.storage_size_in_bytes(),
+        }
+    }
+}
+
+impl Module for TokenEmbeddings {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Dense(embeddings) => embeddings.forward(xs),
+            Self::Quantized(embed) => embed.embedding(xs, DType::F32),
+        }
+    }
+}
+
+/// The tensors read when loading a gguf file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadSummary {
+    pub tensors: usize,
+    /// The size of the tensor data in the file.
+    pub bytes: usize,
+    /// The number of tensors that share the storage of an identical tensor.
+    pub deduplicated: usize,
+    /// The memory saved by sharing the storage of the duplicated tensors.
+    pub deduplicated_bytes: usize,
+    /// The number of tensors converted to another dtype, see [`LayerDTypeOverride`].
+    pub overridden: usize,
+}
+
+// The dtype and shape of a tensor.
+type TensorKind = (GgmlDType, Vec<usize>);
+// The names and tensors sharing a data hash.
+type SameHash<'a> = Vec<(&'a str, Arc<QTensor>)>;
+
+// Reads the tensors of a gguf file. When deduplicating, the tensors whose data is identical to
+// a tensor that has already been read get the same `QTensor`. Only the tensors with the same
+// dtype and shape as another tensor of the file are hashed.
+struct TensorReader<'a, R> {
+    ct: &'a gguf_file::Content,
+    reader: &'a mut R,
+    device: &'a Device,
+    candidates: HashSet<TensorKind>,
+    // The tensors read so far, indexed by the hash of their data.
+    loaded: HashMap<(u64, TensorKind), SameHash<'a>>,
+    overrides: &'a LayerDTypeOverride,
+    // The dtype of the weights after applying the overrides, in loading order.
+    dtypes: Vec<(String, GgmlDType)>,
+    summary: LoadSummary,
+}
+
+impl<'a, R: std::io::Seek + std::io::Read> TensorReader<'a, R> {
+    fn new(
+        ct: &'a gguf_file::Content,
+        reader: &'a mut R,
+        device: &'a Device,
+        dedup: bool,
+        overrides: &'a LayerDTypeOverride,
+    ) -> Self {
+        let mut candidates = HashSet::new();
+        if dedup {
+            let mut seen = HashSet::new();
+            for info in ct.tensor_infos.values() {
+                let key = (info.ggml_dtype, info.shape.dims().to_vec());
+                if !seen.insert(key.clone()) {
+                    candidates.insert(key);
+                }
+            }
+        }
+        Self {
+            ct,
+            reader,
+            device,
+            candidates,
+            loaded: HashMap::new(),
+            overrides,
+            dtypes: vec![],
+            summary: LoadSummary::default(),
+        }
+    }
+
+    fn info(&self, name: &str) -> Result<(&'a str, &'a gguf_file::TensorInfo)> {
+        match self.ct.tensor_infos.get_key_value(name) {
+            Some((name, info)) => Ok((name.as_str(), info)),
+            None => candle::bail!("cannot find tensor info for {name}"),
+        }
+    }
+
+    fn raw_data(&mut self, info: &gguf_file::TensorInfo) -> Result<Vec<u8>> {
+        let dtype = info.ggml_dtype;
+        let size = info.shape.elem_count() / dtype.block_size() * dtype.type_size();
+        let mut raw_data = vec![0u8; size];
+        let offset = self.ct.tensor_data_offset + info.offset;
+        self.reader.seek(std::io::SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut raw_data)?;
+        Ok(raw_data)
+    }
+
+    // Reads a tensor that does not get shared, e.g. a norm that is dequantized right away.
+    fn get_unshared(&mut self, name: &str) -> Result<QTensor> {
+        let tensor = self.ct.tensor(self.reader, name, self.device)?;
+        self.summary.tensors += 1;
+        self.summary.bytes += tensor.storage_size_in_bytes();
+        Ok(tensor)
+    }
+
+    // Reads a weight and converts it to the dtype requested by the overrides.
+    fn get(&mut self, name: &str) -> Result<Arc<QTensor>> {
+        let tensor = self.get_deduped(name)?;
+        let dtype = tensor.dtype();
+        let tensor = self.overrides.apply(name, tensor, self.device)?;
+        if tensor.dtype() != dtype {
+            self.summary.overridden += 1;
+        }
+        self.dtypes.push((name.to_string(), tensor.dtype()));
+        Ok(tensor)
+    }
+
+    // Reads a weight that falls back to another one when missing, e.g. an output head tied to
+    // the token embeddings. The overrides for `name` also apply to the fallback.
+    fn get_or_tied(&mut self, name: &str, tied: Arc<QTensor>) -> Result<Arc<QTensor>> {
+        if self.ct.tensor_infos.contains_key(name) {
+            return self.get(name);
+        }
+        let dtype = tied.dtype();
+        let tensor = self.overrides.apply(name, tied, self.device)?;
+        if tensor.dtype() != dtype {
+            self.summary.overridden += 1;
+        }
+        self.dtypes.push((name.to_string(), tensor.dtype()));
+        Ok(tensor)
+    }
+
+    fn get_deduped(&mut self, name: &str) -> Result<Arc<QTensor>> {
+        let (name, info) = self.info(name)?;
+        let dims = info.shape.dims().to_vec();
+        if !self.candidates.contains(&(info.ggml_dtype, dims.clone())) {
+            return Ok(Arc::new(self.get_unshared(name)?));
+        }
+        let raw_data = self.raw_data(info)?;
+        self.summary.tensors += 1;
+        self.summary.bytes += raw_data.len();
+        let hash = twox_hash::XxHash3_64::oneshot(&raw_data);
+        let key = (hash, (info.ggml_dtype, dims.clone()));
+        let same_hash = self.loaded.get(&key).cloned().unwrap_or_default();
+        for (other, tensor) in same_hash {
+            // Compare the actual data to rule out hash collisions.
+            let (_, other) = self.info(other)?;
+            if self.raw_data(other)? == raw_data {
+                self.summary.deduplicated += 1;
+                self.summary.deduplicated_bytes += raw_data.len();
+                return Ok(tensor);
+            }
+        }
+        let tensor = candle::quantized::ggml_file::qtensor_from_ggml(
+            info.ggml_dtype,
+            &raw_data,
+            dims,
+            self.device,
+        )?;
+        let tensor = Arc::new(tensor);
+        self.loaded
+            .entry(key)
+            .or_default()
+            .push((name, tensor.clone()));
+        Ok(tensor)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Qkv {
+    Split {
+        wq: QMatMul,
+        wk: QMatMul,
+        wv: QMatMul,
+    },
+    // A single projection with the outputs laid out as q, k, then v.
+    Fused(QMatMul),
+}
+
+// How the attention and MLP blocks are combined.
+#[derive(Debug, Clone)]
+enum Residual {
+    // llama: `h = x + attn(norm(x))` followed by `h + mlp(ffn_norm(h))`.
+    Sequential { ffn_norm: Norm },
+    // falcon: `x + attn(norm(x)) + mlp(mlp_norm(x))`, the MLP uses the attention norm output
+    // when it does not have its own norm.
+    Parallel { mlp_norm: Option<Norm> },
+}
+
+/// The weights of a transformer block, see [`LayerWeights::builder`].
+#[doc(hidden)]
+#[derive(Debug, Clone)]
+pub struct LayerWeights {
+    qkv: Qkv,
     attention_wo: QMatMul,
-    attention_norm: RmsNorm,
+    attention_norm: Norm,
     mlp_or_moe: MlpOrMoe,
-    ffn_norm: RmsNorm,
+    residual: Residual,
+}
+
+impl LayerWeights {
+    pub fn builder() -> LayerWeightsBuilder {
+        LayerWeightsBuilder::default()
+    }
+}
+
+/// Assembles a [`LayerWeights`]: a llama block uses [`Self::attention`], [`Self::mlp`] or
+/// [`Self::moe`], and [`Self::ffn_norm`], a falcon block uses [`Self::fused_attention`],
+/// [`Self::gelu_mlp`], and [`Self::parallel_residual`]. The weights can have any dtype,
+/// quantized or not.
+#[doc(hidden)]
+#[derive(Debug, Clone, Default)]
+pub struct LayerWeightsBuilder {
+    qkv: Option<Qkv>,
+    attention_wo: Option<QMatMul>,
+    attention_norm: Option<Norm>,
+    mlp_or_moe: Option<MlpOrMoe>,
+    residual: Option<Residual>,
+}
+
+impl LayerWeightsBuilder {
+    /// Separate q, k, and v projections followed by the output projection `wo`.
+    pub fn attention(
+        mut self,
+        wq: impl Into<Arc<QTensor>>,
+        wk: impl Into<Arc<QTensor>>,
+        wv: impl Into<Arc<QTensor>>,
+        wo: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        self.qkv = Some(Qkv::Split {
+            wq: QMatMul::from_arc(wq.into())?,
+            wk: QMatMul::from_arc(wk.into())?,
+            wv: QMatMul::from_arc(wv.into())?,
+        });
+        self.attention_wo = Some(QMatMul::from_arc(wo.into())?);
+        Ok(self)
+    }
+
+    /// A single projection with the outputs laid out as q, k, then v.
+    pub fn fused_attention(
+        mut self,
+        wqkv: impl Into<Arc<QTensor>>,
+        wo: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        self.qkv = Some(Qkv::Fused(QMatMul::from_arc(wqkv.into())?));
+        self.attention_wo = Some(QMatMul::from_arc(wo.into())?);
+        Ok(self)
+    }
+
+    pub fn attention_norm(mut self, norm: Norm) -> Self {
+        self.attention_norm = Some(norm);
+        self
+    }
+
+    /// The gated silu MLP, `down(silu(gate(x)) * up(x))`.
+    pub fn mlp(
+        mut self,
+        gate: impl Into<Arc<QTensor>>,
+        down: impl Into<Arc<QTensor>>,
+        up: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        self.mlp_or_moe = Some(MlpOrMoe::Mlp(Mlp::new(gate, down, up)?));
+        Ok(self)
+    }
+
+    /// A mixture of experts, each expert is given as its `(gate, down, up)` weights.
+    pub fn moe(
+        mut self,
+        gate_inp: impl Into<Arc<QTensor>>,
+        experts: Vec<(Arc<QTensor>, Arc<QTensor>, Arc<QTensor>)>,
+        n_expert_used: usize,
+    ) -> Result<Self> {
+        let experts = experts
+            .into_iter()
+            .map(|(gate, down, up)| Mlp::new(gate, down, up))
+            .collect::<Result<Vec<_>>>()?;
+        self.mlp_or_moe = Some(MlpOrMoe::MoE {
+            n_expert_used,
+            feed_forward_gate_inp: QMatMul::from_arc(gate_inp.into())?,
+            experts,
+        });
+        Ok(self)
+    }
+
+    /// The falcon MLP without gating, `down(gelu(up(x)))`.
+    pub fn gelu_mlp(
+        mut self,
+        up: impl Into<Arc<QTensor>>,
+        down: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        self.mlp_or_moe = Some(MlpOrMoe::Gelu {
+            up: QMatMul::from_arc(up.into())?,
+            down: QMatMul::from_arc(down.into())?,
+        });
+        Ok(self)
+    }
+
+    /// The llama residual layout, the MLP runs after the attention block on the output of
+    /// `norm`.
+    pub fn ffn_norm(mut self, norm: Norm) -> Self {
+        self.residual = Some(Residual::Sequential { ffn_norm: norm });
+        self
+    }
+
+    /// The falcon residual layout, the attention and MLP blocks run in parallel. The MLP uses
+    /// the attention norm output when `mlp_norm` is `None`.
+    pub fn parallel_residual(mut self, mlp_norm: Option<Norm>) -> Self {
+        self.residual = Some(Residual::Parallel { mlp_norm });
+        self
+    }
+
+    pub fn build(self) -> Result<LayerWeights> {
+        let missing = |what: &str| candle::Error::Msg(format!("no {what} for the layer"));
+        Ok(LayerWeights {
+            qkv: self.qkv.ok_or_else(|| missing("attention"))?,
+            attention_wo: self.attention_wo.ok_or_else(|| missing("attention"))?,
+            attention_norm: self
+                .attention_norm
+                .ok_or_else(|| missing("attention norm"))?,
+            mlp_or_moe: self.mlp_or_moe.ok_or_else(|| missing("mlp"))?,
+            residual: self.residual.ok_or_else(|| missing("residual layout"))?,
+        })
+    }
+}
+
+// A transformer block with its attention state.
+#[derive(Debug, Clone)]
+struct Layer {
+    weights: LayerWeights,
+    // Rotate the two halves of the heads rather than interleaved pairs.
+    neox_rope: bool,
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    cos: Tensor,
-    sin: Tensor,
+    rotary: Arc<RotaryEmbedding>,
     neg_inf: Tensor,
     kv_cache: Option<(Tensor, Tensor)>,
+    // The keys and values of the prompt once the kv cache has been forked, with a batch size of
+    // 1 and shared by all the sequences. `kv_cache` then only holds the per sequence tails.
+    kv_prefix: Option<(Tensor, Tensor)>,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
@@ -169,15 +615,10 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Ten
     Ok(m)
 }
 
-impl LayerWeights {
+impl Layer {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
-        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
-        let cos = self.cos.narrow(0, index_pos, seq_len)?;
-        let sin = self.sin.narrow(0, index_pos, seq_len)?;
-        // The call to contiguous below is only necessary when processing the prompt.
-        // When the seq_len is 1 in the inference loop, this is a no-op.
-        candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+        self.rotary.apply(x, index_pos, self.neox_rope)
     }
 
     fn forward_attn(
@@ -188,9 +629,17 @@ impl LayerWeights {
     ) -> Result<Tensor> {
         let _enter = self.span_attn.enter();
         let (b_sz, seq_len, n_embd) = x.dims3()?;
-        let q = self.attention_wq.forward(x)?;
-        let k = self.attention_wk.forward(x)?;
-        let v = self.attention_wv.forward(x)?;
+        let (q, k, v) = match &self.weights.qkv {
+            Qkv::Split { wq, wk, wv } => (wq.forward(x)?, wk.forward(x)?, wv.forward(x)?),
+            Qkv::Fused(wqkv) => {
+                let qkv = wqkv.forward(x)?;
+                let kv_size = self.n_kv_head * self.head_dim;
+                let q = qkv.narrow(D::Minus1, 0, n_embd)?;
+                let k = qkv.narrow(D::Minus1, n_embd, kv_size)?;
+                let v = qkv.narrow(D::Minus1, n_embd + kv_size, kv_size)?;
+                (q, k, v)
+            }
+        };
 
         let q = q
             .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
@@ -223,7 +672,9 @@ impl LayerWeights {
         };
         self.kv_cache = Some((k.clone(), v.clone()));
 
-        let y = if q.device().is_metal() && seq_len == 1 {
+        let y = if let Some((k_prefix, v_prefix)) = &self.kv_prefix {
+            self.forward_forked_attn(&q, k_prefix, v_prefix, k, v, mask)?
+        } else if q.device().is_metal() && seq_len == 1 {
             // SDPA will do MQA for us
             candle_nn::ops::sdpa(&q, &k, &v, 1. / (self.head_dim as f32).sqrt(), 1.)?
         } else {
@@ -245,114 +696,377 @@ impl LayerWeights {
         };
 
         let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
-        let y = self.attention_wo.forward(&y)?;
+        let y = self.weights.attention_wo.forward(&y)?;
         Ok(y)
     }
+
+    // Attention over the shared prefix followed by the tail of each sequence. The queries of all
+    // the sequences are folded into a single batch entry to attend to the prefix, so that it is
+    // never copied per sequence.
+    fn forward_forked_attn(
+        &self,
+        q: &Tensor,
+        k_prefix: &Tensor,
+        v_prefix: &Tensor,
+        k: Tensor,
+        v: Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        let (b_sz, n_head, seq_len, head_dim) = q.dims4()?;
+        let prefix_len = k_prefix.dim(2)?;
+        let tail_len = k.dim(2)?;
+        let n_rep = self.n_head / self.n_kv_head;
+        let k_prefix = crate::utils::repeat_kv(k_prefix.clone(), n_rep)?;
+        let v_prefix = crate::utils::repeat_kv(v_prefix.clone(), n_rep)?;
+        let k = crate::utils::repeat_kv(k, n_rep)?;
+        let v = crate::utils::repeat_kv(v, n_rep)?;
+
+        // (b, h, s, x) <-> (1, h, b * s, x)
+        let fold = |xs: &Tensor| -> Result<Tensor> {
+            let dim = xs.dim(3)?;
+            xs.transpose(0, 1)?
+                .contiguous()?
+                .reshape((1, n_head, b_sz * seq_len, dim))
+        };
+        let unfold = |xs: &Tensor| -> Result<Tensor> {
+            let dim = xs.dim(3)?;
+            xs.reshape((n_head, b_sz, seq_len, dim))?.transpose(0, 1)
+        };
+        let att_prefix = unfold(&fold(q)?.matmul(&k_prefix.t()?)?)?;
+        let att_tail = q.matmul(&k.t()?)?;
+        let att = (Tensor::cat(&[&att_prefix, &att_tail], 3)? / (head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, &self.neg_inf)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        let y_prefix = fold(&att.narrow(3, 0, prefix_len)?)?.matmul(&v_prefix.contiguous()?)?;
+        let y_tail = att
+            .narrow(3, prefix_len, tail_len)?
+            .contiguous()?
+            .matmul(&v.contiguous()?)?;
+        unfold(&y_prefix)? + y_tail
+    }
+
+    fn forward(&mut self, x: &Tensor, mask: Option<&Tensor>, index_pos: usize) -> Result<Tensor> {
+        let residual = x;
+        let h = self.weights.attention_norm.forward(x)?;
+        let attn = self.forward_attn(&h, mask, index_pos)?;
+        let _enter = self.span_mlp.enter();
+        match &self.weights.residual {
+            Residual::Sequential { ffn_norm } => {
+                let x = (attn + residual)?;
+                let residual = &x;
+                let x = ffn_norm.forward(&x)?;
+                let x = self.weights.mlp_or_moe.forward(&x)?;
+                x + residual
+            }
+            Residual::Parallel { mlp_norm } => {
+                let h = match mlp_norm {
+                    None => h,
+                    Some(mlp_norm) => mlp_norm.forward(x)?,
+                };
+                let mlp = self.weights.mlp_or_moe.forward(&h)?;
+                (attn + mlp)? + residual
+            }
+        }
+    }
+}
+
+/// A head applied to the final hidden states in place of the language modeling head.
+#[derive(Clone)]
+struct OutputHead(std::sync::Arc<dyn Module + Send + Sync>);
+
+impl std::fmt::Debug for OutputHead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "OutputHead")
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct ModelWeights {
-    tok_embeddings: Embedding,
-    layers: Vec<LayerWeights>,
-    norm: RmsNorm,
-    output: QMatMul,
+    tok_embeddings: TokenEmbeddings,
+    layers: Vec<Layer>,
+    norm: Norm,
+    output: Option<QMatMul>,
+    output_head: Option<OutputHead>,
     masks: HashMap<usize, Tensor>,
+    max_logits_chunk: Option<usize>,
+    kv_forks: Option<usize>,
+    max_seq_len: usize,
+    rotary: Arc<RotaryEmbedding>,
+    load_summary: LoadSummary,
+    tensor_dtypes: Vec<(String, GgmlDType)>,
     span: tracing::Span,
     span_output: tracing::Span,
 }
 
-fn precomput_freqs_cis(
-    head_dim: usize,
-    freq_base: f32,
-    device: &Device,
-) -> Result<(Tensor, Tensor)> {
-    let theta: Vec<_> = (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
-        .collect();
-    let theta = Tensor::new(theta.as_slice(), device)?;
-    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
-        .to_dtype(DType::F32)?
-        .reshape((MAX_SEQ_LEN, 1))?
-        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-    let cos = idx_theta.cos()?;
-    let sin = idx_theta.sin()?;
-    Ok((cos, sin))
+// The cos/sin tables of the rotary embeddings, these are built once per model and shared by
+// all the layers.
+#[derive(Debug)]
+struct RotaryEmbedding {
+    cos: Tensor,
+    sin: Tensor,
+}
+
+impl RotaryEmbedding {
+    // The tables cover the `max_seq_len` positions of the model and use the dtype of the
+    // activations.
+    fn new(
+        head_dim: usize,
+        freq_base: f32,
+        max_seq_len: usize,
+        dtype: DType,
+        device: &Device,
+    ) -> Result<Self> {
+        let theta: Vec<_> = (0..head_dim)
+            .step_by(2)
+            .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+            .collect();
+        let theta = Tensor::new(theta.as_slice(), device)?;
+        let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
+            .to_dtype(DType::F32)?
+            .reshape((max_seq_len, 1))?
+            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+        let cos = idx_theta.cos()?.to_dtype(dtype)?;
+        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        Ok(Self { cos, sin })
+    }
+
+    fn apply(&self, x: &Tensor, index_pos: usize, neox: bool) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let max_seq_len = self.cos.dim(0)?;
+        if index_pos + seq_len > max_seq_len {
+            candle::bail!(
+                "positions {index_pos}..{} are past the context size {max_seq_len}",
+                index_pos + seq_len
+            )
+        }
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        // The call to contiguous below is only necessary when processing the prompt.
+        // When the seq_len is 1 in the inference loop, this is a no-op.
+        if neox {
+            candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+        } else {
+            candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
+        }
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        (self.cos.elem_count() + self.sin.elem_count()) * self.cos.dtype().size_in_bytes()
+    }
+}
+
+/// The hyper-parameters of a [`ModelWeights`] assembled with [`ModelWeights::from_layers`].
+#[doc(hidden)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelConfig {
+    pub n_head: usize,
+    pub n_kv_head: usize,
+    pub head_dim: usize,
+    pub embedding_length: usize,
+    /// The number of dimensions covered by the rotary embeddings, usually `head_dim`.
+    pub rope_dim: usize,
+    pub rope_freq_base: f32,
+    /// Rotate the two halves of the heads rather than interleaved pairs, as in falcon.
+    pub neox_rope: bool,
+    pub max_seq_len: usize,
 }
 
 impl ModelWeights {
-    pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
-        let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., &ct.device)?;
-        let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?;
-        let tok_embeddings = ct.remove("tok_embeddings.weight")?;
-        let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
-        let norm = RmsNorm::from_qtensor(ct.remove("norm.weight")?, 1e-5)?;
-        let output = ct.remove("output.weight")?;
-        let mut layers = Vec::with_capacity(ct.hparams.n_layer as usize);
-        for layer_idx in 0..ct.hparams.n_layer {
-            let prefix = format!("layers.{layer_idx}");
-            let attention_wq = ct.remove(&format!("{prefix}.attention.wq.weight"))?;
-            let attention_wk = ct.remove(&format!("{prefix}.attention.wk.weight"))?;
-            let attention_wv = ct.remove(&format!("{prefix}.attention.wv.weight"))?;
-            let attention_wo = ct.remove(&format!("{prefix}.attention.wo.weight"))?;
-            let mlp_or_moe = {
-                let feed_forward_w1 = ct.remove(&format!("{prefix}.feed_forward.w1.weight"))?;
-                let feed_forward_w2 = ct.remove(&format!("{prefix}.feed_forward.w2.weight"))?;
-                let feed_forward_w3 = ct.remove(&format!("{prefix}.feed_forward.w3.weight"))?;
-                MlpOrMoe::Mlp(Mlp {
-                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
-                })
-            };
-            let attention_norm = ct.remove(&format!("{prefix}.attention_norm.weight"))?;
-            let ffn_norm = ct.remove(&format!("{prefix}.ffn_norm.weight"))?;
-            let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
-            let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
-            let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
-            layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
-                attention_norm: RmsNorm::from_qtensor(attention_norm, 1e-5)?,
-                mlp_or_moe,
-                ffn_norm: RmsNorm::from_qtensor(ffn_norm, 1e-5)?,
-                n_head: ct.hparams.n_head as usize,
-                n_kv_head: ct.hparams.n_head as usize / gqa,
-                head_dim: (ct.hparams.n_embd / ct.hparams.n_head) as usize,
-                cos: cos.clone(),
-                sin: sin.clone(),
+    /// Assembles a model from its parts, the loaders go through this too. `output` is the
+    /// language modeling head, pass the `embed` tensor for tied embeddings. The device of the
+    /// model is the one of `embed`.
+    #[doc(hidden)]
+    pub fn from_layers(
+        config: ModelConfig,
+        layers: Vec<LayerWeights>,
+        embed: impl Into<Arc<QTensor>>,
+        norm: Norm,
+        output: impl Into<Arc<QTensor>>,
+    ) -> Result<Self> {
+        Self::assemble(config, layers, embed.into(), norm, output.into(), None)
+    }
+
+    fn assemble(
+        config: ModelConfig,
+        layers: Vec<LayerWeights>,
+        embed: Arc<QTensor>,
+        norm: Norm,
+        output: Arc<QTensor>,
+        quantized_embeddings: Option<bool>,
+    ) -> Result<Self> {
+        let device = embed.device();
+        let rotary = RotaryEmbedding::new(
+            config.rope_dim,
+            config.rope_freq_base,
+            config.max_seq_len,
+            DType::F32,
+            &device,
+        )?;
+        let rotary = Arc::new(rotary);
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, &device)?;
+        let layers = layers
+            .into_iter()
+            .map(|weights| Layer {
+                weights,
+                neox_rope: config.neox_rope,
+                n_head: config.n_head,
+                n_kv_head: config.n_kv_head,
+                head_dim: config.head_dim,
+                rotary: rotary.clone(),
                 neg_inf: neg_inf.clone(),
                 kv_cache: None,
-                span_attn,
-                span_rot,
-                span_mlp,
+                kv_prefix: None,
+                span_attn: tracing::span!(tracing::Level::TRACE, "attn"),
+                span_rot: tracing::span!(tracing::Level::TRACE, "attn-rot"),
+                span_mlp: tracing::span!(tracing::Level::TRACE, "attn-mlp"),
             })
-        }
+            .collect();
+        let tok_embeddings =
+            TokenEmbeddings::new(embed, config.embedding_length, quantized_embeddings)?;
         let span = tracing::span!(tracing::Level::TRACE, "model");
         let span_output = tracing::span!(tracing::Level::TRACE, "output");
         Ok(Self {
-            tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
+            tok_embeddings,
             layers,
             norm,
-            output: QMatMul::from_qtensor(output)?,
+            output: Some(QMatMul::from_arc(output)?),
+            output_head: None,
             masks: HashMap::new(),
+            max_logits_chunk: None,
+            kv_forks: None,
+            max_seq_len: config.max_seq_len,
+            rotary,
+            load_summary: LoadSummary::default(),
+            tensor_dtypes: vec![],
             span,
             span_output,
         })
     }
 
+    pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
+        let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
+        let config = ModelConfig {
+            n_head: ct.hparams.n_head as usize,
+            n_kv_head: ct.hparams.n_head as usize / gqa,
+            head_dim,
+            embedding_length: ct.hparams.n_embd as usize,
+            rope_dim: head_dim,
+            rope_freq_base: 10000.,
+            neox_rope: false,
+            max_seq_len: MAX_SEQ_LEN,
+        };
+        let tok_embeddings = ct.remove("tok_embeddings.weight")?;
+        let norm = Norm::rms(ct.remove("norm.weight")?, 1e-5)?;
+        let output = ct.remove("output.weight")?;
+        let mut layers = Vec::with_capacity(ct.hparams.n_layer as usize);
+        for layer_idx in 0..ct.hparams.n_layer {
+            let prefix = format!("layers.{layer_idx}");
+            let mut get = |name: &str| ct.remove(&format!("{prefix}.{name}.weight"));
+            let layer = LayerWeights::builder()
+                .attention(
+                    get("attention.wq")?,
+                    get("attention.wk")?,
+                    get("attention.wv")?,
+                    get("attention.wo")?,
+                )?
+                .mlp(
+                    get("feed_forward.w1")?,
+                    get("feed_forward.w2")?,
+                    get("feed_forward.w3")?,
+                )?
+                .attention_norm(Norm::rms(get("attention_norm")?, 1e-5)?)
+                .ffn_norm(Norm::rms(get("ffn_norm")?, 1e-5)?)
+                .build()?;
+            layers.push(layer)
+        }
+        Self::from_layers(config, layers, tok_embeddings, norm, output)
+    }
+
+    /// Loads a gguf model, the tensors whose data is duplicated in the file share their
+    /// storage, see [`Self::from_gguf_with_dedup`].
     pub fn from_gguf<R: std::io::Seek + std::io::Read>(
         ct: gguf_file::Content,
         reader: &mut R,
         device: &Device,
+    ) -> Result<Self> {
+        Self::from_gguf_with_dedup(ct, reader, device, true)
+    }
+
+    /// Loads a gguf model. When `dedup` is set, the tensors that have the same dtype and shape
+    /// as another tensor are hashed and the exact duplicates share a single `QTensor`, e.g. an
+    /// output head stored as a copy of the token embeddings. See [`Self::load_summary`] for the
+    /// memory saved this way.
+    pub fn from_gguf_with_dedup<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dedup: bool,
+    ) -> Result<Self> {
+        let overrides = LayerDTypeOverride::default();
+        Self::load_gguf(ct, reader, device, dedup, &overrides, None)
+    }
+
+    /// Loads a gguf model like [`Self::from_gguf_with_dedup`] and converts the weights matched
+    /// by `overrides` to the requested dtype before building the matmuls, e.g. to keep the first
+    /// and last layers in f16. The norms are always loaded as f32. See [`Self::tensor_dtypes`]
+    /// for the resulting dtypes.
+    pub fn from_gguf_with_overrides<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dedup: bool,
+        overrides: &LayerDTypeOverride,
+    ) -> Result<Self> {
+        Self::load_gguf(ct, reader, device, dedup, overrides, None)
+    }
+
+    /// Loads a gguf model like [`Self::from_gguf_with_overrides`]. When `quantized_embeddings`
+    /// is set, the token embeddings stay quantized and only the rows looked up by the forward
+    /// passes are dequantized, rather than dequantizing the whole table at load time. It
+    /// defaults to on for tables above [`crate::kv_budget::QUANTIZED_EMBEDDINGS_MIN_BYTES`]
+    /// once dequantized, except on metal where the lookups are not supported. The other loaders
+    /// use this default too, see [`Self::quantized_embeddings`].
+    pub fn from_gguf_with_embeddings<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dedup: bool,
+        overrides: &LayerDTypeOverride,
+        quantized_embeddings: Option<bool>,
+    ) -> Result<Self> {
+        Self::load_gguf(ct, reader, device, dedup, overrides, quantized_embeddings)
+    }
+
+    fn load_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dedup: bool,
+        overrides: &LayerDTypeOverride,
+        quantized_embeddings: Option<bool>,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle::bail!("cannot find {s} in metadata"),
             Some(v) => Ok(v),
         };
+        if let Ok(arch) = md_get("general.architecture") {
+            if arch.to_string()? == "falcon" {
+                return Self::from_gguf_falcon(
+                    &ct,
+                    reader,
+                    device,
+                    dedup,
+                    overrides,
+                    quantized_embeddings,
+                );
+            }
+        }
 
         // Parameter extraction from metadata.
         let n_expert = md_get("llama.expert_count")
@@ -372,141 +1086,533 @@ impl ModelWeights {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
-        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
-
-        let tok_embeddings_q = ct.tensor(reader, "token_embd.weight", device)?;
-        let tok_embeddings = tok_embeddings_q.dequantize(device)?;
-        let norm = RmsNorm::from_qtensor(
-            ct.tensor(reader, "output_norm.weight", device)?,
-            rms_norm_eps,
-        )?;
-        let output = match ct.tensor(reader, "output.weight", device) {
-            Ok(tensor) => tensor,
-            Err(_) => tok_embeddings_q,
+        let max_seq_len = md_get("llama.context_length")
+            .and_then(|v| v.to_u32())
+            .map_or(MAX_SEQ_LEN, |v| (v as usize).min(MAX_SEQ_LEN));
+        let config = ModelConfig {
+            n_head: head_count,
+            n_kv_head: head_count_kv,
+            head_dim: embedding_length / head_count,
+            embedding_length,
+            rope_dim,
+            rope_freq_base,
+            neox_rope: false,
+            max_seq_len,
         };
+        let mut tensors = TensorReader::new(&ct, reader, device, dedup, overrides);
+
+        let tok_embeddings = tensors.get("token_embd.weight")?;
+        let norm = Norm::rms(tensors.get_unshared("output_norm.weight")?, rms_norm_eps)?;
+        let output = tensors.get_or_tied("output.weight", tok_embeddings.clone())?;
         let mut layers = Vec::with_capacity(block_count);
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
-            let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
-            let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
-            let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
-            let attention_wo =
-                ct.tensor(reader, &format!("{prefix}.attn_output.weight"), device)?;
-            let mlp_or_moe = if n_expert <= 1 {
-                let feed_forward_w1 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_gate.weight"), device)?;
-                let feed_forward_w2 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_down.weight"), device)?;
-                let feed_forward_w3 =
-                    ct.tensor(reader, &format!("{prefix}.ffn_up.weight"), device)?;
-                MlpOrMoe::Mlp(Mlp {
-                    feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                    feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                    feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
-                })
+            let layer = LayerWeights::builder().attention(
+                tensors.get(&format!("{prefix}.attn_q.weight"))?,
+                tensors.get(&format!("{prefix}.attn_k.weight"))?,
+                tensors.get(&format!("{prefix}.attn_v.weight"))?,
+                tensors.get(&format!("{prefix}.attn_output.weight"))?,
+            )?;
+            let layer = if n_expert <= 1 {
+                layer.mlp(
+                    tensors.get(&format!("{prefix}.ffn_gate.weight"))?,
+                    tensors.get(&format!("{prefix}.ffn_down.weight"))?,
+                    tensors.get(&format!("{prefix}.ffn_up.weight"))?,
+                )?
             } else {
-                let feed_forward_gate_inp =
-                    ct.tensor(reader, &format!("{prefix}.ffn_gate_inp.weight"), device)?;
+                let gate_inp = tensors.get(&format!("{prefix}.ffn_gate_inp.weight"))?;
                 let mut experts = Vec::with_capacity(n_expert);
                 for i in 0..n_expert {
-                    let feed_forward_w1 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_gate.{i}.weight"), device)?;
-                    let feed_forward_w2 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_down.{i}.weight"), device)?;
-                    let feed_forward_w3 =
-                        ct.tensor(reader, &format!("{prefix}.ffn_up.{i}.weight"), device)?;
-                    experts.push(Mlp {
-                        feed_forward_w1: QMatMul::from_qtensor(feed_forward_w1)?,
-                        feed_forward_w2: QMatMul::from_qtensor(feed_forward_w2)?,
-                        feed_forward_w3: QMatMul::from_qtensor(feed_forward_w3)?,
-                    })
-                }
-                MlpOrMoe::MoE {
-                    n_expert_used,
-                    feed_forward_gate_inp: QMatMul::from_qtensor(feed_forward_gate_inp)?,
-                    experts,
+                    experts.push((
+                        tensors.get(&format!("{prefix}.ffn_gate.{i}.weight"))?,
+                        tensors.get(&format!("{prefix}.ffn_down.{i}.weight"))?,
+                        tensors.get(&format!("{prefix}.ffn_up.{i}.weight"))?,
+                    ))
                 }
+                layer.moe(gate_inp, experts, n_expert_used)?
             };
-            let attention_norm =
-                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?;
-            let ffn_norm = ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?;
-            let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
-            let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
-            let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
-            layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_wo: QMatMul::from_qtensor(attention_wo)?,
-                attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
-                mlp_or_moe,
-                ffn_norm: RmsNorm::from_qtensor(ffn_norm, rms_norm_eps)?,
-                n_head: head_count,
-                n_kv_head: head_count_kv,
-                head_dim: embedding_length / head_count,
-                cos: cos.clone(),
-                sin: sin.clone(),
-                neg_inf: neg_inf.clone(),
-                kv_cache: None,
-                span_attn,
-                span_rot,
-                span_mlp,
-            })
+            let attention_norm = tensors.get_unshared(&format!("{prefix}.attn_norm.weight"))?;
+            let ffn_norm = tensors.get_unshared(&format!("{prefix}.ffn_norm.weight"))?;
+            let layer = layer
+                .attention_norm(Norm::rms(attention_norm, rms_norm_eps)?)
+                .ffn_norm(Norm::rms(ffn_norm, rms_norm_eps)?)
+                .build()?;
+            layers.push(layer)
         }
-        let span = tracing::span!(tracing::Level::TRACE, "model");
-        let span_output = tracing::span!(tracing::Level::TRACE, "output");
-        Ok(Self {
-            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+        let mut model = Self::assemble(
+            config,
             layers,
+            tok_embeddings,
             norm,
-            output: QMatMul::from_qtensor(output)?,
-            masks: HashMap::new(),
-            span,
-            span_output,
-        })
+            output,
+            quantized_embeddings,
+        )?;
+        model.load_summary = tensors.summary;
+        model.tensor_dtypes = tensors.dtypes;
+        Ok(model)
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
+    // Falcon models use layer norms, a fused qkv projection, neox style rotary embeddings, and
+    // compute the attention and MLP blocks in parallel. The 40b variant normalizes the attention
+    // input with `attn_norm_2` and the MLP input with `attn_norm`, as in llama.cpp.
+    fn from_gguf_falcon<R: std::io::Seek + std::io::Read>(
+        ct: &gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        dedup: bool,
+        overrides: &LayerDTypeOverride,
+        quantized_embeddings: Option<bool>,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+        let head_count = md_get("falcon.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("falcon.attention.head_count_kv")?.to_u32()? as usize;
+        let block_count = md_get("falcon.block_count")?.to_u32()? as usize;
+        let embedding_length = md_get("falcon.embedding_length")?.to_u32()? as usize;
+        let layer_norm_eps = md_get("falcon.attention.layer_norm_epsilon")?.to_f32()? as f64;
+        let max_seq_len = md_get("falcon.context_length")
+            .and_then(|v| v.to_u32())
+            .map_or(MAX_SEQ_LEN, |v| (v as usize).min(MAX_SEQ_LEN));
+        let rope_freq_base = md_get("falcon.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let head_dim = embedding_length / head_count;
+        let config = ModelConfig {
+            n_head: head_count,
+            n_kv_head: head_count_kv,
+            head_dim,
+            embedding_length,
+            rope_dim: head_dim,
+            rope_freq_base,
+            neox_rope: true,
+            max_seq_len,
+        };
+        let mut tensors = TensorReader::new(ct, reader, device, dedup, overrides);
+
+        let tok_embeddings = tensors.get("token_embd.weight")?;
+        let norm = Norm::layer_norm(&mut tensors, "output_norm", layer_norm_eps)?;
+        let output = tensors.get_or_tied("output.weight", tok_embeddings.clone())?;
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let wqkv = tensors.get(&format!("{prefix}.attn_qkv.weight"))?;
+            let attention_wo = tensors.get(&format!("{prefix}.attn_output.weight"))?;
+            let up = tensors.get(&format!("{prefix}.ffn_up.weight"))?;
+            let down = tensors.get(&format!("{prefix}.ffn_down.weight"))?;
+            let attn_norm =
+                Norm::layer_norm(&mut tensors, &format!("{prefix}.attn_norm"), layer_norm_eps)?;
+            let (attention_norm, mlp_norm) = if ct
+                .tensor_infos
+                .contains_key(&format!("{prefix}.attn_norm_2.weight"))
+            {
+                let name = format!("{prefix}.attn_norm_2");
+                let attn_norm_2 = Norm::layer_norm(&mut tensors, &name, layer_norm_eps)?;
+                (attn_norm_2, Some(attn_norm))
+            } else {
+                (attn_norm, None)
+            };
+            let layer = LayerWeights::builder()
+                .fused_attention(wqkv, attention_wo)?
+                .attention_norm(attention_norm)
+                .gelu_mlp(up, down)?
+                .parallel_residual(mlp_norm)
+                .build()?;
+            layers.push(layer)
+        }
+        let mut model = Self::assemble(
+            config,
+            layers,
+            tok_embeddings,
+            norm,
+            output,
+            quantized_embeddings,
+        )?;
+        model.load_summary = tensors.summary;
+        model.tensor_dtypes = tensors.dtypes;
+        Ok(model)
+    }
+
+    /// Loads a gguf model from memory, e.g. when the weights are embedded in the binary or
+    /// downloaded by the host application, the filesystem is never accessed.
+    pub fn from_gguf_bytes(bytes: &[u8], device: &Device) -> Result<Self> {
+        let mut reader = std::io::Cursor::new(bytes);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        Self::from_gguf(ct, &mut reader, device)
+    }
+
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        let mask = if let Some(mask) = self.masks.get(&t) {
+            mask.clone()
         } else {
             let mask: Vec<_> = (0..t)
                 .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
                 .collect();
             let mask = Tensor::from_slice(&mask, (t, t), device)?;
             self.masks.insert(t, mask.clone());
+            mask
+        };
+        if index_pos == 0 {
             Ok(mask)
+        } else {
+            // The tokens already in the kv cache are visible from all the new positions.
+            let prefix = Tensor::zeros((t, index_pos), DType::U8, device)?;
+            Tensor::cat(&[&prefix, &mask], 1)
         }
     }
 
-    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
-        let (_b_sz, seq_len) = x.dims2()?;
+    /// The number of positions currently stored in the kv cache, including the shared prefix
+    /// when the cache has been forked.
+    pub fn kv_cache_len(&self) -> usize {
+        let Some(layer) = self.layers.first() else {
+            return 0;
+        };
+        let len = |kv: &Option<(Tensor, Tensor)>| match kv {
+            None => 0,
+            Some((k, _)) => k.dim(2).unwrap_or(0),
+        };
+        len(&layer.kv_prefix) + len(&layer.kv_cache)
+    }
+
+    /// The size in bytes of the keys and values held by the kv cache. The prefix shared by
+    /// forked sequences is only counted once.
+    pub fn kv_cache_bytes(&self) -> usize {
+        let bytes = |kv: &Option<(Tensor, Tensor)>| match kv {
+            None => 0,
+            Some((k, v)) => (k.elem_count() + v.elem_count()) * k.dtype().size_in_bytes(),
+        };
+        self.layers
+            .iter()
+            .map(|layer| bytes(&layer.kv_prefix) + bytes(&layer.kv_cache))
+            .sum()
+    }
+
+    /// Splits the current sequence into `n` sequences that continue from the same prompt, e.g.
+    /// to sample several completions. The kv cache of the prompt is shared by the sequences
+    /// and each of them only stores its own tail, the following calls to `forward` must use a
+    /// batch size of `n`. Truncating the cache within the prompt copies it for each sequence.
+    pub fn fork_kv_cache(&mut self, n: usize) -> Result<()> {
+        if n == 0 {
+            candle::bail!("cannot fork the kv cache into 0 sequences")
+        }
+        if self.kv_forks.is_some() {
+            candle::bail!("the kv cache has already been forked")
+        }
+        for layer in self.layers.iter() {
+            match &layer.kv_cache {
+                None => candle::bail!("cannot fork an empty kv cache"),
+                Some((k, _)) if k.dim(0)? != 1 => {
+                    candle::bail!("cannot fork a kv cache with a batch size of {}", k.dim(0)?)
+                }
+                Some(_) => {}
+            }
+        }
+        for layer in self.layers.iter_mut() {
+            layer.kv_prefix = layer.kv_cache.take();
+        }
+        self.kv_forks = Some(n);
+        Ok(())
+    }
+
+    /// The number of positions that the model can process, the context length from the gguf
+    /// metadata capped to [`MAX_SEQ_LEN`].
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Lowers the number of positions the model accepts, e.g. to keep the kv cache within a
+    /// memory budget, see [`crate::kv_budget::ContextBudget`]. This never raises the limit.
+    pub fn limit_max_seq_len(&mut self, max_seq_len: usize) {
+        self.max_seq_len = self.max_seq_len.min(max_seq_len)
+    }
+
+    /// The size in bytes of the cos/sin tables of the rotary embeddings, these are shared by all
+    /// the layers.
+    pub fn rope_cache_bytes(&self) -> usize {
+        self.rotary.size_in_bytes()
+    }
+
+    /// Whether the token embeddings are kept quantized, see [`Self::from_gguf_with_embeddings`].
+    pub fn quantized_embeddings(&self) -> bool {
+        matches!(self.tok_embeddings, TokenEmbeddings::Quantized(_))
+    }
+
+    /// The size in bytes of the token embeddings. When they are kept quantized, the table may be
+    /// shared with the output head.
+    pub fn embeddings_bytes(&self) -> usize {
+        self.tok_embeddings.size_in_bytes()
+    }
+
+    /// The dtype of each weight loaded from a gguf file, after applying the overrides, in
+    /// loading order. The norms are not included.
+    pub fn tensor_dtypes(&self) -> &[(String, GgmlDType)] {
+        &self.tensor_dtypes
+    }
+
+    /// The kernels used by each weight matrix in the forward passes so far, the single row
+    /// inputs of the decode steps and the batched inputs of the prompt processing are reported
+    /// separately. The tensors shared by several weights report the kernels of all their uses.
+    pub fn kernel_report(&self) -> Vec<KernelUse> {
+        let mut report = vec![];
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            let mut push = |name: &str, w: &QMatMul| {
+                report.push(w.kernel_use(format!("blk.{layer_idx}.{name}.weight")))
+            };
+            match &layer.weights.qkv {
+                Qkv::Split { wq, wk, wv } => {
+                    push("attn_q", wq);
+                    push("attn_k", wk);
+                    push("attn_v", wv);
+                }
+                Qkv::Fused(wqkv) => push("attn_qkv", wqkv),
+            }
+            push("attn_output", &layer.weights.attention_wo);
+            match &layer.weights.mlp_or_moe {
+                MlpOrMoe::Mlp(mlp) => {
+                    push("ffn_gate", &mlp.feed_forward_w1);
+                    push("ffn_down", &mlp.feed_forward_w2);
+                    push("ffn_up", &mlp.feed_forward_w3);
+                }
+                MlpOrMoe::Gelu { up, down } => {
+                    push("ffn_up", up);
+                    push("ffn_down", down);
+                }
+                MlpOrMoe::MoE {
+                    feed_forward_gate_inp,
+                    experts,
+                    ..
+                } => {
+                    push("ffn_gate_inp", feed_forward_gate_inp);
+                    for (i, mlp) in experts.iter().enumerate() {
+                        push(&format!("ffn_gate.{i}"), &mlp.feed_forward_w1);
+                        push(&format!("ffn_down.{i}"), &mlp.feed_forward_w2);
+                        push(&format!("ffn_up.{i}"), &mlp.feed_forward_w3);
+                    }
+                }
+            }
+        }
+        if let Some(output) = self.output.as_ref() {
+            report.push(output.kernel_use("output.weight".to_string()))
+        }
+        report
+    }
+
+    /// The tensors read from the gguf file, this is empty for ggml files.
+    pub fn load_summary(&self) -> &LoadSummary {
+        &self.load_summary
+    }
+
+    /// The number of sequences sharing the kv cache, `None` if it has not been forked.
+    pub fn kv_forks(&self) -> Option<usize> {
+        self.kv_forks
+    }
+
+    /// Drops the kv cache entries past the first `len` positions, the next call to `forward`
+    /// should then use `index_pos = len`.
+    pub fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        if len == 0 {
+            self.clear_kv_cache();
+            return Ok(());
+        }
+        for layer in self.layers.iter_mut() {
+            let tail_len = match layer.kv_prefix.take() {
+                None => len,
+                Some((k, v)) => {
+                    let prefix_len = k.dim(2)?;
+                    if len >= prefix_len {
+                        layer.kv_prefix = Some((k, v));
+                        len - prefix_len
+                    } else {
+                        // Copy on write, the remaining part of the prompt becomes the start of
+                        // the kv cache of each sequence.
+                        let n = self.kv_forks.unwrap_or(1);
+                        let copy = |xs: Tensor| -> Result<Tensor> {
+                            let (_, n_kv_head, _, head_dim) = xs.dims4()?;
+                            xs.narrow(2, 0, len)?
+                                .broadcast_as((n, n_kv_head, len, head_dim))?
+                                .contiguous()
+                        };
+                        layer.kv_cache = Some((copy(k)?, copy(v)?));
+                        continue;
+                    }
+                }
+            };
+            layer.kv_cache = match layer.kv_cache.take() {
+                Some(_) if tail_len == 0 => None,
+                Some((k, v)) if k.dim(2)? > tail_len => {
+                    Some((k.narrow(2, 0, tail_len)?, v.narrow(2, 0, tail_len)?))
+                }
+                kv_cache => kv_cache,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        for layer in self.layers.iter_mut() {
+            layer.kv_cache = None;
+            layer.kv_prefix = None;
+        }
+        self.kv_forks = None
+    }
+
+    /// Limits the number of positions for which the logits are computed at once in
+    /// [`Self::forward_all`] and [`Self::forward_nll`], this bounds the size of the temporary
+    /// buffers when using a large vocabulary. The results are identical to the unchunked ones.
+    pub fn set_max_logits_chunk(&mut self, max_logits_chunk: Option<usize>) {
+        self.max_logits_chunk = max_logits_chunk
+    }
+
+    pub fn max_logits_chunk(&self) -> Option<usize> {
+        self.max_logits_chunk
+    }
+
+    /// Replaces the language modeling head with a custom one, e.g. a classification head, `None`
+    /// restores the original head. [`Self::forward`] and [`Self::forward_all`] then return the
+    /// output of this head.
+    pub fn set_output_head(&mut self, head: Option<Box<dyn Module + Send + Sync>>) {
+        self.output_head = head.map(|head| OutputHead(head.into()))
+    }
+
+    /// Removes the original language modeling head, this reclaims its memory when a custom head
+    /// is used. It can be put back with [`Self::set_output`].
+    pub fn take_output(&mut self) -> Option<candle::quantized::QMatMul> {
+        self.output.take().map(|output| output.inner)
+    }
+
+    pub fn set_output(&mut self, inner: candle::quantized::QMatMul) {
+        let span = tracing::span!(tracing::Level::TRACE, "qmatmul");
+        self.output = Some(QMatMul { inner, span })
+    }
+
+    fn output_forward(&self, xs: &Tensor) -> Result<Tensor> {
+        match (&self.output_head, &self.output) {
+            (Some(head), _) => head.0.forward(xs),
+            (None, Some(output)) => output.forward(xs),
+            (None, None) => candle::bail!("no output head, the original one has been taken"),
+        }
+    }
+
+    /// Returns the final hidden states for all the positions, with shape
+    /// `(b_sz, seq_len, embedding_length)`.
+    pub fn forward_hidden(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if index_pos == 0 {
+            self.clear_kv_cache()
+        }
+        if let Some(n) = self.kv_forks {
+            if b_sz != n {
+                candle::bail!("the kv cache is forked into {n} sequences but got a batch of {b_sz}")
+            }
+        }
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, index_pos, x.device())?)
         };
+        self.forward_layers(x, mask.as_ref(), index_pos)
+    }
+
+    fn forward_layers(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
         for layer in self.layers.iter_mut() {
-            let x = layer_in;
-            let residual = &x;
-            let x = layer.attention_norm.forward(&x)?;
-            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
-            let x = (attn + residual)?;
-
-            // MLP
-            let _enter = layer.span_mlp.enter();
-            let residual = &x;
-            let x = layer.ffn_norm.forward(&x)?;
-            let x = layer.mlp_or_moe.forward(&x)?;
-            let x = (x + residual)?;
-            layer_in = x
-        }
-        let x = self.norm.forward(&layer_in)?;
-        let x = x.i((.., seq_len - 1, ..))?;
+            layer_in = layer.forward(&layer_in, mask, index_pos)?
+        }
+        self.norm.forward(&layer_in)
+    }
+
+    /// Like [`Self::forward_hidden`] for a batch of right padded sequences starting at position
+    /// 0, e.g. built with [`crate::bucketing::pad_batch`]: row `i` holds `lengths[i]` tokens
+    /// followed by padding. The padding is masked out of the attention so the hidden states of
+    /// the valid positions match the ones of each sequence on its own, the ones of the padding
+    /// positions are meaningless. The kv cache holds all the rows, see
+    /// [`Self::select_kv_cache_row`] to continue one of them.
+    pub fn forward_hidden_padded(&mut self, x: &Tensor, lengths: &[usize]) -> Result<Tensor> {
+        crate::bucketing::check_lengths(x, lengths)?;
+        self.clear_kv_cache();
+        let (_b_sz, seq_len) = x.dims2()?;
+        // (b_sz, 1, seq_len, seq_len), a position sees the previous ones of its own sequence.
+        let mask: Vec<_> = lengths
+            .iter()
+            .flat_map(|&len| {
+                (0..seq_len)
+                    .flat_map(move |i| (0..seq_len).map(move |j| u8::from(j > i || j >= len)))
+            })
+            .collect();
+        let mask = Tensor::from_vec(mask, (lengths.len(), 1, seq_len, seq_len), x.device())?;
+        self.forward_layers(x, Some(&mask), 0)
+    }
+
+    /// Returns the logits for the last valid position of each row of a right padded batch, with
+    /// shape `(b_sz, vocab_size)`, see [`Self::forward_hidden_padded`].
+    pub fn forward_padded(&mut self, x: &Tensor, lengths: &[usize]) -> Result<Tensor> {
+        let x = self.forward_hidden_padded(x, lengths)?;
+        let x = crate::bucketing::last_positions(&x, lengths)?;
+        let _enter = self.span_output.enter();
+        self.output_forward(&x)
+    }
+
+    /// Keeps the first `len` positions of the row `row` of a batched kv cache and drops the
+    /// other rows, e.g. to continue one of the sequences of [`Self::forward_padded`] with
+    /// `index_pos = len`. The model is cheap to clone to continue each row separately.
+    pub fn select_kv_cache_row(&mut self, row: usize, len: usize) -> Result<()> {
+        if self.kv_forks.is_some() {
+            candle::bail!("cannot select a row of a forked kv cache")
+        }
+        for layer in self.layers.iter_mut() {
+            let Some((k, v)) = layer.kv_cache.take() else {
+                candle::bail!("cannot select a row of an empty kv cache")
+            };
+            let (b_sz, _, cache_len, _) = k.dims4()?;
+            if row >= b_sz || len == 0 || len > cache_len {
+                candle::bail!(
+                    "cannot select {len} positions of row {row} in a kv cache of {b_sz} rows of {cache_len} positions"
+                )
+            }
+            let select = |xs: Tensor| xs.narrow(0, row, 1)?.narrow(2, 0, len);
+            layer.kv_cache = Some((select(k)?, select(v)?));
+        }
+        Ok(())
+    }
+
+    /// Returns the logits for the last position, with shape `(b_sz, vocab_size)`.
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.forward_hidden(x, index_pos)?;
+        let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
+        let _enter = self.span_output.enter();
+        self.output_forward(&x)
+    }
+
+    /// Returns the logits for all the positions, with shape `(b_sz, seq_len, vocab_size)`.
+    pub fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.forward_hidden(x, index_pos)?;
+        let _enter = self.span_output.enter();
+        let output = |xs: &Tensor| self.output_forward(xs);
+        crate::utils::chunked_forward(&output, &x, self.max_logits_chunk.unwrap_or(seq_len))
+    }
+
+    /// Returns the negative log likelihood of `targets` for all the positions, with shape
+    /// `(b_sz, seq_len)`. The full logits are never materialized when `max_logits_chunk` is set.
+    pub fn forward_nll(
+        &mut self,
+        x: &Tensor,
+        targets: &Tensor,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        let x = self.forward_hidden(x, index_pos)?;
         let _enter = self.span_output.enter();
-        self.output.forward(&x)
+        let output = |xs: &Tensor| self.output_forward(xs);
+        let chunk_size = self.max_logits_chunk.unwrap_or(seq_len);
+        crate::utils::chunked_nll(&output, &x, targets, chunk_size)
     }
 }
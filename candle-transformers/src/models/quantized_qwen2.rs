@@ -18,6 +18,7 @@ use candle::{
     quantized::{gguf_file, QMatMul},
     DType, Device, IndexOp, Result, Tensor,
 };
+use candle_nn::quantized::QLinear;
 use candle_nn::{Embedding, Module};
 use std::collections::HashMap;
 
@@ -39,12 +40,9 @@ impl Module for Mlp {
 
 #[derive(Debug, Clone)]
 struct LayerWeights {
-    attention_wq: QMatMul,
-    attention_wk: QMatMul,
-    attention_wv: QMatMul,
-    attention_bq: Tensor,
-    attention_bk: Tensor,
-    attention_bv: Tensor,
+    attention_q: QLinear,
+    attention_k: QLinear,
+    attention_v: QLinear,
     attention_wo: QMatMul,
     attention_norm: RmsNorm,
     mlp: Mlp,
@@ -85,13 +83,9 @@ impl LayerWeights {
         let _enter = self.span_attn.enter();
         let (b_sz, seq_len, n_embd) = x.dims3()?;
 
-        let q = self.attention_wq.forward(x)?;
-        let k = self.attention_wk.forward(x)?;
-        let v = self.attention_wv.forward(x)?;
-
-        let q = q.broadcast_add(&self.attention_bq)?;
-        let k = k.broadcast_add(&self.attention_bk)?;
-        let v = v.broadcast_add(&self.attention_bv)?;
+        let q = self.attention_q.forward(x)?;
+        let k = self.attention_k.forward(x)?;
+        let v = self.attention_v.forward(x)?;
 
         let q = q
             .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
@@ -256,12 +250,9 @@ impl ModelWeights {
             let span_mlp = tracing::span!(tracing::Level::TRACE, "attn-mlp");
 
             layers.push(LayerWeights {
-                attention_wq: QMatMul::from_qtensor(attention_wq)?,
-                attention_wk: QMatMul::from_qtensor(attention_wk)?,
-                attention_wv: QMatMul::from_qtensor(attention_wv)?,
-                attention_bq: attention_bq.dequantize(device)?,
-                attention_bk: attention_bk.dequantize(device)?,
-                attention_bv: attention_bv.dequantize(device)?,
+                attention_q: QLinear::new(attention_wq, Some(attention_bq.dequantize(device)?))?,
+                attention_k: QLinear::new(attention_wk, Some(attention_bk.dequantize(device)?))?,
+                attention_v: QLinear::new(attention_wv, Some(attention_bv.dequantize(device)?))?,
                 attention_wo: QMatMul::from_qtensor(attention_wo)?,
                 attention_norm: RmsNorm::from_qtensor(attention_norm, rms_norm_eps)?,
                 cos: cos.clone(),
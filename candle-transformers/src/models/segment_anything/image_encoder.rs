@@ -41,12 +41,24 @@ impl Module for PatchEmbed {
 //   .reshape((b, q_h * q_w, k_h * k_w))
 // Ideally we would perform this operation in place but this is not supported in candle at the
 // moment. We should also investigate using f16 rather than f32.
+pub(crate) const CUSTOM_OPS: [candle::op_registry::CustomOpDescriptor; 1] =
+    [candle::op_registry::CustomOpDescriptor {
+        name: "add3",
+        params: "b, q_h, q_w, k_h, k_w: usize",
+        source: module_path!(),
+    }];
+
 struct Add3(usize, usize, usize, usize, usize);
 impl candle::CustomOp3 for Add3 {
     fn name(&self) -> &'static str {
         "add3"
     }
 
+    fn params(&self) -> String {
+        let Add3(b, q_h, q_w, k_h, k_w) = *self;
+        format!("b={b}, q_h={q_h}, q_w={q_w}, k_h={k_h}, k_w={k_w}")
+    }
+
     fn cpu_fwd(
         &self,
         s1: &candle::CpuStorage,
@@ -0,0 +1,216 @@
+//! Checks for common prompt formatting mistakes.
+//!
+//! Instruct models produce poor outputs when their prompt does not follow the chat template they
+//! were trained on, and nothing fails loudly in this case. [`check`] inspects the final token
+//! sequence of a prompt, before it is processed by the model, and reports:
+//! - the special markers of the chat template that are missing for an instruct model,
+//! - a BOS token that appears more than once, usually because the prompt already contains one
+//!   and the tokenizer adds another,
+//! - a prompt that does not end with the assistant header, so that the model continues the user
+//!   turn rather than answering it.
+//!
+//! The template of a model is guessed from its gguf metadata, see [`LintConfig::from_gguf`].
+use candle::quantized::gguf_file;
+use candle::Result;
+
+/// The chat template families that can be checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelFamily {
+    /// `<|start_header_id|>user<|end_header_id|>...`
+    Llama3,
+    /// `[INST] ... [/INST]`, also used by the llama 2 chat models.
+    Mistral,
+    /// `<|user|>\n...<|assistant|>\n`, also used by the zephyr models.
+    Falcon3,
+    /// `<|im_start|>user\n...<|im_end|>\n<|im_start|>assistant\n`, e.g. qwen or smollm.
+    ChatMl,
+    /// `GPT4 Correct User: ...<|end_of_turn|>GPT4 Correct Assistant:`
+    OpenChat,
+    /// `<｜User｜>...<｜Assistant｜>`
+    DeepSeek,
+}
+
+impl ModelFamily {
+    /// The markers that any prompt following the template contains.
+    pub fn required_markers(&self) -> &'static [&'static str] {
+        match self {
+            Self::Llama3 => &["<|start_header_id|>", "<|end_header_id|>"],
+            Self::Mistral => &["[INST]", "[/INST]"],
+            Self::Falcon3 => &["<|user|>"],
+            Self::ChatMl => &["<|im_start|>"],
+            Self::OpenChat => &["GPT4 Correct User:"],
+            Self::DeepSeek => &["<｜User｜>"],
+        }
+    }
+
+    /// The text that a prompt ends with when the assistant is to answer, whitespace is ignored
+    /// as decoders differ in the spaces they put around special tokens.
+    pub fn assistant_header(&self) -> &'static str {
+        match self {
+            Self::Llama3 => "<|start_header_id|>assistant<|end_header_id|>",
+            Self::Mistral => "[/INST]",
+            Self::Falcon3 => "<|assistant|>",
+            Self::ChatMl => "<|im_start|>assistant",
+            Self::OpenChat => "GPT4 Correct Assistant:",
+            Self::DeepSeek => "<｜Assistant｜>",
+        }
+    }
+
+    /// Guesses the family from a chat template, see [`ModelFamily::from_name`] for the model
+    /// names.
+    pub fn from_chat_template(template: &str) -> Option<Self> {
+        let families = [
+            Self::Llama3,
+            Self::ChatMl,
+            Self::DeepSeek,
+            Self::OpenChat,
+            Self::Falcon3,
+            Self::Mistral,
+        ];
+        families.into_iter().find(|family| {
+            family
+                .required_markers()
+                .iter()
+                .all(|m| template.contains(m))
+        })
+    }
+
+    /// Guesses the family from a model name such as the gguf `general.name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|p| name.contains(p));
+        if has(&["deepseek"]) {
+            Some(Self::DeepSeek)
+        } else if has(&["llama-3", "llama 3", "llama3"]) {
+            Some(Self::Llama3)
+        } else if has(&["falcon3", "falcon-3", "falcon 3", "zephyr"]) {
+            Some(Self::Falcon3)
+        } else if has(&["openchat", "starling"]) {
+            Some(Self::OpenChat)
+        } else if has(&["qwen", "smollm"]) {
+            Some(Self::ChatMl)
+        } else if has(&["mistral", "mixtral", "llama-2", "llama 2", "llama2"]) {
+            Some(Self::Mistral)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the checks need to know about the model.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LintConfig {
+    /// The chat template, `None` when it is unknown in which case only the BOS check runs.
+    pub family: Option<ModelFamily>,
+    /// Whether the model is fine tuned to follow a chat template.
+    pub instruct: bool,
+    pub bos_token_id: Option<u32>,
+}
+
+impl LintConfig {
+    pub fn new(family: Option<ModelFamily>, instruct: bool, bos_token_id: Option<u32>) -> Self {
+        Self {
+            family,
+            instruct,
+            bos_token_id,
+        }
+    }
+
+    /// Guesses the configuration from the gguf metadata. The family comes from
+    /// `tokenizer.chat_template` when present and from `general.name` otherwise. The model is
+    /// considered to be an instruct model when it has a chat template or when its name says so.
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Self> {
+        let string = |key: &str| match ct.metadata.get(key) {
+            None => Ok(None),
+            Some(value) => value.to_string().map(|v| Some(v.as_str())),
+        };
+        let name = string("general.name")?.unwrap_or("");
+        let chat_template = string("tokenizer.chat_template")?;
+        let family = chat_template
+            .and_then(ModelFamily::from_chat_template)
+            .or_else(|| ModelFamily::from_name(name));
+        let lower_name = name.to_lowercase();
+        let instruct = chat_template.is_some()
+            || ["instruct", "chat", "-it", "zephyr", "openchat", "starling"]
+                .iter()
+                .any(|p| lower_name.contains(p));
+        let bos_token_id = match ct.metadata.get("tokenizer.ggml.bos_token_id") {
+            None => None,
+            Some(value) => Some(value.to_u32()?),
+        };
+        Ok(Self::new(family, instruct, bos_token_id))
+    }
+}
+
+/// A possible problem with a prompt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintWarning {
+    /// An instruct model gets a prompt without the markers of its chat template.
+    MissingTemplate {
+        family: ModelFamily,
+        missing: Vec<&'static str>,
+    },
+    /// The BOS token appears more than once.
+    DuplicateBos { count: usize },
+    /// The prompt follows the template but does not end with the assistant header.
+    MissingAssistantHeader { expected: &'static str },
+}
+
+impl std::fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingTemplate { family, missing } => write!(
+                f,
+                "the prompt does not use the {family:?} chat template of this instruct model, \
+                 missing {}",
+                missing.join(", ")
+            ),
+            Self::DuplicateBos { count } => write!(
+                f,
+                "the BOS token appears {count} times, the tokenizer may add one on top of the \
+                 prompt"
+            ),
+            Self::MissingAssistantHeader { expected } => write!(
+                f,
+                "the prompt does not end with the assistant header {expected:?}"
+            ),
+        }
+    }
+}
+
+/// Checks the tokens of a prompt, `decode` returns the text of some tokens with the special
+/// tokens included, e.g. `tokenizer.decode(ids, false)` for a `tokenizers::Tokenizer`.
+pub fn check<D>(tokens: &[u32], config: &LintConfig, decode: D) -> Result<Vec<LintWarning>>
+where
+    D: Fn(&[u32]) -> Result<String>,
+{
+    let mut warnings = vec![];
+    if let Some(bos) = config.bos_token_id {
+        let count = tokens.iter().filter(|&&t| t == bos).count();
+        if count > 1 {
+            warnings.push(LintWarning::DuplicateBos { count })
+        }
+    }
+    let family = match config.family {
+        Some(family) if config.instruct => family,
+        _ => return Ok(warnings),
+    };
+    let text = decode(tokens)?;
+    let missing = family
+        .required_markers()
+        .iter()
+        .copied()
+        .filter(|marker| !text.contains(marker))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        warnings.push(LintWarning::MissingTemplate { family, missing })
+    } else if !without_whitespace(&text).ends_with(&without_whitespace(family.assistant_header())) {
+        let expected = family.assistant_header();
+        warnings.push(LintWarning::MissingAssistantHeader { expected })
+    }
+    Ok(warnings)
+}
+
+fn without_whitespace(s: &str) -> String {
+    s.chars().filter(|c| !c.is_whitespace()).collect()
+}
@@ -81,6 +81,22 @@ pub fn linear(in_dim: usize, out_dim: usize, vb: VarBuilder) -> Result<Linear> {
     })
 }
 
+/// A [`candle_nn::quantized::QLinear`] layer, its bias is only read when `bias` is set.
+pub fn qlinear_b(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: VarBuilder,
+) -> Result<candle_nn::quantized::QLinear> {
+    let bias = if bias {
+        Some(vb.get(out_dim, "bias")?.dequantize(vb.device())?)
+    } else {
+        None
+    };
+    let weight = vb.get((out_dim, in_dim), "weight")?;
+    candle_nn::quantized::QLinear::from_arc(weight, bias)
+}
+
 pub fn layer_norm(size: usize, eps: f64, vb: VarBuilder) -> Result<candle_nn::LayerNorm> {
     let weight = vb.get(size, "weight")?.dequantize(vb.device())?;
     let bias = vb.get(size, "bias")?.dequantize(vb.device())?;
@@ -0,0 +1,244 @@
+//! Known-answer checks of the kernels of a device.
+//!
+//! A broken driver or an unsupported gpu can produce garbage without any error. [`run`] runs the
+//! kernels used by the quantized models on small fixed inputs and compares the results with a
+//! reference computed on the cpu with the simplest implementation of each op. The differences
+//! are relative to the largest magnitude of the reference, so one tolerance works for all sizes.
+use candle::quantized::{ggml_file, GgmlDType, QMatMul, QTensor};
+use candle::{DType, Device, Module, Result, Tensor, D};
+
+/// The outcome of one check.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckResult {
+    pub name: String,
+    /// The largest difference with the reference, relative to the largest reference value.
+    pub max_diff: f32,
+    pub tolerance: f32,
+    /// The error returned by the kernel, if any.
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        // A NaN difference fails.
+        self.error.is_none() && self.max_diff <= self.tolerance
+    }
+}
+
+impl std::fmt::Display for CheckResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = if self.passed() { "pass" } else { "FAIL" };
+        match &self.error {
+            Some(err) => write!(f, "{status} {}: {err}", self.name),
+            None => write!(
+                f,
+                "{status} {}: delta {:.2e}, tolerance {:.0e}",
+                self.name, self.max_diff, self.tolerance
+            ),
+        }
+    }
+}
+
+/// The results of all the checks, see [`run`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub device: String,
+    pub checks: Vec<CheckResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|c| c.passed())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|c| !c.passed())
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let failed = self.failures().count();
+        write!(f, "self-test on {}: ", self.device)?;
+        match failed {
+            0 => write!(f, "{} checks passed", self.checks.len())?,
+            _ => write!(f, "{failed} of {} checks failed", self.checks.len())?,
+        }
+        for check in self.checks.iter() {
+            write!(f, "\n  {check}")?
+        }
+        Ok(())
+    }
+}
+
+// The tolerances of the checks, the quantized matmuls also quantize their activations.
+const QMATMUL_TOLERANCE: f32 = 2e-2;
+const F32_TOLERANCE: f32 = 1e-4;
+const F16_GEMM_TOLERANCE: f32 = 2e-2;
+
+/// Runs the checks on `device`: a quantized matmul for each of `dtypes`, the softmax, rms norm,
+/// and rope kernels, and a f16 gemm with the current reduced precision setting of the cuda
+/// gemms. A kernel that returns an error is reported as a failed check, errors of the cpu
+/// reference are returned.
+pub fn run(device: &Device, dtypes: &[GgmlDType]) -> Result<Report> {
+    run_with_reference(device, dtypes, |_, reference| Ok(reference))
+}
+
+/// Like [`run`], `reference` gets the name and the cpu reference of each check and returns the
+/// values to compare with, e.g. to corrupt a reference and exercise the failure path.
+pub fn run_with_reference<F>(device: &Device, dtypes: &[GgmlDType], reference: F) -> Result<Report>
+where
+    F: FnMut(&str, Tensor) -> Result<Tensor>,
+{
+    let mut checker = Checker {
+        device,
+        reference,
+        checks: vec![],
+    };
+    let mut seen = vec![];
+    for &dtype in dtypes {
+        if !seen.contains(&dtype) {
+            seen.push(dtype);
+            checker.qmatmul(dtype)?
+        }
+    }
+    checker.softmax()?;
+    checker.rms_norm()?;
+    checker.rope()?;
+    checker.f16_gemm()?;
+    Ok(Report {
+        device: format!("{:?}", device.location()),
+        checks: checker.checks,
+    })
+}
+
+// Deterministic values in [-1, 1] so that the checks do not depend on the rng of a device.
+fn input(shape: &[usize], seed: f32) -> Result<Tensor> {
+    let n = shape.iter().product::<usize>();
+    let data = (0..n)
+        .map(|i| (i as f32 * 0.7548777 + seed).sin())
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, shape, &Device::Cpu)
+}
+
+fn relative_diff(actual: &Tensor, expected: &Tensor) -> Result<f32> {
+    let actual = actual.to_device(&Device::Cpu)?.to_dtype(DType::F32)?;
+    let expected = expected.to_dtype(DType::F32)?;
+    let diff = (actual - &expected)?.abs()?.flatten_all()?.max(0)?;
+    let scale = expected.abs()?.flatten_all()?.max(0)?;
+    let (diff, scale) = (diff.to_scalar::<f32>()?, scale.to_scalar::<f32>()?);
+    if diff.is_nan() {
+        return Ok(f32::NAN);
+    }
+    Ok(diff / scale.max(f32::MIN_POSITIVE))
+}
+
+struct Checker<'a, F> {
+    device: &'a Device,
+    reference: F,
+    checks: Vec<CheckResult>,
+}
+
+impl<F: FnMut(&str, Tensor) -> Result<Tensor>> Checker<'_, F> {
+    // Compares the result of `kernel` with `expected`, the cpu reference.
+    fn check(
+        &mut self,
+        name: String,
+        tolerance: f32,
+        expected: Tensor,
+        kernel: impl FnOnce() -> Result<Tensor>,
+    ) -> Result<()> {
+        let expected = (self.reference)(&name, expected)?;
+        let (max_diff, error) = match kernel().and_then(|t| relative_diff(&t, &expected)) {
+            Ok(diff) => (diff, None),
+            Err(err) => (f32::NAN, Some(err.to_string())),
+        };
+        self.checks.push(CheckResult {
+            name,
+            max_diff,
+            tolerance,
+            error,
+        });
+        Ok(())
+    }
+
+    fn qmatmul(&mut self, dtype: GgmlDType) -> Result<()> {
+        let (rows, cols) = (64, 256);
+        let w = input(&[rows, cols], 1.)?;
+        let xs = input(&[4, cols], 2.)?;
+        let qw = QTensor::quantize(&w, dtype)?;
+        let expected = xs.matmul(&qw.dequantize(&Device::Cpu)?.t()?)?;
+        let device = self.device;
+        self.check(
+            format!("qmatmul {dtype:?}"),
+            QMATMUL_TOLERANCE,
+            expected,
+            || {
+                let qw =
+                    ggml_file::qtensor_from_ggml(dtype, &qw.data()?, vec![rows, cols], device)?;
+                let mm = QMatMul::from_qtensor(qw)?;
+                // A single row goes through the matrix-vector kernels.
+                let x0 = mm.forward(&xs.narrow(0, 0, 1)?.to_device(device)?)?;
+                let x = mm.forward(&xs.narrow(0, 1, 3)?.to_device(device)?)?;
+                Tensor::cat(&[x0, x], 0)
+            },
+        )
+    }
+
+    fn softmax(&mut self) -> Result<()> {
+        let xs = (input(&[8, 97], 3.)? * 4.)?;
+        let expected = candle_nn::ops::softmax(&xs, D::Minus1)?;
+        let device = self.device;
+        self.check("softmax".to_string(), F32_TOLERANCE, expected, || {
+            candle_nn::ops::softmax_last_dim(&xs.to_device(device)?)
+        })
+    }
+
+    fn rms_norm(&mut self) -> Result<()> {
+        let xs = input(&[8, 96], 4.)?;
+        let alpha = (input(&[96], 5.)? + 1.)?;
+        let expected = candle_nn::ops::rms_norm_slow(&xs, &alpha, 1e-5)?;
+        let device = self.device;
+        self.check("rms norm".to_string(), F32_TOLERANCE, expected, || {
+            candle_nn::ops::rms_norm(&xs.to_device(device)?, &alpha.to_device(device)?, 1e-5)
+        })
+    }
+
+    fn rope(&mut self) -> Result<()> {
+        use candle_nn::rotary_emb;
+
+        let (b, h, t, d) = (1, 4, 7, 32);
+        let xs = input(&[b, h, t, d], 6.)?;
+        let theta = (input(&[t, d / 2], 7.)? * 3.)?;
+        let (cos, sin) = (theta.cos()?, theta.sin()?);
+        let device = self.device;
+        let on_device = |t: &Tensor| t.to_device(device);
+        // The interleaved variant is used by the llama models, the other one by the neox ones.
+        let expected = rotary_emb::rope_i_slow(&xs, &cos, &sin)?;
+        self.check("rope".to_string(), F32_TOLERANCE, expected, || {
+            rotary_emb::rope_i(&on_device(&xs)?, &on_device(&cos)?, &on_device(&sin)?)
+        })?;
+        let expected = rotary_emb::rope_slow(&xs, &cos, &sin)?;
+        self.check("rope neox".to_string(), F32_TOLERANCE, expected, || {
+            rotary_emb::rope(&on_device(&xs)?, &on_device(&cos)?, &on_device(&sin)?)
+        })
+    }
+
+    fn f16_gemm(&mut self) -> Result<()> {
+        // The reference uses the same f16 inputs, the difference comes from the accumulation
+        // and the rounding of the result.
+        let lhs = input(&[2, 8, 256], 8.)?.to_dtype(DType::F16)?;
+        let rhs = input(&[2, 256, 48], 9.)?.to_dtype(DType::F16)?;
+        let expected = lhs
+            .to_dtype(DType::F32)?
+            .matmul(&rhs.to_dtype(DType::F32)?)?;
+        let name = match candle::cuda::gemm_reduced_precision_f16() {
+            true => "f16 gemm, reduced precision",
+            false => "f16 gemm",
+        };
+        let device = self.device;
+        self.check(name.to_string(), F16_GEMM_TOLERANCE, expected, || {
+            lhs.to_device(device)?.matmul(&rhs.to_device(device)?)
+        })
+    }
+}
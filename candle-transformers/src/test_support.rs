@@ -0,0 +1,181 @@
+//! Tiny deterministic models for tests, built in memory or serialized as gguf.
+//!
+//! [`tiny_test_model`] has the shape of the `tests/fixtures/tiny-llama.gguf` fixture: 2 layers,
+//! a hidden size of 64, and a vocabulary of 256 tokens. With the fixture seed, `20240607`, it
+//! has the same weights as the fixture, and [`tiny_test_gguf`] gives the bytes of the fixture.
+//! [`gguf_bytes`] and [`gguf_content`] write other gguf files.
+use crate::models::quantized_llama::{LayerWeights, ModelConfig, ModelWeights, Norm};
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, Result, Tensor};
+
+pub const VOCAB_SIZE: usize = 256;
+pub const HIDDEN_SIZE: usize = 64;
+pub const FFN_SIZE: usize = 128;
+pub const N_HEAD: usize = 4;
+pub const N_KV_HEAD: usize = 2;
+pub const N_LAYER: usize = 2;
+pub const MAX_SEQ_LEN: usize = 256;
+
+/// A `(rows, cols)` weight with values in `{-s, 0, s}` where `s = 1 / sqrt(cols)`, drawn from a
+/// small lcg so that the values do not depend on the rng implementation. `seed` is advanced
+/// by one step per value.
+pub fn ternary_weight(seed: &mut u64, shape: (usize, usize), dtype: GgmlDType) -> Result<QTensor> {
+    let scale = 1. / (shape.1 as f32).sqrt();
+    let data = (0..shape.0 * shape.1)
+        .map(|_| {
+            *seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (((*seed >> 33) % 3) as f32 - 1.) * scale
+        })
+        .collect::<Vec<_>>();
+    let t = Tensor::from_vec(data, shape, &Device::Cpu)?;
+    QTensor::quantize(&t, dtype)
+}
+
+/// The hyper-parameters of [`tiny_test_model`].
+pub fn tiny_test_config() -> ModelConfig {
+    let head_dim = HIDDEN_SIZE / N_HEAD;
+    ModelConfig {
+        n_head: N_HEAD,
+        n_kv_head: N_KV_HEAD,
+        head_dim,
+        embedding_length: HIDDEN_SIZE,
+        rope_dim: head_dim,
+        rope_freq_base: 10000.,
+        neox_rope: false,
+        max_seq_len: MAX_SEQ_LEN,
+    }
+}
+
+/// A llama model with q8_0 ternary weights and unit norms on the cpu.
+pub fn tiny_test_model(seed: u64) -> Result<ModelWeights> {
+    tiny_test_model_with(seed, |_, _| GgmlDType::Q8_0)
+}
+
+/// Like [`tiny_test_model`] with the dtype of each weight picked by `dtype`, which gets the gguf
+/// name of the weight and its default dtype, e.g. to mix quantized and f16 layers.
+pub fn tiny_test_model_with<F>(seed: u64, dtype: F) -> Result<ModelWeights>
+where
+    F: Fn(&str, GgmlDType) -> GgmlDType,
+{
+    let mut seed = seed;
+    let mut weight =
+        |name: &str, shape| ternary_weight(&mut seed, shape, dtype(name, GgmlDType::Q8_0));
+    let ones = |size: usize| {
+        let t = Tensor::ones(size, DType::F32, &Device::Cpu)?;
+        Norm::rms(QTensor::quantize(&t, GgmlDType::F32)?, 1e-5)
+    };
+    let config = tiny_test_config();
+    let kv = N_KV_HEAD * config.head_dim;
+    // The weights are drawn in the order of the fixture file.
+    let embed = weight("token_embd.weight", (VOCAB_SIZE, HIDDEN_SIZE))?;
+    let output = weight("output.weight", (VOCAB_SIZE, HIDDEN_SIZE))?;
+    let mut layers = Vec::with_capacity(N_LAYER);
+    for i in 0..N_LAYER {
+        let mut get = |name: &str, shape| weight(&format!("blk.{i}.{name}.weight"), shape);
+        let wq = get("attn_q", (HIDDEN_SIZE, HIDDEN_SIZE))?;
+        let wk = get("attn_k", (kv, HIDDEN_SIZE))?;
+        let wv = get("attn_v", (kv, HIDDEN_SIZE))?;
+        let wo = get("attn_output", (HIDDEN_SIZE, HIDDEN_SIZE))?;
+        let gate = get("ffn_gate", (FFN_SIZE, HIDDEN_SIZE))?;
+        let up = get("ffn_up", (FFN_SIZE, HIDDEN_SIZE))?;
+        let down = get("ffn_down", (HIDDEN_SIZE, FFN_SIZE))?;
+        let layer = LayerWeights::builder()
+            .attention(wq, wk, wv, wo)?
+            .mlp(gate, down, up)?
+            .attention_norm(ones(HIDDEN_SIZE)?)
+            .ffn_norm(ones(HIDDEN_SIZE)?)
+            .build()?;
+        layers.push(layer)
+    }
+    ModelWeights::from_layers(config, layers, embed, ones(HIDDEN_SIZE)?, output)
+}
+
+/// Serializes the metadata and the tensors as a gguf file.
+pub fn gguf_bytes(
+    metadata: &[(&str, gguf_file::Value)],
+    tensors: &[(String, QTensor)],
+) -> Result<Vec<u8>> {
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let mut buffer = std::io::Cursor::new(Vec::new());
+    gguf_file::write(&mut buffer, &metadata, &tensors)?;
+    Ok(buffer.into_inner())
+}
+
+/// The header of the gguf file written by [`gguf_bytes`].
+pub fn gguf_content(
+    metadata: &[(&str, gguf_file::Value)],
+    tensors: &[(String, QTensor)],
+) -> Result<gguf_file::Content> {
+    let bytes = gguf_bytes(metadata, tensors)?;
+    gguf_file::Content::read(&mut std::io::Cursor::new(bytes))
+}
+
+/// [`tiny_test_model`] serialized as gguf.
+pub fn tiny_test_gguf(seed: u64) -> Result<Vec<u8>> {
+    tiny_test_gguf_with(seed, |_, _| GgmlDType::Q8_0)
+}
+
+/// [`tiny_test_model_with`] serialized as gguf, the norms are always f32.
+pub fn tiny_test_gguf_with<F>(seed: u64, dtype: F) -> Result<Vec<u8>>
+where
+    F: Fn(&str, GgmlDType) -> GgmlDType,
+{
+    use gguf_file::Value;
+
+    let mut seed = seed;
+    let mut weight = |name: String, shape| -> Result<(String, QTensor)> {
+        let w = ternary_weight(&mut seed, shape, dtype(&name, GgmlDType::Q8_0))?;
+        Ok((name, w))
+    };
+    let ones = |name: String| -> Result<(String, QTensor)> {
+        let t = Tensor::ones(HIDDEN_SIZE, DType::F32, &Device::Cpu)?;
+        Ok((name, QTensor::quantize(&t, GgmlDType::F32)?))
+    };
+    let config = tiny_test_config();
+    let kv = N_KV_HEAD * config.head_dim;
+    let mut tensors = vec![
+        weight("token_embd.weight".to_string(), (VOCAB_SIZE, HIDDEN_SIZE))?,
+        ones("output_norm.weight".to_string())?,
+        weight("output.weight".to_string(), (VOCAB_SIZE, HIDDEN_SIZE))?,
+    ];
+    for i in 0..N_LAYER {
+        for (name, shape) in [
+            ("attn_q", (HIDDEN_SIZE, HIDDEN_SIZE)),
+            ("attn_k", (kv, HIDDEN_SIZE)),
+            ("attn_v", (kv, HIDDEN_SIZE)),
+            ("attn_output", (HIDDEN_SIZE, HIDDEN_SIZE)),
+            ("ffn_gate", (FFN_SIZE, HIDDEN_SIZE)),
+            ("ffn_up", (FFN_SIZE, HIDDEN_SIZE)),
+            ("ffn_down", (HIDDEN_SIZE, FFN_SIZE)),
+        ] {
+            tensors.push(weight(format!("blk.{i}.{name}.weight"), shape)?)
+        }
+        tensors.push(ones(format!("blk.{i}.attn_norm.weight"))?);
+        tensors.push(ones(format!("blk.{i}.ffn_norm.weight"))?);
+    }
+    let metadata = [
+        ("general.architecture", Value::String("llama".to_string())),
+        ("general.name", Value::String("tiny-llama".to_string())),
+        ("llama.attention.head_count", Value::U32(N_HEAD as u32)),
+        (
+            "llama.attention.head_count_kv",
+            Value::U32(N_KV_HEAD as u32),
+        ),
+        ("llama.block_count", Value::U32(N_LAYER as u32)),
+        ("llama.embedding_length", Value::U32(HIDDEN_SIZE as u32)),
+        ("llama.feed_forward_length", Value::U32(FFN_SIZE as u32)),
+        (
+            "llama.rope.dimension_count",
+            Value::U32(config.rope_dim as u32),
+        ),
+        ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ("llama.context_length", Value::U32(MAX_SEQ_LEN as u32)),
+    ];
+    gguf_bytes(&metadata, &tensors)
+}
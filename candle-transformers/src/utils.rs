@@ -1,6 +1,7 @@
 //! Apply penalty and repeat_kv
 
-use candle::{Result, Tensor};
+use candle::{DType, Result, Tensor, D};
+use candle_nn::Module;
 
 pub fn apply_repeat_penalty(logits: &Tensor, penalty: f32, context: &[u32]) -> Result<Tensor> {
     let device = logits.device();
@@ -36,3 +37,58 @@ pub fn repeat_kv(xs: Tensor, n_rep: usize) -> Result<Tensor> {
         Tensor::cat(&vec![&xs; n_rep], 2)?.reshape((b_sz, n_kv_head * n_rep, seq_len, head_dim))
     }
 }
+
+/// Applies `head` to `xs` of shape `(b_sz, seq_len, hidden)` by chunks of at most `chunk_size`
+/// positions, the results are written in a pre-allocated output tensor. This produces the same
+/// values as `head.forward(xs)` while bounding the size of the temporary buffers, which matters
+/// for lm heads with a large vocabulary.
+pub fn chunked_forward<M: Module>(head: &M, xs: &Tensor, chunk_size: usize) -> Result<Tensor> {
+    let (b_sz, seq_len, _hidden) = xs.dims3()?;
+    if chunk_size == 0 {
+        candle::bail!("chunk_size must be positive")
+    }
+    if seq_len <= chunk_size {
+        return head.forward(xs);
+    }
+    let mut out: Option<Tensor> = None;
+    for start in (0..seq_len).step_by(chunk_size) {
+        let len = chunk_size.min(seq_len - start);
+        let ys = head.forward(&xs.narrow(1, start, len)?)?;
+        let out = match out.as_ref() {
+            Some(out) => out,
+            None => {
+                let (_, _, out_dim) = ys.dims3()?;
+                let zeros = Tensor::zeros((b_sz, seq_len, out_dim), ys.dtype(), ys.device())?;
+                out.insert(zeros)
+            }
+        };
+        out.slice_set(&ys, 1, start)?;
+    }
+    out.ok_or_else(|| candle::Error::msg("empty sequence"))
+}
+
+/// Returns the negative log likelihood of `targets`, a `(b_sz, seq_len)` tensor of token ids,
+/// under the logits obtained by applying `head` to `xs` of shape `(b_sz, seq_len, hidden)`. The
+/// logits are computed by chunks of at most `chunk_size` positions and immediately reduced so
+/// that the full logits never exist. The result has shape `(b_sz, seq_len)` and dtype f32.
+pub fn chunked_nll<M: Module>(
+    head: &M,
+    xs: &Tensor,
+    targets: &Tensor,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let (_b_sz, seq_len, _hidden) = xs.dims3()?;
+    if chunk_size == 0 {
+        candle::bail!("chunk_size must be positive")
+    }
+    let targets = targets.to_dtype(DType::U32)?;
+    let mut nlls = Vec::with_capacity(seq_len.div_ceil(chunk_size));
+    for start in (0..seq_len).step_by(chunk_size) {
+        let len = chunk_size.min(seq_len - start);
+        let logits = head.forward(&xs.narrow(1, start, len)?)?;
+        let log_sm = candle_nn::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+        let targets = targets.narrow(1, start, len)?.contiguous()?.unsqueeze(2)?;
+        nlls.push(log_sm.gather(&targets, 2)?.squeeze(2)?.neg()?)
+    }
+    Tensor::cat(&nlls, 1)
+}
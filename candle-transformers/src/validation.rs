@@ -0,0 +1,561 @@
+//! Validation of a run configuration without loading the weights, e.g. for a CI dry run.
+//!
+//! [`validate_configuration`] runs every check that does not need the tensor data: the gguf
+//! header and metadata are parsed, the tokenizer is compared with the embeddings, a sample
+//! conversation is rendered with the chat template, the stop criteria, the constrained decoding
+//! schedule and the regexes are compiled, and the memory of the weights and of the kv cache is
+//! checked against the free memory of the device, only as a warning on cpu. The problems are
+//! collected in a [`ValidationReport`] rather than returned as errors so that a single run lists
+//! all of them.
+use crate::generation::chat::{ChatFormat, ChatMessage, Role};
+use crate::generation::constraint::ConstraintSchedule;
+use crate::generation::{GenerationParams, StopConditions, TokenBudget, TokenBudgetError};
+use crate::kv_budget::{self, ContextBudget, MemoryEstimate};
+use crate::models::quantized_llama::MAX_SEQ_LEN;
+use crate::prompt_lint;
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Result};
+use std::path::Path;
+
+/// The tokenizer of a run, as seen by the checks.
+pub trait TokenizerInfo {
+    /// The number of tokens, including the added tokens.
+    fn vocab_size(&self) -> usize;
+
+    /// The added tokens together with their content, see [`StopConditions::new`].
+    fn added_tokens(&self) -> Vec<(u32, String)>;
+
+    /// Tokenizes `text`, with the special tokens that start a sequence when
+    /// `add_special_tokens` is set.
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>>;
+
+    /// Decodes some tokens with the special tokens included.
+    fn decode(&self, tokens: &[u32]) -> Result<String>;
+}
+
+/// What a run is made of, the fields mirror the options of the loaders and of the generation.
+pub struct ValidationConfig<'a> {
+    /// The gguf file, only its header is read.
+    pub model: &'a Path,
+    /// `None` skips the checks that need a tokenizer.
+    pub tokenizer: Option<&'a dyn TokenizerInfo>,
+    /// The chat template, `None` skips the rendering of [`sample_conversation`].
+    pub chat_format: Option<&'a dyn ChatFormat>,
+    pub params: GenerationParams,
+    /// The constrained decoding schedule, e.g. for tool calling.
+    pub schedule: Option<ConstraintSchedule>,
+    /// Regexes compiled by the run, e.g. the patterns of a `RegexGuardrail`.
+    pub patterns: Vec<String>,
+    /// The requested context length, the one of the model when `None`.
+    pub context_length: Option<usize>,
+    /// The positions kept free after the prompt and the generated tokens, see
+    /// [`TokenBudget::with_reserve`].
+    pub context_reserve: usize,
+    pub kv_dtype: DType,
+    /// The free memory of the device, `None` when it is unknown.
+    pub free_bytes: Option<u64>,
+    /// The memory kept aside for the activations and the allocator.
+    pub reserve_bytes: u64,
+    /// A fixed kv cache budget, used instead of the free memory when set.
+    pub kv_budget_bytes: Option<u64>,
+    /// See [`kv_budget::quantized_embeddings`].
+    pub quantized_embeddings: Option<bool>,
+    pub device: Device,
+}
+
+impl<'a> ValidationConfig<'a> {
+    /// A configuration with the default generation parameters and f32 kv cache, where the
+    /// memory and the tokenizer are not checked.
+    pub fn new(model: &'a Path, device: &Device) -> Self {
+        Self {
+            model,
+            tokenizer: None,
+            chat_format: None,
+            params: GenerationParams::default(),
+            schedule: None,
+            patterns: vec![],
+            context_length: None,
+            context_reserve: 0,
+            kv_dtype: DType::F32,
+            free_bytes: None,
+            reserve_bytes: 0,
+            kv_budget_bytes: None,
+            quantized_embeddings: None,
+            device: device.clone(),
+        }
+    }
+}
+
+/// The part of the configuration a [`ValidationEntry`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Check {
+    /// The model file and its gguf header.
+    Model,
+    /// The hyper-parameters and tensors listed in the gguf header.
+    Metadata,
+    Tokenizer,
+    ChatTemplate,
+    /// The generation parameters, see [`GenerationParams::validate`].
+    Generation,
+    StopCriteria,
+    Schedule,
+    Pattern,
+    /// The weights and the kv cache against the free memory.
+    Memory,
+    /// The sample conversation and the generated tokens against the context length.
+    Context,
+}
+
+impl std::fmt::Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Model => "model",
+            Self::Metadata => "metadata",
+            Self::Tokenizer => "tokenizer",
+            Self::ChatTemplate => "chat-template",
+            Self::Generation => "generation",
+            Self::StopCriteria => "stop-criteria",
+            Self::Schedule => "schedule",
+            Self::Pattern => "pattern",
+            Self::Memory => "memory",
+            Self::Context => "context",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Errors make the run fail, warnings are likely mistakes that do not prevent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationEntry {
+    pub check: Check,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for ValidationEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity} [{}]: {}", self.check, self.message)
+    }
+}
+
+/// The outcome of [`validate_configuration`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationReport {
+    pub entries: Vec<ValidationEntry>,
+    /// The memory of the weights and of the kv cache, when the gguf header could be read.
+    pub memory: Option<MemoryEstimate>,
+    /// The context length negotiated with the kv cache budget.
+    pub context: Option<ContextBudget>,
+    /// The number of tokens of the rendered [`sample_conversation`].
+    pub sample_prompt_tokens: Option<usize>,
+}
+
+impl ValidationReport {
+    pub fn error(&mut self, check: Check, message: impl Into<String>) {
+        self.push(check, Severity::Error, message.into())
+    }
+
+    pub fn warning(&mut self, check: Check, message: impl Into<String>) {
+        self.push(check, Severity::Warning, message.into())
+    }
+
+    fn push(&mut self, check: Check, severity: Severity, message: String) {
+        self.entries.push(ValidationEntry {
+            check,
+            severity,
+            message,
+        })
+    }
+
+    /// Whether the configuration can run, i.e. there is no error.
+    pub fn is_valid(&self) -> bool {
+        self.errors().next().is_none()
+    }
+
+    pub fn errors(&self) -> impl Iterator<Item = &ValidationEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.severity == Severity::Error)
+    }
+
+    pub fn warnings(&self) -> impl Iterator<Item = &ValidationEntry> {
+        self.entries
+            .iter()
+            .filter(|e| e.severity == Severity::Warning)
+    }
+
+    /// The entries about `check`.
+    pub fn entries_for(&self, check: Check) -> impl Iterator<Item = &ValidationEntry> {
+        self.entries.iter().filter(move |e| e.check == check)
+    }
+
+    /// The memory of the weights and of the kv cache for the negotiated context length.
+    pub fn projected_bytes(&self) -> Option<u64> {
+        let memory = self.memory.as_ref()?;
+        let context_length = self.context.as_ref().map_or(0, |c| c.effective);
+        Some(memory.total_bytes(context_length))
+    }
+}
+
+impl std::fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for entry in self.entries.iter() {
+            writeln!(f, "{entry}")?
+        }
+        if let Some(context) = self.context.as_ref() {
+            writeln!(f, "{context}")?
+        }
+        if let Some(bytes) = self.projected_bytes() {
+            writeln!(f, "projected memory: {}", kv_budget::format_bytes(bytes))?
+        }
+        let count = |n: usize, what: &str| match n {
+            1 => format!("1 {what}"),
+            n => format!("{n} {what}s"),
+        };
+        match self.is_valid() {
+            true => write!(f, "the configuration is valid")?,
+            false => write!(
+                f,
+                "the configuration is invalid, {}",
+                count(self.errors().count(), "error")
+            )?,
+        }
+        match self.warnings().count() {
+            0 => Ok(()),
+            n => write!(f, " ({})", count(n, "warning")),
+        }
+    }
+}
+
+/// The conversation rendered with the chat template of a [`ValidationConfig`].
+pub fn sample_conversation() -> Vec<ChatMessage> {
+    vec![
+        ChatMessage::new(Role::System, "You are a helpful assistant."),
+        ChatMessage::new(Role::User, "Hello!"),
+        ChatMessage::new(Role::Assistant, "Hello, how can I help you?"),
+        ChatMessage::new(Role::User, "What is the capital of France?"),
+    ]
+}
+
+/// The metadata keys read by `quantized_llama` for each supported architecture.
+const REQUIRED_METADATA: [(&str, &[&str]); 2] = [
+    (
+        "llama",
+        &[
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "rope.dimension_count",
+            "attention.layer_norm_rms_epsilon",
+        ],
+    ),
+    (
+        "falcon",
+        &[
+            "attention.head_count",
+            "attention.head_count_kv",
+            "block_count",
+            "embedding_length",
+            "attention.layer_norm_epsilon",
+        ],
+    ),
+];
+
+/// Runs the checks of a configuration, see the module documentation. This only fails on
+/// unexpected errors, the problems with the configuration are in the report.
+pub fn validate_configuration(config: &ValidationConfig) -> Result<ValidationReport> {
+    let mut report = ValidationReport::default();
+    if let Err(err) = config.params.validate() {
+        report.error(Check::Generation, err.to_string())
+    }
+    let ct = read_header(config.model, &mut report);
+    let model_vocab = match ct.as_ref() {
+        Some(ct) => check_metadata(ct, &mut report),
+        None => None,
+    };
+    let tokenizer = config.tokenizer;
+    if let (Some(tokenizer), Some(model_vocab)) = (tokenizer, model_vocab) {
+        let vocab_size = tokenizer.vocab_size();
+        if vocab_size > model_vocab {
+            report.error(
+                Check::Tokenizer,
+                format!(
+                    "the tokenizer has {vocab_size} tokens but the model only embeds {model_vocab}"
+                ),
+            )
+        } else if vocab_size < model_vocab {
+            report.warning(
+                Check::Tokenizer,
+                format!(
+                    "the tokenizer has {vocab_size} tokens and the model embeds {model_vocab}, \
+                     the vocabulary may be padded or the tokenizer may not match the model"
+                ),
+            )
+        }
+    }
+    if let (Some(format), Some(tokenizer)) = (config.chat_format, tokenizer) {
+        check_chat_template(format, tokenizer, ct.as_ref(), &mut report)?
+    }
+
+    let added_tokens = tokenizer.map(|t| t.added_tokens()).unwrap_or_default();
+    let added_tokens = added_tokens.iter().map(|(id, t)| (*id, t.as_str()));
+    if let Err(err) = StopConditions::new(&config.params.stop_criteria(), added_tokens.clone()) {
+        report.error(Check::StopCriteria, err.to_string())
+    }
+    if let (Some(schedule), Some(tokenizer)) = (config.schedule.as_ref(), tokenizer) {
+        let encode = |option: &str| tokenizer.encode(option, false);
+        if let Err(err) = schedule.resolve(added_tokens, encode) {
+            report.error(Check::Schedule, err.to_string())
+        }
+    }
+    for pattern in config.patterns.iter() {
+        if let Err(err) = fancy_regex::Regex::new(pattern) {
+            report.error(Check::Pattern, format!("invalid regex {pattern:?}: {err}"))
+        }
+    }
+    // The memory is estimated from the hyper-parameters, this is skipped when they are invalid.
+    let metadata_ok = report.entries_for(Check::Metadata).next().is_none();
+    if let (Some(ct), true) = (ct.as_ref(), metadata_ok) {
+        check_memory(ct, config, &mut report)
+    }
+    Ok(report)
+}
+
+// Parses the gguf header and checks that the file holds the tensor data it lists.
+fn read_header(path: &Path, report: &mut ValidationReport) -> Option<gguf_file::Content> {
+    let mut file = match std::fs::File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            report.error(
+                Check::Model,
+                format!("cannot open {}: {err}", path.display()),
+            );
+            return None;
+        }
+    };
+    let ct = match gguf_file::Content::read(&mut file) {
+        Ok(ct) => ct,
+        Err(err) => {
+            let message = format!("{} is not a valid gguf file: {err}", path.display());
+            report.error(Check::Model, message);
+            return None;
+        }
+    };
+    let file_size = file.metadata().map(|m| m.len()).unwrap_or(u64::MAX);
+    let data_end = ct
+        .tensor_infos
+        .values()
+        .map(|info| {
+            let dtype = info.ggml_dtype;
+            let size = info.shape.elem_count() * dtype.type_size() / dtype.block_size();
+            ct.tensor_data_offset + info.offset + size as u64
+        })
+        .max()
+        .unwrap_or(0);
+    if data_end > file_size {
+        report.error(
+            Check::Model,
+            format!(
+                "the file is truncated, it has {file_size} bytes and its tensors end at {data_end}"
+            ),
+        )
+    }
+    Some(ct)
+}
+
+// Checks the hyper-parameters and the tensors read by the loader, returns the number of rows of
+// the token embeddings.
+fn check_metadata(ct: &gguf_file::Content, report: &mut ValidationReport) -> Option<usize> {
+    let arch = match ct.metadata.get("general.architecture") {
+        None => "llama".to_string(),
+        Some(arch) => match arch.to_string() {
+            Ok(arch) => arch.clone(),
+            Err(err) => {
+                report.error(Check::Metadata, format!("general.architecture: {err}"));
+                return None;
+            }
+        },
+    };
+    // Models such as mistral use the llama layout and keys.
+    let Some((prefix, keys)) = REQUIRED_METADATA.iter().find(|(a, _)| *a == arch) else {
+        report.error(
+            Check::Metadata,
+            format!("unsupported architecture {arch:?}, expected llama or falcon"),
+        );
+        return None;
+    };
+    let missing = keys
+        .iter()
+        .map(|key| format!("{prefix}.{key}"))
+        .filter(|key| !ct.metadata.contains_key(key))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let message = format!("missing metadata keys: {}", missing.join(", "));
+        report.error(Check::Metadata, message)
+    }
+    let block_count = ct
+        .metadata
+        .get(&format!("{prefix}.block_count"))
+        .and_then(|v| v.to_u32().ok())
+        .unwrap_or(0);
+    let mut tensors = vec![
+        "token_embd.weight".to_string(),
+        "output_norm.weight".to_string(),
+    ];
+    tensors.extend((0..block_count).map(|i| format!("blk.{i}.attn_output.weight")));
+    let missing = tensors
+        .into_iter()
+        .filter(|name| !ct.tensor_infos.contains_key(name))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let message = format!("missing tensors: {}", missing.join(", "));
+        report.error(Check::Metadata, message)
+    }
+    ct.tensor_infos
+        .get("token_embd.weight")
+        .and_then(|info| info.shape.dims().first().copied())
+}
+
+// Renders the sample conversation, tokenizes it and lints the tokens.
+fn check_chat_template(
+    format: &dyn ChatFormat,
+    tokenizer: &dyn TokenizerInfo,
+    ct: Option<&gguf_file::Content>,
+    report: &mut ValidationReport,
+) -> Result<()> {
+    let text = format.render(&sample_conversation());
+    if text.is_empty() {
+        report.error(Check::ChatTemplate, "the sample conversation renders empty");
+        return Ok(());
+    }
+    let tokens = match tokenizer.encode(&text, true) {
+        Ok(tokens) => tokens,
+        Err(err) => {
+            let message = format!("cannot tokenize the sample conversation: {err}");
+            report.error(Check::ChatTemplate, message);
+            return Ok(());
+        }
+    };
+    report.sample_prompt_tokens = Some(tokens.len());
+    let lint_config = match ct.map(prompt_lint::LintConfig::from_gguf) {
+        Some(Ok(config)) => config,
+        None | Some(Err(_)) => return Ok(()),
+    };
+    for warning in prompt_lint::check(&tokens, &lint_config, |ids| tokenizer.decode(ids))? {
+        report.warning(Check::ChatTemplate, warning.to_string())
+    }
+    Ok(())
+}
+
+// The same negotiation as a run: the weights have to fit in the free memory, the kv cache gets
+// what is left, and the sample conversation has to fit in the resulting context.
+fn check_memory(ct: &gguf_file::Content, config: &ValidationConfig, report: &mut ValidationReport) {
+    let estimate = match MemoryEstimate::from_gguf(ct, config.kv_dtype, &config.device) {
+        Ok(estimate) => estimate,
+        Err(err) => {
+            report.error(Check::Metadata, err.to_string());
+            return;
+        }
+    };
+    if config.quantized_embeddings == Some(true) && config.device.is_metal() {
+        report.error(
+            Check::Memory,
+            "quantized embeddings are not supported on metal",
+        )
+    }
+    let quantized_embeddings = kv_budget::quantized_embeddings(
+        estimate.dense_embeddings_bytes,
+        config.quantized_embeddings,
+        &config.device,
+    );
+    let estimate = estimate.with_quantized_embeddings(quantized_embeddings);
+    report.memory = Some(estimate);
+    let mut free_bytes = config.free_bytes;
+    if let Some(free) = free_bytes {
+        if let Err(err) = estimate.check(free, config.reserve_bytes) {
+            // The free memory of the cpu leaves out the swap and the overcommit, the weights are
+            // still loaded and the free memory does not cap the context.
+            if config.device.is_cpu() {
+                report.warning(Check::Memory, err.to_string());
+                free_bytes = None
+            } else {
+                report.error(Check::Memory, err.to_string())
+            }
+        }
+    }
+    let budget = match (config.kv_budget_bytes, free_bytes) {
+        (Some(budget), _) => Some(budget),
+        (None, free) => free.map(|free| estimate.kv_budget(free, config.reserve_bytes)),
+    };
+
+    let max_seq_len = model_context_length(ct);
+    let requested = match config.context_length {
+        None => max_seq_len,
+        Some(len) if len > max_seq_len => {
+            report.warning(
+                Check::Context,
+                format!("the context length {len} is above the {max_seq_len} positions supported by the model"),
+            );
+            max_seq_len
+        }
+        Some(len) => len,
+    };
+    let context = match ContextBudget::negotiate(&estimate.kv, requested, budget) {
+        Ok(context) => context,
+        Err(err) => {
+            report.error(Check::Memory, err.to_string());
+            return;
+        }
+    };
+    if context.is_capped() {
+        report.warning(Check::Memory, context.to_string())
+    }
+    report.context = Some(context);
+
+    let Some(prompt_len) = report.sample_prompt_tokens else {
+        return;
+    };
+    let budget = TokenBudget::new(context.effective).with_reserve(config.context_reserve);
+    let max_tokens = config.params.max_tokens;
+    match budget.plan(prompt_len, max_tokens) {
+        Err(err @ TokenBudgetError::NoContext { .. }) => {
+            report.error(Check::Context, err.to_string())
+        }
+        Err(err @ TokenBudgetError::PromptTooLong { .. }) => report.error(
+            Check::Context,
+            format!("the sample conversation does not fit: {err}"),
+        ),
+        Ok(plan) if plan.new_tokens < max_tokens => report.warning(
+            Check::Context,
+            format!(
+                "only {} of the {max_tokens} new tokens fit after the sample conversation",
+                plan.new_tokens
+            ),
+        ),
+        Ok(_) => {}
+    }
+}
+
+// The context length of the model as loaded by `quantized_llama`, capped to `MAX_SEQ_LEN`.
+fn model_context_length(ct: &gguf_file::Content) -> usize {
+    let arch = ct
+        .metadata
+        .get("general.architecture")
+        .and_then(|v| v.to_string().ok().cloned())
+        .unwrap_or_else(|| "llama".to_string());
+    ct.metadata
+        .get(&format!("{arch}.context_length"))
+        .and_then(|v| v.to_u32().ok())
+        .map_or(MAX_SEQ_LEN, |v| (v as usize).min(MAX_SEQ_LEN))
+}
@@ -0,0 +1,228 @@
+//! Pruning the vocabulary of gguf models, for deployments that only ever produce a small set of
+//! tokens.
+//!
+//! [`prune_vocab`] writes a copy of a gguf file where the rows of the token embeddings and of the
+//! output head are restricted to the kept tokens, all the other tensors are copied verbatim. The
+//! pruned model uses its own ids, `0..keep.len()`, and the original id of each of them is stored
+//! in the metadata under [`ORIGINAL_IDS_KEY`]. [`VocabRemap`] reads this table back to map the
+//! ids produced by the original tokenizer to the pruned ones and the sampled ids back.
+use candle::quantized::{ggml_file, gguf_file, QTensor};
+use candle::{Device, Result};
+use std::collections::HashMap;
+
+/// The metadata key holding the original id of each token of a pruned model.
+pub const ORIGINAL_IDS_KEY: &str = "candle.vocab.original_ids";
+
+/// The tensors that have one row per token.
+const VOCAB_TENSORS: [&str; 2] = ["token_embd.weight", "output.weight"];
+
+/// Maps between the ids of the original vocabulary and the ids of a pruned model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VocabRemap {
+    original_ids: Vec<u32>,
+    pruned_ids: HashMap<u32, u32>,
+}
+
+impl VocabRemap {
+    /// `original_ids[i]` is the original id of the pruned token `i`.
+    pub fn new(original_ids: Vec<u32>) -> Result<Self> {
+        let mut pruned_ids = HashMap::with_capacity(original_ids.len());
+        for (pruned_id, &original_id) in original_ids.iter().enumerate() {
+            if pruned_ids.insert(original_id, pruned_id as u32).is_some() {
+                candle::bail!("token {original_id} appears more than once in the vocab remap")
+            }
+        }
+        Ok(Self {
+            original_ids,
+            pruned_ids,
+        })
+    }
+
+    /// Reads the remap table of a pruned model, returns `None` for models that were not pruned.
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Option<Self>> {
+        let ids = match ct.metadata.get(ORIGINAL_IDS_KEY) {
+            None => return Ok(None),
+            Some(ids) => ids.to_vec()?,
+        };
+        let ids = ids.iter().map(|v| v.to_u32()).collect::<Result<Vec<_>>>()?;
+        Ok(Some(Self::new(ids)?))
+    }
+
+    /// The number of tokens of the pruned model.
+    pub fn vocab_size(&self) -> usize {
+        self.original_ids.len()
+    }
+
+    pub fn original_ids(&self) -> &[u32] {
+        &self.original_ids
+    }
+
+    pub fn to_pruned(&self, original_id: u32) -> Option<u32> {
+        self.pruned_ids.get(&original_id).copied()
+    }
+
+    pub fn to_original(&self, pruned_id: u32) -> Option<u32> {
+        self.original_ids.get(pruned_id as usize).copied()
+    }
+
+    /// Maps the output of the original tokenizer, e.g. a prompt, to the pruned ids. This fails if
+    /// some of the tokens have been pruned.
+    pub fn encode(&self, original_ids: &[u32]) -> Result<Vec<u32>> {
+        original_ids
+            .iter()
+            .map(|&id| match self.to_pruned(id) {
+                Some(id) => Ok(id),
+                None => candle::bail!("token {id} is not part of the pruned vocabulary"),
+            })
+            .collect()
+    }
+
+    /// Maps ids sampled from the pruned model back to the original ids, for decoding them with
+    /// the original tokenizer.
+    pub fn decode(&self, pruned_ids: &[u32]) -> Result<Vec<u32>> {
+        pruned_ids
+            .iter()
+            .map(|&id| match self.to_original(id) {
+                Some(id) => Ok(id),
+                None => candle::bail!("token {id} is out of the pruned vocabulary"),
+            })
+            .collect()
+    }
+}
+
+/// The vocabulary sizes and the tensor data sizes before and after pruning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneSummary {
+    pub original_vocab_size: usize,
+    pub pruned_vocab_size: usize,
+    pub original_bytes: usize,
+    pub pruned_bytes: usize,
+}
+
+/// Writes a copy of the gguf model `ct` that only knows about the tokens in `keep`, these are
+/// original ids. The pruned ids follow the order of the sorted original ids.
+///
+/// The token embeddings and the output head, when not tied to the embeddings, are sliced by rows,
+/// a row always covers whole quantization blocks so the quantized data is copied without being
+/// requantized. Token metadata arrays (`tokenizer.ggml.tokens`, scores, ...) are pruned the same
+/// way and the special token ids are remapped, or dropped when the token has been pruned.
+pub fn prune_vocab<R, W>(
+    ct: &gguf_file::Content,
+    reader: &mut R,
+    keep: &[u32],
+    writer: &mut W,
+) -> Result<PruneSummary>
+where
+    R: std::io::Seek + std::io::Read,
+    W: std::io::Seek + std::io::Write,
+{
+    let vocab_size = match ct.tensor_infos.get(VOCAB_TENSORS[0]) {
+        None => candle::bail!("cannot find {} in the gguf file", VOCAB_TENSORS[0]),
+        Some(info) => info.shape.dims()[0],
+    };
+    if ct.metadata.contains_key(ORIGINAL_IDS_KEY) {
+        candle::bail!("the vocabulary of this model has already been pruned")
+    }
+    let mut keep = keep.to_vec();
+    keep.sort_unstable();
+    keep.dedup();
+    if keep.is_empty() {
+        candle::bail!("the list of tokens to keep is empty")
+    }
+    if let Some(&id) = keep.iter().find(|&&id| id as usize >= vocab_size) {
+        candle::bail!("token {id} is out of the vocabulary of size {vocab_size}")
+    }
+    let remap = VocabRemap::new(keep)?;
+
+    let mut original_bytes = 0;
+    let mut pruned_bytes = 0;
+    let mut names = ct.tensor_infos.keys().collect::<Vec<_>>();
+    names.sort();
+    let mut tensors = Vec::with_capacity(names.len());
+    for name in names {
+        let tensor = ct.tensor(reader, name, &Device::Cpu)?;
+        original_bytes += tensor.storage_size_in_bytes();
+        let tensor = if VOCAB_TENSORS.contains(&name.as_str()) {
+            prune_rows(&tensor, remap.original_ids(), vocab_size)
+                .map_err(|e| e.context(format!("pruning {name}")))?
+        } else {
+            tensor
+        };
+        pruned_bytes += tensor.storage_size_in_bytes();
+        tensors.push((name.as_str(), tensor));
+    }
+
+    let mut metadata = ct
+        .metadata
+        .iter()
+        .filter_map(|(key, value)| {
+            let value = prune_metadata(key, value, &remap, vocab_size)?;
+            Some((key.as_str(), value))
+        })
+        .collect::<Vec<_>>();
+    let original_ids = remap
+        .original_ids()
+        .iter()
+        .map(|&id| gguf_file::Value::U32(id));
+    let original_ids = gguf_file::Value::Array(original_ids.collect());
+    metadata.push((ORIGINAL_IDS_KEY, original_ids));
+    metadata.sort_by_key(|(key, _)| *key);
+
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    gguf_file::write(writer, &metadata, &tensors)?;
+    Ok(PruneSummary {
+        original_vocab_size: vocab_size,
+        pruned_vocab_size: remap.vocab_size(),
+        original_bytes,
+        pruned_bytes,
+    })
+}
+
+fn prune_rows(tensor: &QTensor, keep: &[u32], vocab_size: usize) -> Result<QTensor> {
+    let dims = tensor.shape().dims();
+    if dims.first() != Some(&vocab_size) {
+        candle::bail!("expected {vocab_size} rows, got shape {:?}", tensor.shape())
+    }
+    let dtype = tensor.dtype();
+    let row_len = dims[1..].iter().product::<usize>();
+    if row_len % dtype.block_size() != 0 {
+        candle::bail!("rows of {row_len} elements are not aligned on {dtype:?} blocks")
+    }
+    let row_bytes = row_len / dtype.block_size() * dtype.type_size();
+    let data = tensor.data()?;
+    let mut pruned = Vec::with_capacity(keep.len() * row_bytes);
+    for &id in keep {
+        let start = id as usize * row_bytes;
+        pruned.extend_from_slice(&data[start..start + row_bytes]);
+    }
+    let mut dims = dims.to_vec();
+    dims[0] = keep.len();
+    ggml_file::qtensor_from_ggml(dtype, &pruned, dims, &Device::Cpu)
+}
+
+// Returns the metadata value for the pruned model, `None` when the entry has to be dropped.
+fn prune_metadata(
+    key: &str,
+    value: &gguf_file::Value,
+    remap: &VocabRemap,
+    vocab_size: usize,
+) -> Option<gguf_file::Value> {
+    use gguf_file::Value;
+
+    match value {
+        Value::Array(values) if key.starts_with("tokenizer.") && values.len() == vocab_size => {
+            let values = remap
+                .original_ids()
+                .iter()
+                .map(|&id| values[id as usize].clone());
+            Some(Value::Array(values.collect()))
+        }
+        _ if key.starts_with("tokenizer.") && key.ends_with("_token_id") => {
+            let id = value.to_u32().ok()?;
+            remap.to_pruned(id).map(Value::U32)
+        }
+        _ if key.ends_with(".vocab_size") => Some(Value::U32(remap.vocab_size() as u32)),
+        _ => Some(value.clone()),
+    }
+}
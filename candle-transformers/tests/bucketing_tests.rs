@@ -0,0 +1,164 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::bucketing::{
+    last_positions, pad_batch, plan_buckets, unpad, Bucket, BucketConfig,
+};
+use candle_transformers::test_support::tiny_test_model;
+
+fn max_abs_diff(lhs: &Tensor, rhs: &Tensor) -> Result<f32> {
+    (lhs - rhs)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()
+}
+
+// Checks the invariants of a plan: each sequence is in exactly one bucket, the buckets are
+// padded to their longest sequence, and the padding and size bounds hold.
+fn check_plan(lengths: &[usize], config: &BucketConfig, buckets: &[Bucket]) {
+    let mut seen = vec![0; lengths.len()];
+    for bucket in buckets.iter() {
+        assert!(!bucket.is_empty());
+        assert!(bucket.len() <= config.max_batch_size.max(1), "{bucket:?}");
+        let items = bucket.items.iter().map(|&i| lengths[i]).collect::<Vec<_>>();
+        assert_eq!(bucket.seq_len, items[0], "{bucket:?}");
+        assert!(items.windows(2).all(|w| w[0] >= w[1]), "{items:?}");
+        let padding = items.iter().map(|len| bucket.seq_len - len).sum::<usize>();
+        assert_eq!(bucket.padding, padding);
+        assert!(
+            bucket.pad_ratio() <= config.max_pad_ratio + 1e-12,
+            "{bucket:?} {items:?} {config:?}"
+        );
+        for &i in bucket.items.iter() {
+            seen[i] += 1
+        }
+    }
+    assert!(seen.iter().all(|&n| n == 1), "{seen:?}");
+}
+
+#[test]
+fn plan_bounds_the_padding() {
+    let mut seed = 42u64;
+    let mut random = |max: usize| {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        1 + (seed >> 33) as usize % max
+    };
+    let distributions: Vec<Vec<usize>> = vec![
+        vec![],
+        vec![5],
+        vec![7; 20],
+        // Long and short sequences interleaved.
+        (0..40).map(|i| if i % 2 == 0 { 512 } else { 1 }).collect(),
+        // One outlier among many short sequences, and the reverse.
+        std::iter::once(4096)
+            .chain(std::iter::repeat_n(3, 30))
+            .collect(),
+        std::iter::once(1)
+            .chain(std::iter::repeat_n(300, 30))
+            .collect(),
+        // Lengths just below the ratio threshold of each other.
+        (0..30)
+            .map(|i| (1000. * 0.9f64.powi(i)) as usize + 1)
+            .collect(),
+        (1..=64).collect(),
+        (0..200).map(|_| random(1000)).collect(),
+        (0..200).map(|_| random(4)).collect(),
+    ];
+    for lengths in distributions.iter() {
+        for max_pad_ratio in [0., 0.1, 0.25, 0.5, 1.] {
+            for max_batch_size in [0, 1, 3, 8, 64] {
+                let config = BucketConfig {
+                    max_pad_ratio,
+                    max_batch_size,
+                };
+                let buckets = plan_buckets(lengths, &config);
+                check_plan(lengths, &config, &buckets);
+                if max_pad_ratio == 0. {
+                    assert!(buckets.iter().all(|b| b.padding == 0))
+                }
+            }
+        }
+    }
+
+    // Sequences of the same length fill the buckets.
+    let config = BucketConfig::default();
+    let buckets = plan_buckets(&[7; 20], &config);
+    assert_eq!(
+        buckets.iter().map(|b| b.len()).collect::<Vec<_>>(),
+        [8, 8, 4]
+    );
+    assert_eq!(buckets[0].items, (0..8).collect::<Vec<_>>());
+    // The long and short sequences never share a bucket.
+    let lengths = [512, 1, 500, 2, 510, 1];
+    let buckets = plan_buckets(&lengths, &config);
+    let items = buckets.iter().map(|b| b.items.clone()).collect::<Vec<_>>();
+    assert_eq!(items, [vec![0, 4, 2], vec![3, 1], vec![5]]);
+}
+
+#[test]
+fn pad_batch_and_unpad() -> Result<()> {
+    let device = Device::Cpu;
+    let (xs, lengths) = pad_batch(&[&[1, 2, 3], &[4], &[5, 6]], 0, &device)?;
+    assert_eq!(lengths, [3, 1, 2]);
+    assert_eq!(xs.to_vec2::<u32>()?, [[1, 2, 3], [4, 0, 0], [5, 6, 0]]);
+    let rows = unpad(&xs, &lengths)?;
+    let rows = rows
+        .iter()
+        .map(|r| r.to_vec1::<u32>())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(rows, [vec![1, 2, 3], vec![4], vec![5, 6]]);
+    assert_eq!(last_positions(&xs, &lengths)?.to_vec1::<u32>()?, [3, 4, 6]);
+
+    assert!(pad_batch(&[&[1], &[]], 0, &device).is_err());
+    assert!(pad_batch(&[], 0, &device).is_err());
+    assert!(unpad(&xs, &[3, 1]).is_err());
+    assert!(last_positions(&xs, &[3, 4, 1]).is_err());
+    Ok(())
+}
+
+#[test]
+fn padded_forward_matches_unbatched() -> Result<()> {
+    let device = Device::Cpu;
+    let sequences: Vec<Vec<u32>> = [7, 3, 5, 1]
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| (0..len).map(|t| (17 * i as u32 + 31 * t) % 256).collect())
+        .collect();
+    let slices = sequences.iter().map(|s| s.as_slice()).collect::<Vec<_>>();
+    let (xs, lengths) = pad_batch(&slices, 0, &device)?;
+    let mut batched = tiny_test_model(0)?;
+    let hidden = batched.forward_hidden_padded(&xs, &lengths)?;
+    let hidden = unpad(&hidden, &lengths)?;
+    let logits = batched.forward_padded(&xs, &lengths)?;
+
+    let mut single = tiny_test_model(0)?;
+    for (row, sequence) in sequences.iter().enumerate() {
+        let input = Tensor::new(sequence.as_slice(), &device)?.unsqueeze(0)?;
+        let expected = single.forward_hidden(&input, 0)?.squeeze(0)?;
+        let diff = max_abs_diff(&hidden[row], &expected)?;
+        assert!(diff < 1e-5, "hidden states of row {row}: {diff}");
+        let expected = single.forward(&input, 0)?.squeeze(0)?;
+        let diff = max_abs_diff(&logits.get(row)?, &expected)?;
+        assert!(diff < 1e-5, "logits of row {row}: {diff}");
+
+        // Each row continues from its part of the batched kv cache.
+        let mut continued = batched.clone();
+        continued.select_kv_cache_row(row, sequence.len())?;
+        assert_eq!(continued.kv_cache_len(), sequence.len());
+        for (step, token) in [3u32, 200, 9].into_iter().enumerate() {
+            let input = Tensor::new(&[token], &device)?.unsqueeze(0)?;
+            let index_pos = sequence.len() + step;
+            let lhs = continued.forward(&input, index_pos)?;
+            let rhs = single.forward(&input, index_pos)?;
+            let diff = max_abs_diff(&lhs, &rhs)?;
+            assert!(diff < 1e-5, "step {step} of row {row}: {diff}");
+        }
+    }
+
+    assert!(batched.forward_padded(&xs, &[7, 3, 5]).is_err());
+    assert!(batched.forward_padded(&xs, &[7, 3, 8, 1]).is_err());
+    assert!(batched.select_kv_cache_row(4, 1).is_err());
+    assert!(batched.select_kv_cache_row(0, 8).is_err());
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use candle::{Device, Result, Tensor};
+use candle_transformers::generation::chat::ChatHistory;
 use candle_transformers::generation::LogitsProcessor;
 
 #[test]
@@ -76,3 +77,1653 @@ fn sample_gumbel() -> Result<()> {
     }
     Ok(())
 }
+
+// A model that always predicts the token at the current position in `script`.
+#[derive(Clone)]
+struct ScriptedModel {
+    script: Vec<u32>,
+    vocab_size: usize,
+}
+
+impl candle_transformers::generation::CausalLm for ScriptedModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (b_size, seq_len) = input.dims2()?;
+        let pos = index_pos + seq_len - 1;
+        let mut logits = vec![0f32; self.vocab_size];
+        logits[self.script[pos % self.script.len()] as usize] = 10.;
+        Tensor::new(logits.as_slice(), input.device())?.broadcast_left(b_size)
+    }
+}
+
+#[test]
+fn compare_divergence() -> Result<()> {
+    use candle_transformers::generation::compare::{first_divergence, CompareConfig};
+
+    assert_eq!(first_divergence(&[1, 2, 3], &[1, 2, 3]), None);
+    assert_eq!(first_divergence(&[1, 2, 3], &[1, 5, 3]), Some(1));
+    assert_eq!(first_divergence(&[1, 2], &[1, 2, 3]), Some(2));
+    assert_eq!(first_divergence(&[], &[]), None);
+
+    let script = (0..32).map(|i| i % 7).collect::<Vec<u32>>();
+    let mut diverging = script.clone();
+    // The prompt has 3 tokens so the first generated token is predicted at position 2.
+    diverging[2 + 5] = 6;
+    let mut lhs = ScriptedModel {
+        script,
+        vocab_size: 8,
+    };
+    let mut rhs = ScriptedModel {
+        script: diverging,
+        vocab_size: 8,
+    };
+    let device = Device::Cpu;
+    let config = CompareConfig::greedy(10, None);
+    let cmp = config.compare((&mut lhs, &device), (&mut rhs, &device), &[0, 1, 2])?;
+    assert_eq!(cmp.lhs.tokens, [2, 3, 4, 5, 6, 0, 1, 2, 3, 4]);
+    assert_eq!(cmp.rhs.tokens, [2, 3, 4, 5, 6, 6, 1, 2, 3, 4]);
+    assert_eq!(cmp.divergence, Some(5));
+
+    let mut same = lhs.clone();
+    let cmp = config.compare((&mut lhs, &device), (&mut same, &device), &[0])?;
+    assert_eq!(cmp.divergence, None);
+
+    // Generation stops on the eos token.
+    let config = CompareConfig::greedy(10, Some(5));
+    let run = config.run(&mut lhs, &[0, 1, 2], &device)?;
+    assert_eq!(run.tokens, [2, 3, 4, 5]);
+    Ok(())
+}
+
+#[test]
+fn sample_degenerate() -> Result<()> {
+    use candle_transformers::generation::{DegenerateHandling, Sampling};
+
+    let device = Device::Cpu;
+    let banned = Tensor::new(&[f32::NEG_INFINITY; 4], &device)?;
+    let nan = Tensor::new(&[0.1, f32::NAN, 0.3, 0.2], &device)?;
+    let unfiltered = Tensor::new(&[0.1f32, 0.2, 0.4, 0.3], &device)?;
+
+    // Fall back on the unfiltered logits by default.
+    let mut logits_process = LogitsProcessor::new(42, None, None);
+    assert_eq!(
+        logits_process.degenerate_handling(),
+        DegenerateHandling::Unfiltered
+    );
+    let token = logits_process.sample(&unfiltered)?;
+    assert_eq!(token, 2);
+    assert_eq!(logits_process.degenerate_count(), 0);
+    let token = logits_process.sample_with_unfiltered(&banned, &unfiltered)?;
+    assert_eq!(token, 2);
+    assert_eq!(logits_process.degenerate_count(), 1);
+    // NaN values are ignored in the fallback.
+    let token = logits_process.sample(&nan)?;
+    assert_eq!(token, 2);
+    assert_eq!(logits_process.degenerate_count(), 2);
+    // Nothing to fall back on.
+    assert!(logits_process.sample(&banned).is_err());
+    assert_eq!(logits_process.degenerate_count(), 3);
+
+    // A filter that removes every candidate falls back on the temperature scaled logits.
+    let mut logits_process = LogitsProcessor::from_sampling(
+        42,
+        Sampling::TopK {
+            k: 2,
+            temperature: 1.0,
+        },
+    );
+    let token = logits_process.sample_f(&unfiltered, |prs| prs.fill(0.))?;
+    assert!(token < 4);
+    assert_eq!(logits_process.degenerate_count(), 1);
+
+    let mut logits_process = LogitsProcessor::new(42, Some(0.8), None);
+    logits_process.set_degenerate_handling(DegenerateHandling::Eos(7));
+    assert_eq!(logits_process.sample(&nan)?, 7);
+    assert_eq!(logits_process.sample(&banned)?, 7);
+    assert_eq!(logits_process.sample(&unfiltered)?, 0);
+    assert_eq!(logits_process.degenerate_count(), 2);
+
+    let mut logits_process = LogitsProcessor::new(42, Some(0.8), Some(0.9));
+    logits_process.set_degenerate_handling(DegenerateHandling::Error);
+    let err = logits_process.sample_with_unfiltered(&banned, &unfiltered);
+    let err = err.unwrap_err().to_string();
+    assert!(
+        err.contains("degenerate sampling distribution over 4 tokens"),
+        "{err}"
+    );
+    assert_eq!(logits_process.degenerate_count(), 1);
+    Ok(())
+}
+
+#[test]
+fn sample_zero_temperature_grid() -> Result<()> {
+    use candle_transformers::generation::{DegenerateHandling, Sampling};
+
+    let logits = Tensor::new(&[0.1f32, 0.2, 3.0, 0.4, 2.9, -1.0], &Device::Cpu)?;
+    let temperatures = [0.0, 0.5, 1.0, 2.0];
+    let ks = [1, 2, 3, 6, 10];
+    let ps = [1e-9, 0.3, 0.5, 0.9, 1.0];
+    let mut grid = vec![Sampling::ArgMax];
+    for &temperature in temperatures.iter() {
+        grid.push(Sampling::All { temperature });
+        for &k in ks.iter() {
+            grid.push(Sampling::TopK { k, temperature });
+            for &p in ps.iter() {
+                grid.push(Sampling::TopKThenTopP { k, p, temperature });
+            }
+        }
+        for &p in ps.iter() {
+            grid.push(Sampling::TopP { p, temperature });
+        }
+    }
+    for sampling in grid {
+        sampling.validate()?;
+        let temperature = sampling.temperature();
+        // Greedy over the candidates, or a single candidate left by the filters.
+        let greedy = match sampling {
+            Sampling::ArgMax => true,
+            Sampling::TopK { k, .. } | Sampling::TopKThenTopP { k, .. } if k == 1 => true,
+            Sampling::TopP { p, .. } | Sampling::TopKThenTopP { p, .. } if p < 1e-6 => true,
+            _ => temperature == Some(0.),
+        };
+        let mut logits_process = LogitsProcessor::from_sampling(42, sampling.clone());
+        for _ in 0..20 {
+            let token = logits_process.sample(&logits)?;
+            if greedy {
+                assert_eq!(token, 2, "{sampling:?}");
+            }
+            if let Sampling::TopK { k: 2, .. } | Sampling::TopKThenTopP { k: 2, .. } = sampling {
+                assert!(token == 2 || token == 4, "{sampling:?} {token}")
+            }
+        }
+    }
+
+    // With a zero temperature, the largest logit among the remaining candidates is picked.
+    let sampling = Sampling::TopK {
+        k: 3,
+        temperature: 0.,
+    };
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    assert_eq!(logits_process.sample_f(&logits, |prs| prs[2] = 0.)?, 4);
+    // An empty candidate set is a degenerate distribution.
+    logits_process.set_degenerate_handling(DegenerateHandling::Error);
+    assert!(logits_process
+        .sample_f(&logits, |prs| prs.fill(0.))
+        .is_err());
+    logits_process.set_degenerate_handling(DegenerateHandling::Unfiltered);
+    assert_eq!(logits_process.sample_f(&logits, |prs| prs.fill(0.))?, 2);
+
+    // Out of range parameters are errors rather than silently falling back on some default.
+    let invalid = [
+        Sampling::All { temperature: -0.5 },
+        Sampling::All {
+            temperature: f64::NAN,
+        },
+        Sampling::TopK {
+            k: 0,
+            temperature: 0.,
+        },
+        Sampling::TopP {
+            p: 0.,
+            temperature: 0.,
+        },
+        Sampling::TopP {
+            p: 1.5,
+            temperature: 1.,
+        },
+        Sampling::TopKThenTopP {
+            k: 2,
+            p: -0.1,
+            temperature: 0.,
+        },
+        Sampling::TopKThenTopP {
+            k: 2,
+            p: 0.5,
+            temperature: -1.,
+        },
+    ];
+    for sampling in invalid {
+        assert!(sampling.validate().is_err(), "{sampling:?}");
+        let mut logits_process = LogitsProcessor::from_sampling(42, sampling.clone());
+        assert!(logits_process.sample(&logits).is_err(), "{sampling:?}");
+    }
+    let mut logits_process = LogitsProcessor::new(42, Some(-1.), None);
+    let err = logits_process.sample(&logits).unwrap_err().to_string();
+    assert!(err.contains("temperature must be non-negative"), "{err}");
+    Ok(())
+}
+
+#[test]
+fn sampler_slots() -> Result<()> {
+    use candle_transformers::generation::{
+        sample_batch, GenerationParams, PenaltyState, SamplerSlot, Sampling, StopConditions,
+        StopCriteria,
+    };
+
+    let device = Device::Cpu;
+    let greedy = GenerationParams {
+        temperature: 0.,
+        repeat_penalty: 1.,
+        ..Default::default()
+    };
+    let high_temp = GenerationParams {
+        seed: 1,
+        temperature: 5.,
+        repeat_penalty: 1.,
+        ..Default::default()
+    };
+    let penalized = GenerationParams {
+        temperature: 0.,
+        repeat_penalty: 100.,
+        repeat_last_n: 8,
+        ..Default::default()
+    };
+    let params = [greedy, high_temp, penalized];
+    let mut slots = params
+        .iter()
+        .map(|p| SamplerSlot::from_params(p, []))
+        .collect::<Result<Vec<_>>>()?;
+    // The single sequence equivalents.
+    let mut processors = params
+        .iter()
+        .map(|p| LogitsProcessor::from_sampling(p.seed, p.sampling()))
+        .collect::<Vec<_>>();
+    let mut histories = vec![vec![]; 3];
+
+    let base = Tensor::rand(0.5f32, 1.5, 16, &device)?;
+    let mut generated = vec![vec![]; 3];
+    for step in 0..12 {
+        let noise = Tensor::rand(0f32, 0.1, (3, 16), &device)?;
+        let logits = noise.broadcast_add(&base)?;
+        let tokens = sample_batch(&logits, &mut slots)?;
+        for (index, token) in tokens.into_iter().enumerate() {
+            let row = logits.get(index)?;
+            let p = &params[index];
+            let start_at = histories[index].len().saturating_sub(p.repeat_last_n);
+            let penalized = candle_transformers::utils::apply_repeat_penalty(
+                &row,
+                p.repeat_penalty,
+                &histories[index][start_at..],
+            )?;
+            let expected = processors[index].sample(&penalized)?;
+            assert_eq!(token, Some(expected), "step {step} row {index}");
+            histories[index].push(expected);
+            generated[index].push(expected);
+        }
+    }
+    // The heavily penalized greedy sequence does not repeat itself within its window.
+    for window in generated[2].windows(8) {
+        let distinct = window.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(distinct.len(), 8, "{:?}", generated[2]);
+    }
+    assert_eq!(slots[2].penalty().tokens(), &generated[2][4..]);
+    let distinct = generated[1]
+        .iter()
+        .collect::<std::collections::HashSet<_>>();
+    assert!(distinct.len() > 3, "{:?}", generated[1]);
+
+    // A slot stops on its stop tokens while the other ones keep going.
+    let stop = StopConditions::new(&[StopCriteria::StopTokens([3].into())], [])?;
+    let sampling = Sampling::ArgMax;
+    let mut slots = vec![
+        SamplerSlot::new(
+            LogitsProcessor::from_sampling(0, sampling.clone()),
+            PenaltyState::new(1., 64),
+            stop,
+        ),
+        SamplerSlot::new(
+            LogitsProcessor::from_sampling(0, sampling),
+            PenaltyState::new(1., 64),
+            StopConditions::default(),
+        ),
+    ];
+    let logits = Tensor::new(&[[0f32, 0., 0., 1.], [0., 0., 0., 1.]], &device)?;
+    assert_eq!(sample_batch(&logits, &mut slots)?, [None, Some(3)]);
+    assert!(slots[0].is_finished());
+    assert_eq!(sample_batch(&logits, &mut slots)?, [None, Some(3)]);
+    assert!(sample_batch(&logits.get(0)?.unsqueeze(0)?, &mut slots).is_err());
+    Ok(())
+}
+
+#[test]
+fn penalty_snapshot() -> Result<()> {
+    use candle_transformers::generation::{PenaltySnapshot, PenaltyState};
+
+    // A long restored chat, the penalty only looks at its last 64 tokens.
+    let history = (0..30_000u32).map(|i| (i * 7919) % 32).collect::<Vec<_>>();
+    let mut pushed = PenaltyState::new(1.3, 64);
+    for &token in history.iter() {
+        pushed.push(token)
+    }
+    let state = PenaltyState::from_history(1.3, 64, &history);
+    assert_eq!(state.tokens(), &history[history.len() - 64..]);
+    assert_eq!(state, pushed);
+
+    let snapshot = serde_json::to_string(&state.snapshot()).map_err(candle::Error::wrap)?;
+    let snapshot: PenaltySnapshot = serde_json::from_str(&snapshot).map_err(candle::Error::wrap)?;
+    let logits = Tensor::rand(-2f32, 2., 32, &Device::Cpu)?;
+    let expected = state.apply(&logits)?.unwrap().to_vec1::<f32>()?;
+    let (restored, warning) = PenaltyState::restore(&snapshot, 1.3, 64, &history);
+    assert_eq!(warning, None);
+    assert_eq!(restored, state);
+    assert_eq!(
+        restored.apply(&logits)?.unwrap().to_vec1::<f32>()?,
+        expected
+    );
+
+    // A restored state used as is, even when the history disagrees with it.
+    let (restored, warning) = PenaltyState::restore(&snapshot, 1.3, 64, &[0; 30_000]);
+    assert_eq!(
+        (restored.tokens(), warning),
+        (snapshot.tokens.as_slice(), None)
+    );
+
+    // Other penalty settings, another version, or another history length reconstruct the state.
+    let (restored, warning) = PenaltyState::restore(&snapshot, 1.3, 16, &history);
+    assert!(warning
+        .unwrap()
+        .contains("repeat-last-n 64, now 1.3 and 16"));
+    assert_eq!(restored, PenaltyState::from_history(1.3, 16, &history));
+    let (restored, warning) = PenaltyState::restore(&snapshot, 1.1, 64, &history);
+    assert!(warning.is_some());
+    assert_eq!(restored.repeat_penalty(), 1.1);
+    let old = PenaltySnapshot {
+        version: 0,
+        ..snapshot.clone()
+    };
+    let (restored, warning) = PenaltyState::restore(&old, 1.3, 64, &history);
+    assert!(warning.unwrap().contains("version 0"));
+    assert_eq!(restored, state);
+    let (restored, warning) = PenaltyState::restore(&snapshot, 1.3, 64, &history[..10]);
+    assert!(warning.is_some());
+    assert_eq!(restored.tokens(), &history[..10]);
+    Ok(())
+}
+
+#[test]
+fn token_budget() {
+    use candle_transformers::generation::{TokenBudget, TokenBudgetError, TokenPlan};
+
+    let plan = |new_tokens, truncate| {
+        Ok(TokenPlan {
+            new_tokens,
+            truncate,
+        })
+    };
+    let budget = TokenBudget::new(16).with_reserve(4);
+    assert_eq!(budget.available(), 12);
+    assert_eq!(budget.plan(3, 8), plan(8, 0));
+    // The prompt and the new tokens fill the available positions exactly.
+    assert_eq!(budget.plan(4, 8), plan(8, 0));
+    assert_eq!(budget.plan(5, 8), plan(7, 1));
+    assert_eq!(budget.plan(11, 8), plan(1, 7));
+    assert_eq!(budget.plan(11, 1), plan(1, 0));
+    assert_eq!(
+        budget.plan(12, 8),
+        Err(TokenBudgetError::PromptTooLong {
+            prompt_len: 12,
+            available: 12,
+            truncate: 1
+        })
+    );
+    // The prompt alone fits when no token is requested.
+    assert_eq!(budget.plan(12, 0), plan(0, 0));
+    assert!(budget.plan(13, 0).is_err());
+    // The prompt is never dropped entirely.
+    assert_eq!(budget.plan(2, 100), plan(10, 1));
+    assert_eq!(budget.plan(1, 100), plan(11, 0));
+    assert_eq!(budget.plan(0, 100), plan(12, 0));
+    // With truncation the requested tokens are counted after the truncated prompt.
+    assert_eq!(budget.plan_truncated(3, 8), plan(8, 0));
+    assert_eq!(budget.plan_truncated(11, 8), plan(8, 7));
+    assert_eq!(budget.plan_truncated(12, 8), plan(8, 8));
+    assert_eq!(budget.plan_truncated(2, 100), plan(11, 1));
+    assert_eq!(budget.plan_truncated(13, 0), plan(0, 1));
+
+    for (max_context, reserved) in [(0, 0), (4, 4), (4, 5)] {
+        let budget = TokenBudget::new(max_context).with_reserve(reserved);
+        let err = budget.plan(0, 1).unwrap_err();
+        assert_eq!(
+            err,
+            TokenBudgetError::NoContext {
+                max_context,
+                reserved
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "no room left in a context of {max_context} tokens with {reserved} reserved tokens"
+            )
+        );
+    }
+
+    // Every small combination against the definition.
+    for max_context in 0..12 {
+        for reserved in 0..4 {
+            let budget = TokenBudget::new(max_context).with_reserve(reserved);
+            let available = max_context.saturating_sub(reserved);
+            for prompt_len in 0..14 {
+                for max_new_tokens in 0..14 {
+                    match budget.plan(prompt_len, max_new_tokens) {
+                        Ok(p) => {
+                            assert!(prompt_len + p.new_tokens <= available);
+                            assert!(p.new_tokens <= max_new_tokens);
+                            assert!(max_new_tokens == 0 || p.new_tokens > 0);
+                            let fits = prompt_len + max_new_tokens <= available;
+                            assert_eq!(p.new_tokens == max_new_tokens, fits);
+                            assert_eq!(p.truncate == 0, fits || prompt_len <= 1);
+                            assert!(p.truncate < prompt_len.max(1));
+                            if p.truncate > 0 {
+                                let kept = prompt_len - p.truncate;
+                                assert!(kept + max_new_tokens.min(available - 1) <= available);
+                            }
+                        }
+                        Err(TokenBudgetError::NoContext { .. }) => assert_eq!(available, 0),
+                        Err(TokenBudgetError::PromptTooLong { truncate, .. }) => {
+                            assert!(available > 0);
+                            assert!(prompt_len + max_new_tokens.min(1) > available);
+                            assert!(budget.plan(prompt_len - truncate, max_new_tokens).is_ok());
+                            assert!(budget
+                                .plan(prompt_len - truncate + 1, max_new_tokens)
+                                .is_err());
+                        }
+                    }
+                    match budget.plan_truncated(prompt_len, max_new_tokens) {
+                        Ok(p) => {
+                            let kept = prompt_len - p.truncate;
+                            let untruncated = TokenPlan { truncate: 0, ..p };
+                            assert_eq!(budget.plan(kept, max_new_tokens), Ok(untruncated));
+                            assert!(p.new_tokens == max_new_tokens || kept <= 1);
+                        }
+                        Err(err) => assert_eq!(budget.plan(prompt_len, max_new_tokens), Err(err)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fixture_tokenizer() -> tokenizers::Tokenizer {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/tiny-llama-tokenizer.json"
+    );
+    tokenizers::Tokenizer::from_file(path).unwrap()
+}
+
+#[test]
+fn stop_token_pattern() -> Result<()> {
+    use candle_transformers::generation::{StopConditions, StopCriteria, StopReason};
+
+    let tokenizer = fixture_tokenizer();
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    let added_tokens = added_tokens.iter().map(|(&id, t)| (id, t.content.as_str()));
+    let criteria = [
+        StopCriteria::StopTokenPattern(r"^<\|tool_.*\|>$".to_string()),
+        StopCriteria::StopTokens([2].into_iter().collect()),
+    ];
+    let stop = StopConditions::new(&criteria, added_tokens)?;
+    let tool_call = tokenizer.token_to_id("<|tool_call|>").unwrap();
+    let tool_result = tokenizer.token_to_id("<|tool_result|>").unwrap();
+    let im_end = tokenizer.token_to_id("<|im_end|>").unwrap();
+    let expected = [2, tool_call, tool_result].into_iter().collect();
+    assert_eq!(stop.stop_tokens(), &expected);
+    assert_eq!(
+        stop.check_token(tool_call),
+        Some(StopReason::Token(tool_call))
+    );
+    assert_eq!(stop.check_token(im_end), None);
+
+    // Patterns are only matched against the added tokens that are passed in, "stop" matches
+    // when it is one of them and nothing matches without added tokens.
+    let criteria = [StopCriteria::StopTokenPattern("stop".to_string())];
+    let stop = StopConditions::new(&criteria, [(23, "stop"), (4, "<|im_end|>")])?;
+    assert_eq!(stop.stop_tokens(), &[23].into_iter().collect());
+    let stop = StopConditions::new(&criteria, std::iter::empty())?;
+    assert!(stop.is_empty());
+
+    assert!(StopConditions::new(
+        &[StopCriteria::StopTokenPattern("(".to_string())],
+        std::iter::empty()
+    )
+    .is_err());
+    Ok(())
+}
+
+#[test]
+fn stop_precedence() -> Result<()> {
+    use candle_transformers::generation::{StopConditions, StopCriteria, StopReason};
+
+    let criteria = [
+        StopCriteria::Sequence("cat sat".to_string()),
+        StopCriteria::StopTokens([16].into_iter().collect()),
+    ];
+    let stop = StopConditions::new(&criteria, std::iter::empty())?;
+    assert_eq!(stop.check(14, "the cat"), None);
+    // Both criteria fire when sampling "sat", the token one wins so "sat" is never emitted.
+    assert_eq!(stop.check(16, "the cat sat"), Some(StopReason::Token(16)));
+    assert_eq!(
+        stop.check(12, "the cat sat the"),
+        Some(StopReason::Sequence("cat sat".to_string()))
+    );
+    assert!(StopConditions::new(&[StopCriteria::Sequence(String::new())], []).is_err());
+    Ok(())
+}
+
+#[test]
+fn stop_string_matcher() -> Result<()> {
+    use candle_transformers::generation::{StopReason, StopStringMatcher};
+
+    let sequences = ["Help".to_string(), "\nUser:".to_string()];
+    let mut matcher = StopStringMatcher::new(&sequences)?;
+    // "Hel" could start "Help" and is held back until it diverges.
+    assert_eq!(matcher.push("Hi! Hel"), "Hi! ");
+    assert_eq!(matcher.pending(), "Hel");
+    assert_eq!(matcher.push("lo"), "Hello");
+    assert_eq!(matcher.push("\nUs"), "");
+    assert_eq!(matcher.push("er: hi"), "");
+    assert_eq!(
+        matcher.stop_reason(),
+        Some(&StopReason::Sequence("\nUser:".to_string()))
+    );
+    assert_eq!(matcher.push("more"), "");
+    assert_eq!(matcher.finish(), "");
+
+    // A match does not end the text while an earlier stop sequence can still complete.
+    let sequences = ["abcd".to_string(), "c".to_string()];
+    let mut matcher = StopStringMatcher::new(&sequences)?;
+    assert_eq!(matcher.push("xabc"), "x");
+    assert_eq!(matcher.stop_reason(), None);
+    assert_eq!(matcher.push("d"), "");
+    assert_eq!(
+        matcher.stop_reason(),
+        Some(&StopReason::Sequence("abcd".to_string()))
+    );
+    let mut matcher = StopStringMatcher::new(&sequences)?;
+    assert_eq!(matcher.push("xabc"), "x");
+    assert_eq!(matcher.push("e"), "ab");
+    let mut matcher = StopStringMatcher::new(&sequences)?;
+    assert_eq!(matcher.push("xabc"), "x");
+    assert_eq!(matcher.finish(), "ab");
+    assert_eq!(
+        matcher.stop_reason(),
+        Some(&StopReason::Sequence("c".to_string()))
+    );
+    assert!(StopStringMatcher::new(&[String::new()]).is_err());
+    Ok(())
+}
+
+#[test]
+fn stop_string_stream() -> Result<()> {
+    use candle_transformers::generation::{StopReason, TextStream};
+    use rand::{Rng, SeedableRng};
+
+    // The text is split in random byte chunks, one per token, so that the characters and the
+    // stop sequences get split over several tokens. The streamed text has to match the full
+    // text truncated at the first occurrence of a stop sequence.
+    let alphabet = ['a', 'b', 'é', '€', '😀', ' '];
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    for _ in 0..2000 {
+        let random_string = |rng: &mut rand::rngs::StdRng, max_len: usize| {
+            let len = rng.random_range(1..=max_len);
+            (0..len)
+                .map(|_| alphabet[rng.random_range(0..alphabet.len())])
+                .collect::<String>()
+        };
+        let text = random_string(&mut rng, 30);
+        let n_sequences = rng.random_range(0..4);
+        let sequences = (0..n_sequences)
+            .map(|_| random_string(&mut rng, 4))
+            .collect::<Vec<_>>();
+        let mut chunks = vec![];
+        let mut bytes = text.as_bytes();
+        while !bytes.is_empty() {
+            let len = rng.random_range(1..=bytes.len().min(5));
+            chunks.push(bytes[..len].to_vec());
+            bytes = &bytes[len..];
+        }
+        let decode = |ids: &[u32]| {
+            let bytes = ids.iter().flat_map(|&id| chunks[id as usize].clone());
+            Ok(String::from_utf8_lossy(&bytes.collect::<Vec<_>>()).to_string())
+        };
+
+        let first_match = sequences.iter().filter_map(|s| text.find(s.as_str())).min();
+        let expected = &text[..first_match.unwrap_or(text.len())];
+
+        let mut stream = TextStream::new(decode, &sequences)?;
+        let mut streamed = String::new();
+        for id in 0..chunks.len() {
+            streamed.push_str(&stream.push(id as u32)?);
+            if stream.is_stopped() {
+                break;
+            }
+        }
+        streamed.push_str(&stream.finish()?);
+        assert_eq!(streamed, expected, "{text:?} {sequences:?}");
+        match (first_match, stream.stop_reason()) {
+            (None, None) => {}
+            (Some(pos), Some(StopReason::Sequence(s))) => {
+                assert!(text[pos..].starts_with(s.as_str()), "{text:?} {s:?}")
+            }
+            (_, reason) => panic!("{text:?} {sequences:?} {reason:?}"),
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn generate_text_stop_sequence() -> Result<()> {
+    use candle_transformers::generation::{
+        Sampling, StopConditions, StopCriteria, StopReason, TextGeneration,
+    };
+
+    let words = ["<s>", "Sure", ",", " here", "\n", "Us", "er", ":", " hi"];
+    let model = ScriptedModel {
+        script: vec![1, 2, 3, 4, 5, 6, 7, 8, 0],
+        vocab_size: words.len(),
+    };
+    let decode = |ids: &[u32]| Ok(ids.iter().map(|&id| words[id as usize]).collect::<String>());
+    let logits_processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    let mut generation = TextGeneration::new(model, logits_processor, &Device::Cpu);
+    generation.push_prompt(&[0])?;
+    let criteria = [StopCriteria::Sequence("\nUser:".to_string())];
+    let stop = StopConditions::new(&criteria, [])?;
+    let mut chunks = vec![];
+    let tokens = generation.generate_text(20, &stop, decode, |text| {
+        chunks.push(text.to_string());
+        Ok(())
+    })?;
+    assert_eq!(chunks, ["Sure", ",", " here"]);
+    assert_eq!(tokens, [1, 2, 3, 4, 5, 6, 7]);
+    assert_eq!(
+        generation.stop_reason(),
+        Some(&StopReason::Sequence("\nUser:".to_string()))
+    );
+
+    // Text held back when the token budget runs out is released.
+    let mut generation = TextGeneration::new(
+        generation.into_inner(),
+        LogitsProcessor::from_sampling(0, Sampling::ArgMax),
+        &Device::Cpu,
+    );
+    generation.push_prompt(&[0])?;
+    let mut text = String::new();
+    generation.generate_text(5, &stop, decode, |t| {
+        text.push_str(t);
+        Ok(())
+    })?;
+    assert_eq!(text, "Sure, here\nUs");
+    assert_eq!(generation.stop_reason(), None);
+    Ok(())
+}
+
+#[test]
+fn eval_f64_accumulation() -> Result<()> {
+    use candle_transformers::generation::eval::NllAccumulator;
+
+    // Each 1e-8 is below half an ulp of 1.0 in f32 so the f32 sum never moves.
+    let n = 100_000;
+    let mut values = vec![1e-8f32; n];
+    values[0] = 1.0;
+    let nll = Tensor::new(values.as_slice(), &Device::Cpu)?;
+    let f32_sum = values.iter().fold(0f32, |acc, v| acc + v);
+    assert_eq!(f32_sum, 1.0);
+
+    let mut acc = NllAccumulator::new();
+    acc.add(&nll.reshape((10, n / 10))?)?;
+    assert_eq!(acc.count(), n);
+    let expected = 1.0 + (n - 1) as f64 * 1e-8f32 as f64;
+    assert!((acc.sum() - expected).abs() < 1e-12, "{}", acc.sum());
+    assert!((acc.sum() - f32_sum as f64) > 9e-4);
+    assert!((acc.perplexity() - acc.mean().exp()).abs() < 1e-12);
+    assert!(NllAccumulator::new().mean().is_nan());
+    Ok(())
+}
+
+#[test]
+fn eval_gemm_precision_restored() -> Result<()> {
+    use candle_transformers::generation::eval::{
+        with_gemm_precision, with_gemm_precision_in, GemmPrecision, GemmSettings,
+    };
+
+    // Settings that are stored in memory, so that the restore is checked without cuda.
+    struct Settings(std::cell::Cell<GemmPrecision>);
+
+    impl GemmSettings for Settings {
+        fn get(&self) -> GemmPrecision {
+            self.0.get()
+        }
+
+        fn set(&self, precision: GemmPrecision) {
+            self.0.set(precision)
+        }
+    }
+
+    let reduced = GemmPrecision {
+        reduced_f16: true,
+        reduced_bf16: false,
+        reduced_f32: true,
+    };
+    let settings = Settings(std::cell::Cell::new(reduced));
+    let full = GemmPrecision::full();
+    let inside = with_gemm_precision_in(&settings, full, || Ok(settings.get()))?;
+    assert_eq!(inside, full);
+    assert_eq!(settings.get(), reduced);
+    let err = with_gemm_precision_in::<_, ()>(&settings, full, || candle::bail!("eval failed"));
+    assert!(err.is_err());
+    assert_eq!(settings.get(), reduced);
+    let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        with_gemm_precision_in::<_, ()>(&settings, full, || panic!("eval panicked"))
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(settings.get(), reduced);
+
+    // The cuda settings, these do not change without the cuda feature.
+    let before = GemmPrecision::current();
+    let inside = with_gemm_precision(GemmPrecision::full(), || Ok(GemmPrecision::current()))?;
+    if cfg!(feature = "cuda") {
+        assert_eq!(inside, GemmPrecision::full());
+    }
+    assert_eq!(GemmPrecision::current(), before);
+
+    let err = with_gemm_precision::<()>(GemmPrecision::full(), || candle::bail!("eval failed"));
+    assert!(err.is_err());
+    assert_eq!(GemmPrecision::current(), before);
+    Ok(())
+}
+
+#[test]
+fn batch_errors() -> Result<()> {
+    use candle_transformers::generation::batch::{
+        run_batch, BatchError, BatchErrorKind, BatchGenerator, BatchOutput, BatchResult,
+    };
+
+    // Words are tokens, the context is 8 tokens and generating more than 4 tokens runs out of
+    // memory unless the cache has just been cleared.
+    #[derive(Default)]
+    struct WordGenerator {
+        cleared: usize,
+        fresh: bool,
+    }
+
+    impl BatchGenerator for WordGenerator {
+        fn generate(
+            &mut self,
+            prompt: &str,
+            max_tokens: usize,
+        ) -> std::result::Result<BatchResult, BatchError> {
+            let fresh = std::mem::take(&mut self.fresh);
+            let len = prompt.split_whitespace().count();
+            if len + max_tokens > 8 {
+                let msg = format!("{len} prompt tokens + {max_tokens} > 8");
+                return Err(BatchError::new(BatchErrorKind::ContextOverflow, msg));
+            }
+            if max_tokens > 4 && !fresh {
+                let err = candle::Error::Msg("CUDA_ERROR_OUT_OF_MEMORY, out of memory".into());
+                return Err(BatchError::from_model_error(&err));
+            }
+            Ok(BatchResult {
+                text: prompt.to_uppercase(),
+                generated_tokens: max_tokens,
+                max_tokens,
+            })
+        }
+
+        fn clear_cache(&mut self) {
+            self.cleared += 1;
+            self.fresh = true;
+        }
+    }
+
+    let input = [
+        r#"{"id": "a", "prompt": "hello world", "max_tokens": 2}"#,
+        r#"{"id": "b", "prompt": "one two three four five six seven"}"#,
+        r#"{"id": "c", "prompt": "#,
+        "",
+        r#"{"prompt": "oom", "max_tokens": 6}"#,
+    ]
+    .join("\n");
+    let mut output = vec![];
+    let mut generator = WordGenerator::default();
+    let summary = run_batch(&mut generator, input.as_bytes(), &mut output, 3)?;
+    assert_eq!((summary.succeeded, summary.failed), (2, 2));
+    assert_eq!(summary.exit_code(), 1);
+    assert_eq!(generator.cleared, 1);
+
+    let output = String::from_utf8(output).map_err(candle::Error::wrap)?;
+    let lines = output
+        .lines()
+        .map(|l| serde_json::from_str::<BatchOutput>(l).map_err(candle::Error::wrap))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(lines.len(), 4);
+    assert_eq!(
+        lines.iter().map(|l| l.line).collect::<Vec<_>>(),
+        [1, 2, 3, 5]
+    );
+    for l in lines.iter() {
+        assert!(l.result.is_some() != l.error.is_some());
+    }
+    assert_eq!(lines[0].id.as_deref(), Some("a"));
+    assert_eq!(lines[0].result.as_ref().unwrap().text, "HELLO WORLD");
+    let err = lines[1].error.as_ref().unwrap();
+    assert_eq!(err.kind, BatchErrorKind::ContextOverflow);
+    assert!(!err.retriable);
+    assert_eq!(lines[2].id, None);
+    assert_eq!(
+        lines[2].error.as_ref().unwrap().kind,
+        BatchErrorKind::InvalidRequest
+    );
+    assert_eq!(lines[3].result.as_ref().unwrap().max_tokens, 3);
+
+    let mut output = vec![];
+    let input = r#"{"prompt": "fine"}"#;
+    let summary = run_batch(&mut generator, input.as_bytes(), &mut output, 3)?;
+    assert_eq!(summary.exit_code(), 0);
+    Ok(())
+}
+
+#[test]
+fn batch_bucketed() -> Result<()> {
+    use candle_transformers::bucketing::BucketConfig;
+    use candle_transformers::generation::batch::{
+        run_batch, run_batch_bucketed, BatchError, BatchErrorKind, BatchGenerator, BatchResult,
+    };
+
+    // Words are tokens, prompts with a `?` cannot be bucketed, and the prompts with `oom` run
+    // out of memory when generated in a bucket.
+    #[derive(Default)]
+    struct WordGenerator {
+        buckets: Vec<Vec<usize>>,
+        cleared: usize,
+    }
+
+    impl BatchGenerator for WordGenerator {
+        fn generate(
+            &mut self,
+            prompt: &str,
+            max_tokens: usize,
+        ) -> std::result::Result<BatchResult, BatchError> {
+            if prompt.split_whitespace().count() > 8 {
+                return Err(BatchError::new(BatchErrorKind::ContextOverflow, prompt));
+            }
+            Ok(BatchResult {
+                text: prompt.to_uppercase(),
+                generated_tokens: max_tokens,
+                max_tokens,
+            })
+        }
+
+        fn clear_cache(&mut self) {
+            self.cleared += 1
+        }
+
+        fn prompt_len(&mut self, prompt: &str) -> Option<usize> {
+            (!prompt.contains('?')).then(|| prompt.split_whitespace().count())
+        }
+
+        fn generate_bucket(
+            &mut self,
+            requests: &[(&str, usize)],
+        ) -> Vec<std::result::Result<BatchResult, BatchError>> {
+            let lengths = requests.iter().map(|(p, _)| p.split_whitespace().count());
+            self.buckets.push(lengths.collect());
+            requests
+                .iter()
+                .map(|&(prompt, max_tokens)| match prompt.contains("oom") {
+                    true => Err(BatchError::new(BatchErrorKind::Oom, "out of memory")),
+                    false => self.generate(prompt, max_tokens),
+                })
+                .collect()
+        }
+    }
+
+    let input = [
+        r#"{"id": "a", "prompt": "one two three four", "max_tokens": 2}"#,
+        r#"{"id": "b", "prompt": "one"}"#,
+        r#"{"id": "c", "prompt": "#,
+        r#"{"id": "d", "prompt": "why not?"}"#,
+        "",
+        r#"{"id": "e", "prompt": "one two three oom"}"#,
+        r#"{"id": "f", "prompt": "one two"}"#,
+        r#"{"id": "g", "prompt": "a b c d e f g h i"}"#,
+        r#"{"id": "h", "prompt": "one two three"}"#,
+    ]
+    .join("\n");
+    let mut expected = vec![];
+    let mut generator = WordGenerator::default();
+    let summary = run_batch(&mut generator, input.as_bytes(), &mut expected, 3)?;
+    assert_eq!((summary.succeeded, summary.failed), (6, 2));
+    assert!(generator.buckets.is_empty());
+
+    // The output does not depend on the bucketing.
+    let config = BucketConfig {
+        max_pad_ratio: 0.25,
+        max_batch_size: 3,
+    };
+    let mut output = vec![];
+    let mut generator = WordGenerator::default();
+    let bucketed = run_batch_bucketed(&mut generator, input.as_bytes(), &mut output, 3, &config)?;
+    assert_eq!(bucketed, summary);
+    assert_eq!(
+        String::from_utf8(output).map_err(candle::Error::wrap)?,
+        String::from_utf8(expected).map_err(candle::Error::wrap)?
+    );
+    assert_eq!(generator.buckets, [vec![9], vec![4, 4, 3], vec![2, 1]]);
+    assert_eq!(generator.cleared, 1);
+    Ok(())
+}
+
+#[test]
+fn constraint_schedule() -> Result<()> {
+    use candle_transformers::generation::constraint::{ConstraintSchedule, Phase, TokenTrie};
+    use candle_transformers::generation::{
+        LogitsProcessor, Sampling, StopCriteria, StopReason, TextGeneration,
+    };
+
+    let tokenizer = fixture_tokenizer();
+    let encode = |s: &str| {
+        let tokens = tokenizer.encode(s, false).map_err(candle::Error::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    };
+    let decode = |t: &[u32]| tokenizer.decode(t, false).map_err(candle::Error::msg);
+    let id = |s: &str| tokenizer.token_to_id(s).unwrap();
+
+    // The model predicts "hello" everywhere except where it has to pick between options.
+    let mut script = vec![id("hello"); 16];
+    script[0] = id("the");
+    // Position 1 is "the", "cat" gets forced at position 2.
+    script[2] = id("sat");
+    // Position 3 is "sat", "on" gets forced at position 4.
+    script[4] = id("a");
+    // "dog" gets forced at position 6 and the free phase starts there.
+    script[7] = id("</s>");
+    script[8] = id("no");
+    let model = ScriptedModel {
+        script,
+        vocab_size: tokenizer.get_vocab_size(true),
+    };
+    let lp = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    let mut generation = TextGeneration::new(model, lp, &Device::Cpu);
+
+    let options = [
+        "the cat sat on mat",
+        "the cat sat on a dog",
+        "the cat ran home",
+        "yes",
+    ];
+    let schedule = ConstraintSchedule::new(vec![
+        Phase::OneOf {
+            options: options.iter().map(|s| s.to_string()).collect(),
+        },
+        Phase::Free {
+            until: StopCriteria::StopTokens([id("</s>")].into()),
+        },
+        Phase::OneOf {
+            options: vec!["yes".to_string(), "no".to_string()],
+        },
+    ]);
+    let schedule = schedule.resolve(std::iter::empty(), encode)?;
+    generation.push_prompt(&[id("<s>")])?;
+    let outputs = generation.run_schedule(&schedule, 4, decode)?;
+    assert_eq!(outputs.len(), 3);
+    assert_eq!(outputs[0].choice, Some(1));
+    assert_eq!(outputs[0].tokens, encode(options[1])?);
+    // The free phase is not constrained by the previous options.
+    assert_eq!(outputs[1].tokens, [id("hello")]);
+    assert_eq!(outputs[1].stop, Some(StopReason::Token(id("</s>"))));
+    assert_eq!(outputs[2].choice, Some(1));
+    assert_eq!(schedule.choices(&outputs), ["the cat sat on a dog", "no"]);
+    assert_eq!(
+        generation.tokens(),
+        [
+            &[id("<s>")],
+            encode("the cat sat on a dog hello </s> no")?.as_slice()
+        ]
+        .concat()
+    );
+
+    let trie = TokenTrie::new(&[encode("one two")?, encode("one three")?])?;
+    let (choice, tokens) = trie.walk(|forced, allowed| {
+        assert_eq!(forced, [id("one")]);
+        assert_eq!(allowed, &[id("two"), id("three")].into());
+        Ok(id("three"))
+    })?;
+    assert_eq!((choice, tokens), (1, vec![id("one"), id("three")]));
+
+    // With an end token, the model picks between the shorter option and the continuations.
+    let options = vec!["yes".to_string(), "yes and no".to_string()];
+    let schedule = ConstraintSchedule::new(vec![Phase::OneOf { options }])
+        .with_option_end(id("</s>"))
+        .resolve(std::iter::empty(), encode)?;
+    for (choice, picked) in [(0, "</s>"), (1, "and")] {
+        let mut script = vec![id("hello"); 8];
+        script[1] = id(picked);
+        let model = ScriptedModel {
+            script,
+            vocab_size: tokenizer.get_vocab_size(true),
+        };
+        let lp = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+        let mut generation = TextGeneration::new(model, lp, &Device::Cpu);
+        generation.push_prompt(&[id("<s>")])?;
+        let outputs = generation.run_schedule(&schedule, 8, decode)?;
+        assert_eq!(outputs[0].choice, Some(choice));
+        let expected = [encode(["yes", "yes and no"][choice])?, vec![id("</s>")]].concat();
+        assert_eq!(outputs[0].tokens, expected);
+        assert_eq!(generation.tokens()[1..], expected);
+    }
+    let trie = TokenTrie::new(&[
+        [encode("yes and no")?, vec![id("</s>")]].concat(),
+        [encode("yes")?, vec![id("</s>")]].concat(),
+    ])?;
+    let (choice, _) = trie.walk(|forced, allowed| {
+        assert_eq!(forced, [id("yes")]);
+        assert_eq!(allowed, &[id("and"), id("</s>")].into());
+        Ok(id("</s>"))
+    })?;
+    assert_eq!(choice, 1);
+
+    assert!(TokenTrie::new(&[encode("yes")?, encode("yes")?]).is_err());
+    // Without an end token, the longer option of a prefix pair could never be picked.
+    let error = |options: &[Vec<u32>]| {
+        let error = TokenTrie::new(options).unwrap_err().to_string();
+        error.lines().next().unwrap().to_string()
+    };
+    assert_eq!(
+        error(&[encode("yes")?, encode("one")?, encode("yes and no")?]),
+        "option 0 is a prefix of option 2 once tokenized, end the options with a token"
+    );
+    assert_eq!(
+        error(&[encode("yes and no")?, encode("yes")?]),
+        "option 1 is a prefix of option 0 once tokenized, end the options with a token"
+    );
+    assert!(TokenTrie::new(&[]).is_err());
+    Ok(())
+}
+
+#[test]
+fn generation_params_validation() -> Result<()> {
+    use candle_transformers::generation::{GenerationParams, Sampling};
+
+    let params = GenerationParams::default()
+        .with_seed(42)
+        .with_max_tokens(16)
+        .with_temperature(0.)
+        .with_top_k(3)
+        .with_repeat_penalty(1.2, 8)
+        .with_stop_token_pattern(r"^<\|im_end\|>$");
+    params.validate()?;
+    assert_eq!(
+        params.sampling(),
+        Sampling::TopK {
+            k: 3,
+            temperature: 0.
+        }
+    );
+    assert_eq!(params.stop_criteria().len(), 1);
+
+    // Only keep the message, the error has a backtrace when RUST_BACKTRACE is set.
+    let error = |params: GenerationParams| {
+        let error = params.validate().unwrap_err().to_string();
+        error.lines().next().unwrap().to_string()
+    };
+    let default = GenerationParams::default;
+    assert_eq!(
+        error(default().with_top_p(1.5)),
+        "top_p must be in (0,1], got 1.5"
+    );
+    assert_eq!(
+        error(default().with_top_p(0.)),
+        "top_p must be in (0,1], got 0"
+    );
+    assert_eq!(error(default().with_top_k(0)), "top_k must be positive");
+    assert_eq!(
+        error(default().with_temperature(-1.)),
+        "temperature must be non-negative, got -1"
+    );
+    assert_eq!(
+        error(default().with_temperature(f64::NAN)),
+        "temperature must be non-negative, got NaN"
+    );
+    assert_eq!(
+        error(default().with_repeat_penalty(0., 64)),
+        "repeat_penalty must be positive, got 0"
+    );
+    assert_eq!(
+        error(default().with_best_of(0, 1.)),
+        "best_of must be positive"
+    );
+    assert_eq!(
+        error(default().with_best_of(2, f64::INFINITY)),
+        "length_penalty must be finite, got inf"
+    );
+    assert!(error(default().with_stop_token_pattern("(")).starts_with("invalid stop token pattern"));
+    Ok(())
+}
+
+#[test]
+fn generation_params_json() -> Result<()> {
+    use candle_transformers::generation::GenerationParams;
+
+    let params = GenerationParams::default()
+        .with_top_p(0.9)
+        .with_stop_token_pattern("^<eos>$");
+    let json = serde_json::to_string(&params).unwrap();
+    let roundtrip: GenerationParams = serde_json::from_str(&json).unwrap();
+    assert_eq!(roundtrip, params);
+    // Missing fields get their default value.
+    let params: GenerationParams = serde_json::from_str(r#"{"temperature": 0.2}"#).unwrap();
+    assert_eq!(params, GenerationParams::default().with_temperature(0.2));
+    Ok(())
+}
+
+#[test]
+fn generation_params_presets() -> Result<()> {
+    use candle_transformers::generation::{GenerationParams, Preset, Sampling};
+
+    let sampling =
+        |name: &str| Ok::<_, candle::Error>(GenerationParams::from_preset(name)?.sampling());
+    assert_eq!(sampling("greedy")?, Sampling::ArgMax);
+    assert_eq!(
+        sampling("llama-cpp-default")?,
+        Sampling::TopKThenTopP {
+            k: 40,
+            p: 0.95,
+            temperature: 0.8
+        }
+    );
+    assert_eq!(
+        sampling("creative")?,
+        Sampling::TopP {
+            p: 0.95,
+            temperature: 1.
+        }
+    );
+    assert_eq!(
+        sampling("precise")?,
+        Sampling::TopKThenTopP {
+            k: 40,
+            p: 0.9,
+            temperature: 0.2
+        }
+    );
+    for preset in Preset::ALL {
+        let params = preset.params();
+        params.validate()?;
+        assert_eq!(preset.to_string().parse::<Preset>()?, preset);
+        // Only the sampling parameters are set by the presets.
+        assert_eq!(params.seed, GenerationParams::default().seed);
+        assert_eq!(params.max_tokens, GenerationParams::default().max_tokens);
+        assert_eq!(params.repeat_last_n, 64);
+    }
+    let penalty =
+        |name: &str| Ok::<_, candle::Error>(GenerationParams::from_preset(name)?.repeat_penalty);
+    assert_eq!(penalty("greedy")?, 1.);
+    assert_eq!(penalty("llama-cpp-default")?, 1.);
+    assert_eq!(penalty("creative")?, 1.1);
+    assert_eq!(penalty("precise")?, 1.1);
+
+    // Explicit settings take precedence over the preset.
+    let params = GenerationParams::from_preset("precise")?
+        .with_temperature(0.7)
+        .with_top_k(5);
+    assert_eq!(
+        params.sampling(),
+        Sampling::TopKThenTopP {
+            k: 5,
+            p: 0.9,
+            temperature: 0.7
+        }
+    );
+
+    let error = GenerationParams::from_preset("default")
+        .unwrap_err()
+        .to_string();
+    assert_eq!(
+        error.lines().next().unwrap(),
+        "unknown preset \"default\", expected one of greedy, llama-cpp-default, creative, precise"
+    );
+    Ok(())
+}
+
+// A model with the same next token distribution at every position.
+struct FixedDistributionModel {
+    logprobs: Vec<f32>,
+}
+
+impl candle_transformers::generation::CausalLm for FixedDistributionModel {
+    fn forward(&mut self, input: &Tensor, _index_pos: usize) -> Result<Tensor> {
+        let (b_size, _seq_len) = input.dims2()?;
+        Tensor::new(self.logprobs.as_slice(), input.device())?.broadcast_left(b_size)
+    }
+
+    fn fork_kv_cache(&mut self, _n: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Counts the forward passes of a model.
+struct CountingModel<M> {
+    model: M,
+    forwards: std::rc::Rc<std::cell::Cell<usize>>,
+}
+
+impl<M: candle_transformers::generation::CausalLm> candle_transformers::generation::CausalLm
+    for CountingModel<M>
+{
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self.forwards.set(self.forwards.get() + 1);
+        self.model.forward(input, index_pos)
+    }
+}
+
+#[test]
+fn guardrail_bans_word() -> Result<()> {
+    use candle_transformers::generation::guardrail::{GuardrailDecision, RegexGuardrail};
+    use candle_transformers::generation::{Sampling, StopConditions, StopReason, TextGeneration};
+
+    // The banned word is the most likely token, and can also be made of two tokens.
+    let words = ["<s>", " the", " cat", " secret", " sec", "ret", " sat", "."];
+    let logprobs = [-9., -2., -2., -0.5, -1.5, -1., -2., -3.];
+    let decode = |ids: &[u32]| Ok(ids.iter().map(|&id| words[id as usize]).collect::<String>());
+    let forwards = std::rc::Rc::new(std::cell::Cell::new(0));
+    let model = CountingModel {
+        model: FixedDistributionModel {
+            logprobs: logprobs.to_vec(),
+        },
+        forwards: forwards.clone(),
+    };
+    let sampler = || LogitsProcessor::from_sampling(7, Sampling::All { temperature: 1. });
+    let max_resamples = 3;
+    let mut generation = TextGeneration::new(model, sampler(), &Device::Cpu)
+        .with_guardrail(RegexGuardrail::new("secret")?, max_resamples);
+    generation.push_prompt(&[0])?;
+    let stop = StopConditions::default();
+    let mut text = String::new();
+    let tokens = generation.generate_text(200, &stop, decode, |t| {
+        text.push_str(t);
+        Ok(())
+    })?;
+    assert_eq!(tokens.len(), 200);
+    assert_eq!(generation.stop_reason(), None);
+    assert!(!text.contains("secret"), "{text}");
+    assert_eq!(text, decode(&tokens)?);
+    // The resamples reuse the logits of the step, the hook runs at most once per sample.
+    let stats = generation.guardrail_stats().unwrap();
+    assert!(stats.resamples > 0);
+    assert_eq!(stats.checks, tokens.len() + stats.resamples);
+    assert!(stats.checks <= (max_resamples + 1) * tokens.len());
+    assert_eq!(forwards.get(), tokens.len());
+    assert!(generation.generate(1, &stop, |_| Ok(())).is_err());
+
+    // A hook that rejects everything aborts once the resamples of the step are exhausted.
+    let model = FixedDistributionModel {
+        logprobs: logprobs.to_vec(),
+    };
+    let reject = |token: u32, _: &str| Ok(GuardrailDecision::Resample([token].into()));
+    let mut generation =
+        TextGeneration::new(model, sampler(), &Device::Cpu).with_guardrail(reject, max_resamples);
+    generation.push_prompt(&[0])?;
+    let tokens = generation.generate_text(10, &stop, decode, |_| Ok(()))?;
+    assert!(tokens.is_empty());
+    assert!(matches!(
+        generation.stop_reason(),
+        Some(StopReason::Guardrail(_))
+    ));
+    let stats = generation.guardrail_stats().unwrap();
+    assert_eq!((stats.checks, stats.resamples, stats.aborts), (4, 3, 1));
+
+    // The hook can abort with its own reason, the text accepted so far is kept.
+    let model = FixedDistributionModel {
+        logprobs: logprobs.to_vec(),
+    };
+    let abort = |_: u32, text: &str| match text.len() > 12 {
+        true => Ok(GuardrailDecision::Abort("too long".to_string())),
+        false => Ok(GuardrailDecision::Allow),
+    };
+    let mut generation =
+        TextGeneration::new(model, sampler(), &Device::Cpu).with_guardrail(abort, max_resamples);
+    generation.push_prompt(&[0])?;
+    let tokens = generation.generate_text(10, &stop, decode, |_| Ok(()))?;
+    assert!(!tokens.is_empty() && decode(&tokens)?.len() <= 12);
+    assert_eq!(
+        generation.stop_reason(),
+        Some(&StopReason::Guardrail("too long".to_string()))
+    );
+    Ok(())
+}
+
+#[test]
+fn best_of_scoring() -> Result<()> {
+    use candle_transformers::generation::best_of::length_normalized_score;
+    use candle_transformers::generation::{BestOf, Candidate};
+
+    assert_eq!(length_normalized_score(&[-1., -1.], 0.), -2.);
+    assert_eq!(length_normalized_score(&[-1., -1.], 1.), -1.);
+    assert_eq!(length_normalized_score(&[-1., -1., -1., -1.], 0.5), -2.);
+    assert_eq!(length_normalized_score(&[], 1.), 0.);
+
+    // Two tokens at -1 each against a single token at -1.5: the sum prefers the short one and
+    // the mean the long one.
+    let best_of = |alpha| {
+        let long = Candidate::new(vec![1, 2], vec![-1., -1.], alpha);
+        let short = Candidate::new(vec![1], vec![-1.5], alpha);
+        BestOf::new(vec![long, short])
+    };
+    assert_eq!(best_of(0.)?.best, 1);
+    assert_eq!(best_of(0.)?.best().tokens, [1]);
+    assert_eq!(best_of(1.)?.best, 0);
+    assert_eq!(best_of(1.)?.best().tokens, [1, 2]);
+
+    // Ties go to the first candidate.
+    let tied = (0..3).map(|i| Candidate::new(vec![i], vec![-1.], 1.));
+    assert_eq!(BestOf::new(tied.collect())?.best, 0);
+    assert!(BestOf::new(vec![]).is_err());
+    Ok(())
+}
+
+#[test]
+fn best_of_generation() -> Result<()> {
+    use candle_transformers::generation::{
+        generate_best_of, generate_forked, GenerationParams, SamplerSlot,
+    };
+
+    let device = Device::Cpu;
+    // The token 0 is the end of sequence token.
+    let logprobs = [0.2f32, 0.3, 0.5].map(f32::ln).to_vec();
+    let params = GenerationParams::default()
+        .with_temperature(1.)
+        .with_repeat_penalty(1., 64)
+        .with_max_tokens(6)
+        .with_stop_token_pattern("^</s>$");
+    let added_tokens = [(0, "</s>")];
+    let mut model = FixedDistributionModel {
+        logprobs: logprobs.clone(),
+    };
+
+    for alpha in [0., 1.] {
+        let res = generate_best_of(
+            &mut model,
+            &[1, 2],
+            &params,
+            8,
+            alpha,
+            added_tokens,
+            &device,
+        )?;
+        assert_eq!(res.candidates.len(), 8);
+        for candidate in res.candidates.iter() {
+            // The logprobs are the ones of the sampled tokens, including the stop token.
+            let mut sampled = candidate.tokens.clone();
+            if candidate.logprobs.len() == sampled.len() + 1 {
+                sampled.push(0)
+            } else {
+                assert_eq!(candidate.logprobs.len(), 6);
+            }
+            for (&token, &lp) in sampled.iter().zip(candidate.logprobs.iter()) {
+                assert!((lp - logprobs[token as usize]).abs() < 1e-5, "{token} {lp}");
+            }
+            let sum = candidate.logprobs.iter().map(|&lp| lp as f64).sum::<f64>();
+            let expected = sum / (candidate.logprobs.len() as f64).powf(alpha);
+            assert!((candidate.score - expected).abs() < 1e-9);
+        }
+        let max = res
+            .candidates
+            .iter()
+            .map(|c| c.score)
+            .fold(f64::MIN, f64::max);
+        assert_eq!(res.best().score, max);
+        // Each candidate has its own seed.
+        assert!(res
+            .candidates
+            .iter()
+            .any(|c| c.tokens != res.candidates[0].tokens));
+    }
+
+    // A single candidate is the plain generation with the same seed.
+    let res = generate_best_of(&mut model, &[1, 2], &params, 1, 1., added_tokens, &device)?;
+    let mut slots = vec![SamplerSlot::from_params(&params, added_tokens)?];
+    let tokens = generate_forked(&mut model, &[1, 2], &mut slots, 6, &device)?;
+    assert_eq!(res.best().tokens, tokens[0]);
+
+    assert!(generate_best_of(&mut model, &[1], &params, 0, 1., [], &device).is_err());
+    let invalid = params.clone().with_top_p(2.);
+    assert!(generate_best_of(&mut model, &[1], &invalid, 2, 1., [], &device).is_err());
+    Ok(())
+}
+
+#[test]
+fn regression_detects_dtype_change() -> Result<()> {
+    use candle::quantized::GgmlDType;
+    use candle_transformers::generation::regression::{compare, record};
+    use candle_transformers::test_support::{tiny_test_model, tiny_test_model_with};
+
+    let prompts = vec![vec![1, 2, 3], vec![40, 41]];
+    let device = &Device::Cpu;
+    let baseline = record(&mut tiny_test_model(9)?, &prompts, 8, device)?;
+    let same = record(&mut tiny_test_model(9)?, &prompts, 8, device)?;
+    let report = compare(&baseline, &same, 0.)?;
+    assert!(report.passed(), "{report}");
+    assert_eq!(report.max_logit_delta(), 0.);
+
+    let mut f16 = tiny_test_model_with(9, |_, _| GgmlDType::F16)?;
+    let drifted = record(&mut f16, &prompts, 8, device)?;
+    let report = compare(&baseline, &drifted, 1e-6)?;
+    assert!(!report.passed(), "{report}");
+    assert!(report.max_logit_delta() > 1e-6);
+    Ok(())
+}
+
+// One token per latin-1 character, the vocabulary of the tiny test model has 256 tokens.
+struct Latin1ChatFormat;
+
+impl candle_transformers::generation::chat::ChatFormat for Latin1ChatFormat {
+    fn render(&self, messages: &[candle_transformers::generation::chat::ChatMessage]) -> String {
+        use candle_transformers::generation::chat::Role;
+        let mut text = String::new();
+        for message in messages {
+            let role = match message.role {
+                Role::System => "S",
+                Role::User => "U",
+                Role::Assistant => "A",
+            };
+            text.push_str(&format!("{role}:{}\n", message.content));
+        }
+        text.push_str("A:");
+        text
+    }
+
+    fn encode(&self, text: &str) -> Result<Vec<u32>> {
+        Ok(text.chars().map(u32::from).collect())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        Ok(tokens.iter().map(|&t| char::from(t as u8)).collect())
+    }
+}
+
+// Drives a scripted conversation that does not fit in the context, checking that each prompt
+// leaves room for the whole answer and that the system prompt is kept.
+fn scripted_chat<S>(summarizer: S) -> Result<(ChatHistory, usize)>
+where
+    S: candle_transformers::generation::chat::Summarizer + 'static,
+{
+    use candle_transformers::generation::chat::{ChatFormat, ChatSession, Role};
+    use candle_transformers::generation::{Sampling, StopConditions, TextGeneration, TokenBudget};
+    use candle_transformers::test_support::tiny_test_model;
+
+    let system = "You are a terse assistant.";
+    let budget = TokenBudget::new(256).with_reserve(56);
+    let max_new_tokens = 12;
+    let generation = TextGeneration::new(
+        tiny_test_model(3)?,
+        LogitsProcessor::from_sampling(3, Sampling::ArgMax),
+        &Device::Cpu,
+    );
+    let stop = StopConditions::new(&[], [])?;
+    let mut session = ChatSession::new(generation, Latin1ChatFormat, stop, budget)
+        .with_summarizer(summarizer)
+        .with_history(ChatHistory::new(Some(system.to_string())));
+    let mut compactions = 0;
+    for turn in 0..12 {
+        let question = format!("question {turn}, please answer briefly");
+        let prompt = session.push_user(&question, max_new_tokens)?;
+        compactions += prompt.compactions;
+        if turn > 0 && prompt.compactions == 0 {
+            // Only the new turn is processed, the answer tokens are rendered back identically.
+            let expected = format!("U:{question}\nA:").len() + 1;
+            assert_eq!(prompt.processed_tokens, expected);
+        }
+        assert_eq!(prompt.new_tokens, max_new_tokens);
+        assert!(prompt.prompt_tokens + prompt.new_tokens <= budget.available());
+        let rendered = Latin1ChatFormat.render(&session.history().to_messages());
+        assert!(rendered.starts_with(&format!("S:{system}\n")), "{rendered}");
+        assert!(
+            rendered.ends_with(&format!("U:{question}\nA:")),
+            "{rendered}"
+        );
+        let mut text = String::new();
+        let answer = session.answer(|t| {
+            text.push_str(t);
+            Ok(())
+        })?;
+        assert_eq!(answer.len(), max_new_tokens);
+        let last = session.history().messages.last().unwrap();
+        assert_eq!(
+            (last.role, last.content.as_str()),
+            (Role::Assistant, text.as_str())
+        );
+        assert!(session.generation().tokens().len() <= budget.available());
+        assert_eq!(session.history().system.as_deref(), Some(system));
+    }
+    Ok((session.history().clone(), compactions))
+}
+
+#[test]
+fn chat_history_compaction() -> Result<()> {
+    use candle_transformers::generation::chat::{DropOldestTurns, Role, SummarizeTurns};
+
+    let (history, compactions) = scripted_chat(DropOldestTurns)?;
+    assert!(compactions > 0);
+    assert_eq!(history.summary, None);
+    // The last turns are kept whole.
+    assert!(history.turns() > 1);
+    assert_eq!(history.messages[0].role, Role::User);
+    let last = &history.messages[history.messages.len() - 2];
+    assert_eq!(last.content, "question 11, please answer briefly");
+
+    let (history, compactions) = scripted_chat(SummarizeTurns::new(8))?;
+    assert!(compactions > 0);
+    let summary = history.summary.as_ref().unwrap();
+    assert!(summary.chars().count() <= 8);
+    assert_eq!(history.messages[0].role, Role::User);
+    Ok(())
+}
+
+// A model with a kv cache of `kv_len` positions, the logits depend on the position and on the
+// token so that a stale cache gives different logits.
+struct CachedModel {
+    kv_len: usize,
+    vocab_size: usize,
+    scale: f32,
+}
+
+impl CachedModel {
+    fn logits(&self, pos: usize, token: u32) -> Vec<f32> {
+        (0..self.vocab_size)
+            .map(|i| self.scale * ((i * 7 + pos * 3 + token as usize) % 11) as f32)
+            .collect()
+    }
+}
+
+impl candle_transformers::generation::CausalLm for CachedModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        if index_pos != self.kv_len {
+            candle::bail!(
+                "forward at {index_pos} with {} cached positions",
+                self.kv_len
+            )
+        }
+        let tokens = input.squeeze(0)?.to_vec1::<u32>()?;
+        self.kv_len += tokens.len();
+        let logits = self.logits(self.kv_len - 1, tokens[tokens.len() - 1]);
+        Tensor::new(logits.as_slice(), input.device())?.unsqueeze(0)
+    }
+
+    fn truncate_kv_cache(&mut self, len: usize) -> Result<()> {
+        self.kv_len = self.kv_len.min(len);
+        Ok(())
+    }
+
+    fn max_seq_len(&self) -> Option<usize> {
+        Some(64 / self.scale as usize)
+    }
+}
+
+fn log_softmax(logits: &[f32]) -> Vec<f64> {
+    let max = logits.iter().fold(f32::MIN, |m, &v| m.max(v)) as f64;
+    let sum = logits.iter().map(|&v| (v as f64 - max).exp()).sum::<f64>();
+    logits.iter().map(|&v| v as f64 - max - sum.ln()).collect()
+}
+
+#[test]
+fn ensemble_combination() -> Result<()> {
+    use candle_transformers::generation::{
+        CausalLm, EnsembleModel, Sampling, StopConditions, TextGeneration,
+    };
+
+    let device = Device::Cpu;
+    let model = |scale| CachedModel {
+        kv_len: 0,
+        vocab_size: 5,
+        scale,
+    };
+    for weights in [(0.7, 0.3), (1.5, -0.5), (1., 0.)] {
+        let mut ensemble = EnsembleModel::new([(model(1.), weights.0), (model(2.), weights.1)])?;
+        assert_eq!(ensemble.max_seq_len(), Some(32));
+        let input = Tensor::new(&[[3u32, 1, 4]], &device)?;
+        let logits = ensemble.forward(&input, 0)?.squeeze(0)?.to_vec1::<f32>()?;
+        let lhs = log_softmax(&model(1.).logits(2, 4));
+        let rhs = log_softmax(&model(2.).logits(2, 4));
+        for i in 0..5 {
+            let expected = weights.0 * lhs[i] + weights.1 * rhs[i];
+            assert!(
+                (logits[i] as f64 - expected).abs() < 1e-5,
+                "{weights:?} {i}"
+            );
+        }
+        // A weight of one with a zero weight gives back the log-probabilities of the model.
+        if weights.1 == 0. {
+            let sum = logits.iter().map(|&v| (v as f64).exp()).sum::<f64>();
+            assert!((sum - 1.).abs() < 1e-5);
+        }
+    }
+
+    // The members see the same tokens at the same positions through the generation, including
+    // after a rollback.
+    let ensemble = EnsembleModel::new([(model(1.), 0.7), (model(2.), 0.3)])?;
+    let logits_processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    let mut generation = TextGeneration::new(ensemble, logits_processor, &device);
+    let stop = StopConditions::new(&[], [])?;
+    let checkpoint = generation.push_prompt(&[1, 2])?;
+    let first = generation.generate(6, &stop, |_| Ok(()))?;
+    for member in generation.model().members() {
+        assert_eq!(member.model.kv_len, 2 + 5);
+    }
+    generation.rollback_to(&checkpoint)?;
+    for member in generation.model().members() {
+        assert_eq!(member.model.kv_len, 2);
+    }
+    assert_eq!(generation.generate(6, &stop, |_| Ok(()))?, first);
+
+    // The vocabularies have to match and the weights have to be finite.
+    let other = CachedModel {
+        vocab_size: 6,
+        ..model(1.)
+    };
+    let mut ensemble = EnsembleModel::new([(model(1.), 0.5), (other, 0.5)])?;
+    let input = Tensor::new(&[[1u32]], &device)?;
+    assert!(ensemble.forward(&input, 0).is_err());
+    assert!(EnsembleModel::new([(model(1.), f64::NAN)]).is_err());
+    assert!(EnsembleModel::<CachedModel>::new([]).is_err());
+    Ok(())
+}
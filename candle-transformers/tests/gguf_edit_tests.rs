@@ -0,0 +1,122 @@
+use candle::quantized::gguf_file::{self, MetadataEdit, Value};
+use candle::{Device, Result};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/tiny-llama.gguf"
+);
+
+fn out_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "candle-gguf-edit-{name}-{}.gguf",
+        std::process::id()
+    ))
+}
+
+fn read(path: &Path) -> Result<(gguf_file::Content, std::fs::File)> {
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    Ok((content, file))
+}
+
+// The hash of the data of each tensor.
+fn tensor_hashes(path: &Path) -> Result<HashMap<String, u64>> {
+    let (content, mut file) = read(path)?;
+    let mut hashes = HashMap::new();
+    for name in content.tensor_infos.keys() {
+        let tensor = content.tensor(&mut file, name, &Device::Cpu)?;
+        let mut hasher = std::hash::DefaultHasher::new();
+        tensor.data()?.hash(&mut hasher);
+        hashes.insert(name.clone(), hasher.finish());
+    }
+    Ok(hashes)
+}
+
+fn load_model(path: &Path) -> Result<ModelWeights> {
+    let (content, mut file) = read(path)?;
+    ModelWeights::from_gguf(content, &mut file, &Device::Cpu)
+}
+
+#[test]
+fn set() -> Result<()> {
+    let out = out_path("set");
+    let template = "{% for m in messages %}{{ m.content }}{% endfor %}";
+    gguf_file::edit(
+        FIXTURE,
+        &out,
+        vec![
+            MetadataEdit::Set("tokenizer.ggml.eos_token_id".to_string(), Value::U32(2)),
+            MetadataEdit::Set(
+                "tokenizer.chat_template".to_string(),
+                Value::String(template.to_string()),
+            ),
+            MetadataEdit::Set("llama.context_length".to_string(), Value::U32(128)),
+        ],
+    )?;
+    let (content, _) = read(&out)?;
+    let (original, _) = read(Path::new(FIXTURE))?;
+    assert_eq!(content.metadata.len(), original.metadata.len() + 2);
+    assert_eq!(content.metadata["tokenizer.ggml.eos_token_id"].to_u32()?, 2);
+    assert_eq!(
+        content.metadata["tokenizer.chat_template"].to_string()?,
+        template
+    );
+    assert_eq!(
+        content.metadata["general.architecture"].to_string()?,
+        "llama"
+    );
+    assert_eq!(tensor_hashes(&out)?, tensor_hashes(Path::new(FIXTURE))?);
+    assert_eq!(load_model(&out)?.max_seq_len(), 128);
+    std::fs::remove_file(out)?;
+    Ok(())
+}
+
+#[test]
+fn delete() -> Result<()> {
+    let out = out_path("delete");
+    gguf_file::edit(
+        FIXTURE,
+        &out,
+        vec![MetadataEdit::Delete("general.name".to_string())],
+    )?;
+    let (content, _) = read(&out)?;
+    assert!(!content.metadata.contains_key("general.name"));
+    assert_eq!(tensor_hashes(&out)?, tensor_hashes(Path::new(FIXTURE))?);
+    load_model(&out)?;
+    std::fs::remove_file(&out)?;
+
+    // A missing key is an error and no file gets written.
+    let edits = vec![MetadataEdit::Delete("general.nope".to_string())];
+    assert!(gguf_file::edit(FIXTURE, &out, edits).is_err());
+    assert!(!out.exists());
+    Ok(())
+}
+
+#[test]
+fn rename_tensor() -> Result<()> {
+    let out = out_path("rename");
+    let rename =
+        |old: &str, new: &str| MetadataEdit::RenameTensor(old.to_string(), new.to_string());
+    gguf_file::edit(FIXTURE, &out, vec![rename("output.weight", "output.orig")])?;
+    let mut hashes = tensor_hashes(Path::new(FIXTURE))?;
+    let output = hashes.remove("output.weight").unwrap();
+    hashes.insert("output.orig".to_string(), output);
+    assert_eq!(tensor_hashes(&out)?, hashes);
+    // Without an output head the model uses the token embeddings.
+    load_model(&out)?;
+
+    // Renaming back in place gives the original tensors.
+    gguf_file::edit(&out, &out, vec![rename("output.orig", "output.weight")])?;
+    assert_eq!(tensor_hashes(&out)?, tensor_hashes(Path::new(FIXTURE))?);
+    std::fs::remove_file(&out)?;
+
+    assert!(gguf_file::edit(FIXTURE, &out, vec![rename("nope.weight", "x")]).is_err());
+    let existing = rename("output.weight", "token_embd.weight");
+    assert!(gguf_file::edit(FIXTURE, &out, vec![existing]).is_err());
+    assert!(!out.exists());
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::kv_budget::{
+    dense_embeddings_bytes, quantized_embeddings, weights_bytes, ActivationConfig, ContextBudget,
+    KvCacheConfig, MemoryEstimate, PrefillStrategy, QUANTIZED_EMBEDDINGS_MIN_BYTES,
+};
+use candle_transformers::test_support::gguf_content;
+
+const MIB: u64 = 1024 * 1024;
+const GIB: u64 = 1024 * MIB;
+
+fn config(n_layer: usize, n_kv_head: usize, head_dim: usize, dtype: DType) -> KvCacheConfig {
+    KvCacheConfig {
+        n_layer,
+        n_kv_head,
+        head_dim,
+        dtype,
+    }
+}
+
+#[test]
+fn bytes_per_token() {
+    // llama-2-7b: 32 layers of 32 heads of dim 128, no grouped query attention.
+    // 2 * 32 * 32 * 128 * 2 bytes = 512KiB per position, 2GiB for 4096 positions.
+    let llama2_7b = config(32, 32, 128, DType::F16);
+    assert_eq!(llama2_7b.bytes_per_token(), 524_288);
+    assert_eq!(llama2_7b.bytes(4096), 2 * GIB);
+    // llama-3-8b: same shape but 8 kv heads, 2 * 32 * 8 * 128 * 2 bytes = 128KiB.
+    let llama3_8b = config(32, 8, 128, DType::F16);
+    assert_eq!(llama3_8b.bytes_per_token(), 131_072);
+    assert_eq!(llama3_8b.bytes(8192), GIB);
+    // The same model with a f32 cache takes twice as much.
+    let llama3_8b_f32 = config(32, 8, 128, DType::F32);
+    assert_eq!(llama3_8b_f32.bytes_per_token(), 262_144);
+    // llama-2-70b: 80 layers, 8 kv heads, 2 * 80 * 8 * 128 * 2 bytes = 320KiB.
+    let llama2_70b = config(80, 8, 128, DType::BF16);
+    assert_eq!(llama2_70b.bytes_per_token(), 327_680);
+    assert_eq!(llama2_70b.max_context_length(10 * GIB), 32_768);
+    assert_eq!(llama2_70b.max_context_length(327_679), 0);
+}
+
+#[test]
+fn from_gguf() -> Result<()> {
+    use gguf_file::Value;
+
+    // Grouped query attention with the head dim derived from the embedding length.
+    let ct = gguf_content(
+        &[
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.block_count", Value::U32(32)),
+            ("llama.embedding_length", Value::U32(4096)),
+            ("llama.attention.head_count", Value::U32(32)),
+            ("llama.attention.head_count_kv", Value::U32(8)),
+        ],
+        &[],
+    )?;
+    let kv = KvCacheConfig::from_gguf(&ct, DType::F16)?;
+    assert_eq!(kv, config(32, 8, 128, DType::F16));
+    assert_eq!(kv.bytes_per_token(), 131_072);
+
+    // No head_count_kv, an explicit key length, and another architecture prefix.
+    let ct = gguf_content(
+        &[
+            ("general.architecture", Value::String("falcon".to_string())),
+            ("falcon.block_count", Value::U32(2)),
+            ("falcon.embedding_length", Value::U32(64)),
+            ("falcon.attention.head_count", Value::U32(4)),
+            ("falcon.attention.key_length", Value::U32(32)),
+        ],
+        &[],
+    )?;
+    let kv = KvCacheConfig::from_gguf(&ct, DType::F32)?;
+    assert_eq!(kv, config(2, 4, 32, DType::F32));
+    // 2 * 2 * 4 * 32 * 4 bytes.
+    assert_eq!(kv.bytes_per_token(), 2048);
+
+    let ct = gguf_content(
+        &[("general.architecture", Value::String("llama".to_string()))],
+        &[],
+    )?;
+    assert!(KvCacheConfig::from_gguf(&ct, DType::F32).is_err());
+    Ok(())
+}
+
+#[test]
+fn weights_from_header() -> Result<()> {
+    use gguf_file::Value;
+
+    let dev = &Device::Cpu;
+    let f32_weight = QTensor::quantize(&Tensor::zeros((8, 32), DType::F32, dev)?, GgmlDType::F32)?;
+    let q8_weight = QTensor::quantize(&Tensor::zeros((4, 64), DType::F32, dev)?, GgmlDType::Q8_0)?;
+    let ct = gguf_content(
+        &[
+            ("llama.block_count", Value::U32(1)),
+            ("llama.embedding_length", Value::U32(32)),
+            ("llama.attention.head_count", Value::U32(2)),
+        ],
+        &[
+            ("a.weight".to_string(), f32_weight),
+            ("b.weight".to_string(), q8_weight),
+        ],
+    )?;
+    // 256 f32 values, and 8 q8_0 blocks of 32 values that take 34 bytes each.
+    assert_eq!(weights_bytes(&ct), 256 * 4 + 8 * 34);
+    let estimate = MemoryEstimate::from_gguf(&ct, DType::F32, dev)?;
+    // 2 * 1 * 2 * 16 * 4 bytes per position.
+    assert_eq!(estimate.kv.bytes_per_token(), 256);
+    assert_eq!(estimate.total_bytes(10), 1296 + 2560);
+    Ok(())
+}
+
+#[test]
+fn memory_estimate() -> Result<()> {
+    let estimate = MemoryEstimate {
+        weights_bytes: 4 * GIB,
+        dense_embeddings_bytes: 0,
+        quantized_embeddings: false,
+        kv: config(32, 8, 128, DType::F16),
+    };
+    // 8GiB free minus 4GiB of weights and a 512MiB reserve leaves 3.5GiB, 28672 positions.
+    let budget = estimate.kv_budget(8 * GIB, 512 * MIB);
+    assert_eq!(budget, 3584 * MIB);
+    assert_eq!(estimate.kv.max_context_length(budget), 28_672);
+    estimate.check(8 * GIB, 512 * MIB)?;
+    assert!(estimate.check(4 * GIB, 512 * MIB).is_err());
+    assert_eq!(estimate.kv_budget(4 * GIB, 512 * MIB), 0);
+    Ok(())
+}
+
+#[test]
+fn embeddings_memory() -> Result<()> {
+    use gguf_file::{TensorInfo, Value};
+
+    // The header of llama-3-8b with its 128256 x 4096 token embeddings in q4k.
+    let mut ct = gguf_content(
+        &[
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.block_count", Value::U32(32)),
+            ("llama.embedding_length", Value::U32(4096)),
+            ("llama.attention.head_count", Value::U32(32)),
+            ("llama.attention.head_count_kv", Value::U32(8)),
+        ],
+        &[],
+    )?;
+    let info = TensorInfo {
+        ggml_dtype: GgmlDType::Q4K,
+        shape: (128_256, 4096).into(),
+        offset: 0,
+    };
+    ct.tensor_infos
+        .insert("token_embd.weight".to_string(), info);
+    // 2052096 blocks of 256 values that take 144 bytes each.
+    let q4k_bytes = 295_501_824;
+    assert_eq!(weights_bytes(&ct), q4k_bytes);
+    assert_eq!(dense_embeddings_bytes(&ct), 2_101_346_304);
+
+    // The f32 table takes 1.96GiB, above the threshold, so it stays quantized by default.
+    let estimate = MemoryEstimate::from_gguf(&ct, DType::F16, &Device::Cpu)?;
+    assert!(estimate.quantized_embeddings);
+    assert_eq!(estimate.embeddings_bytes(), 0);
+    assert_eq!(estimate.saved_embeddings_bytes(), 2_101_346_304);
+    assert_eq!(estimate.total_bytes(0), q4k_bytes);
+
+    let dense = estimate.with_quantized_embeddings(false);
+    assert_eq!(dense.saved_embeddings_bytes(), 0);
+    assert_eq!(dense.total_bytes(0), q4k_bytes + 2_101_346_304);
+    assert_eq!(
+        dense.kv_budget(8 * GIB, 0) + 2_101_346_304,
+        estimate.kv_budget(8 * GIB, 0)
+    );
+
+    let dev = &Device::Cpu;
+    assert!(quantized_embeddings(2_101_346_304, None, dev));
+    assert!(!quantized_embeddings(
+        QUANTIZED_EMBEDDINGS_MIN_BYTES,
+        None,
+        dev
+    ));
+    assert!(quantized_embeddings(0, Some(true), dev));
+    assert!(!quantized_embeddings(2_101_346_304, Some(false), dev));
+
+    // The quantized lookups are not supported on metal, the table is dequantized there.
+    #[allow(unused_mut)]
+    let mut devices = vec![];
+    #[cfg(feature = "cuda")]
+    devices.push(Device::new_cuda(0)?);
+    #[cfg(feature = "metal")]
+    devices.push(Device::new_metal(0)?);
+    for device in devices {
+        let estimate = MemoryEstimate::from_gguf(&ct, DType::F16, &device)?;
+        assert_eq!(estimate.quantized_embeddings, !device.is_metal());
+        let expected = match device.is_metal() {
+            true => q4k_bytes + 2_101_346_304,
+            false => q4k_bytes,
+        };
+        assert_eq!(estimate.total_bytes(0), expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn negotiate() -> Result<()> {
+    let kv = config(32, 8, 128, DType::F16);
+    let context = ContextBudget::negotiate(&kv, 32_768, Some(GIB))?;
+    assert_eq!(context.max_supported, Some(8192));
+    assert_eq!(context.effective, 8192);
+    assert_eq!(context.kv_bytes, GIB);
+    assert!(context.is_capped());
+    assert_eq!(
+        context.to_string(),
+        "context length capped from 32768 to 8192: the kv cache takes 128.00KiB per position and its budget is 1.00GiB"
+    );
+
+    let context = ContextBudget::negotiate(&kv, 4096, Some(GIB))?;
+    assert_eq!(context.effective, 4096);
+    assert_eq!(context.kv_bytes, 512 * MIB);
+    assert!(!context.is_capped());
+
+    let context = ContextBudget::negotiate(&kv, 4096, None)?;
+    assert_eq!(context.max_supported, None);
+    assert_eq!(context.effective, 4096);
+
+    assert!(ContextBudget::negotiate(&kv, 4096, Some(131_071)).is_err());
+    Ok(())
+}
+
+#[test]
+fn kv_cache_matches_estimate() -> Result<()> {
+    use candle_transformers::test_support::{self, tiny_test_model};
+
+    let mut model = tiny_test_model(1)?;
+    let input = Tensor::new(&[[1u32, 2, 3, 4, 5, 6, 7]], &Device::Cpu)?;
+    model.forward(&input, 0)?;
+    let model_config = test_support::tiny_test_config();
+    // The kv cache of the quantized models holds the f32 activations.
+    let kv = config(
+        test_support::N_LAYER,
+        model_config.n_kv_head,
+        model_config.head_dim,
+        DType::F32,
+    );
+    // 2 * 2 layers * 2 kv heads * 16 * 4 bytes per position.
+    assert_eq!(kv.bytes_per_token(), 512);
+    assert_eq!(model.kv_cache_bytes() as u64, kv.bytes(7));
+    Ok(())
+}
+
+#[test]
+fn prefill_plan() -> Result<()> {
+    use gguf_file::Value;
+
+    let dev = &Device::Cpu;
+    let embd = QTensor::quantize(&Tensor::zeros((48, 32), DType::F32, dev)?, GgmlDType::F32)?;
+    let ffn = QTensor::quantize(&Tensor::zeros((96, 32), DType::F32, dev)?, GgmlDType::F32)?;
+    let ct = gguf_content(
+        &[
+            ("llama.block_count", Value::U32(1)),
+            ("llama.embedding_length", Value::U32(32)),
+            ("llama.feed_forward_length", Value::U32(96)),
+            ("llama.attention.head_count", Value::U32(4)),
+            ("llama.attention.head_count_kv", Value::U32(2)),
+        ],
+        &[
+            ("token_embd.weight".to_string(), embd),
+            ("blk.0.ffn_up.weight".to_string(), ffn),
+        ],
+    )?;
+    let config = ActivationConfig::from_gguf(&ct)?;
+    assert_eq!(config.vocab_size, 48);
+    assert_eq!(config.feed_forward_length, 96);
+    assert_eq!(
+        (config.n_head, config.n_kv_head, config.head_dim),
+        (4, 2, 8)
+    );
+    assert_eq!(config.max_weight_elems, 96 * 32);
+
+    // llama-3-8b, the scores of a 8192 tokens prompt take 32 * 8192^2 * 4 bytes = 8GiB and each
+    // of the 4 copies is counted.
+    let llama3_8b = ActivationConfig {
+        embedding_length: 4096,
+        feed_forward_length: 14336,
+        n_head: 32,
+        n_kv_head: 8,
+        head_dim: 128,
+        vocab_size: 128_256,
+        max_weight_elems: 128_256 * 4096,
+    };
+    let single_shot = llama3_8b.prefill_bytes(8192, 0);
+    assert!(single_shot > 32 * GIB && single_shot < 40 * GIB);
+    // A single token is dominated by the copies of the cached keys and values.
+    assert!(llama3_8b.prefill_bytes(1, 8191) < GIB);
+
+    let plan = llama3_8b.plan_prefill(8192, None);
+    assert_eq!(plan.strategy, PrefillStrategy::SingleShot);
+    let plan = llama3_8b.plan_prefill(8192, Some(64 * GIB));
+    assert_eq!(plan.strategy, PrefillStrategy::SingleShot);
+    let plan = llama3_8b.plan_prefill(8192, Some(8 * GIB));
+    let chunk = match plan.strategy {
+        PrefillStrategy::Chunked { chunk_size } => chunk_size,
+        strategy => panic!("unexpected {strategy}"),
+    };
+    // The largest chunk whose last pass fits.
+    assert!(llama3_8b.prefill_bytes(chunk, 8192 - chunk) <= 8 * GIB);
+    assert!(llama3_8b.prefill_bytes(chunk + 1, 8191 - chunk) > 8 * GIB);
+    assert_eq!(plan.strategy.chunk_size(8192), chunk);
+    let plan = llama3_8b.plan_prefill(8192, Some(MIB));
+    assert_eq!(plan.strategy, PrefillStrategy::TokenByToken);
+    assert_eq!(
+        plan.to_string(),
+        format!(
+            "prefill token by token: a single pass over the 8192 prompt tokens needs about {:.2}GiB and 1.00MiB is free",
+            single_shot as f64 / GIB as f64
+        )
+    );
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::models::quantized_llama::{LayerWeights, ModelConfig, ModelWeights, Norm};
+use candle_transformers::test_support::{self, tiny_test_model, tiny_test_model_with};
+
+// The seed used to generate `tests/fixtures/tiny-llama.gguf`.
+const FIXTURE_SEED: u64 = 20240607;
+
+fn tokens(len: usize) -> Result<Tensor> {
+    let tokens = (0..len as u32).map(|i| (i * 7 + 3) % test_support::VOCAB_SIZE as u32);
+    Tensor::new(tokens.collect::<Vec<_>>(), &Device::Cpu)?.unsqueeze(0)
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+#[test]
+fn same_as_fixture() -> Result<()> {
+    let path =
+        std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tiny-llama.gguf");
+    assert_eq!(
+        test_support::tiny_test_gguf(FIXTURE_SEED)?,
+        std::fs::read(&path)?
+    );
+    let mut file = std::fs::File::open(path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let mut from_gguf = ModelWeights::from_gguf(content, &mut file, &Device::Cpu)?;
+    let mut from_layers = tiny_test_model(FIXTURE_SEED)?;
+    assert_eq!(from_layers.max_seq_len(), from_gguf.max_seq_len());
+    let input = tokens(9)?;
+    let expected = from_gguf.forward_all(&input, 0)?;
+    let logits = from_layers.forward_all(&input, 0)?;
+    assert_eq!(logits.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    // Decoding from the kv cache.
+    let next = tokens(10)?.narrow(1, 9, 1)?;
+    let expected = from_gguf.forward(&next, 9)?;
+    let logits = from_layers.forward(&next, 9)?;
+    assert_eq!(logits.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+
+    let mut other = tiny_test_model(FIXTURE_SEED + 1)?;
+    let logits = other.forward_all(&input, 0)?;
+    assert!(max_diff(&logits, &from_gguf.forward_all(&input, 0)?)? > 0.);
+    Ok(())
+}
+
+#[test]
+fn mixed_dtypes() -> Result<()> {
+    // The first layer in f16, the rest in q8_0. The weight scales round differently in both
+    // formats so the logits are close but not identical.
+    let mut q8 = tiny_test_model(3)?;
+    let mut mixed = tiny_test_model_with(3, |name, dtype| {
+        if name.starts_with("blk.0.") {
+            GgmlDType::F16
+        } else {
+            dtype
+        }
+    })?;
+    let input = tokens(7)?;
+    let expected = q8.forward_all(&input, 0)?;
+    let logits = mixed.forward_all(&input, 0)?;
+    assert_eq!(logits.dims(), [1, 7, test_support::VOCAB_SIZE]);
+    let diff = max_diff(&logits, &expected)?;
+    assert!(diff > 0. && diff < 0.1, "{diff}");
+    assert_eq!(
+        logits.argmax(2)?.to_vec2::<u32>()?,
+        expected.argmax(2)?.to_vec2::<u32>()?
+    );
+
+    let report = mixed.kernel_report();
+    let dtype = |name: &str| report.iter().find(|k| k.name == name).map(|k| k.dtype);
+    // f16 weights are dequantized when building the matmuls.
+    assert_ne!(dtype("blk.0.attn_q.weight"), Some(GgmlDType::Q8_0));
+    assert_eq!(dtype("blk.1.attn_q.weight"), Some(GgmlDType::Q8_0));
+    Ok(())
+}
+
+#[test]
+fn builder() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut seed = 11;
+    let mut w = |shape| test_support::ternary_weight(&mut seed, shape, GgmlDType::Q8_0);
+    let norm = || -> Result<Norm> {
+        let weight = QTensor::quantize(&Tensor::ones(32, DType::F32, dev)?, GgmlDType::F32)?;
+        let bias = QTensor::quantize(&Tensor::zeros(32, DType::F32, dev)?, GgmlDType::F32)?;
+        Norm::layer(&weight, &bias, 1e-5)
+    };
+
+    // The layer parts are required.
+    assert!(LayerWeights::builder().build().is_err());
+    let no_residual = LayerWeights::builder()
+        .fused_attention(w((96, 32))?, w((32, 32))?)?
+        .attention_norm(norm()?)
+        .gelu_mlp(w((64, 32))?, w((32, 64))?)?
+        .build();
+    assert!(no_residual.is_err());
+
+    // A falcon style model with a fused qkv projection and a parallel residual.
+    let layer = LayerWeights::builder()
+        .fused_attention(w((96, 32))?, w((32, 32))?)?
+        .attention_norm(norm()?)
+        .gelu_mlp(w((64, 32))?, w((32, 64))?)?
+        .parallel_residual(None)
+        .build()?;
+    let config = ModelConfig {
+        n_head: 4,
+        n_kv_head: 4,
+        head_dim: 8,
+        embedding_length: 32,
+        rope_dim: 8,
+        rope_freq_base: 10000.,
+        neox_rope: true,
+        max_seq_len: 16,
+    };
+    let embed = std::sync::Arc::new(w((48, 32))?);
+    let mut model = ModelWeights::from_layers(config, vec![layer], embed.clone(), norm()?, embed)?;
+    assert_eq!(model.max_seq_len(), 16);
+    let input = Tensor::new(&[[1u32, 2, 3]], dev)?;
+    assert_eq!(model.forward(&input, 0)?.dims(), [1, 48]);
+    assert_eq!(model.kv_cache_len(), 3);
+    assert!(model.forward(&input, 14).is_err());
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use candle::{op_registry, Device, Result, Tensor};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/tiny-llama.gguf"
+);
+
+type Fields = BTreeMap<String, String>;
+
+// Records the fields of the custom op spans.
+#[derive(Clone, Default)]
+struct CustomOpSpans(Arc<Mutex<Vec<Fields>>>);
+
+struct FieldsVisitor<'a>(&'a mut Fields);
+
+impl Visit for FieldsVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{value:?}"));
+    }
+}
+
+impl<S: tracing::Subscriber> Layer<S> for CustomOpSpans {
+    fn on_new_span(&self, attrs: &Attributes<'_>, _id: &Id, _ctx: Context<'_, S>) {
+        if attrs.metadata().name() == "custom-op" {
+            let mut fields = Fields::new();
+            attrs.record(&mut FieldsVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields)
+        }
+    }
+}
+
+#[test]
+fn registry() -> Result<()> {
+    use op_registry::CustomOpDescriptor;
+
+    candle_transformers::register_custom_ops()?;
+    // Registering twice is fine.
+    candle_transformers::register_custom_ops()?;
+    let names = op_registry::list()
+        .iter()
+        .map(|d| d.name)
+        .collect::<Vec<_>>();
+    for name in [
+        "qmatmul",
+        "rms-norm",
+        "rotary-emb-int",
+        "softmax-last-dim",
+        "metal-sdpa",
+    ] {
+        assert!(names.contains(&name), "{name} {names:?}");
+    }
+    let rms_norm = op_registry::get("rms-norm").unwrap();
+    assert_eq!(rms_norm.params, "eps: f32");
+    assert_eq!(rms_norm.source, "candle_nn::ops");
+    let conflict = CustomOpDescriptor {
+        name: "rms-norm",
+        params: "eps: f64",
+        source: "my_kernels",
+    };
+    assert!(op_registry::register(conflict).is_err());
+    let fused = CustomOpDescriptor {
+        name: "fused-rms-norm",
+        params: "eps: f32",
+        source: "my_kernels",
+    };
+    op_registry::register(fused)?;
+    assert_eq!(op_registry::get("fused-rms-norm"), Some(fused));
+    Ok(())
+}
+
+#[test]
+fn spans_of_a_forward() -> Result<()> {
+    use candle::quantized::gguf_file;
+    use candle_transformers::models::quantized_llama::ModelWeights;
+
+    candle_transformers::register_custom_ops()?;
+    let mut file = std::fs::File::open(FIXTURE)?;
+    let ct = gguf_file::Content::read(&mut file)?;
+    let mut model = ModelWeights::from_gguf(ct, &mut file, &Device::Cpu)?;
+    let input = Tensor::new(&[[1u32, 2, 3, 4, 5]], &Device::Cpu)?;
+
+    let spans = CustomOpSpans::default();
+    let subscriber = tracing_subscriber::registry().with(spans.clone());
+    tracing::subscriber::with_default(subscriber, || model.forward(&input, 0))?;
+
+    let spans = spans.0.lock().unwrap();
+    let mut ops = BTreeMap::new();
+    for fields in spans.iter() {
+        let op = &fields["op"];
+        assert!(op_registry::get(op).is_some(), "{op} is not registered");
+        ops.entry(op.as_str()).or_insert_with(|| fields.clone());
+    }
+    let ops_names = ops.keys().copied().collect::<Vec<_>>();
+    assert_eq!(
+        ops_names,
+        ["qmatmul", "rms-norm", "rotary-emb-int", "softmax-last-dim"]
+    );
+    // The first rms norm is the one of the attention of the first layer.
+    assert_eq!(ops["rms-norm"]["params"], "eps=1e-5");
+    assert_eq!(ops["rms-norm"]["shapes"], "[1, 5, 64] [64]");
+    assert_eq!(ops["qmatmul"]["params"], "dtype=Q8_0");
+    assert_eq!(ops["softmax-last-dim"]["params"], "");
+    Ok(())
+}
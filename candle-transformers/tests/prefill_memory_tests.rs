@@ -0,0 +1,88 @@
+// Compares the activation estimates of `kv_budget` with the peak allocations of the fixture
+// model. This test binary counts all its allocations, so it has a single test.
+use candle::quantized::gguf_file;
+use candle::{Device, Result, Tensor};
+use candle_transformers::kv_budget::ActivationConfig;
+use candle_transformers::models::quantized_llama::ModelWeights;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/tests/fixtures/tiny-llama.gguf"
+);
+
+struct PeakAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+            PEAK.fetch_max(current, Ordering::SeqCst);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::SeqCst);
+    }
+}
+
+#[global_allocator]
+static ALLOC: PeakAlloc = PeakAlloc;
+
+fn forward(model: &mut ModelWeights, len: usize, past: usize) -> Result<Tensor> {
+    let tokens = (0..len as u32).map(|t| t % 200).collect::<Vec<_>>();
+    let input = Tensor::new(tokens.as_slice(), &Device::Cpu)?.unsqueeze(0)?;
+    model.forward(&input, past)
+}
+
+// The peak allocation of a forward pass on `seq_len` tokens after `past` cached ones.
+fn measured_peak(seq_len: usize, past: usize) -> Result<u64> {
+    let mut file = std::fs::File::open(FIXTURE)?;
+    let ct = gguf_file::Content::read(&mut file)?;
+    let mut model = ModelWeights::from_gguf(ct, &mut file, &Device::Cpu)?;
+    if past > 0 {
+        forward(&mut model, past, 0)?;
+    }
+    let base = CURRENT.load(Ordering::SeqCst);
+    PEAK.store(base, Ordering::SeqCst);
+    let logits = forward(&mut model, seq_len, past)?;
+    let peak = PEAK.load(Ordering::SeqCst) - base;
+    drop(logits);
+    Ok(peak as u64)
+}
+
+#[test]
+fn prefill_peaks() -> Result<()> {
+    let mut file = std::fs::File::open(FIXTURE)?;
+    let ct = gguf_file::Content::read(&mut file)?;
+    let config = ActivationConfig::from_gguf(&ct)?;
+    // The first forward pass allocates the thread pools and the caches of the process.
+    measured_peak(8, 0)?;
+    for (seq_len, past) in [(32, 0), (64, 0), (128, 0), (256, 0), (128, 128), (64, 192)] {
+        let measured = measured_peak(seq_len, past)?;
+        let estimate = config.prefill_bytes(seq_len, past);
+        println!("seq_len {seq_len} past {past}: measured {measured} estimate {estimate}");
+        assert!(
+            estimate >= measured,
+            "{seq_len} {past}: {estimate} < {measured}"
+        );
+        assert!(
+            estimate <= 2 * measured,
+            "{seq_len} {past}: {estimate} > 2 * {measured}"
+        );
+    }
+    // The chunk picked for a budget keeps the measured peak of its last chunk within it.
+    let budget = config.prefill_bytes(256, 0) / 3;
+    let plan = config.plan_prefill(256, Some(budget));
+    let chunk = plan.strategy.chunk_size(256);
+    assert!(chunk > 1 && chunk < 256, "{plan}");
+    assert!(measured_peak(chunk, 256 - chunk)? <= budget);
+    Ok(())
+}
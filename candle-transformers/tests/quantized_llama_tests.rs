@@ -0,0 +1,803 @@
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{DType, Device, IndexOp, Result, Tensor, D};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_transformers::test_support::{
+    self, gguf_bytes, ternary_weight, tiny_test_gguf, FFN_SIZE, HIDDEN_SIZE, N_HEAD, N_KV_HEAD,
+    N_LAYER, VOCAB_SIZE,
+};
+
+fn tiny_llama(seed: u64) -> Result<ModelWeights> {
+    ModelWeights::from_gguf_bytes(&tiny_test_gguf(seed)?, &Device::Cpu)
+}
+
+fn tokens(len: usize) -> Result<Tensor> {
+    let tokens = (0..len as u32).map(|i| (i * 7 + 3) % VOCAB_SIZE as u32);
+    Tensor::new(tokens.collect::<Vec<_>>(), &Device::Cpu)?.unsqueeze(0)
+}
+
+#[test]
+fn forward_all_chunked() -> Result<()> {
+    let mut model = tiny_llama(42)?;
+    let input = tokens(11)?;
+    let logits = model.forward_all(&input, 0)?;
+    assert_eq!(logits.dims(), [1, 11, VOCAB_SIZE]);
+    let last = model.forward(&input, 0)?;
+    assert_eq!(
+        last.to_vec2::<f32>()?,
+        logits.i((.., 10))?.to_vec2::<f32>()?
+    );
+
+    for chunk in [1, 3, 4, 11, 32] {
+        model.set_max_logits_chunk(Some(chunk));
+        let chunked = model.forward_all(&input, 0)?;
+        assert_eq!(chunked.to_vec3::<f32>()?, logits.to_vec3::<f32>()?);
+    }
+    model.set_max_logits_chunk(Some(0));
+    assert!(model.forward_all(&input, 0).is_err());
+    Ok(())
+}
+
+#[test]
+fn forward_nll_chunked() -> Result<()> {
+    let mut model = tiny_llama(1337)?;
+    let input = tokens(9)?;
+    let targets = tokens(10)?.narrow(1, 1, 9)?;
+    let logits = model.forward_all(&input, 0)?;
+    let log_sm = candle_nn::ops::log_softmax(&logits, D::Minus1)?;
+    let expected = log_sm
+        .gather(&targets.unsqueeze(2)?, 2)?
+        .squeeze(2)?
+        .neg()?
+        .to_vec2::<f32>()?;
+    for chunk in [None, Some(2), Some(4)] {
+        model.set_max_logits_chunk(chunk);
+        let nll = model.forward_nll(&input, &targets, 0)?;
+        assert_eq!(nll.to_vec2::<f32>()?, expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn kv_cache_offset_prompt() -> Result<()> {
+    let mut model = tiny_llama(7)?;
+    let input = tokens(12)?;
+    let expected = model.forward_all(&input, 0)?.i((.., 8..))?;
+    model.truncate_kv_cache(8)?;
+    assert_eq!(model.kv_cache_len(), 8);
+    let logits = model.forward_all(&input.narrow(1, 8, 4)?, 8)?;
+    let diff = (logits - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    model.clear_kv_cache();
+    assert_eq!(model.kv_cache_len(), 0);
+    Ok(())
+}
+
+#[test]
+fn regenerate_turn() -> Result<()> {
+    use candle_transformers::generation::{
+        LogitsProcessor, Sampling, StopConditions, TextGeneration,
+    };
+
+    let sampling = Sampling::All { temperature: 1.0 };
+    let stop = StopConditions::default();
+    let lp = LogitsProcessor::from_sampling(299792458, sampling.clone());
+    let mut generation = TextGeneration::new(tiny_llama(3)?, lp, &Device::Cpu);
+    generation.push_prompt(&[1, 2, 3, 4])?;
+    let first = generation.generate(5, &stop, |_| Ok(()))?;
+    let history = generation.tokens();
+
+    let checkpoint = generation.push_prompt(&[5, 6])?;
+    assert_eq!(checkpoint.kv_len(), history.len() + 2);
+    let mut streamed = vec![];
+    let answer = generation.generate(8, &stop, |t| {
+        streamed.push(t);
+        Ok(())
+    })?;
+    assert_eq!(streamed, answer);
+
+    // Same sampler state, same answer.
+    generation.rollback_to(&checkpoint)?;
+    assert_eq!(generation.tokens().len(), checkpoint.kv_len());
+    assert_eq!(generation.generate(8, &stop, |_| Ok(()))?, answer);
+
+    // Checkpoints past the current position cannot be restored.
+    let past = generation.push_prompt(&[7])?;
+    generation.rollback_to(&checkpoint)?;
+    assert!(generation.rollback_to(&past).is_err());
+
+    // A different seed gives a different answer on top of the same history.
+    generation.set_logits_processor(LogitsProcessor::from_sampling(42, sampling));
+    let regen = generation.generate(8, &stop, |_| Ok(()))?;
+    assert_ne!(regen, answer);
+    assert_eq!(generation.tokens()[..history.len()], history);
+    assert_eq!(history[4..], first);
+
+    // The kv cache matches a fresh run over the whole conversation.
+    let tokens = generation.tokens();
+    let (last, prefix) = tokens.split_last().unwrap();
+    let logits = generation.model_mut().forward(
+        &Tensor::new(&[*last], &Device::Cpu)?.unsqueeze(0)?,
+        prefix.len(),
+    )?;
+    let mut fresh = tiny_llama(3)?;
+    let expected = fresh.forward(
+        &Tensor::new(tokens.as_slice(), &Device::Cpu)?.unsqueeze(0)?,
+        0,
+    )?;
+    let diff = (logits - expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+
+    Ok(())
+}
+
+#[test]
+fn manifest_snapshot() -> Result<()> {
+    use candle_transformers::generation::GenerationParams;
+    use candle_transformers::manifest::{self, BuildInfo, CheckpointInfo};
+
+    let bytes = tiny_test_gguf(42)?;
+    let content = gguf_file::Content::read(&mut std::io::Cursor::new(&bytes))?;
+    let model = CheckpointInfo {
+        path: Some("tiny.gguf".to_string()),
+        file_size: bytes.len() as u64,
+        sha256: "0".repeat(64),
+        device: manifest::device_description(&Device::Cpu),
+    };
+    let build = BuildInfo {
+        version: "0.9.1".to_string(),
+        features: vec![],
+        git_hash: None,
+    };
+    let params = GenerationParams {
+        seed: 42,
+        max_tokens: 16,
+        ..Default::default()
+    };
+    let manifest = manifest::build(&content, &model, &build, &params);
+    let json = serde_json::to_value(&manifest).map_err(candle::Error::wrap)?;
+    let expected = serde_json::json!({
+        "model": {
+            "path": "tiny.gguf",
+            "file_size": bytes.len(),
+            "sha256": "0".repeat(64),
+            "device": "cpu",
+            "gguf_version": 2,
+            "metadata": {"general.architecture": "llama", "general.name": "tiny-llama"},
+            "tensor_count": 21,
+            "parameter_count": 106816,
+            "tensor_dtypes": {"F32": 5, "Q8_0": 16},
+        },
+        "build": {"version": "0.9.1", "features": [], "git_hash": null},
+        "generation": {
+            "seed": 42,
+            "max_tokens": 16,
+            "temperature": 0.8,
+            "top_k": null,
+            "top_p": null,
+            "repeat_penalty": 1.100000023841858,
+            "repeat_last_n": 64,
+            "stop_token_patterns": [],
+            "best_of": 1,
+            "length_penalty": 1.0,
+        },
+    });
+    assert_eq!(json, expected);
+    let parsed: manifest::Manifest = serde_json::from_value(json).map_err(candle::Error::wrap)?;
+    assert_eq!(parsed, manifest);
+    Ok(())
+}
+
+#[test]
+fn from_gguf_bytes() -> Result<()> {
+    use candle_transformers::generation::compare::CompareConfig;
+
+    let bytes = tiny_test_gguf(5)?;
+    let mut model = ModelWeights::from_gguf_bytes(&bytes, &Device::Cpu)?;
+    let config = CompareConfig::greedy(8, None);
+    let run = config.run(&mut model, &[1, 2, 3], &Device::Cpu)?;
+    assert_eq!(run.tokens.len(), 8);
+    assert!(run.tokens.iter().all(|&t| (t as usize) < VOCAB_SIZE));
+
+    let mut reader = std::io::Cursor::new(bytes.as_slice());
+    let content = gguf_file::Content::read(&mut reader)?;
+    let mut model = ModelWeights::from_gguf(content, &mut reader, &Device::Cpu)?;
+    assert_eq!(
+        config.run(&mut model, &[1, 2, 3], &Device::Cpu)?.tokens,
+        run.tokens
+    );
+
+    assert!(ModelWeights::from_gguf_bytes(&bytes[..bytes.len() / 2], &Device::Cpu).is_err());
+    Ok(())
+}
+
+#[test]
+fn custom_output_head() -> Result<()> {
+    use candle_nn::{Module, VarBuilder, VarMap};
+
+    let mut model = tiny_llama(11)?;
+    let input = tokens(6)?;
+    let logits = model.forward(&input, 0)?;
+    assert_eq!(logits.dims(), [1, VOCAB_SIZE]);
+
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+    let head = candle_nn::linear(HIDDEN_SIZE, 3, vb.pp("head"))?;
+    model.set_output_head(Some(Box::new(head.clone())));
+    let out = model.forward(&input, 0)?;
+    assert_eq!(out.dims(), [1, 3]);
+    assert_eq!(model.forward_all(&input, 0)?.dims(), [1, 6, 3]);
+    let hidden = model.forward_hidden(&input, 0)?;
+    let expected = head.forward(&hidden.i((.., 5))?)?;
+    assert_eq!(out.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+
+    // The gradients flow into the head but stop at the frozen quantized backbone.
+    let loss = head.forward(&hidden)?.sqr()?.mean_all()?;
+    let grads = loss.backward()?;
+    for var in varmap.all_vars() {
+        assert!(grads.get(&var).is_some());
+    }
+    assert!(grads.get(&hidden).is_none());
+
+    // Restoring the original head.
+    model.set_output_head(None);
+    assert_eq!(
+        model.forward(&input, 0)?.to_vec2::<f32>()?,
+        logits.to_vec2::<f32>()?
+    );
+    let output = model.take_output().unwrap();
+    assert!(model.forward(&input, 0).is_err());
+    model.set_output(output);
+    assert_eq!(
+        model.forward(&input, 0)?.to_vec2::<f32>()?,
+        logits.to_vec2::<f32>()?
+    );
+    Ok(())
+}
+
+fn vector(seed: &mut u64, size: usize, offset: f32) -> Result<QTensor> {
+    let t = ternary_weight(seed, (1, size), GgmlDType::F32)?.dequantize(&Device::Cpu)?;
+    QTensor::quantize(&(t.squeeze(0)? + offset as f64)?, GgmlDType::F32)
+}
+
+// A tiny falcon model, with the two norms of falcon-40b and a non default rope base when
+// `falcon_40b` is set. The weights are kept in f32 so that the outputs can be compared against a
+// reference implementation.
+fn tiny_falcon_gguf(seed: u64, falcon_40b: bool) -> Result<Vec<u8>> {
+    use gguf_file::Value;
+
+    let mut seed = seed;
+    let f32 = GgmlDType::F32;
+    let head_dim = HIDDEN_SIZE / N_HEAD;
+    let qkv = HIDDEN_SIZE + 2 * N_KV_HEAD * head_dim;
+    let mut tensors = vec![
+        (
+            "token_embd.weight".to_string(),
+            ternary_weight(&mut seed, (VOCAB_SIZE, HIDDEN_SIZE), f32)?,
+        ),
+        (
+            "output_norm.weight".to_string(),
+            vector(&mut seed, HIDDEN_SIZE, 1.)?,
+        ),
+        (
+            "output_norm.bias".to_string(),
+            vector(&mut seed, HIDDEN_SIZE, 0.)?,
+        ),
+    ];
+    for i in 0..N_LAYER {
+        for (name, shape) in [
+            ("attn_qkv", (qkv, HIDDEN_SIZE)),
+            ("attn_output", (HIDDEN_SIZE, HIDDEN_SIZE)),
+            ("ffn_up", (FFN_SIZE, HIDDEN_SIZE)),
+            ("ffn_down", (HIDDEN_SIZE, FFN_SIZE)),
+        ] {
+            tensors.push((
+                format!("blk.{i}.{name}.weight"),
+                ternary_weight(&mut seed, shape, f32)?,
+            ))
+        }
+        let norms: &[&str] = if falcon_40b {
+            &["attn_norm", "attn_norm_2"]
+        } else {
+            &["attn_norm"]
+        };
+        for norm in norms {
+            let w = vector(&mut seed, HIDDEN_SIZE, 1.)?;
+            let b = vector(&mut seed, HIDDEN_SIZE, 0.)?;
+            tensors.push((format!("blk.{i}.{norm}.weight"), w));
+            tensors.push((format!("blk.{i}.{norm}.bias"), b));
+        }
+    }
+    let mut metadata = vec![
+        ("general.architecture", Value::String("falcon".to_string())),
+        ("general.name", Value::String("tiny".to_string())),
+        ("falcon.attention.head_count", Value::U32(N_HEAD as u32)),
+        (
+            "falcon.attention.head_count_kv",
+            Value::U32(N_KV_HEAD as u32),
+        ),
+        ("falcon.block_count", Value::U32(N_LAYER as u32)),
+        ("falcon.embedding_length", Value::U32(HIDDEN_SIZE as u32)),
+        ("falcon.attention.layer_norm_epsilon", Value::F32(1e-5)),
+    ];
+    if falcon_40b {
+        metadata.push(("falcon.rope.freq_base", Value::F32(1000.)))
+    }
+    gguf_bytes(&metadata, &tensors)
+}
+
+// A straightforward falcon forward pass on the whole sequence, returning the last position. This
+// follows `llm_build_falcon` in llama.cpp.
+fn falcon_reference(gguf: &[u8], tokens: &[u32]) -> Result<Tensor> {
+    let dev = &Device::Cpu;
+    let mut reader = std::io::Cursor::new(gguf);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let freq_base = match ct.metadata.get("falcon.rope.freq_base") {
+        Some(v) => v.to_f32()?,
+        None => 10000.,
+    };
+    let mut get = |name: &str| ct.tensor(&mut reader, name, dev)?.dequantize(dev);
+    let layer_norm = |xs: &Tensor, w: &Tensor, b: &Tensor| -> Result<Tensor> {
+        let xs = xs.broadcast_sub(&xs.mean_keepdim(D::Minus1)?)?;
+        let var = xs.sqr()?.mean_keepdim(D::Minus1)?;
+        let xs = xs.broadcast_div(&(var + 1e-5)?.sqrt()?)?;
+        xs.broadcast_mul(w)?.broadcast_add(b)
+    };
+    let gelu = |xs: &Tensor| -> Result<Tensor> {
+        let inner = ((xs + (xs.powf(3.)? * 0.044715)?)? * (2. / std::f64::consts::PI).sqrt())?;
+        (xs * 0.5)? * (inner.tanh()? + 1.)?
+    };
+    let seq_len = tokens.len();
+    let head_dim = HIDDEN_SIZE / N_HEAD;
+    let kv = N_KV_HEAD * head_dim;
+    let positions = Tensor::arange(0u32, seq_len as u32, dev)?.to_dtype(DType::F32)?;
+    let inv_freq = (0..head_dim / 2)
+        .map(|i| 1. / freq_base.powf(2. * i as f32 / head_dim as f32))
+        .collect::<Vec<_>>();
+    let inv_freq = Tensor::new(inv_freq, dev)?;
+    let freqs = positions
+        .unsqueeze(1)?
+        .broadcast_mul(&inv_freq.unsqueeze(0)?)?;
+    let (cos, sin) = (freqs.cos()?, freqs.sin()?);
+    let rope = |xs: &Tensor| -> Result<Tensor> {
+        let x1 = xs.narrow(D::Minus1, 0, head_dim / 2)?;
+        let x2 = xs.narrow(D::Minus1, head_dim / 2, head_dim / 2)?;
+        let r1 = (x1.broadcast_mul(&cos)? - x2.broadcast_mul(&sin)?)?;
+        let r2 = (x2.broadcast_mul(&cos)? + x1.broadcast_mul(&sin)?)?;
+        Tensor::cat(&[r1, r2], D::Minus1)
+    };
+    let mask = (0..seq_len)
+        .flat_map(|i| (0..seq_len).map(move |j| if j > i { f32::NEG_INFINITY } else { 0. }))
+        .collect::<Vec<_>>();
+    let mask = Tensor::from_vec(mask, (seq_len, seq_len), dev)?;
+
+    let embeddings = get("token_embd.weight")?;
+    let mut xs = embeddings.index_select(&Tensor::new(tokens, dev)?, 0)?;
+    for i in 0..N_LAYER {
+        let p = format!("blk.{i}");
+        let attn_norm = layer_norm(
+            &xs,
+            &get(&format!("{p}.attn_norm.weight"))?,
+            &get(&format!("{p}.attn_norm.bias"))?,
+        )?;
+        // falcon-40b normalizes the attention input with `attn_norm_2`, the MLP input is always
+        // `attn_norm`.
+        let attn_in = if ct
+            .tensor_infos
+            .contains_key(&format!("{p}.attn_norm_2.weight"))
+        {
+            layer_norm(
+                &xs,
+                &get(&format!("{p}.attn_norm_2.weight"))?,
+                &get(&format!("{p}.attn_norm_2.bias"))?,
+            )?
+        } else {
+            attn_norm.clone()
+        };
+        let qkv = attn_in.matmul(&get(&format!("{p}.attn_qkv.weight"))?.t()?)?;
+        let heads = |xs: Tensor, n: usize| -> Result<Tensor> {
+            xs.reshape((seq_len, n, head_dim))?.transpose(0, 1)
+        };
+        let q = rope(&heads(qkv.narrow(1, 0, HIDDEN_SIZE)?, N_HEAD)?)?;
+        let k = rope(&heads(qkv.narrow(1, HIDDEN_SIZE, kv)?, N_KV_HEAD)?)?;
+        let v = heads(qkv.narrow(1, HIDDEN_SIZE + kv, kv)?, N_KV_HEAD)?;
+        let mut ys = vec![];
+        for head in 0..N_HEAD {
+            let kv_head = head / (N_HEAD / N_KV_HEAD);
+            let att = q.get(head)?.matmul(&k.get(kv_head)?.t()?)?;
+            let att = ((att / (head_dim as f64).sqrt())? + &mask)?;
+            let att = candle_nn::ops::softmax_last_dim(&att)?;
+            ys.push(att.matmul(&v.get(kv_head)?)?)
+        }
+        let ys = Tensor::cat(&ys, 1)?;
+        let attn = ys.matmul(&get(&format!("{p}.attn_output.weight"))?.t()?)?;
+        let up = attn_norm.matmul(&get(&format!("{p}.ffn_up.weight"))?.t()?)?;
+        let mlp = gelu(&up)?.matmul(&get(&format!("{p}.ffn_down.weight"))?.t()?)?;
+        xs = ((xs + attn)? + mlp)?;
+    }
+    let xs = layer_norm(&xs, &get("output_norm.weight")?, &get("output_norm.bias")?)?;
+    xs.narrow(0, seq_len - 1, 1)?
+        .matmul(&embeddings.t()?)?
+        .squeeze(0)
+}
+
+#[test]
+fn falcon_parallel_residual() -> Result<()> {
+    let input = tokens(7)?;
+    let ids = input.squeeze(0)?.to_vec1::<u32>()?;
+    for falcon_40b in [false, true] {
+        let gguf = tiny_falcon_gguf(42, falcon_40b)?;
+        let expected = falcon_reference(&gguf, &ids)?;
+        let mut model = ModelWeights::from_gguf_bytes(&gguf, &Device::Cpu)?;
+        let logits = model.forward(&input, 0)?.squeeze(0)?;
+        let diff = (&logits - &expected)?.abs()?.max(0)?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "falcon_40b {falcon_40b}: max diff {diff}");
+
+        // Decoding the last token from the kv cache gives the same logits.
+        let mut model = ModelWeights::from_gguf_bytes(&gguf, &Device::Cpu)?;
+        model.forward(&input.narrow(1, 0, 6)?, 0)?;
+        let logits = model.forward(&input.narrow(1, 6, 1)?, 6)?.squeeze(0)?;
+        let diff = (&logits - &expected)?.abs()?.max(0)?.to_scalar::<f32>()?;
+        assert!(diff < 1e-4, "falcon_40b {falcon_40b}: max diff {diff}");
+    }
+    Ok(())
+}
+
+fn max_diff(a: &Tensor, b: &Tensor) -> Result<f32> {
+    (a - b)?.abs()?.flatten_all()?.max(0)?.to_scalar::<f32>()
+}
+
+#[test]
+fn fork_kv_cache() -> Result<()> {
+    let dev = &Device::Cpu;
+    let prompt = tokens(6)?;
+    let tails: [&[u32]; 3] = [&[5, 9, 2, 40], &[7, 7, 31, 1], &[60, 3, 3, 12]];
+    let mut model = tiny_llama(5)?;
+    model.forward(&prompt, 0)?;
+    let prompt_bytes = model.kv_cache_bytes();
+    model.fork_kv_cache(tails.len())?;
+    assert_eq!(model.kv_forks(), Some(3));
+    assert!(model.forward(&tokens(1)?, 6).is_err());
+
+    // One token at a time, then the last two tokens in a single step.
+    let mut forked = vec![];
+    for pos in 0..2 {
+        let input = tails.iter().map(|t| t[pos]).collect::<Vec<_>>();
+        let input = Tensor::new(input, dev)?.unsqueeze(1)?;
+        forked.push(model.forward(&input, 6 + pos)?);
+    }
+    let input = tails.iter().map(|t| t[2..].to_vec()).collect::<Vec<_>>();
+    forked.push(model.forward(&Tensor::new(input, dev)?, 8)?);
+    assert_eq!(model.kv_cache_len(), 10);
+
+    // The prompt is stored once, each sequence only adds its own tail.
+    let per_position = prompt_bytes / 6;
+    assert_eq!(model.kv_cache_bytes(), prompt_bytes + 3 * 4 * per_position);
+    assert!(model.kv_cache_bytes() < 3 * 10 * per_position);
+
+    for (i, tail) in tails.iter().enumerate() {
+        let mut single = tiny_llama(5)?;
+        single.forward(&prompt, 0)?;
+        let mut expected = vec![];
+        for (pos, &token) in tail[..2].iter().enumerate() {
+            let input = Tensor::new(&[[token]], dev)?;
+            expected.push(single.forward(&input, 6 + pos)?);
+        }
+        expected.push(single.forward(&Tensor::new(&tail[2..], dev)?.unsqueeze(0)?, 8)?);
+        for (forked, expected) in forked.iter().zip(expected.iter()) {
+            let diff = max_diff(&forked.get(i)?, &expected.get(0)?)?;
+            assert!(diff < 1e-4, "sequence {i}: max diff {diff}");
+        }
+    }
+
+    // Truncating within the prompt copies it for each sequence.
+    model.truncate_kv_cache(4)?;
+    assert_eq!(model.kv_cache_len(), 4);
+    assert_eq!(model.kv_cache_bytes(), 3 * 4 * per_position);
+    let input = Tensor::new(&[[11u32], [12], [13]], dev)?;
+    let logits = model.forward(&input, 4)?;
+    for (i, token) in [11u32, 12, 13].into_iter().enumerate() {
+        let mut single = tiny_llama(5)?;
+        single.forward(&prompt.narrow(1, 0, 4)?, 0)?;
+        let expected = single.forward(&Tensor::new(&[[token]], dev)?, 4)?;
+        let diff = max_diff(&logits.get(i)?, &expected.get(0)?)?;
+        assert!(diff < 1e-4, "sequence {i}: max diff {diff}");
+    }
+
+    // Starting over from position 0 drops the fork.
+    model.forward(&prompt, 0)?;
+    assert_eq!(model.kv_forks(), None);
+    assert_eq!(model.kv_cache_bytes(), prompt_bytes);
+    Ok(())
+}
+
+#[test]
+fn generate_forked() -> Result<()> {
+    use candle_transformers::generation::{
+        generate_forked, LogitsProcessor, PenaltyState, SamplerSlot, Sampling, StopConditions,
+    };
+
+    let dev = &Device::Cpu;
+    let prompt = tokens(5)?.squeeze(0)?.to_vec1::<u32>()?;
+    let slot = |seed: u64| {
+        let sampling = Sampling::All { temperature: 1.0 };
+        let logits_processor = LogitsProcessor::from_sampling(seed, sampling);
+        let penalty = PenaltyState::new(1.1, 16);
+        SamplerSlot::new(logits_processor, penalty, StopConditions::default())
+    };
+    let mut model = tiny_llama(8)?;
+    let mut slots = (0..4).map(slot).collect::<Vec<_>>();
+    let generated = generate_forked(&mut model, &prompt, &mut slots, 6, dev)?;
+    assert_eq!(generated.len(), 4);
+
+    // Each completion is the one obtained by decoding the sequence on its own.
+    for (seed, generated) in generated.iter().enumerate() {
+        assert_eq!(generated.len(), 6);
+        let mut model = tiny_llama(8)?;
+        let mut slot = slot(seed as u64);
+        slot.push_tokens(&prompt);
+        let mut tokens = prompt.clone();
+        let mut index_pos = 0;
+        for _ in 0..6 {
+            let input = Tensor::new(&tokens[index_pos..], dev)?.unsqueeze(0)?;
+            let logits = model.forward(&input, index_pos)?.squeeze(0)?;
+            index_pos = tokens.len();
+            tokens.push(slot.sample(&logits)?.unwrap());
+        }
+        assert_eq!(&tokens[prompt.len()..], generated.as_slice());
+    }
+    assert!(generated.iter().any(|g| g != &generated[0]));
+    Ok(())
+}
+
+// The tiny model with its output head stored as a copy of the token embeddings.
+fn tiny_gguf_duplicated(seed: u64) -> Result<Vec<u8>> {
+    let bytes = tiny_test_gguf(seed)?;
+    let mut reader = std::io::Cursor::new(&bytes);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let mut tensors = vec![];
+    for name in ct.tensor_infos.keys() {
+        let source = if name == "output.weight" {
+            "token_embd.weight"
+        } else {
+            name.as_str()
+        };
+        tensors.push((name.clone(), ct.tensor(&mut reader, source, &Device::Cpu)?));
+    }
+    let metadata = ct.metadata.iter().map(|(k, v)| (k.as_str(), v.clone()));
+    gguf_bytes(&metadata.collect::<Vec<_>>(), &tensors)
+}
+
+#[test]
+fn dedup_tensors() -> Result<()> {
+    let bytes = tiny_gguf_duplicated(13)?;
+    let load = |dedup: bool| -> Result<ModelWeights> {
+        let mut reader = std::io::Cursor::new(&bytes);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        ModelWeights::from_gguf_with_dedup(ct, &mut reader, &Device::Cpu, dedup)
+    };
+    let embd_bytes = VOCAB_SIZE * HIDDEN_SIZE / 32 * GgmlDType::Q8_0.type_size();
+
+    let mut deduped = load(true)?;
+    let summary = *deduped.load_summary();
+    assert_eq!(summary.tensors, 3 + 9 * N_LAYER);
+    assert_eq!(summary.deduplicated, 1);
+    assert_eq!(summary.deduplicated_bytes, embd_bytes);
+
+    let mut plain = load(false)?;
+    assert_eq!(plain.load_summary().deduplicated, 0);
+    assert_eq!(plain.load_summary().bytes, summary.bytes);
+    let input = tokens(5)?;
+    assert_eq!(
+        deduped.forward(&input, 0)?.to_vec2::<f32>()?,
+        plain.forward(&input, 0)?.to_vec2::<f32>()?
+    );
+
+    // Tensors with the same shape but different data are not shared.
+    let mut reader = std::io::Cursor::new(tiny_test_gguf(13)?);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let model = ModelWeights::from_gguf(ct, &mut reader, &Device::Cpu)?;
+    assert_eq!(model.load_summary().deduplicated, 0);
+    assert_eq!(model.load_summary().bytes, summary.bytes);
+    Ok(())
+}
+
+#[test]
+fn layer_dtype_parsing() -> Result<()> {
+    use candle_transformers::layer_dtype::{LayerDTypeOverride, TensorSelector};
+
+    let overrides: LayerDTypeOverride =
+        "*:q4_0, 0-1:f16,30:F16,blk.*.ffn_down.weight:q6_k".parse()?;
+    assert_eq!(
+        overrides.rules(),
+        [
+            (TensorSelector::Glob("*".to_string()), GgmlDType::Q4_0),
+            (TensorSelector::Layers { first: 0, last: 1 }, GgmlDType::F16),
+            (
+                TensorSelector::Layers {
+                    first: 30,
+                    last: 30
+                },
+                GgmlDType::F16
+            ),
+            (
+                TensorSelector::Glob("blk.*.ffn_down.weight".to_string()),
+                GgmlDType::Q6K
+            ),
+        ]
+    );
+    // The last matching rule wins.
+    assert_eq!(
+        overrides.dtype_for("blk.1.attn_q.weight"),
+        Some(GgmlDType::F16)
+    );
+    assert_eq!(
+        overrides.dtype_for("blk.1.ffn_down.weight"),
+        Some(GgmlDType::Q6K)
+    );
+    assert_eq!(
+        overrides.dtype_for("blk.2.attn_q.weight"),
+        Some(GgmlDType::Q4_0)
+    );
+    assert_eq!(
+        overrides.dtype_for("blk.30.attn_q.weight"),
+        Some(GgmlDType::F16)
+    );
+    assert_eq!(overrides.dtype_for("output.weight"), Some(GgmlDType::Q4_0));
+    assert_eq!(LayerDTypeOverride::new().dtype_for("output.weight"), None);
+
+    let glob = |glob: &str, name: &str| TensorSelector::Glob(glob.to_string()).matches(name);
+    assert!(glob("blk.?.attn_*.weight", "blk.3.attn_q.weight"));
+    assert!(!glob("blk.?.attn_*.weight", "blk.13.attn_q.weight"));
+    assert!(glob("output.weight", "output.weight"));
+    assert!(!glob("output", "output.weight"));
+    let layers = TensorSelector::Layers { first: 2, last: 3 };
+    assert!(layers.matches("blk.3.ffn_up.weight"));
+    assert!(!layers.matches("blk.4.ffn_up.weight"));
+    assert!(!layers.matches("token_embd.weight"));
+
+    for invalid in ["0-1", "3-1:f16", "0-1:q3", ":f16", "1-2-3:f16"] {
+        assert!(invalid.parse::<LayerDTypeOverride>().is_err(), "{invalid}");
+    }
+    Ok(())
+}
+
+#[test]
+fn layer_dtype_override() -> Result<()> {
+    use candle_transformers::layer_dtype::LayerDTypeOverride;
+
+    let bytes = test_support::tiny_test_gguf_with(42, |_, _| GgmlDType::F32)?;
+    let load = |overrides: &str| -> Result<ModelWeights> {
+        let mut reader = std::io::Cursor::new(&bytes);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let overrides: LayerDTypeOverride = overrides.parse()?;
+        ModelWeights::from_gguf_with_overrides(ct, &mut reader, &Device::Cpu, true, &overrides)
+    };
+
+    let mut reference = load("")?;
+    assert_eq!(reference.load_summary().overridden, 0);
+    let mut q4 = load("*:q4_0")?;
+    assert_eq!(q4.load_summary().overridden, 2 + 7 * N_LAYER);
+    assert!(q4
+        .tensor_dtypes()
+        .iter()
+        .all(|(_, d)| *d == GgmlDType::Q4_0));
+    // Keep the first layer and the output head in a higher precision.
+    let mut mixed = load("*:q4_0,0:f16,output.weight:q8_0")?;
+    assert_eq!(mixed.load_summary().overridden, 2 + 7 * N_LAYER);
+    for (name, dtype) in mixed.tensor_dtypes() {
+        let expected = if name.starts_with("blk.0.") {
+            GgmlDType::F16
+        } else if name == "output.weight" {
+            GgmlDType::Q8_0
+        } else {
+            GgmlDType::Q4_0
+        };
+        assert_eq!(*dtype, expected, "{name}");
+    }
+
+    // The per-token negative log likelihoods, which give the perplexity, move closer to the ones
+    // of the f32 model.
+    let input = tokens(16)?;
+    let targets = tokens(17)?.narrow(1, 1, 16)?;
+    let reference = reference.forward_nll(&input, &targets, 0)?;
+    let nll_error = |model: &mut ModelWeights| -> Result<f32> {
+        let nll = model.forward_nll(&input, &targets, 0)?;
+        (nll - &reference)?.abs()?.mean_all()?.to_scalar::<f32>()
+    };
+    let q4 = nll_error(&mut q4)?;
+    let mixed = nll_error(&mut mixed)?;
+    assert!(mixed < q4, "q4: {q4} mixed: {mixed}");
+
+    assert!(load("*:q4k").is_err());
+    Ok(())
+}
+
+#[test]
+fn kernel_report() -> Result<()> {
+    use candle::quantized::{KernelSelection, QMatMulKernel};
+
+    let mut model = tiny_llama(42)?;
+    let report = model.kernel_report();
+    assert_eq!(report.len(), 7 * N_LAYER + 1);
+    assert!(report
+        .iter()
+        .all(|w| w.kernels == KernelSelection::default()));
+    assert_eq!(report[0].name, "blk.0.attn_q.weight");
+    assert_eq!(report[0].shape.dims(), [HIDDEN_SIZE, HIDDEN_SIZE]);
+    assert_eq!(report[0].dtype, GgmlDType::Q8_0);
+
+    // Processing the prompt uses batched matmuls, except for the output head which only runs
+    // on the last position.
+    model.forward(&tokens(4)?, 0)?;
+    let vec_dot = Some(QMatMulKernel::CpuVecDot);
+    for w in model.kernel_report() {
+        let expected = if w.name == "output.weight" {
+            KernelSelection {
+                matvec: vec_dot,
+                batched: None,
+            }
+        } else {
+            KernelSelection {
+                matvec: None,
+                batched: vec_dot,
+            }
+        };
+        assert_eq!(w.kernels, expected, "{}", w.name);
+    }
+    model.forward(&tokens(1)?, 4)?;
+    assert!(model
+        .kernel_report()
+        .iter()
+        .all(|w| w.kernels.matvec == vec_dot));
+
+    // The f32 weights are dequantized when loading.
+    let mut model = ModelWeights::from_gguf_bytes(
+        &test_support::tiny_test_gguf_with(42, |_, _| GgmlDType::F32)?,
+        &Device::Cpu,
+    )?;
+    model.forward(&tokens(4)?, 0)?;
+    for w in model.kernel_report() {
+        assert_eq!(w.dtype, GgmlDType::F32);
+        assert_eq!(w.kernels.batched, Some(QMatMulKernel::Dequantized));
+    }
+    Ok(())
+}
+
+#[test]
+fn quantized_embeddings() -> Result<()> {
+    use candle_transformers::layer_dtype::LayerDTypeOverride;
+
+    let bytes = tiny_test_gguf(21)?;
+    let load = |quantized: Option<bool>| -> Result<ModelWeights> {
+        let mut reader = std::io::Cursor::new(&bytes);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let overrides = LayerDTypeOverride::default();
+        let device = &Device::Cpu;
+        ModelWeights::from_gguf_with_embeddings(
+            ct,
+            &mut reader,
+            device,
+            true,
+            &overrides,
+            quantized,
+        )
+    };
+    let embd_bytes = VOCAB_SIZE * HIDDEN_SIZE / 32 * GgmlDType::Q8_0.type_size();
+
+    // The tiny table is below the threshold.
+    let mut dense = load(None)?;
+    assert!(!dense.quantized_embeddings());
+    assert_eq!(dense.embeddings_bytes(), VOCAB_SIZE * HIDDEN_SIZE * 4);
+    let mut quantized = load(Some(true))?;
+    assert!(quantized.quantized_embeddings());
+    assert_eq!(quantized.embeddings_bytes(), embd_bytes);
+
+    // The rows are dequantized with the same kernel, so the outputs are identical.
+    for (input, index_pos) in [(tokens(6)?, 0), (tokens(1)?, 6)] {
+        assert_eq!(
+            dense.forward(&input, index_pos)?.to_vec2::<f32>()?,
+            quantized.forward(&input, index_pos)?.to_vec2::<f32>()?
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,62 @@
+use candle::quantized::GgmlDType;
+use candle::{Device, Result};
+use candle_transformers::self_test;
+
+const DTYPES: [GgmlDType; 9] = [
+    GgmlDType::F32,
+    GgmlDType::F16,
+    GgmlDType::Q4_0,
+    GgmlDType::Q8_0,
+    GgmlDType::Q2K,
+    GgmlDType::Q3K,
+    GgmlDType::Q4K,
+    GgmlDType::Q5K,
+    GgmlDType::Q6K,
+];
+
+#[test]
+fn cpu_passes() -> Result<()> {
+    let report = self_test::run(&Device::Cpu, &DTYPES)?;
+    assert!(report.passed(), "{report}");
+    // One quantized matmul per dtype, duplicates are checked once.
+    let report = self_test::run(&Device::Cpu, &[GgmlDType::Q4K, GgmlDType::Q4K])?;
+    let names = report
+        .checks
+        .iter()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names[..5],
+        ["qmatmul Q4K", "softmax", "rms norm", "rope", "rope neox"]
+    );
+    // The name of the gemm check tells whether the reduced precision is enabled.
+    assert!(names[5].starts_with("f16 gemm"), "{names:?}");
+    assert_eq!(names.len(), 6);
+    Ok(())
+}
+
+#[test]
+fn corrupted_reference_fails() -> Result<()> {
+    let report =
+        self_test::run_with_reference(&Device::Cpu, &DTYPES, |name, reference| match name {
+            "softmax" => reference * 1.5,
+            "qmatmul Q4K" => reference * 1.1,
+            _ => Ok(reference),
+        })?;
+    assert!(!report.passed());
+    let failures = report
+        .failures()
+        .map(|c| c.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(failures, ["qmatmul Q4K", "softmax"]);
+    for check in report.failures() {
+        assert!(check.max_diff > check.tolerance, "{check}");
+        assert_eq!(check.error, None);
+    }
+    assert!(
+        report.to_string().contains("2 of 14 checks failed"),
+        "{report}"
+    );
+    assert!(report.to_string().contains("FAIL softmax"), "{report}");
+    Ok(())
+}
@@ -0,0 +1,462 @@
+// End-to-end tests on the tiny llama fixture, see `examples/tiny_llama_fixture.rs` to regenerate
+// the fixture and the goldens.
+use candle::quantized::{gguf_file, GgmlDType};
+use candle::{Device, Result, Tensor};
+use candle_transformers::generation::{
+    ContextOverflow, LogitsProcessor, Sampling, StopConditions, StopCriteria, StopReason,
+    TextGeneration,
+};
+use candle_transformers::models::quantized_llama::{ModelWeights, MAX_SEQ_LEN};
+use candle_transformers::test_support::{gguf_bytes, gguf_content};
+use candle_transformers::vocab_pruning::{prune_vocab, VocabRemap};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+struct Golden {
+    text: String,
+    prompt_tokens: Vec<u32>,
+    tokens: Vec<u32>,
+}
+
+fn golden(name: &str) -> Golden {
+    let goldens = std::fs::read_to_string(format!("{FIXTURES}/tiny-llama-goldens.json")).unwrap();
+    let goldens: serde_json::Value = serde_json::from_str(&goldens).unwrap();
+    let golden = &goldens[name];
+    let ids = |v: &serde_json::Value| -> Vec<u32> {
+        let ids = v.as_array().unwrap().iter();
+        ids.map(|v| v.as_u64().unwrap() as u32).collect()
+    };
+    Golden {
+        text: golden["text"].as_str().unwrap().to_string(),
+        prompt_tokens: ids(&golden["prompt_tokens"]),
+        tokens: ids(&golden["tokens"]),
+    }
+}
+
+fn tokenizer() -> tokenizers::Tokenizer {
+    tokenizers::Tokenizer::from_file(format!("{FIXTURES}/tiny-llama-tokenizer.json")).unwrap()
+}
+
+fn encode(text: &str) -> Vec<u32> {
+    tokenizer().encode(text, true).unwrap().get_ids().to_vec()
+}
+
+fn load_model() -> Result<ModelWeights> {
+    let path = format!("{FIXTURES}/tiny-llama.gguf");
+    let mut file = std::fs::File::open(&path)?;
+    let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(&path))?;
+    ModelWeights::from_gguf(content, &mut file, &Device::Cpu)
+}
+
+fn greedy(model: ModelWeights) -> TextGeneration<ModelWeights> {
+    let logits_processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    TextGeneration::new(model, logits_processor, &Device::Cpu)
+}
+
+#[test]
+fn fixture_content() -> Result<()> {
+    let path = format!("{FIXTURES}/tiny-llama.gguf");
+    assert!(std::fs::metadata(&path)?.len() < 1 << 20);
+    let mut file = std::fs::File::open(&path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let md = |k: &str| content.metadata[k].to_u32();
+    assert_eq!(md("llama.block_count")?, 2);
+    assert_eq!(md("llama.embedding_length")?, 64);
+    assert_eq!(
+        content.tensor_infos["token_embd.weight"].shape.dims(),
+        [256, 64]
+    );
+    assert_eq!(tokenizer().get_vocab_size(true), 256);
+    Ok(())
+}
+
+// Read, load, forward, and sample without going through the generation helpers.
+#[test]
+fn greedy_pipeline() -> Result<()> {
+    let golden = golden("prompt");
+    assert_eq!(encode(&golden.text), golden.prompt_tokens);
+    let mut model = load_model()?;
+    let mut logits_processor = LogitsProcessor::from_sampling(0, Sampling::ArgMax);
+    let mut tokens = golden.prompt_tokens.clone();
+    let mut index_pos = 0;
+    for _ in 0..golden.tokens.len() {
+        let input = Tensor::new(&tokens[index_pos..], &Device::Cpu)?.unsqueeze(0)?;
+        let logits = model.forward(&input, index_pos)?.squeeze(0)?;
+        index_pos = tokens.len();
+        tokens.push(logits_processor.sample(&logits)?);
+    }
+    assert_eq!(&tokens[golden.prompt_tokens.len()..], golden.tokens);
+    Ok(())
+}
+
+#[test]
+fn greedy_text_generation() -> Result<()> {
+    let golden = golden("prompt");
+    let mut generation = greedy(load_model()?);
+    generation.push_prompt(&golden.prompt_tokens)?;
+    let stop = StopConditions::default();
+    let mut streamed = vec![];
+    let tokens = generation.generate(golden.tokens.len(), &stop, |t| {
+        streamed.push(t);
+        Ok(())
+    })?;
+    assert_eq!(tokens, golden.tokens);
+    assert_eq!(streamed, golden.tokens);
+    Ok(())
+}
+
+#[test]
+fn chat_template() -> Result<()> {
+    let golden = golden("chat");
+    let user = "hello world";
+    let text = format!("<|im_start|> user {user} <|im_end|> <|im_start|> assistant");
+    assert_eq!(text, golden.text);
+    let tokens = encode(&text);
+    // <s> <|im_start|> user hello world <|im_end|> <|im_start|> assistant
+    assert_eq!(tokens, [1, 3, 7, 10, 11, 4, 3, 8]);
+    assert_eq!(tokens, golden.prompt_tokens);
+    let mut generation = greedy(load_model()?);
+    generation.push_prompt(&tokens)?;
+    let generated =
+        generation.generate(golden.tokens.len(), &StopConditions::default(), |_| Ok(()))?;
+    assert_eq!(generated, golden.tokens);
+    Ok(())
+}
+
+#[test]
+fn stop_tokens() -> Result<()> {
+    let golden = golden("prompt");
+    let tokenizer = tokenizer();
+    // Stop on the first golden token that did not appear before.
+    let (index, stop_token) = golden
+        .tokens
+        .iter()
+        .enumerate()
+        .find(|(i, t)| *i > 0 && !golden.tokens[..*i].contains(t))
+        .map(|(i, t)| (i, *t))
+        .unwrap();
+    let criteria = [
+        StopCriteria::StopTokens([stop_token].into()),
+        StopCriteria::StopTokenPattern(r"^<\|im_end\|>$".to_string()),
+    ];
+    let added_tokens = tokenizer.get_added_tokens_decoder();
+    let added_tokens = added_tokens.iter().map(|(id, t)| (*id, t.content.as_str()));
+    let stop = StopConditions::new(&criteria, added_tokens)?;
+    assert!(stop.stop_tokens().contains(&4));
+    let mut generation = greedy(load_model()?);
+    generation.push_prompt(&golden.prompt_tokens)?;
+    let tokens = generation.generate(golden.tokens.len(), &stop, |_| Ok(()))?;
+    assert_eq!(tokens, golden.tokens[..index]);
+    assert_eq!(
+        stop.check_token(stop_token),
+        Some(StopReason::Token(stop_token))
+    );
+    Ok(())
+}
+
+#[test]
+fn context_overflow_stop() -> Result<()> {
+    let golden = golden("prompt");
+    let model = load_model()?;
+    let max_seq_len = model.max_seq_len();
+    assert_eq!(max_seq_len, 256);
+    let mut generation = greedy(model);
+    generation.push_prompt(&golden.prompt_tokens)?;
+    let tokens = generation.generate(1000, &StopConditions::default(), |_| Ok(()))?;
+    // The last token is sampled from the logits of the last position of the context.
+    assert_eq!(tokens.len(), max_seq_len + 1 - golden.prompt_tokens.len());
+    assert_eq!(&tokens[..golden.tokens.len()], golden.tokens);
+    assert_eq!(generation.stop_reason(), Some(&StopReason::Length));
+    assert_eq!(generation.model().kv_cache_len(), max_seq_len);
+    // Going on stops right away, sampling directly is an error rather than a model failure.
+    let more = generation.generate(10, &StopConditions::default(), |_| Ok(()))?;
+    assert!(more.is_empty());
+    assert_eq!(generation.stop_reason(), Some(&StopReason::Length));
+    assert!(generation.sample_next(None).is_err());
+    Ok(())
+}
+
+#[test]
+fn rope_cache() -> Result<()> {
+    let path = format!("{FIXTURES}/tiny-llama.gguf");
+    let mut file = std::fs::File::open(&path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let rope_dim = content.metadata["llama.rope.dimension_count"].to_u32()? as usize;
+    let mut model = ModelWeights::from_gguf(content, &mut file, &Device::Cpu)?;
+    // A single f32 cos and sin table of rope_dim / 2 columns for the model context size.
+    let max_seq_len = model.max_seq_len();
+    assert!(max_seq_len < MAX_SEQ_LEN);
+    assert_eq!(model.rope_cache_bytes(), max_seq_len * rope_dim * 4);
+    // Positions past the context size are an error.
+    let prompt = Tensor::ones((1, max_seq_len - 1), candle::DType::U32, &Device::Cpu)?;
+    model.forward(&prompt, 0)?;
+    let input = Tensor::new(&[[1u32]], &Device::Cpu)?;
+    model.forward(&input, max_seq_len - 1)?;
+    assert!(model.forward(&input, max_seq_len).is_err());
+    Ok(())
+}
+
+#[test]
+fn context_overflow_rotate() -> Result<()> {
+    let golden = golden("prompt");
+    let model = load_model()?;
+    let max_seq_len = model.max_seq_len();
+    let sink_tokens = 4;
+    let rotate = ContextOverflow::Rotate { sink_tokens };
+    let mut generation = greedy(model).with_context_overflow(rotate);
+    let checkpoint = generation.push_prompt(&golden.prompt_tokens)?;
+    let sample_len = 2 * max_seq_len;
+    let tokens = generation.generate(sample_len, &StopConditions::default(), |_| Ok(()))?;
+    // The positions of the checkpoint have been rotated away, even though the kv cache is
+    // longer than it was at the checkpoint.
+    assert!(generation.tokens().len() > checkpoint.kv_len());
+    let err = generation.rollback_to(&checkpoint).unwrap_err().to_string();
+    assert!(err.contains("rotated since the checkpoint"), "{err}");
+    assert_eq!(tokens.len(), sample_len);
+    assert_eq!(&tokens[..golden.tokens.len()], golden.tokens);
+    assert_eq!(generation.stop_reason(), None);
+    assert!(generation.model().kv_cache_len() <= max_seq_len);
+    // The sink tokens are kept, the conversation ends with the latest generated tokens.
+    let conversation = generation.tokens();
+    assert!(conversation.len() <= max_seq_len + 1);
+    assert_eq!(
+        conversation[..sink_tokens],
+        golden.prompt_tokens[..sink_tokens]
+    );
+    assert!(conversation.ends_with(&tokens[tokens.len() - 16..]));
+
+    let rotate = ContextOverflow::Rotate {
+        sink_tokens: max_seq_len / 2,
+    };
+    let mut generation = greedy(load_model()?).with_context_overflow(rotate);
+    generation.push_prompt(&golden.prompt_tokens)?;
+    assert!(generation
+        .generate(sample_len, &StopConditions::default(), |_| Ok(()))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn pruned_vocab() -> Result<()> {
+    let golden = golden("prompt");
+    let path = format!("{FIXTURES}/tiny-llama.gguf");
+    let mut file = std::fs::File::open(&path)?;
+    let content = gguf_file::Content::read(&mut file)?;
+    let keep = (0..256)
+        .filter(|id| id % 4 == 0)
+        .chain(golden.prompt_tokens.iter().copied())
+        .collect::<Vec<u32>>();
+    let mut pruned = std::io::Cursor::new(vec![]);
+    let summary = prune_vocab(&content, &mut file, &keep, &mut pruned)?;
+    let pruned = pruned.into_inner();
+    assert_eq!(summary.original_vocab_size, 256);
+    assert!(summary.pruned_vocab_size < 256 / 4 + golden.prompt_tokens.len());
+    assert!(summary.pruned_bytes < summary.original_bytes);
+    let pruned_content = gguf_file::Content::read(&mut std::io::Cursor::new(&pruned))?;
+    let remap = VocabRemap::from_gguf(&pruned_content)?.unwrap();
+    assert_eq!(remap.vocab_size(), summary.pruned_vocab_size);
+    assert!(VocabRemap::from_gguf(&content)?.is_none());
+    // Pruning an already pruned model or keeping unknown tokens is an error.
+    let mut out = std::io::Cursor::new(vec![]);
+    let mut reader = std::io::Cursor::new(&pruned);
+    assert!(prune_vocab(&pruned_content, &mut reader, &[0], &mut out).is_err());
+    assert!(prune_vocab(&content, &mut file, &[0, 256], &mut out).is_err());
+
+    // The pruned model matches the original one with its logits restricted to the kept tokens.
+    let mut model = ModelWeights::from_gguf_bytes(&pruned, &Device::Cpu)?;
+    let mut reference = load_model()?;
+    let kept = Tensor::new(remap.original_ids(), &Device::Cpu)?;
+    let mut tokens = golden.prompt_tokens.clone();
+    let mut index_pos = 0;
+    for _ in 0..32 {
+        let input = Tensor::new(&tokens[index_pos..], &Device::Cpu)?.unsqueeze(0)?;
+        let logits = reference.forward(&input, index_pos)?.squeeze(0)?;
+        let logits = logits.index_select(&kept, 0)?;
+        let input = Tensor::new(remap.encode(&tokens[index_pos..])?, &Device::Cpu)?;
+        let pruned_logits = model.forward(&input.unsqueeze(0)?, index_pos)?.squeeze(0)?;
+        assert_eq!(logits.to_vec1::<f32>()?, pruned_logits.to_vec1::<f32>()?);
+        let next_token = pruned_logits.argmax(0)?.to_scalar::<u32>()?;
+        index_pos = tokens.len();
+        tokens.push(remap.decode(&[next_token])?[0]);
+    }
+    Ok(())
+}
+
+#[test]
+fn pruned_vocab_metadata() -> Result<()> {
+    use gguf_file::Value;
+
+    let embeddings = Tensor::arange(0f32, 8. * 32., &Device::Cpu)?.reshape((8, 32))?;
+    let embeddings = candle::quantized::QTensor::quantize(&embeddings, GgmlDType::Q8_0)?;
+    let tokens = (0..8).map(|i| Value::String(format!("t{i}"))).collect();
+    let tokens = Value::Array(tokens);
+    let metadata = [
+        ("general.architecture", Value::String("llama".to_string())),
+        ("llama.vocab_size", Value::U32(8)),
+        ("tokenizer.ggml.bos_token_id", Value::U32(1)),
+        ("tokenizer.ggml.eos_token_id", Value::U32(7)),
+        ("tokenizer.ggml.tokens", tokens),
+    ];
+    let tensors = [("token_embd.weight".to_string(), embeddings)];
+    let embeddings = &tensors[0].1;
+    let mut original = std::io::Cursor::new(gguf_bytes(&metadata, &tensors)?);
+    let content = gguf_file::Content::read(&mut original)?;
+    let mut pruned = std::io::Cursor::new(vec![]);
+    prune_vocab(&content, &mut original, &[5, 1, 3, 3], &mut pruned)?;
+    pruned.set_position(0);
+    let content = gguf_file::Content::read(&mut pruned)?;
+    let md = &content.metadata;
+    let tokens = md["tokenizer.ggml.tokens"].to_vec()?;
+    let tokens = tokens.iter().map(|t| t.to_string().unwrap().as_str());
+    assert_eq!(tokens.collect::<Vec<_>>(), ["t1", "t3", "t5"]);
+    assert_eq!(md["tokenizer.ggml.bos_token_id"].to_u32()?, 0);
+    assert!(!md.contains_key("tokenizer.ggml.eos_token_id"));
+    assert_eq!(md["llama.vocab_size"].to_u32()?, 3);
+    let remap = VocabRemap::from_gguf(&content)?.unwrap();
+    assert_eq!(remap.original_ids(), [1, 3, 5]);
+    assert_eq!(remap.encode(&[5, 1])?, [2, 0]);
+    assert!(remap.encode(&[2]).is_err());
+    assert_eq!(remap.decode(&[1])?, [3]);
+    assert!(remap.decode(&[3]).is_err());
+    let pruned = content.tensor(&mut pruned, "token_embd.weight", &Device::Cpu)?;
+    let expected = embeddings
+        .dequantize(&Device::Cpu)?
+        .index_select(&Tensor::new(&[1u32, 3, 5], &Device::Cpu)?, 0)?;
+    let pruned = pruned.dequantize(&Device::Cpu)?;
+    assert_eq!(pruned.to_vec2::<f32>()?, expected.to_vec2::<f32>()?);
+    Ok(())
+}
+
+#[test]
+fn prompt_lint() -> Result<()> {
+    use candle_transformers::prompt_lint::{check, LintConfig, LintWarning, ModelFamily};
+
+    let tokenizer = tokenizer();
+    let decode = |ids: &[u32]| tokenizer.decode(ids, false).map_err(candle::Error::msg);
+    let lint = |config: &LintConfig, text: &str| check(&encode(text), config, decode);
+    let chat = LintConfig::new(Some(ModelFamily::ChatMl), true, Some(1));
+
+    assert_eq!(lint(&chat, &golden("chat").text)?, []);
+    assert_eq!(
+        lint(&chat, "hello world")?,
+        [LintWarning::MissingTemplate {
+            family: ModelFamily::ChatMl,
+            missing: vec!["<|im_start|>"]
+        }]
+    );
+    assert_eq!(
+        lint(&chat, "<|im_start|> user hello world <|im_end|>")?,
+        [LintWarning::MissingAssistantHeader {
+            expected: "<|im_start|>assistant"
+        }]
+    );
+    // The tokenizer adds a BOS token in front of the one in the text.
+    let text = format!("<s> {}", golden("chat").text);
+    assert_eq!(
+        lint(&chat, &text)?,
+        [LintWarning::DuplicateBos { count: 2 }]
+    );
+    // Base models only get the BOS check.
+    let base = LintConfig::new(Some(ModelFamily::ChatMl), false, Some(1));
+    assert_eq!(lint(&base, "hello world")?, []);
+    assert_eq!(
+        lint(&base, "<s> hello world")?,
+        [LintWarning::DuplicateBos { count: 2 }]
+    );
+    let unknown = LintConfig::new(None, true, None);
+    assert_eq!(lint(&unknown, "<s> hello world")?, []);
+
+    let config = |metadata: &[(&str, &str)]| {
+        let metadata = metadata
+            .iter()
+            .map(|(k, v)| (*k, gguf_file::Value::String(v.to_string())))
+            .collect::<Vec<_>>();
+        LintConfig::from_gguf(&gguf_content(&metadata, &[])?)
+    };
+    let instruct = |family| LintConfig::new(Some(family), true, None);
+    assert_eq!(
+        config(&[("general.name", "Falcon3 1B Instruct")])?,
+        instruct(ModelFamily::Falcon3)
+    );
+    assert_eq!(
+        config(&[("general.name", "Meta Llama 3.1 8B Instruct")])?,
+        instruct(ModelFamily::Llama3)
+    );
+    assert_eq!(
+        config(&[("general.name", "Meta-Llama-3-8B")])?,
+        LintConfig::new(Some(ModelFamily::Llama3), false, None)
+    );
+    // The chat template takes precedence over the name.
+    let template = "{{ '<|start_header_id|>' + message['role'] + '<|end_header_id|>' }}";
+    assert_eq!(
+        config(&[
+            ("general.name", "model"),
+            ("tokenizer.chat_template", template)
+        ])?,
+        instruct(ModelFamily::Llama3)
+    );
+    Ok(())
+}
+
+#[test]
+fn regression_baseline() -> Result<()> {
+    use candle_transformers::generation::regression::{compare, record, Recording, TokenChange};
+
+    let prompts = [encode("hello"), encode("the cat sat on")];
+    let mut model = load_model()?;
+    let baseline = record(&mut model, &prompts, 8, &Device::Cpu)?;
+    assert_eq!(baseline.prompts.len(), 2);
+    assert!(baseline.prompts.iter().all(|p| p.steps.len() == 8));
+    // The same model, reused or reloaded, gives identical runs.
+    for current in [
+        record(&mut model, &prompts, 8, &Device::Cpu)?,
+        record(&mut load_model()?, &prompts, 8, &Device::Cpu)?,
+    ] {
+        let report = compare(&baseline, &current, 0.)?;
+        assert!(report.passed(), "{report}");
+        assert_eq!(report.max_logit_delta(), 0.);
+        assert!(report.prompts.iter().all(|p| p.token_change.is_none()));
+    }
+
+    let mut bytes = vec![];
+    baseline.write(&mut bytes)?;
+    // The magic, the prompt count, then for each prompt the two counts, the tokens and the steps.
+    let n_tokens = prompts.iter().map(|p| p.len()).sum::<usize>();
+    assert_eq!(bytes.len(), 8 + 4 + 2 * (4 + 4) + 4 * n_tokens + 2 * 8 * 8);
+    assert_eq!(Recording::read(&mut bytes.as_slice())?, baseline);
+    assert!(Recording::read(&mut &bytes[1..]).is_err());
+
+    // Logit drift within and above the tolerance.
+    let mut drifted = baseline.clone();
+    drifted.prompts[1].steps[2].logit += 0.005;
+    let report = compare(&baseline, &drifted, 0.01)?;
+    assert!(report.passed(), "{report}");
+    assert_eq!(report.prompts[1].max_logit_delta_step, Some(2));
+    assert!(!compare(&baseline, &drifted, 0.001)?.passed());
+
+    // Only the steps before the first token change are compared.
+    let mut changed = baseline.clone();
+    changed.prompts[0].steps[5].token += 1;
+    changed.prompts[0].steps[6].logit += 100.;
+    let report = compare(&baseline, &changed, 0.01)?;
+    assert!(!report.passed());
+    let token = baseline.prompts[0].steps[5].token;
+    let change = TokenChange {
+        step: 5,
+        baseline: token,
+        current: token + 1,
+    };
+    assert_eq!(report.prompts[0].token_change, Some(change));
+    assert_eq!(report.max_logit_delta(), 0.);
+
+    let mut nan = baseline.clone();
+    nan.prompts[0].steps[0].logit = f32::NAN;
+    assert!(!compare(&baseline, &nan, f32::MAX)?.passed());
+
+    let mut other_prompt = baseline.clone();
+    other_prompt.prompts[0].prompt.push(1);
+    assert!(compare(&baseline, &other_prompt, 0.01).is_err());
+    let fewer_steps = record(&mut model, &prompts, 4, &Device::Cpu)?;
+    assert!(compare(&baseline, &fewer_steps, 0.01).is_err());
+    Ok(())
+}
@@ -0,0 +1,330 @@
+use candle::quantized::gguf_file;
+use candle::{Device, Result};
+use candle_transformers::generation::chat::{ChatFormat, ChatMessage};
+use candle_transformers::generation::constraint::{ConstraintSchedule, Phase};
+use candle_transformers::generation::GenerationParams;
+use candle_transformers::test_support::gguf_bytes;
+use candle_transformers::validation::{
+    validate_configuration, Check, Severity, TokenizerInfo, ValidationConfig, ValidationReport,
+};
+use std::path::{Path, PathBuf};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+const GIB: u64 = 1024 * 1024 * 1024;
+
+struct Tokenizer {
+    inner: tokenizers::Tokenizer,
+    // Reported instead of the actual vocabulary size when set.
+    vocab_size: Option<usize>,
+}
+
+impl Tokenizer {
+    fn fixture() -> Self {
+        let path = format!("{FIXTURES}/tiny-llama-tokenizer.json");
+        let inner = tokenizers::Tokenizer::from_file(path).unwrap();
+        Self {
+            inner,
+            vocab_size: None,
+        }
+    }
+}
+
+impl TokenizerInfo for Tokenizer {
+    fn vocab_size(&self) -> usize {
+        self.vocab_size
+            .unwrap_or_else(|| self.inner.get_vocab_size(true))
+    }
+
+    fn added_tokens(&self) -> Vec<(u32, String)> {
+        let added_tokens = self.inner.get_added_tokens_decoder();
+        added_tokens
+            .into_iter()
+            .map(|(id, t)| (id, t.content))
+            .collect()
+    }
+
+    fn encode(&self, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+        let tokens = self
+            .inner
+            .encode(text, add_special_tokens)
+            .map_err(candle::Error::msg)?;
+        Ok(tokens.get_ids().to_vec())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.inner.decode(tokens, false).map_err(candle::Error::msg)
+    }
+}
+
+// Renders the messages as `role: content` lines, repeated `repeat` times.
+struct LinesFormat {
+    repeat: usize,
+}
+
+impl ChatFormat for LinesFormat {
+    fn render(&self, messages: &[ChatMessage]) -> String {
+        let lines = messages
+            .iter()
+            .map(|m| format!("{:?}: {}\n", m.role, m.content))
+            .collect::<String>();
+        lines.repeat(self.repeat)
+    }
+
+    fn encode(&self, _text: &str) -> Result<Vec<u32>> {
+        unreachable!()
+    }
+
+    fn decode(&self, _tokens: &[u32]) -> Result<String> {
+        unreachable!()
+    }
+}
+
+fn fixture() -> PathBuf {
+    Path::new(FIXTURES).join("tiny-llama.gguf")
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "candle-validation-{name}-{}.gguf",
+        std::process::id()
+    ))
+}
+
+// The checks with errors, in report order.
+fn failed_checks(report: &ValidationReport) -> Vec<Check> {
+    report.errors().map(|e| e.check).collect()
+}
+
+#[test]
+fn valid_configuration() -> Result<()> {
+    let model = fixture();
+    let tokenizer = Tokenizer::fixture();
+    let format = LinesFormat { repeat: 1 };
+    let config = ValidationConfig {
+        tokenizer: Some(&tokenizer),
+        chat_format: Some(&format),
+        params: GenerationParams::default()
+            .with_max_tokens(32)
+            .with_stop_token_pattern("^<\\|.*\\|>$"),
+        schedule: Some(ConstraintSchedule::new(vec![Phase::OneOf {
+            options: vec!["then".to_string(), "home".to_string()],
+        }])),
+        patterns: vec!["(?i)password".to_string()],
+        free_bytes: Some(8 * GIB),
+        reserve_bytes: GIB,
+        ..ValidationConfig::new(&model, &Device::Cpu)
+    };
+    let report = validate_configuration(&config)?;
+    assert!(report.is_valid(), "{report}");
+    assert_eq!(report.warnings().count(), 0, "{report}");
+
+    let memory = report.memory.unwrap();
+    let context = report.context.unwrap();
+    assert_eq!(context.effective, 256);
+    assert!(!context.is_capped());
+    assert_eq!(
+        report.projected_bytes(),
+        Some(memory.weights_bytes + memory.embeddings_bytes() + context.kv_bytes)
+    );
+    let prompt_tokens = report.sample_prompt_tokens.unwrap();
+    assert!(prompt_tokens > 10, "{prompt_tokens}");
+    assert!(report.to_string().ends_with("the configuration is valid"));
+
+    // Asking for more tokens than fit after the sample conversation is only a warning.
+    let config = ValidationConfig {
+        params: GenerationParams::default().with_max_tokens(1000),
+        ..config
+    };
+    let report = validate_configuration(&config)?;
+    assert!(report.is_valid(), "{report}");
+    let warnings = report.warnings().collect::<Vec<_>>();
+    assert_eq!(warnings.len(), 1);
+    assert_eq!(warnings[0].check, Check::Context);
+    let expected = format!("only {} of the 1000", 256 - prompt_tokens);
+    assert!(warnings[0].message.contains(&expected), "{}", warnings[0]);
+    Ok(())
+}
+
+#[test]
+fn model_file_errors() -> Result<()> {
+    let missing = Path::new(FIXTURES).join("missing.gguf");
+    let report = validate_configuration(&ValidationConfig::new(&missing, &Device::Cpu))?;
+    assert_eq!(failed_checks(&report), [Check::Model]);
+    assert!(report.memory.is_none());
+
+    let not_gguf = Path::new(FIXTURES).join("tiny-llama-tokenizer.json");
+    let report = validate_configuration(&ValidationConfig::new(&not_gguf, &Device::Cpu))?;
+    assert_eq!(failed_checks(&report), [Check::Model]);
+    assert!(report.errors().next().unwrap().message.contains("gguf"));
+
+    // The header is intact but the tensor data is cut.
+    let bytes = std::fs::read(fixture())?;
+    let truncated = temp_path("truncated");
+    std::fs::write(&truncated, &bytes[..bytes.len() - 100])?;
+    let report = validate_configuration(&ValidationConfig::new(&truncated, &Device::Cpu));
+    std::fs::remove_file(&truncated)?;
+    let report = report?;
+    assert_eq!(failed_checks(&report), [Check::Model]);
+    assert!(report
+        .errors()
+        .next()
+        .unwrap()
+        .message
+        .contains("truncated"));
+    Ok(())
+}
+
+#[test]
+fn metadata_errors() -> Result<()> {
+    use gguf_file::Value;
+
+    let write = |name: &str, metadata: &[(&str, Value)]| -> Result<PathBuf> {
+        let path = temp_path(name);
+        std::fs::write(&path, gguf_bytes(metadata, &[])?)?;
+        Ok(path)
+    };
+    let path = write(
+        "gpt2",
+        &[("general.architecture", Value::String("gpt2".to_string()))],
+    )?;
+    let report = validate_configuration(&ValidationConfig::new(&path, &Device::Cpu));
+    std::fs::remove_file(&path)?;
+    let report = report?;
+    assert_eq!(failed_checks(&report), [Check::Metadata]);
+    assert!(report.errors().next().unwrap().message.contains("gpt2"));
+
+    let path = write(
+        "incomplete",
+        &[
+            ("general.architecture", Value::String("llama".to_string())),
+            ("llama.block_count", Value::U32(2)),
+            ("llama.attention.head_count", Value::U32(4)),
+        ],
+    )?;
+    let report = validate_configuration(&ValidationConfig::new(&path, &Device::Cpu));
+    std::fs::remove_file(&path)?;
+    let report = report?;
+    let messages = report
+        .entries_for(Check::Metadata)
+        .map(|e| e.message.as_str())
+        .collect::<Vec<_>>();
+    assert!(messages[0].contains("llama.embedding_length"), "{report}");
+    assert!(!messages[0].contains("llama.block_count"), "{report}");
+    assert!(messages[1].contains("blk.1.attn_output.weight"), "{report}");
+    Ok(())
+}
+
+#[test]
+fn tokenizer_and_template_errors() -> Result<()> {
+    let model = fixture();
+    let actual_vocab = Tokenizer::fixture().vocab_size();
+    let too_large = Tokenizer {
+        vocab_size: Some(actual_vocab + 10),
+        ..Tokenizer::fixture()
+    };
+    let config = ValidationConfig {
+        tokenizer: Some(&too_large),
+        ..ValidationConfig::new(&model, &Device::Cpu)
+    };
+    let report = validate_configuration(&config)?;
+    assert_eq!(failed_checks(&report), [Check::Tokenizer]);
+
+    let too_small = Tokenizer {
+        vocab_size: Some(actual_vocab - 10),
+        ..Tokenizer::fixture()
+    };
+    let config = ValidationConfig {
+        tokenizer: Some(&too_small),
+        ..config
+    };
+    let report = validate_configuration(&config)?;
+    assert!(report.is_valid(), "{report}");
+    let entry = report.entries_for(Check::Tokenizer).next().unwrap();
+    assert_eq!(entry.severity, Severity::Warning);
+
+    // A template that renders nothing, and one whose output does not fit in the context.
+    let tokenizer = Tokenizer::fixture();
+    let empty = LinesFormat { repeat: 0 };
+    let config = ValidationConfig {
+        tokenizer: Some(&tokenizer),
+        chat_format: Some(&empty),
+        ..ValidationConfig::new(&model, &Device::Cpu)
+    };
+    let report = validate_configuration(&config)?;
+    assert_eq!(failed_checks(&report), [Check::ChatTemplate]);
+    assert!(report.sample_prompt_tokens.is_none());
+
+    let long = LinesFormat { repeat: 100 };
+    let config = ValidationConfig {
+        chat_format: Some(&long),
+        ..config
+    };
+    let report = validate_configuration(&config)?;
+    assert_eq!(failed_checks(&report), [Check::Context]);
+    assert!(report.sample_prompt_tokens.unwrap() > 256);
+    Ok(())
+}
+
+#[test]
+fn generation_errors() -> Result<()> {
+    let model = fixture();
+    let tokenizer = Tokenizer::fixture();
+    let config = ValidationConfig {
+        tokenizer: Some(&tokenizer),
+        params: GenerationParams::default()
+            .with_top_p(1.5)
+            .with_stop_token_pattern("<|("),
+        schedule: Some(ConstraintSchedule::new(vec![Phase::OneOf {
+            options: vec!["search".to_string(), "search".to_string()],
+        }])),
+        patterns: vec!["ok".to_string(), "[unclosed".to_string()],
+        ..ValidationConfig::new(&model, &Device::Cpu)
+    };
+    let report = validate_configuration(&config)?;
+    assert_eq!(
+        failed_checks(&report),
+        [
+            Check::Generation,
+            Check::StopCriteria,
+            Check::Schedule,
+            Check::Pattern
+        ]
+    );
+    let pattern = report.entries_for(Check::Pattern).next().unwrap();
+    assert!(pattern.message.contains("[unclosed"), "{pattern}");
+    Ok(())
+}
+
+#[test]
+fn memory_errors() -> Result<()> {
+    let model = fixture();
+    let config = ValidationConfig {
+        free_bytes: Some(GIB),
+        reserve_bytes: GIB,
+        ..ValidationConfig::new(&model, &Device::Cpu)
+    };
+    let report = validate_configuration(&config)?;
+    // On cpu the weights not fitting in the free memory is only a warning, and the context is
+    // then not capped by the free memory.
+    assert!(report.is_valid(), "{report}");
+    let warnings = report.warnings().map(|e| e.check).collect::<Vec<_>>();
+    assert_eq!(warnings, [Check::Memory]);
+    assert!(report.memory.is_some());
+    let context = report.context.unwrap();
+    assert!(!context.is_capped());
+
+    // A budget of 64 positions caps the context, this is only a warning. The budget still
+    // applies when the weights do not fit in the free memory.
+    let kv_bytes_per_token = report.memory.unwrap().kv.bytes_per_token();
+    let config = ValidationConfig {
+        kv_budget_bytes: Some(64 * kv_bytes_per_token),
+        context_length: Some(100_000),
+        ..config
+    };
+    let report = validate_configuration(&config)?;
+    assert!(report.is_valid(), "{report}");
+    let warnings = report.warnings().map(|e| e.check).collect::<Vec<_>>();
+    assert_eq!(warnings, [Check::Memory, Check::Context, Check::Memory]);
+    assert_eq!(report.context.unwrap().effective, 64);
+    Ok(())
+}
@@ -52,6 +52,7 @@ enum Quantization {
     Q6k,
     Q8k,
     F16,
+    Bf16,
     F32,
 }
 
@@ -71,6 +72,7 @@ impl Quantization {
             Quantization::Q6k => GgmlDType::Q6K,
             Quantization::Q8k => GgmlDType::Q8K,
             Quantization::F16 => GgmlDType::F16,
+            Quantization::Bf16 => GgmlDType::BF16,
             Quantization::F32 => GgmlDType::F32,
         }
     }